@@ -0,0 +1,11 @@
+//! Arbitrary advertisement bytes, as they come off the air, into
+//! `unpack_frame`: it must never panic, and whatever it accepts must repack
+//! to the same bytes.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    ble_chirp::fuzz_unpack_frame(data);
+});
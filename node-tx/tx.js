@@ -1,26 +1,36 @@
 // node-tx/tx.js
 const bleno  = require('@abandonware/bleno');
 const crypto = require('node:crypto');
+const fs     = require('node:fs');
 
 const COMPANY_ID = 0xFFFF;
-const VER = 1;
+const VER = 9;        // encrypted
+const VER_PLAIN = 11; // unencrypted, trailing CRC16
+const MSG_ID_LEN = 6;
 const MAX_PAYLOAD = 20; // final per-frame payload size limit
 
 function parseArgs() {
   const args = process.argv.slice(2);
-  let topic = 7, ttl = 3, passphrase = null, textParts = [];
+  let topic = 7, ttl = 3, keyFile = null, textParts = [];
   for (let i = 0; i < args.length; i++) {
     const a = args[i];
     if (a === '--topic') topic = parseInt(args[++i], 10);
     else if (a.startsWith('--topic=')) topic = parseInt(a.split('=')[1], 10);
     else if (a === '--ttl') ttl = parseInt(args[++i], 10);
     else if (a.startsWith('--ttl=')) ttl = parseInt(a.split('=')[1], 10);
-    else if (a === '--pass' || a === '--passphrase') passphrase = args[++i];
-    else if (a.startsWith('--pass=')) passphrase = a.split('=')[1];
+    else if (a === '--key-file') keyFile = args[++i];
+    else if (a.startsWith('--key-file=')) keyFile = a.split('=')[1];
+    else if (a === '--pass' || a === '--passphrase' || a.startsWith('--pass=') || a.startsWith('--passphrase=')) {
+      // The Rust side derives passphrase keys with Argon2id, which Node has no
+      // built-in for; a key from anything else would never decrypt. Adding it
+      // means matching `crypto::salt_for` too (SHA-256 of the room name), which
+      // the Rust tests pin.
+      throw new Error('--pass is not supported by tx.js; share a key file instead (`ble-chirp keygen <file>`) and pass --key-file <file>');
+    }
     else textParts.push(a);
   }
   if (textParts.length === 0) textParts = ['hello from node'];
-  return { topic, ttl, passphrase, text: textParts.join(' ') };
+  return { topic, ttl, keyFile, text: textParts.join(' ') };
 }
 
 function chunk(buf, size) {
@@ -29,42 +39,82 @@ function chunk(buf, size) {
   return out;
 }
 
-function packFrame({ topic, ttl, msgId, seq, tot, payload }) {
-  const b = Buffer.alloc(2 + 1 + 1 + 1 + 4 + 1 + 1 + payload.length);
+// CRC-16/CCITT-FALSE, as in the Rust `crc16_ccitt`.
+function crc16(buf) {
+  let crc = 0xffff;
+  for (const byte of buf) {
+    crc ^= byte << 8;
+    for (let i = 0; i < 8; i++) crc = crc & 0x8000 ? ((crc << 1) ^ 0x1021) & 0xffff : (crc << 1) & 0xffff;
+  }
+  return crc;
+}
+
+function packFrame({ topic, ttl, msgId, seq, tot, tsMs, payload, plain }) {
+  const b = Buffer.alloc(2 + 1 + 1 + 1 + 1 + 1 + MSG_ID_LEN + 1 + 1 + 8 + payload.length + (plain ? 2 : 0));
   b.writeUInt16LE(COMPANY_ID, 0);
   let off = 2;
-  b.writeUInt8(VER, off++);      // ver
+  b.writeUInt8(plain ? VER_PLAIN : VER, off++); // ver
   b.writeUInt8(topic, off++);    // topic
   b.writeUInt8(ttl, off++);      // ttl
-  msgId.copy(b, off); off += 4;  // msgId
+  b.writeUInt8(ttl, off++);      // initial ttl
+  b.writeUInt8(0, off++);        // kind: data
+  msgId.copy(b, off); off += MSG_ID_LEN; // msgId
   b.writeUInt8(seq, off++);      // seq
   b.writeUInt8(tot, off++);      // tot
-  payload.copy(b, off);
+  b.writeBigUInt64LE(tsMs, off); off += 8; // send time, unix ms
+  payload.copy(b, off); off += payload.length;
+  if (plain) b.writeUInt16LE(crc16(b.subarray(2, off)), off);
   return b;
 }
 
-function deriveKey(passphrase) {
-  if (!passphrase) return null;
-  return crypto.createHash('sha256').update(passphrase, 'utf8').digest(); // 32 bytes
+// Same formats as the Rust `--key-file`: 32 raw bytes, or 32 bytes as hex or base64.
+function loadKeyFile(path) {
+  if (!path) return null;
+  const raw = fs.readFileSync(path);
+  if (raw.length === 32) return raw;
+  const line = raw.toString('utf8').trim();
+  const key = /^[0-9a-fA-F]{64}$/.test(line) ? Buffer.from(line, 'hex') : Buffer.from(line, 'base64');
+  if (key.length !== 32) throw new Error(`key file ${path} must hold 32 raw bytes or 32 bytes as hex/base64`);
+  return key;
+}
+
+// Header bytes bound into the AEAD, as in the Rust `Frame::aad`: version,
+// topic, initial ttl, flags, msgId, seq, tot, timestamp. The current ttl is
+// left out because relays decrement it.
+function aad({ topic, ttl, msgId, seq, tot, tsMs }) {
+  const b = Buffer.alloc(4 + MSG_ID_LEN + 2 + 8);
+  let off = 0;
+  b.writeUInt8(VER, off++);
+  b.writeUInt8(topic, off++);
+  b.writeUInt8(ttl, off++);
+  b.writeUInt8(0, off++); // flags
+  msgId.copy(b, off); off += MSG_ID_LEN;
+  b.writeUInt8(seq, off++);
+  b.writeUInt8(tot, off++);
+  b.writeBigUInt64LE(tsMs, off);
+  return b;
 }
 
-function encrypt(key, msgId, seq, payload) {
-  // Nonce: 12 bytes, msgId in first 4, seq at index 4, rest zeroed
+function encrypt(key, hdr, payload) {
+  // Nonce: 12 bytes, msgId in first 6, seq at index 6, topic at 7, rest zeroed
   const nonce = Buffer.alloc(12, 0);
-  msgId.copy(nonce, 0, 0, 4);
-  nonce[4] = seq & 0xff;
+  hdr.msgId.copy(nonce, 0, 0, MSG_ID_LEN);
+  nonce[MSG_ID_LEN] = hdr.seq & 0xff;
+  nonce[MSG_ID_LEN + 1] = hdr.topic & 0xff;
   const cipher = crypto.createCipheriv('chacha20-poly1305', key, nonce, { authTagLength: 16 });
+  cipher.setAAD(aad(hdr), { plaintextLength: payload.length });
   const ct = Buffer.concat([cipher.update(payload), cipher.final()]);
   const tag = cipher.getAuthTag();
   return Buffer.concat([ct, tag]); // matches Rust (ct || tag)
 }
 
 async function main() {
-  const { topic, ttl, passphrase, text } = parseArgs();
-  const key   = deriveKey(passphrase);
+  const { topic, ttl, keyFile, text } = parseArgs();
+  const key   = loadKeyFile(keyFile);
   const msg   = Buffer.from(text, 'utf8');
-  const msgId = crypto.randomBytes(4);
-  const ENC_OVERHEAD = key ? 16 : 0;
+  const msgId = crypto.randomBytes(MSG_ID_LEN);
+  const tsMs  = BigInt(Date.now());
+  const ENC_OVERHEAD = key ? 16 : 2; // AEAD tag or CRC
   const CHUNK_SIZE = Math.max(1, MAX_PAYLOAD - ENC_OVERHEAD);
   const parts = chunk(msg, CHUNK_SIZE);
 
@@ -72,8 +122,9 @@ async function main() {
   console.log(`Advertising topic=${topic} ttl=${ttl} chunks=${parts.length}${key ? ' (encrypted)' : ''}`);
 
   for (let i = 0; i < parts.length; i++) {
-    const payload = key ? encrypt(key, msgId, i, parts[i]) : parts[i];
-    const frame = packFrame({ topic, ttl, msgId, seq: i, tot: parts.length, payload });
+    const hdr = { topic, ttl, msgId, seq: i, tot: parts.length, tsMs };
+    const payload = key ? encrypt(key, hdr, parts[i]) : parts[i];
+    const frame = packFrame({ ...hdr, payload, plain: !key });
     bleno.startAdvertising('chirp', [], { manufacturerData: frame }, err => {
       if (err) console.error('adv err', err);
     });
@@ -85,4 +136,7 @@ async function main() {
   process.exit(0);
 }
 
-main().catch(console.error);
+main().catch(e => {
+  console.error(e.message ?? e);
+  process.exit(1);
+});
@@ -0,0 +1,104 @@
+//! Delivery receipts: with `--ack-log`, chat appends one JSON line for each
+//! acknowledgement of a message it sent, the first time each receiver acks
+//! it. The file is an audit trail of who received what, and when.
+//!
+//! An acker is identified by its identity public key, or, if it runs without
+//! `--identity`, by an id it picked at random for that run. Nothing in an ack
+//! proves which of the two it is.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::mesh::Ack;
+
+/// One logged acknowledgement.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    /// Hex `msg_id` of our message.
+    pub msg_id: String,
+    /// Hex key, or per-run id, of the receiver who acked it.
+    pub acker: String,
+    /// Unix ms the ack was heard.
+    pub ts: u64,
+}
+
+impl Receipt {
+    pub fn new(ack: &Ack, ts: u64) -> Self {
+        Self {
+            msg_id: hex::encode(ack.msg_id),
+            acker: hex::encode(ack.acker),
+            ts,
+        }
+    }
+}
+
+/// The receipt log, open for appending.
+pub struct AckLog {
+    file: File,
+}
+
+impl AckLog {
+    /// Open `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    /// Write `receipt` as one line, flushed so a crash can't lose it.
+    pub fn append(&mut self, receipt: &Receipt) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(receipt)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::ACKER_LEN;
+
+    #[test]
+    fn receipts_are_appended_one_per_line() {
+        let path = std::env::temp_dir().join(format!("ack-log-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let acks = [
+            Ack {
+                msg_id: [1; 6],
+                acker: [0xaa; ACKER_LEN],
+            },
+            Ack {
+                msg_id: [1; 6],
+                acker: [0xbb; ACKER_LEN],
+            },
+        ];
+        // Reopened in between, as by a second run of chat.
+        for (i, ack) in acks.iter().enumerate() {
+            AckLog::open(&path)
+                .unwrap()
+                .append(&Receipt::new(ack, 1000 + i as u64))
+                .unwrap();
+        }
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let logged: Vec<Receipt> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let want = |acker: &str, ts| Receipt {
+            msg_id: "010101010101".into(),
+            acker: acker.repeat(ACKER_LEN),
+            ts,
+        };
+        assert_eq!(logged, [want("aa", 1000), want("bb", 1001)]);
+    }
+}
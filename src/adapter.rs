@@ -0,0 +1,111 @@
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use anyhow::Context;
+use btleplug::api::{Central, Manager as _};
+use btleplug::platform::{Adapter, Manager};
+use tokio::time::sleep;
+
+use crate::transport::Transport;
+
+const REBIND_ATTEMPTS: u32 = 30;
+const REBIND_DELAY: Duration = Duration::from_secs(2);
+
+pub async fn resolve(index: usize) -> anyhow::Result<Adapter> {
+    let manager = Manager::new().await.context("btleplug Manager::new")?;
+    let adapters = manager.adapters().await.context("list adapters")?;
+    let adapter = adapters
+        .get(index)
+        .ok_or_else(|| anyhow::anyhow!("adapter {} not found", index))?
+        .clone();
+    Ok(adapter)
+}
+
+/// `list-adapters`: print each adapter with its `--adapter` index.
+pub async fn list() -> anyhow::Result<()> {
+    let manager = Manager::new().await.context("btleplug Manager::new")?;
+    let adapters = manager.adapters().await.context("list adapters")?;
+    let mut found = Vec::with_capacity(adapters.len());
+    for a in &adapters {
+        let info = a
+            .adapter_info()
+            .await
+            .unwrap_or_else(|e| format!("(no info: {e})"));
+        found.push((info, a.can_advertise().await));
+    }
+    print!("{}", format_adapters(&found, cfg!(target_os = "macos")));
+    Ok(())
+}
+
+/// The `list-adapters` listing of `adapters`, as (info, can advertise) in
+/// `--adapter` order, with the hint for macOS if `macos`.
+fn format_adapters(adapters: &[(String, bool)], macos: bool) -> String {
+    if adapters.is_empty() {
+        return "No BLE adapters found.\n".to_string();
+    }
+    let mut out = String::new();
+    for (index, (info, can_advertise)) in adapters.iter().enumerate() {
+        let can_advertise = if *can_advertise { "yes" } else { "no" };
+        let _ = writeln!(out, "{index}: {info} (advertising: {can_advertise})");
+    }
+    if macos {
+        out.push_str(
+            "btleplug can't advertise on macOS: these adapters can rx but not tx or relay.\n",
+        );
+    }
+    out
+}
+
+/// Identity used to find the same adapter again after a hot-unplug (indices
+/// may shift when a dongle comes back).
+pub async fn identity(adapter: &Adapter) -> String {
+    adapter.adapter_info().await.unwrap_or_default()
+}
+
+/// Wait for an adapter matching `info` to reappear and return a fresh handle.
+pub async fn rebind(info: &str) -> anyhow::Result<Adapter> {
+    for attempt in 1..=REBIND_ATTEMPTS {
+        if let Ok(manager) = Manager::new().await
+            && let Ok(adapters) = manager.adapters().await
+        {
+            for a in adapters {
+                if a.adapter_info().await.is_ok_and(|i| i == info) {
+                    eprintln!("adapter {info} re-acquired (attempt {attempt})");
+                    return Ok(a);
+                }
+            }
+        }
+        sleep(REBIND_DELAY).await;
+    }
+    anyhow::bail!("adapter {info} did not come back after {REBIND_ATTEMPTS} attempts")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_adapters_is_said_plainly() {
+        assert_eq!(format_adapters(&[], false), "No BLE adapters found.\n");
+        assert_eq!(format_adapters(&[], true), "No BLE adapters found.\n");
+    }
+
+    #[test]
+    fn each_adapter_is_listed_by_its_index() {
+        let adapters = [
+            ("hci0 (usb:v1D6Bp0246d0537)".to_string(), true),
+            ("hci1".to_string(), false),
+        ];
+        assert_eq!(
+            format_adapters(&adapters, false),
+            "0: hci0 (usb:v1D6Bp0246d0537) (advertising: yes)\n\
+             1: hci1 (advertising: no)\n"
+        );
+        let mac = format_adapters(&adapters[1..], true);
+        assert_eq!(
+            mac,
+            "0: hci1 (advertising: no)\n\
+             btleplug can't advertise on macOS: these adapters can rx but not tx or relay.\n"
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::time::sleep;
+
+use crate::mesh::tag_message;
+use crate::{TxOpts, crypto, signed_len, tx, validate_chunk_size};
+
+/// Where each tick's message comes from. A file is re-read on every tick so
+/// the broadcast content can be updated while the beacon runs; an inline
+/// message is expected to be tagged already.
+pub enum MsgSource {
+    Inline(String),
+    File {
+        path: PathBuf,
+        nick: Option<String>,
+        subtopic: Option<String>,
+    },
+}
+
+impl MsgSource {
+    pub fn read(&self) -> anyhow::Result<String> {
+        match self {
+            MsgSource::Inline(m) => Ok(m.clone()),
+            MsgSource::File {
+                path,
+                nick,
+                subtopic,
+            } => {
+                let m = std::fs::read_to_string(path)
+                    .with_context(|| format!("read message file {}", path.display()))?;
+                Ok(tag_message(
+                    nick.as_deref(),
+                    subtopic.as_deref(),
+                    m.trim_end_matches(['\r', '\n']),
+                ))
+            }
+        }
+    }
+}
+
+pub enum Spec {
+    Every(Duration),
+    #[cfg(feature = "cron")]
+    Cron(Box<cron::Schedule>),
+}
+
+impl Spec {
+    #[cfg(feature = "cron")]
+    pub fn cron(expr: &str) -> anyhow::Result<Self> {
+        use std::str::FromStr;
+        let s = cron::Schedule::from_str(expr)
+            .map_err(|e| anyhow::anyhow!("bad cron expression {expr:?}: {e}"))?;
+        Ok(Spec::Cron(Box::new(s)))
+    }
+
+    fn next_delay(&self) -> Option<Duration> {
+        match self {
+            Spec::Every(d) => Some(*d),
+            #[cfg(feature = "cron")]
+            Spec::Cron(s) => {
+                let next = s.upcoming(chrono::Utc).next()?;
+                Some((next - chrono::Utc::now()).to_std().unwrap_or_default())
+            }
+        }
+    }
+}
+
+/// Broadcast `source` at every tick of `spec` until Ctrl-C. A tick is skipped
+/// if the previous broadcast is still on the air.
+pub async fn run(
+    adapter: btleplug::platform::Adapter,
+    opts: TxOpts,
+    key: Option<crypto::Keys>,
+    id_key: Option<crypto::SigningKey>,
+    source: MsgSource,
+    spec: Spec,
+) -> anyhow::Result<()> {
+    let mut inflight: Option<tokio::task::JoinHandle<()>> = None;
+    loop {
+        if inflight.as_ref().is_some_and(|h| !h.is_finished()) {
+            eprintln!("beacon: previous broadcast still running, skipping tick");
+        } else {
+            match source.read().and_then(|msg| {
+                validate_chunk_size(
+                    opts.chunk_size,
+                    key.is_some(),
+                    signed_len(msg.as_bytes(), id_key.as_ref()),
+                )?;
+                Ok(msg)
+            }) {
+                Ok(msg) => {
+                    let adapter = adapter.clone();
+                    let (key, id_key) = (key.clone(), id_key.clone());
+                    inflight = Some(tokio::spawn(async move {
+                        if let Err(e) = tx(
+                            adapter,
+                            &opts,
+                            msg.as_bytes(),
+                            key.as_ref(),
+                            id_key.as_ref(),
+                        )
+                        .await
+                        {
+                            eprintln!("beacon tx err: {e}");
+                        }
+                    }));
+                }
+                Err(e) => eprintln!("beacon: {e:#}"),
+            }
+        }
+
+        let Some(delay) = spec.next_delay() else {
+            break;
+        };
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    // Let an in-progress broadcast finish so the advertiser isn't left running.
+    if let Some(h) = inflight {
+        let _ = h.await;
+    }
+    println!("Beacon stopped.");
+    Ok(())
+}
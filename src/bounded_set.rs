@@ -0,0 +1,124 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+/// Insertion-ordered set that forgets its oldest entries past `cap`.
+pub struct BoundedSet<T> {
+    set: HashSet<T>,
+    order: VecDeque<T>,
+    cap: usize,
+}
+
+impl<T: Clone + Eq + Hash> BoundedSet<T> {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            set: HashSet::with_capacity(cap),
+            order: VecDeque::with_capacity(cap),
+            cap: cap.max(1),
+        }
+    }
+
+    pub fn contains(&self, v: &T) -> bool {
+        self.set.contains(v)
+    }
+
+    /// Returns false if `v` was already present.
+    pub fn insert(&mut self, v: T) -> bool {
+        if !self.set.insert(v.clone()) {
+            return false;
+        }
+        if self.order.len() >= self.cap
+            && let Some(old) = self.order.pop_front()
+        {
+            self.set.remove(&old);
+        }
+        self.order.push_back(v);
+        true
+    }
+}
+
+/// Insertion-ordered map that forgets its oldest keys past `cap`.
+pub struct BoundedMap<K, V> {
+    map: HashMap<K, V>,
+    order: VecDeque<K>,
+    cap: usize,
+}
+
+impl<K: Clone + Eq + Hash, V> BoundedMap<K, V> {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            map: HashMap::with_capacity(cap),
+            order: VecDeque::with_capacity(cap),
+            cap: cap.max(1),
+        }
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+        self.map.get(k)
+    }
+
+    pub fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        self.map.get_mut(k)
+    }
+
+    /// Overwriting an existing key keeps its original age.
+    pub fn insert(&mut self, k: K, v: V) {
+        if self.map.insert(k.clone(), v).is_some() {
+            return;
+        }
+        if self.order.len() >= self.cap
+            && let Some(old) = self.order.pop_front()
+        {
+            self.map.remove(&old);
+        }
+        self.order.push_back(k);
+    }
+
+    /// Forget `k`. Cheapest when it is the newest key.
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let v = self.map.remove(k)?;
+        if let Some(i) = self.order.iter().rposition(|o| o == k) {
+            self.order.remove(i);
+        }
+        Some(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_full_set_forgets_its_oldest_entry() {
+        let mut set = BoundedSet::new(2);
+        assert!(set.insert(1));
+        assert!(set.insert(2));
+        assert!(!set.insert(1), "already present");
+        assert!(set.insert(3));
+        assert!(!set.contains(&1));
+        assert!(set.contains(&2) && set.contains(&3));
+    }
+
+    #[test]
+    fn a_full_map_forgets_its_oldest_key() {
+        let mut map = BoundedMap::new(2);
+        map.insert('a', 1);
+        map.insert('b', 2);
+        // Overwriting keeps 'a' the oldest.
+        map.insert('a', 10);
+        map.insert('c', 3);
+        assert_eq!(map.get(&'a'), None);
+        assert_eq!((map.get(&'b'), map.get(&'c')), (Some(&2), Some(&3)));
+    }
+
+    #[test]
+    fn a_removed_key_comes_back_as_the_newest() {
+        let mut map = BoundedMap::new(2);
+        map.insert('a', 1);
+        map.insert('b', 2);
+        assert_eq!(map.remove(&'a'), Some(1));
+        map.insert('a', 3);
+        map.insert('c', 4);
+        assert_eq!(map.get(&'b'), None);
+        assert_eq!(map.get(&'a'), Some(&3));
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use btleplug::api::{Central, CentralEvent, ScanFilter};
+use futures::StreamExt;
+use tokio::time::sleep;
+
+use crate::compress::Compress;
+use crate::fec::Fec;
+use crate::{COMPANY_ID, MAX_PAYLOAD, MsgId, TxOpts, tx, unpack_frame};
+
+const DWELL_LADDER_MS: [u64; 5] = [500, 300, 200, 100, 50];
+const GAP_LADDER_MS: [u64; 2] = [60, 20];
+const MIN_RECEPTION: f64 = 0.95;
+
+/// Advertise bursts of distinct frames on `tx_adapter` while `rx_adapter`
+/// scans, stepping down dwell/gap until reception falls below 95%, and
+/// recommend the fastest setting that stayed above it.
+pub async fn calibrate(
+    tx_adapter: btleplug::platform::Adapter,
+    rx_adapter: btleplug::platform::Adapter,
+    topic: u8,
+    frames: u8,
+) -> anyhow::Result<()> {
+    let seen: Arc<Mutex<HashSet<(MsgId, u8)>>> = Arc::default();
+    rx_adapter.start_scan(ScanFilter::default()).await?;
+    let mut events = rx_adapter.events().await?;
+    let seen_rx = seen.clone();
+    tokio::spawn(async move {
+        while let Some(evt) = events.next().await {
+            if let CentralEvent::ManufacturerDataAdvertisement {
+                manufacturer_data, ..
+            } = evt
+                && let Some(f) = manufacturer_data
+                    .get(&COMPANY_ID)
+                    .and_then(|md| unpack_frame(md).ok())
+                && f.topic == topic
+            {
+                seen_rx.lock().unwrap().insert((f.msg_id, f.seq));
+            }
+        }
+    });
+
+    // `frames` chunks of filler; tx picks a fresh msg_id per trial.
+    let msg = "x".repeat(frames as usize * MAX_PAYLOAD);
+    let mut best: Option<(u64, u64)> = None;
+    println!("dwell_ms gap_ms  received");
+    for dwell_ms in DWELL_LADDER_MS {
+        for gap_ms in GAP_LADDER_MS {
+            seen.lock().unwrap().clear();
+            let opts = TxOpts {
+                topic,
+                ttl: 0,
+                dwell_ms,
+                gap_ms,
+                repeats: 1,
+                // Don't let the limiter mask what the radio can do.
+                rate: 1000.0,
+                burst: None,
+                adaptive: false,
+                rotate_in_place: false,
+                repeat_for: None,
+                chunk_size: MAX_PAYLOAD,
+                per_message_key: false,
+                private_topic: false,
+                compress: Compress::Never,
+                fec: Fec::None,
+                presence: false,
+            };
+            tx(tx_adapter.clone(), &opts, msg.as_bytes(), None, None).await?;
+            // Give the scanner a moment to drain late events.
+            sleep(Duration::from_millis(500)).await;
+            let got = seen.lock().unwrap().len();
+            let ratio = got as f64 / frames as f64;
+            println!("{dwell_ms:>8} {gap_ms:>6}  {got}/{frames}");
+            if ratio >= MIN_RECEPTION && best.is_none_or(|(d, g)| dwell_ms + gap_ms < d + g) {
+                best = Some((dwell_ms, gap_ms));
+            }
+        }
+    }
+
+    match best {
+        Some((dwell_ms, gap_ms)) => {
+            let rate = 1000.0 / (dwell_ms + gap_ms) as f64;
+            println!(
+                "recommended: --dwell-ms {dwell_ms} --rate {rate:.1} (inter-chunk gap {gap_ms} ms)"
+            );
+        }
+        None => println!(
+            "no setting reached {:.0}% reception; move the adapters closer or check interference",
+            MIN_RECEPTION * 100.0
+        ),
+    }
+    Ok(())
+}
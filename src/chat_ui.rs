@@ -1,104 +1,1759 @@
+use std::collections::{HashMap, HashSet};
 use std::io::stdout;
-use std::time::Duration;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
+use chrono::{Local, TimeZone};
+#[cfg(feature = "notify")]
+use crossterm::event::{DisableFocusChange, EnableFocusChange};
 use crossterm::{
-    event::{self, Event as CEvent, KeyCode},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event as CEvent, KeyCode, KeyModifiers,
+        MouseEventKind,
+    },
     execute,
     terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
 };
 use ratatui::{backend::CrosstermBackend, prelude::*, widgets::*};
+use tokio::task::JoinHandle;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-use crate::{rx_loop, tx};
+use crate::ack_log::{AckLog, Receipt};
+use crate::compress::Compress;
+use crate::crypto::SigningKey;
+use crate::fec::Fec;
+use crate::history::{self, Entry, History};
+use crate::identity::Trust;
+use crate::mesh::{
+    ACKER_LEN, Ack, MAX_REASM, REASM_TIMEOUT, SEEN_CAP, SEEN_TTL, TYPING_INTERVAL, TYPING_TIMEOUT,
+    Typing, tag_message, typing_frame,
+};
+use crate::mute::{Mute, MuteList, parse_mute};
+#[cfg(feature = "notify")]
+use crate::notify::{Notifier, Notify};
+use crate::presence::{self, Peer, PeerId, RosterEvent};
+use crate::relay::{self, MAX_RELAYS, RelayTopics};
+use crate::send_queue::{Priority, SendQueue};
+use crate::{
+    MAX_PAYLOAD, MAX_RECONNECT, MsgId, RxOpts, TxOpts, do_relay, estimate_airtime, now_ms, rx_loop,
+    signed_len, topic_from_room, tx,
+};
+
+const STATUS_REFRESH: Duration = Duration::from_secs(1);
+/// Lines moved per mouse wheel notch.
+const WHEEL_LINES: usize = 3;
+/// Rows the input box grows to before it scrolls.
+const MAX_INPUT_ROWS: usize = 4;
+/// Shown after our own messages until they're confirmed.
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const SPIN_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a sent message waits to be heard repeated by a relay before it
+/// is shown as unconfirmed.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Messages estimated to take longer than this to send get a warning.
+const LONG_SEND: Duration = Duration::from_secs(10);
+
+pub struct ChatOpts {
+    pub topic: u8,
+    /// The `--room` name `topic` came from, for its tab.
+    pub room: Option<String>,
+    /// More rooms to join, each in its own tab.
+    pub join: Vec<String>,
+    /// Keys for some of the `join` rooms, by topic, each salted with its own
+    /// room name; the rest use the main keys.
+    pub join_keys: HashMap<u8, Vec<crate::crypto::Keys>>,
+    pub ttl: u8,
+    pub rate: f64,
+    /// Dwell, gap, repeats and FEC for our messages, as `tx` resolves them.
+    pub delivery: crate::schedule::Delivery,
+    /// Split messages needing more than this many chunks into separate sends.
+    pub split_chunks: Option<usize>,
+    /// `TOPIC=COLOR` entries, e.g. `7=green` or `200=#ff8800`.
+    pub themes: Vec<String>,
+    /// Tag outgoing messages with this sub-topic and show only matching ones.
+    pub subtopic: Option<String>,
+    /// Sign outgoing messages with this key.
+    pub id_key: Option<SigningKey>,
+    pub trust: Trust,
+    pub max_age: Option<Duration>,
+    /// Send with a masked topic byte.
+    pub private_topic: bool,
+    /// Slow down while the channel is busy.
+    pub adaptive: bool,
+    /// Name announced in presence beacons and listed first in the roster.
+    pub nick: Option<String>,
+    /// Announce `nick` this often; see `presence`.
+    pub presence_interval: Option<Duration>,
+    /// Log messages to the history file and reload up to this many of them
+    /// at start; `None` keeps no history.
+    pub history_limit: Option<usize>,
+    /// Send typing notices while composing and show others'.
+    pub typing_indicator: bool,
+    /// Acknowledge messages received and count acknowledgements of ours.
+    pub ack: bool,
+    /// Log each acknowledgement of ours to this file; see `ack_log`.
+    pub ack_log: Option<std::path::PathBuf>,
+    /// Senders not to show; `/mute` and `/unmute` edit it.
+    pub mutes: MuteList,
+    /// Which messages pop up a desktop notification.
+    #[cfg(feature = "notify")]
+    pub notify: Notify,
+}
 
 pub async fn chat(
     adapter: btleplug::platform::Adapter,
-    topic: u8,
-    ttl: u8,
-    key: Option<crate::crypto::KeyBytes>,
-    rate: f64,
+    chat_opts: ChatOpts,
+    keys: Vec<crate::crypto::Keys>,
+    stats: std::sync::Arc<crate::stats::Stats>,
 ) -> anyhow::Result<()> {
+    // Send with the first key; receive with all of them.
+    let key = keys.first().cloned();
+    let ChatOpts {
+        topic,
+        room,
+        join,
+        mut join_keys,
+        ttl,
+        rate,
+        delivery,
+        split_chunks,
+        themes,
+        subtopic,
+        id_key,
+        trust,
+        max_age,
+        private_topic,
+        adaptive,
+        mut nick,
+        presence_interval,
+        history_limit,
+        typing_indicator,
+        ack,
+        ack_log,
+        mutes,
+        #[cfg(feature = "notify")]
+        notify,
+    } = chat_opts;
+    let themes = parse_themes(&themes)?;
+    let room_color = |topic: u8| themes.get(&topic).copied().unwrap_or(Color::Reset);
+    let mut rooms = Rooms::new(topic, room, key.clone());
+    // A joined room with keys of its own sends with the first of them, and
+    // its messages are opened with all of them.
+    let mut keys = keys;
+    for name in join {
+        let topic = topic_from_room(&name);
+        let own = join_keys.remove(&topic).unwrap_or_default();
+        let send = own.first().cloned().or_else(|| key.clone());
+        keys.extend(own);
+        rooms.join(topic, Some(name), send);
+    }
+    let mut ack_log = ack_log.as_deref().map(AckLog::open).transpose()?;
+    // Earlier messages in the rooms joined now pick up where they left off.
+    let mut history = None;
+    if let Some(limit) = history_limit {
+        let path = history::default_path()
+            .context("no config directory for the chat history; pass --no-history")?;
+        for e in history::load_tail(&path, limit)? {
+            if let Some(room) = rooms.get_mut(e.topic) {
+                room.messages.push(ChatLine {
+                    color: e.color,
+                    text: e.text,
+                    at_ms: e.ts,
+                    nick: e.nick,
+                    sent: None,
+                });
+            }
+        }
+        history = Some(History::open(&path)?);
+        rooms.current_mut().messages.push(system_line(format!(
+            "history is kept unencrypted in {}; --no-history turns it off",
+            path.display()
+        )));
+    }
+
     enable_raw_mode()?;
     let mut out = stdout();
-    execute!(out, EnterAlternateScreen)?;
+    execute!(out, EnterAlternateScreen, EnableMouseCapture)?;
+    #[cfg(feature = "notify")]
+    execute!(out, EnableFocusChange)?;
     let backend = CrosstermBackend::new(out);
     let mut terminal = Terminal::new(backend)?;
 
-    let (msg_tx, mut msg_rx) = tokio::sync::mpsc::unbounded_channel::<([u8; 4], String, u8)>();
+    let (msg_tx, mut msg_rx) = tokio::sync::mpsc::unbounded_channel::<(u8, ChatLine)>();
+    let (roster_tx, mut roster_rx) = tokio::sync::mpsc::unbounded_channel();
+    // Our own messages: the msg_id each went out with (`None` if sending
+    // failed), and msg_ids heard back from a relay.
+    let (sent_tx, mut sent_rx) = tokio::sync::mpsc::unbounded_channel::<(u64, Option<MsgId>)>();
+    let (echo_tx, mut echo_rx) = tokio::sync::mpsc::unbounded_channel::<MsgId>();
+    let (typing_tx, mut typing_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (ack_tx, mut ack_rx) = tokio::sync::mpsc::unbounded_channel::<Ack>();
+    // Acks carry our identity key, or without one an id that lasts this run.
+    let acker = ack.then(|| match &id_key {
+        Some(k) => k.verifying_key().to_bytes(),
+        None => rand::random(),
+    });
 
-    // spawn receiver
+    // One queue for our own sends and relays so typed messages go out first.
+    let queue = SendQueue::spawn();
+
+    // One receiver for every topic; messages are routed to their room's tab,
+    // and dropped if that room isn't joined.
     let adapter_rx = adapter.clone();
-    let key_rx = key.clone();
+    let status_stats = stats.clone();
+    let rx_opts = RxOpts {
+        topic_filter: None,
+        relay: true,
+        keys: keys.clone(),
+        stats,
+        relay_done: None,
+        queue: Some(queue.clone()),
+        events: None,
+        subtopic: subtopic.clone(),
+        max_age,
+        debug_frames: false,
+        reasm_timeout: REASM_TIMEOUT,
+        max_reasm: MAX_REASM,
+        nack_after: None,
+        seen_cache: SEEN_CAP,
+        seen_ttl: SEEN_TTL,
+        max_relays: MAX_RELAYS,
+        relay_topics: RelayTopics::All,
+        suppress_relay: false,
+        max_reconnect: MAX_RECONNECT,
+        roster: Some(roster_tx),
+        typing: typing_indicator.then_some(typing_tx),
+        ack_as: acker,
+        acks: ack.then_some(ack_tx),
+        mutes: mutes.clone(),
+        rng_seed: None,
+    };
     tokio::spawn(async move {
-        let _ = rx_loop(adapter_rx, Some(topic), true, key_rx, move |t, id, text| {
-            let _ = msg_tx.send((id, text, t));
-        })
+        let _ = rx_loop(
+            adapter_rx,
+            rx_opts,
+            move |d, _| {
+                // One of ours, repeated by a relay: proof it got out.
+                if relay::Originated::global().contains(&d.msg_id) {
+                    let _ = echo_tx.send(d.msg_id);
+                    return;
+                }
+                // Without a nickname, fall back to the start of the msg_id.
+                let (who, color) = match &d.nick {
+                    Some(n) => (n.clone(), presence::nick_color(n)),
+                    None => (hex::encode(&d.msg_id[..4]), (d.msg_id[0] % 216) + 16),
+                };
+                let line = match trust.label(d.signer.as_ref()) {
+                    Some(from) => format!("{who} {from}: {}", d.text()),
+                    None => format!("{who}: {}", d.text()),
+                };
+                // The sender's clock, unless it has none.
+                let at_ms = Some(d.ts_ms).filter(|&t| t != 0).unwrap_or_else(now_ms);
+                let _ = msg_tx.send((
+                    d.topic,
+                    ChatLine {
+                        color,
+                        text: line,
+                        at_ms,
+                        nick: d.nick.clone(),
+                        sent: None,
+                    },
+                ));
+            },
+            |_| {},
+        )
         .await;
     });
 
-    let mut input = String::new();
-    let mut messages: Vec<([u8; 4], String, u8)> = Vec::new();
+    let mut opts = TxOpts {
+        topic,
+        ttl,
+        dwell_ms: delivery.dwell_ms,
+        gap_ms: delivery.gap_ms,
+        repeats: delivery.repeats,
+        rate,
+        burst: None,
+        adaptive,
+        rotate_in_place: false,
+        repeat_for: delivery.repeat_secs.map(Duration::from_secs),
+        chunk_size: MAX_PAYLOAD,
+        per_message_key: false,
+        private_topic,
+        compress: Compress::Never,
+        fec: delivery.fec,
+        presence: false,
+    };
+    // One announcer per joined room, restarted when the nickname changes.
+    // A beacon is one short frame, resent every interval anyway.
+    let presence_opts = TxOpts {
+        repeat_for: None,
+        fec: Fec::None,
+        ..opts
+    };
+    let spawn_presence = |room: &Room, nick: &Option<String>| {
+        let (nick, interval) = (nick.clone()?, presence_interval?);
+        Some(tokio::spawn(presence::announce(
+            adapter.clone(),
+            TxOpts {
+                topic: room.topic,
+                ..presence_opts
+            },
+            nick,
+            id_key.clone(),
+            room.key.clone(),
+            interval,
+            Some(queue.clone()),
+        )))
+    };
+    let mut announcers: HashMap<u8, JoinHandle<()>> = HashMap::new();
+    for r in &rooms.rooms {
+        announcers.extend(spawn_presence(r, &nick).map(|t| (r.topic, t)));
+    }
+    let mut peers: HashMap<PeerId, Peer> = HashMap::new();
+    let mut input = InputLine::default();
+    let mut show_time = true;
+    let mut own_color = nick.as_deref().map_or(16, presence::nick_color);
+    // Lines scrolled up from the newest message; 0 follows new messages.
+    let mut scroll = 0usize;
+    // Message rows visible, and drawn in all, at the last draw.
+    let mut page = 1usize;
+    let mut total_rows = 0usize;
+    // Only redraw when something visible changed; idle chat shouldn't burn CPU.
+    let mut dirty = true;
+    let mut last_status = Instant::now();
+    // Numbers our own messages, to match each with its send result.
+    let mut next_send = 0u64;
+    let mut echoes = Echoes::default();
+    // Who has acknowledged each of our messages, kept by msg_id since an ack
+    // can beat the send's result back.
+    let mut acked: HashMap<MsgId, HashSet<[u8; ACKER_LEN]>> = HashMap::new();
+    let mut typists = Typists::default();
+    // When we last sent a typing notice.
+    let mut typing_sent: Option<Instant> = None;
+    #[cfg(feature = "notify")]
+    let mut notifier = Notifier::new(notify);
 
     loop {
-        terminal.draw(|f| {
-            let areas = Layout::default()
-                .direction(Direction::Horizontal)
-                .constraints([Constraint::Length(20), Constraint::Min(1)].as_ref())
-                .split(f.size());
-            let rooms = List::new(vec![ListItem::new(format!("{:#04x}", topic))])
-                .block(Block::default().title("Rooms").borders(Borders::ALL));
-            f.render_widget(rooms, areas[0]);
-
-            let inner = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints([Constraint::Min(1), Constraint::Length(3)].as_ref())
-                .split(areas[1]);
-            let lines: Vec<Line> = messages
-                .iter()
-                .map(|(id, msg, _)| {
-                    let color = Color::Indexed((id[0] % 216) + 16);
-                    Line::styled(msg.clone(), Style::default().fg(color))
-                })
-                .collect();
-            let msg_box = Paragraph::new(lines)
-                .block(Block::default().borders(Borders::ALL).title("Messages"));
-            f.render_widget(msg_box, inner[0]);
-            let inp = Paragraph::new(input.as_str())
+        if dirty {
+            dirty = false;
+            terminal.draw(|f| {
+                let areas = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Length(20), Constraint::Min(1)].as_ref())
+                    .split(f.size());
+                let side = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints(
+                        [
+                            Constraint::Length(rooms.rooms.len() as u16 + 2),
+                            Constraint::Min(1),
+                        ]
+                        .as_ref(),
+                    )
+                    .split(areas[0]);
+                let tabs: Vec<ListItem> = rooms
+                    .rooms
+                    .iter()
+                    .enumerate()
+                    .map(|(i, r)| {
+                        let mut spans = vec![Span::styled(
+                            format!("{} {}", i + 1, r.label()),
+                            Style::default().fg(room_color(r.topic)),
+                        )];
+                        if r.unread > 0 {
+                            spans.push(Span::styled(
+                                format!(" ({})", r.unread),
+                                Style::default().add_modifier(Modifier::BOLD),
+                            ));
+                        }
+                        let item = ListItem::new(Line::from(spans));
+                        if i == rooms.current {
+                            item.style(Style::default().add_modifier(Modifier::REVERSED))
+                        } else {
+                            item
+                        }
+                    })
+                    .collect();
+                let tab_list =
+                    List::new(tabs).block(Block::default().title("Rooms").borders(Borders::ALL));
+                f.render_widget(tab_list, side[0]);
+                let mut here: Vec<&Peer> = peers.values().collect();
+                here.sort_by(|a, b| a.nick.cmp(&b.nick));
+                let items: Vec<ListItem> = nick
+                    .iter()
+                    .map(|n| ListItem::new(format!("{n} (you)")))
+                    .chain(here.iter().map(|p| ListItem::new(p.nick.as_str())))
+                    .collect();
+                let roster =
+                    List::new(items).block(Block::default().title("Here").borders(Borders::ALL));
+                f.render_widget(roster, side[1]);
+
+                // Inside the borders.
+                let text_width = areas[1].width.saturating_sub(2) as usize;
+                let input_rows = wrap(&input.text, text_width);
+                let input_height = input_rows.len().min(MAX_INPUT_ROWS) as u16 + 2;
+                let inner = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints(
+                        [
+                            Constraint::Min(1),
+                            Constraint::Length(input_height),
+                            Constraint::Length(1),
+                        ]
+                        .as_ref(),
+                    )
+                    .split(areas[1]);
+                // Wrapped here rather than by the Paragraph so scrolling
+                // counts the rows actually drawn. Continuation rows are
+                // indented past the timestamp to keep its column clear.
+                let today_ms = now_ms();
+                let spin = (today_ms / SPIN_INTERVAL.as_millis() as u64) as usize % SPINNER.len();
+                let mut lines: Vec<Line> = Vec::new();
+                let shown = rooms.current();
+                for m in &shown.messages {
+                    let stamp = if show_time {
+                        format!("[{}] ", clock(m.at_ms, today_ms))
+                    } else {
+                        String::new()
+                    };
+                    let mark = m.sent.map(|s| s.mark(spin)).unwrap_or_default();
+                    let indent = stamp.width();
+                    let style = Style::default().fg(Color::Indexed(m.color));
+                    let rows = wrap(&m.text, text_width.saturating_sub(indent + mark.width()));
+                    let last = rows.len() - 1;
+                    for (i, row) in rows.into_iter().map(|r| &m.text[r]).enumerate() {
+                        let lead = if i == 0 {
+                            Span::styled(stamp.clone(), Style::default().fg(Color::DarkGray))
+                        } else {
+                            Span::raw(" ".repeat(indent))
+                        };
+                        let mut spans = vec![lead, Span::styled(row, style)];
+                        if i == last {
+                            spans.push(Span::styled(
+                                mark.clone(),
+                                Style::default().fg(Color::DarkGray),
+                            ));
+                        }
+                        lines.push(Line::from(spans));
+                    }
+                }
+                // Scrolled back: keep the same rows in view as more arrive.
+                if scroll > 0 {
+                    scroll += lines.len().saturating_sub(total_rows);
+                }
+                total_rows = lines.len();
+                page = inner[0].height.saturating_sub(2).max(1) as usize;
+                scroll = clamp_scroll(scroll, total_rows, page);
+                let bottom = total_rows.saturating_sub(page);
+                let top = bottom - scroll;
+                let mut title = shown.label();
+                if let Some(sub) = &subtopic {
+                    title = format!("{title} #{sub}");
+                }
+                if scroll > 0 {
+                    title = format!("{title} (scrolled up {scroll})");
+                }
+                let msg_box = Paragraph::new(lines)
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .border_style(Style::default().fg(room_color(shown.topic)))
+                            .title(title),
+                    )
+                    .scroll((top.min(u16::MAX as usize) as u16, 0));
+                f.render_widget(msg_box, inner[0]);
+                let mut bar = ScrollbarState::new(bottom).position(top);
+                f.render_stateful_widget(
+                    Scrollbar::new(ScrollbarOrientation::VerticalRight),
+                    inner[0].inner(&Margin {
+                        vertical: 1,
+                        horizontal: 0,
+                    }),
+                    &mut bar,
+                );
+                // A long input shows the rows up to the one with the cursor.
+                let (cursor_row, cursor_col) = cursor_at(&input.text, &input_rows, input.cursor);
+                let first = (cursor_row + 1).saturating_sub(MAX_INPUT_ROWS);
+                let inp = Paragraph::new(
+                    input_rows[first..]
+                        .iter()
+                        .take(MAX_INPUT_ROWS)
+                        .map(|r| Line::raw(&input.text[r.clone()]))
+                        .collect::<Vec<_>>(),
+                )
                 .block(Block::default().borders(Borders::ALL).title("Input"));
-            f.render_widget(inp, inner[1]);
-        })?;
+                f.render_widget(inp, inner[1]);
+                f.set_cursor(
+                    inner[1].x + 1 + cursor_col.min(text_width.saturating_sub(1)) as u16,
+                    inner[1].y + 1 + (cursor_row - first) as u16,
+                );
+                let mut status = vec![Span::styled(
+                    status_stats.summary(),
+                    Style::default().fg(Color::DarkGray),
+                )];
+                if let Some(who) = typists.describe(shown.topic) {
+                    status.insert(
+                        0,
+                        Span::styled(
+                            format!("{who}  "),
+                            Style::default().add_modifier(Modifier::ITALIC),
+                        ),
+                    );
+                }
+                let status = Paragraph::new(Line::from(status));
+                f.render_widget(status, inner[2]);
+            })?;
+        }
+
+        // The goodput figure decays with time, so refresh it even when idle;
+        // spinners need it more often.
+        let refresh = if rooms.own_sends().any(|s| s.pending()) {
+            SPIN_INTERVAL
+        } else {
+            STATUS_REFRESH
+        };
+        if last_status.elapsed() >= refresh {
+            last_status = Instant::now();
+            Echoes::expire(rooms.own_sends(), last_status);
+            dirty = true;
+        }
+        while let Ok((id, msg_id)) = sent_rx.try_recv() {
+            let Some(s) = rooms.own_sends().find(|s| s.id == id) else {
+                continue;
+            };
+            echoes.sent(s, msg_id, Instant::now());
+            s.acks = msg_id.and_then(|m| acked.get(&m)).map_or(0, HashSet::len);
+            dirty = true;
+        }
+        while let Ok(msg_id) = echo_rx.try_recv() {
+            echoes.heard(rooms.own_sends(), msg_id);
+            dirty = true;
+        }
+        while let Ok(a) = ack_rx.try_recv() {
+            // Acks for other people's messages pass through too.
+            if !relay::Originated::global().contains(&a.msg_id) {
+                continue;
+            }
+            let ackers = acked.entry(a.msg_id).or_default();
+            if !ackers.insert(a.acker) {
+                continue;
+            }
+            let n = ackers.len();
+            if let Some(log) = &mut ack_log
+                && let Err(e) = log.append(&Receipt::new(&a, crate::now_ms()))
+            {
+                ack_log = None;
+                let err = format!("ack log turned off: {e:#}");
+                rooms.current_mut().messages.push(system_line(err));
+            }
+            if let Some(s) = rooms.own_sends().find(|s| s.msg_id == Some(a.msg_id)) {
+                s.acks = n;
+            }
+            dirty = true;
+        }
 
-        while let Ok(m) = msg_rx.try_recv() {
-            messages.push(m);
+        while let Ok((topic, m)) = msg_rx.try_recv() {
+            // Whoever sent it has stopped typing it.
+            if let Some(nick) = &m.nick {
+                typists.stopped(topic, nick);
+            }
+            #[cfg(feature = "notify")]
+            let room = rooms.get_mut(topic).map(|r| r.label()).unwrap_or_default();
+            let Some(line) = rooms.deliver(topic, m) else {
+                continue;
+            };
+            let err = record(&mut history, topic, line);
+            #[cfg(feature = "notify")]
+            {
+                // The line is "SENDER: BODY"; only the body can mention us.
+                let body = line.text.split_once(": ").map_or("", |(_, b)| b);
+                notifier.message(&room, &line.text, body, nick.as_deref(), Instant::now());
+            }
+            if let Some(err) = err {
+                rooms.current_mut().messages.push(system_line(err));
+            }
+            dirty = true;
+        }
+        while let Ok(t) = typing_rx.try_recv() {
+            typists.heard(t, Instant::now());
+            dirty = true;
+        }
+        dirty |= typists.expire(Instant::now());
+        while let Ok(ev) = roster_rx.try_recv() {
+            match ev {
+                RosterEvent::Joined(p) => {
+                    peers.insert(p.id(), p);
+                }
+                RosterEvent::Left(p) => {
+                    peers.remove(&p.id());
+                }
+            }
+            dirty = true;
         }
 
         if event::poll(Duration::from_millis(50))? {
-            if let CEvent::Key(kev) = event::read()? {
-                match kev.code {
-                    KeyCode::Char(c) => input.push(c),
-                    KeyCode::Backspace => {
-                        input.pop();
+            match event::read()? {
+                CEvent::Resize(..) => dirty = true,
+                #[cfg(feature = "notify")]
+                CEvent::FocusGained => notifier.set_focused(true),
+                #[cfg(feature = "notify")]
+                CEvent::FocusLost => notifier.set_focused(false),
+                CEvent::Mouse(mev) => match mev.kind {
+                    MouseEventKind::ScrollUp => {
+                        scroll = clamp_scroll(scroll + WHEEL_LINES, total_rows, page);
+                        dirty = true;
                     }
-                    KeyCode::Enter => {
-                        let m = input.clone();
-                        input.clear();
-                        // UI needs its own copy since we move `m` into the task
-                        let ui_copy = m.clone();
-                        let adapter_tx = adapter.clone();
-                        let key_tx = key.clone();
-                        tokio::spawn(async move {
-                            let _ = tx(adapter_tx, topic, ttl, &m, 500, rate, key_tx).await;
-                        });
-                        messages.push(([0; 4], ui_copy, topic));
+                    MouseEventKind::ScrollDown => {
+                        scroll = scroll.saturating_sub(WHEEL_LINES);
+                        dirty = true;
                     }
-                    KeyCode::Esc => break,
                     _ => {}
+                },
+                CEvent::Key(kev) => {
+                    dirty = true;
+                    let ctrl = kev.modifiers.contains(KeyModifiers::CONTROL);
+                    match kev.code {
+                        KeyCode::PageUp => {
+                            scroll = clamp_scroll(scroll + page, total_rows, page);
+                        }
+                        KeyCode::PageDown => scroll = scroll.saturating_sub(page),
+                        KeyCode::Home if ctrl => {
+                            scroll = clamp_scroll(usize::MAX, total_rows, page);
+                        }
+                        KeyCode::End if ctrl => scroll = 0,
+                        KeyCode::Home => input.home(),
+                        KeyCode::End => input.end(),
+                        KeyCode::Left => input.left(),
+                        KeyCode::Right => input.right(),
+                        KeyCode::Char('n') if ctrl => {
+                            rooms.select(rooms.current + 1);
+                            scroll = 0;
+                        }
+                        KeyCode::Char('p') if ctrl => {
+                            rooms.select(rooms.current + rooms.rooms.len() - 1);
+                            scroll = 0;
+                        }
+                        KeyCode::Char(c @ '1'..='9')
+                            if kev.modifiers.contains(KeyModifiers::ALT) =>
+                        {
+                            let i = c as usize - '1' as usize;
+                            if i < rooms.rooms.len() {
+                                rooms.select(i);
+                                scroll = 0;
+                            }
+                        }
+                        KeyCode::Char('w') if ctrl => input.delete_word(),
+                        KeyCode::Char('u') if ctrl => input.clear(),
+                        KeyCode::Char(c) => {
+                            input.insert(c);
+                            let due = typing_sent.is_none_or(|t| t.elapsed() >= TYPING_INTERVAL);
+                            if let Some(nick) = nick.as_deref().filter(|_| typing_indicator && due)
+                            {
+                                typing_sent = Some(Instant::now());
+                                let topic = rooms.current().topic;
+                                let key = rooms.current().key.as_ref();
+                                if let Some(f) =
+                                    typing_frame(topic, nick, key, &mut rand::thread_rng())
+                                {
+                                    queue.push(Priority::Relay, do_relay(adapter.clone(), f));
+                                }
+                            }
+                        }
+                        KeyCode::Backspace => input.backspace(),
+                        KeyCode::Delete => input.delete(),
+                        KeyCode::Enter if input.text.is_empty() => {}
+                        KeyCode::Enter if input.text.starts_with('/') => {
+                            let messages = &mut rooms.current_mut().messages;
+                            let mut say = |text: String| messages.push(system_line(text));
+                            let joined = match parse_command(&input.take()) {
+                                Err(e) => {
+                                    say(e);
+                                    None
+                                }
+                                Ok(Command::Help) => {
+                                    HELP.iter().for_each(|l| say(l.to_string()));
+                                    None
+                                }
+                                Ok(Command::Time) => {
+                                    show_time = !show_time;
+                                    None
+                                }
+                                Ok(Command::Clear) => {
+                                    messages.clear();
+                                    None
+                                }
+                                Ok(Command::Quit) => break,
+                                Ok(Command::Nick(n)) => {
+                                    say(format!("you are now {n}"));
+                                    own_color = presence::nick_color(&n);
+                                    nick = Some(n);
+                                    announcers.drain().for_each(|(_, t)| t.abort());
+                                    for r in &rooms.rooms {
+                                        announcers
+                                            .extend(spawn_presence(r, &nick).map(|t| (r.topic, t)));
+                                    }
+                                    None
+                                }
+                                Ok(Command::Leave) => {
+                                    match rooms.leave() {
+                                        Some(left) => {
+                                            if let Some(task) = announcers.remove(&left.topic) {
+                                                task.abort();
+                                            }
+                                        }
+                                        None => rooms
+                                            .current_mut()
+                                            .messages
+                                            .push(system_line("can't leave the last room".into())),
+                                    }
+                                    None
+                                }
+                                Ok(Command::Mute(None)) => {
+                                    let list = mutes.list();
+                                    if list.is_empty() {
+                                        say("nobody is muted".into());
+                                    }
+                                    list.iter().for_each(|m| say(format!("muted: {m}")));
+                                    None
+                                }
+                                // Only for this run; the config's `mute` list
+                                // is what persists.
+                                Ok(Command::Mute(Some(m))) => {
+                                    let who = m.to_string();
+                                    say(if mutes.add(m) {
+                                        format!("muted {who} until you quit")
+                                    } else {
+                                        format!("{who} is already muted")
+                                    });
+                                    None
+                                }
+                                Ok(Command::Unmute(m)) => {
+                                    say(if mutes.remove(&m) {
+                                        format!("unmuted {m}")
+                                    } else {
+                                        format!("{m} wasn't muted")
+                                    });
+                                    None
+                                }
+                                Ok(Command::Topic(t)) => Some((t, None)),
+                                Ok(Command::Room(r)) => Some((topic_from_room(&r), Some(r))),
+                            };
+                            scroll = 0;
+                            if let Some((topic, name)) = joined {
+                                let i = rooms.join(topic, name, key.clone());
+                                rooms.select(i);
+                                if !announcers.contains_key(&topic) {
+                                    announcers.extend(
+                                        spawn_presence(rooms.current(), &nick).map(|t| (topic, t)),
+                                    );
+                                }
+                            }
+                        }
+                        KeyCode::Enter => {
+                            let m = input.take();
+                            typing_sent = None;
+                            scroll = 0;
+                            opts.topic = rooms.current().topic;
+                            let key = rooms.current().key.clone();
+                            let messages = &mut rooms.current_mut().messages;
+                            let budget = split_chunks.map(|n| {
+                                let budget = split_budget(
+                                    n,
+                                    &opts,
+                                    nick.as_deref(),
+                                    subtopic.as_deref(),
+                                    id_key.as_ref(),
+                                );
+                                (n, budget)
+                            });
+                            let parts = match budget {
+                                Some((_, Some(budget))) => split_message(&m, budget),
+                                Some((n, None)) => {
+                                    messages.push(system_line(format!(
+                                        "--split-chunks {n} leaves no room once tagged and signed; sending unsplit"
+                                    )));
+                                    vec![m]
+                                }
+                                None => vec![m],
+                            };
+                            let n = parts.len();
+                            for (i, p) in parts.into_iter().enumerate() {
+                                let mut line = if n > 1 {
+                                    format!("[{}/{}] {}", i + 1, n, p)
+                                } else {
+                                    p.clone()
+                                };
+                                if let Some(nick) = &nick {
+                                    line = format!("{nick}: {line}");
+                                }
+                                let body = tag_message(nick.as_deref(), subtopic.as_deref(), &p);
+                                let len = signed_len(body.as_bytes(), id_key.as_ref());
+                                let est = estimate_airtime(len, &opts, key.is_some());
+                                let too_long = est.chunks > u8::MAX as usize;
+                                next_send += 1;
+                                let id = next_send;
+                                let line = ChatLine {
+                                    color: own_color,
+                                    text: line,
+                                    at_ms: now_ms(),
+                                    nick: nick.clone(),
+                                    sent: Some(Sent {
+                                        id,
+                                        msg_id: None,
+                                        delivery: if too_long {
+                                            Delivery::Failed
+                                        } else {
+                                            Delivery::Sending
+                                        },
+                                        acks: 0,
+                                    }),
+                                };
+                                let err = record(&mut history, opts.topic, &line);
+                                messages.push(line);
+                                messages.extend(err.map(system_line));
+                                if too_long {
+                                    messages.push(system_line(format!(
+                                        "too long to send: {} chunks, at most {}; --split-chunks splits it",
+                                        est.chunks,
+                                        u8::MAX
+                                    )));
+                                    continue;
+                                }
+                                if est.duration > LONG_SEND {
+                                    messages.push(system_line(format!(
+                                        "long message: {} frames, ~{:.0}s of airtime",
+                                        est.frames,
+                                        est.duration.as_secs_f64()
+                                    )));
+                                }
+                                // Parts queue back to back so they go out in order,
+                                // each with its own msg_id.
+                                let adapter_tx = adapter.clone();
+                                let (key, id_key) = (key.clone(), id_key.clone());
+                                let sent_tx = sent_tx.clone();
+                                queue.push(Priority::User, async move {
+                                    let msg_id = tx(
+                                        adapter_tx,
+                                        &opts,
+                                        body.as_bytes(),
+                                        key.as_ref(),
+                                        id_key.as_ref(),
+                                    )
+                                    .await;
+                                    let _ = sent_tx.send((id, msg_id.ok()));
+                                });
+                            }
+                        }
+                        KeyCode::F(2) => rooms
+                            .current_mut()
+                            .messages
+                            .push(system_line(status_stats.to_json())),
+                        KeyCode::Esc => break,
+                        _ => {}
+                    }
                 }
+                _ => {}
             }
         }
     }
 
     disable_raw_mode()?;
     let mut out = std::io::stdout();
-    execute!(out, LeaveAlternateScreen)?;
+    execute!(out, LeaveAlternateScreen, DisableMouseCapture)?;
+    #[cfg(feature = "notify")]
+    execute!(out, DisableFocusChange)?;
     Ok(())
 }
+
+/// One line of the message pane.
+struct ChatLine {
+    /// 256-colour palette index.
+    color: u8,
+    text: String,
+    /// Sent time for received messages, else when it was shown; Unix ms.
+    at_ms: u64,
+    /// The sender's nickname, if it sent one.
+    nick: Option<String>,
+    /// How far along it is, if it's one of ours.
+    sent: Option<Sent>,
+}
+
+/// One of our own messages.
+#[derive(Clone, Copy)]
+struct Sent {
+    /// Matches the send's result on the way back from the queue.
+    id: u64,
+    /// Once every chunk went out.
+    msg_id: Option<MsgId>,
+    delivery: Delivery,
+    /// Receivers who acknowledged it, with `--ack`.
+    acks: usize,
+}
+
+impl Sent {
+    /// An acknowledgement settles it, whatever was heard relayed.
+    fn pending(&self) -> bool {
+        self.acks == 0 && self.delivery.pending()
+    }
+
+    fn mark(&self, spin: usize) -> String {
+        match self.acks {
+            0 => self.delivery.mark(spin),
+            n => format!(" ✓ delivered to {n}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Delivery {
+    /// Queued, or still being advertised.
+    Sending,
+    /// Every chunk went out, at this instant; waiting to hear it relayed.
+    Sent(Instant),
+    /// A relay was heard repeating it, so it reached at least one node.
+    Relayed,
+    /// Not heard relayed within `ECHO_TIMEOUT`. With no relaying neighbours
+    /// it may still have been received directly.
+    Unconfirmed,
+    Failed,
+}
+
+impl Delivery {
+    fn pending(&self) -> bool {
+        matches!(self, Delivery::Sending | Delivery::Sent(..))
+    }
+
+    /// Shown after the message, with spinner frame `spin` while pending.
+    fn mark(&self, spin: usize) -> String {
+        match self {
+            Delivery::Sending | Delivery::Sent(..) => format!(" {}", SPINNER[spin]),
+            Delivery::Relayed => " ✓".to_string(),
+            Delivery::Unconfirmed => " sent (unconfirmed)".to_string(),
+            Delivery::Failed => " not sent".to_string(),
+        }
+    }
+}
+
+/// Matches our own messages with relays heard repeating them.
+#[derive(Default)]
+struct Echoes {
+    /// Heard before `tx` returned the msg_id they belong to.
+    early: HashSet<MsgId>,
+}
+
+impl Echoes {
+    /// `tx` finished sending `s` as `msg_id` at `now`, or failed with `None`.
+    fn sent(&mut self, s: &mut Sent, msg_id: Option<MsgId>, now: Instant) {
+        s.msg_id = msg_id;
+        s.delivery = match msg_id {
+            // A relay can repeat the message before the last repeat of it
+            // has gone out.
+            Some(m) if self.early.remove(&m) => Delivery::Relayed,
+            Some(_) => Delivery::Sent(now),
+            None => Delivery::Failed,
+        };
+    }
+
+    /// A relay was heard repeating `msg_id`, one of ours.
+    fn heard<'a>(&mut self, mut sends: impl Iterator<Item = &'a mut Sent>, msg_id: MsgId) {
+        match sends.find(|s| s.msg_id == Some(msg_id)) {
+            Some(s) if matches!(s.delivery, Delivery::Sent(_)) => s.delivery = Delivery::Relayed,
+            // Already settled, by an earlier echo or the timeout.
+            Some(_) => {}
+            None => {
+                self.early.insert(msg_id);
+            }
+        }
+    }
+
+    /// Give up waiting on sends not heard relayed within `ECHO_TIMEOUT`.
+    fn expire<'a>(sends: impl Iterator<Item = &'a mut Sent>, now: Instant) {
+        for s in sends {
+            if matches!(s.delivery, Delivery::Sent(at) if now - at >= ECHO_TIMEOUT) {
+                s.delivery = Delivery::Unconfirmed;
+            }
+        }
+    }
+}
+
+/// One tab: a joined topic and its messages.
+struct Room {
+    topic: u8,
+    /// The room name it was joined by, if any.
+    name: Option<String>,
+    /// Key its messages, presence and typing notices are sent with.
+    key: Option<crate::crypto::Keys>,
+    messages: Vec<ChatLine>,
+    /// Messages received while another tab was shown.
+    unread: usize,
+}
+
+impl Room {
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{:#04x}", self.topic),
+        }
+    }
+}
+
+/// The joined rooms, one of them shown.
+struct Rooms {
+    rooms: Vec<Room>,
+    current: usize,
+}
+
+impl Rooms {
+    fn new(topic: u8, name: Option<String>, key: Option<crate::crypto::Keys>) -> Self {
+        let mut rooms = Self {
+            rooms: Vec::new(),
+            current: 0,
+        };
+        rooms.join(topic, name, key);
+        rooms
+    }
+
+    fn current(&self) -> &Room {
+        &self.rooms[self.current]
+    }
+
+    fn current_mut(&mut self) -> &mut Room {
+        &mut self.rooms[self.current]
+    }
+
+    /// Index of the tab for `topic`, opening one sending with `key` if it
+    /// isn't joined yet.
+    fn join(&mut self, topic: u8, name: Option<String>, key: Option<crate::crypto::Keys>) -> usize {
+        if let Some(i) = self.rooms.iter().position(|r| r.topic == topic) {
+            return i;
+        }
+        self.rooms.push(Room {
+            topic,
+            name,
+            key,
+            messages: Vec::new(),
+            unread: 0,
+        });
+        self.rooms.len() - 1
+    }
+
+    /// Show tab `i`, wrapping around, and mark it read.
+    fn select(&mut self, i: usize) {
+        self.current = i % self.rooms.len();
+        self.current_mut().unread = 0;
+    }
+
+    /// Close the shown tab, unless it is the only one.
+    fn leave(&mut self) -> Option<Room> {
+        if self.rooms.len() == 1 {
+            return None;
+        }
+        let left = self.rooms.remove(self.current);
+        self.select(self.current.min(self.rooms.len() - 1));
+        Some(left)
+    }
+
+    /// Our own messages in every room, newest last.
+    fn own_sends(&mut self) -> impl Iterator<Item = &mut Sent> {
+        self.rooms
+            .iter_mut()
+            .flat_map(|r| r.messages.iter_mut())
+            .filter_map(|l| l.sent.as_mut())
+    }
+
+    fn get_mut(&mut self, topic: u8) -> Option<&mut Room> {
+        self.rooms.iter_mut().find(|r| r.topic == topic)
+    }
+
+    /// Add `line` to the tab for `topic`, counting it as unread unless that
+    /// tab is shown. `None` if that topic isn't joined.
+    fn deliver(&mut self, topic: u8, line: ChatLine) -> Option<&ChatLine> {
+        let i = self.rooms.iter().position(|r| r.topic == topic)?;
+        if i != self.current {
+            self.rooms[i].unread += 1;
+        }
+        let messages = &mut self.rooms[i].messages;
+        messages.push(line);
+        messages.last()
+    }
+}
+
+/// Who is typing in each room, as heard from typing notices.
+#[derive(Default)]
+struct Typists {
+    /// When each (topic, nick) was last heard typing.
+    heard: HashMap<(u8, String), Instant>,
+}
+
+impl Typists {
+    fn heard(&mut self, t: Typing, now: Instant) {
+        self.heard.insert((t.topic, t.nick), now);
+    }
+
+    fn stopped(&mut self, topic: u8, nick: &str) {
+        self.heard.remove(&(topic, nick.to_string()));
+    }
+
+    /// Forget typists silent for `TYPING_TIMEOUT` as of `now`. True if any
+    /// were forgotten.
+    fn expire(&mut self, now: Instant) -> bool {
+        let before = self.heard.len();
+        self.heard
+            .retain(|_, at| now.saturating_duration_since(*at) < TYPING_TIMEOUT);
+        self.heard.len() != before
+    }
+
+    /// `ana is typing…`, `ana and bo are typing…`, or `None` if nobody is.
+    fn describe(&self, topic: u8) -> Option<String> {
+        let mut nicks: Vec<&str> = self
+            .heard
+            .keys()
+            .filter(|(t, _)| *t == topic)
+            .map(|(_, n)| n.as_str())
+            .collect();
+        nicks.sort_unstable();
+        match nicks.as_slice() {
+            [] => None,
+            [one] => Some(format!("{one} is typing…")),
+            [rest @ .., last] => Some(format!("{} and {last} are typing…", rest.join(", "))),
+        }
+    }
+}
+
+/// An input line starting with `/`.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Nick(String),
+    Topic(u8),
+    Room(String),
+    Leave,
+    /// Mute a sender, or list the mutes with `None`.
+    Mute(Option<Mute>),
+    Unmute(Mute),
+    Time,
+    Clear,
+    Help,
+    Quit,
+}
+
+/// Shown by `/help`.
+const HELP: &[&str] = &[
+    "/nick NAME   change the nickname sent with messages",
+    "/topic N     join topic N (decimal or 0x hex) in a new tab",
+    "/room NAME   join room NAME in a new tab; the key stays the same",
+    "/leave       leave the shown room",
+    "/mute [WHO]  hide messages from WHO (key:HEX, id:HEX or nick:NAME), or list",
+    "/unmute WHO  show WHO's messages again",
+    "Ctrl-N/Ctrl-P or Alt-1..9 switch tabs",
+    "F2 shows the receive counters as JSON",
+    "/time        hide or show timestamps",
+    "/clear       clear the message pane",
+    "/quit        leave (as does Esc)",
+];
+
+/// Palette index for `system_line`s.
+const SYSTEM_COLOR: u8 = 244;
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+    let arg = arg.trim();
+    let need = |what: &str| {
+        if arg.is_empty() {
+            Err(format!("usage: {name} {what}"))
+        } else {
+            Ok(arg)
+        }
+    };
+    match name {
+        "/nick" => presence::parse_nick(need("NAME")?).map(Command::Nick),
+        "/topic" => {
+            let n = need("N")?;
+            match n.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => n.parse(),
+            }
+            .map(Command::Topic)
+            .map_err(|_| format!("not a topic (0-255): {n}"))
+        }
+        "/room" => need("NAME").map(|r| Command::Room(r.to_string())),
+        "/leave" => Ok(Command::Leave),
+        "/mute" if arg.is_empty() => Ok(Command::Mute(None)),
+        "/mute" => parse_mute(arg).map(|m| Command::Mute(Some(m))),
+        "/unmute" => parse_mute(need("WHO")?).map(Command::Unmute),
+        "/time" => Ok(Command::Time),
+        "/clear" => Ok(Command::Clear),
+        "/help" => Ok(Command::Help),
+        "/quit" => Ok(Command::Quit),
+        _ => Err(format!("unknown command {name}; try /help")),
+    }
+}
+
+fn system_line(text: String) -> ChatLine {
+    ChatLine {
+        color: SYSTEM_COLOR,
+        text: format!("* {text}"),
+        at_ms: now_ms(),
+        nick: None,
+        sent: None,
+    }
+}
+
+/// Append `line` to `history`, if it's on. On failure the history is turned
+/// off, and the error returned to show.
+fn record(history: &mut Option<History>, topic: u8, line: &ChatLine) -> Option<String> {
+    let entry = Entry {
+        topic,
+        ts: line.at_ms,
+        nick: line.nick.clone(),
+        color: line.color,
+        text: line.text.clone(),
+    };
+    let err = history.as_mut()?.append(&entry).err()?;
+    *history = None;
+    Some(format!("history turned off: {err:#}"))
+}
+
+/// The text being typed, and the cursor in it as a byte offset on a char
+/// boundary.
+#[derive(Default)]
+struct InputLine {
+    text: String,
+    cursor: usize,
+}
+
+impl InputLine {
+    fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    fn left(&mut self) {
+        self.cursor = self.prev();
+    }
+
+    fn right(&mut self) {
+        self.cursor = self.next();
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    /// Delete the character before the cursor.
+    fn backspace(&mut self) {
+        let prev = self.prev();
+        self.text.replace_range(prev..self.cursor, "");
+        self.cursor = prev;
+    }
+
+    /// Delete the character under the cursor.
+    fn delete(&mut self) {
+        let next = self.next();
+        self.text.replace_range(self.cursor..next, "");
+    }
+
+    /// Delete the word before the cursor and any spaces between it and the
+    /// cursor, as `Ctrl-W` does in a shell.
+    fn delete_word(&mut self) {
+        let before = &self.text[..self.cursor];
+        let end = before.trim_end().len();
+        let start = before[..end]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    fn clear(&mut self) {
+        self.take();
+    }
+
+    /// The text, leaving the line empty.
+    fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.text)
+    }
+
+    fn prev(&self) -> usize {
+        self.text[..self.cursor]
+            .char_indices()
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next(&self) -> usize {
+        self.text[self.cursor..]
+            .chars()
+            .next()
+            .map_or(self.cursor, |c| self.cursor + c.len_utf8())
+    }
+}
+
+/// `HH:MM:SS` in local time for `at_ms`, prefixed with the date (`MM-DD`)
+/// when it isn't on the same local day as `now_ms`.
+fn clock(at_ms: u64, now_ms: u64) -> String {
+    let local = |ms: u64| Local.timestamp_millis_opt(ms as i64).single();
+    match (local(at_ms), local(now_ms)) {
+        (Some(at), Some(now)) if at.date_naive() == now.date_naive() => {
+            at.format("%H:%M:%S").to_string()
+        }
+        (Some(at), _) => at.format("%m-%d %H:%M:%S").to_string(),
+        (None, _) => "--:--:--".to_string(),
+    }
+}
+
+/// Break `text` into rows at most `width` terminal columns wide, measured
+/// with `unicode-width` so wide CJK and emoji characters count double, as
+/// byte ranges of `text`. Rows break after a space where one is available,
+/// mid-word otherwise, and at every newline.
+fn wrap(text: &str, width: usize) -> Vec<Range<usize>> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == '\n' {
+            rows.push(start..i);
+            start = i + 1;
+            continue;
+        }
+        let w = c.width().unwrap_or(0);
+        while start < i && text[start..i].width() + w > width {
+            // Carry the partial word after the last space to the next row.
+            let end = text[start..i].rfind(' ').map_or(i, |j| start + j + 1);
+            rows.push(start..end);
+            start = end;
+        }
+    }
+    rows.push(start..text.len());
+    rows
+}
+
+/// Row and display column of byte offset `cursor` in `text` wrapped into
+/// `rows`. At a break the cursor goes at the start of the next row.
+fn cursor_at(text: &str, rows: &[Range<usize>], cursor: usize) -> (usize, usize) {
+    let row = rows.iter().rposition(|r| r.start <= cursor).unwrap_or(0);
+    let col = rows.get(row).map_or(0, |r| text[r.start..cursor].width());
+    (row, col)
+}
+
+/// Keep a scroll offset, counted in lines up from the newest, within what
+/// `total` lines in a `height`-line window can show.
+fn clamp_scroll(scroll: usize, total: usize, height: usize) -> usize {
+    scroll.min(total.saturating_sub(height))
+}
+
+/// Bytes of text that still fit in `chunks` chunks of `opts` once `tx` has
+/// tagged them with `nick` and `subtopic` and signed them with `id_key`. The
+/// AEAD tag rides in each frame beside its chunk, so encryption costs no
+/// chunk space. `None` if the tag and signature alone fill them.
+fn split_budget(
+    chunks: usize,
+    opts: &TxOpts,
+    nick: Option<&str>,
+    subtopic: Option<&str>,
+    id_key: Option<&SigningKey>,
+) -> Option<usize> {
+    let overhead = signed_len(tag_message(nick, subtopic, "").as_bytes(), id_key);
+    (chunks * opts.chunk_size)
+        .checked_sub(overhead)
+        .filter(|&b| b > 0)
+}
+
+/// Split `text` into pieces of at most `max_bytes`, preferring to break after
+/// a sentence end, then at a space, and only then mid-word.
+fn split_message(text: &str, max_bytes: usize) -> Vec<String> {
+    let max_bytes = max_bytes.max(1);
+    let mut parts = Vec::new();
+    let mut rest = text.trim();
+    while rest.len() > max_bytes {
+        let mut cut = max_bytes;
+        while !rest.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        let window = &rest[..cut];
+        let at = window
+            .rfind(['.', '!', '?'])
+            .map(|i| i + 1)
+            .or_else(|| window.rfind(' '))
+            .filter(|&i| i > 0)
+            .unwrap_or(cut.max(rest.chars().next().map_or(1, char::len_utf8)));
+        parts.push(rest[..at].trim_end().to_string());
+        rest = rest[at..].trim_start();
+    }
+    if !rest.is_empty() || parts.is_empty() {
+        parts.push(rest.to_string());
+    }
+    parts
+}
+
+fn parse_themes(entries: &[String]) -> anyhow::Result<HashMap<u8, Color>> {
+    let mut themes = HashMap::new();
+    for e in entries {
+        let (topic, color) = e
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("theme {e:?} is not TOPIC=COLOR"))?;
+        let topic: u8 = topic
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("theme {e:?}: bad topic"))?;
+        let color: Color = color
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("theme {e:?}: unknown color"))?;
+        themes.insert(topic, color);
+    }
+    Ok(themes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MSG_ID_LEN;
+
+    fn typing(topic: u8, nick: &str) -> Typing {
+        Typing {
+            topic,
+            nick: nick.to_string(),
+        }
+    }
+
+    #[test]
+    fn typists_expire_after_typing_timeout() {
+        let t0 = Instant::now();
+        let mut typists = Typists::default();
+        typists.heard(typing(7, "ana"), t0);
+        typists.heard(typing(7, "bo"), t0 + TYPING_TIMEOUT / 2);
+        typists.heard(typing(8, "cy"), t0);
+        assert_eq!(
+            typists.describe(7).as_deref(),
+            Some("ana and bo are typing…")
+        );
+
+        assert!(!typists.expire(t0 + TYPING_TIMEOUT - Duration::from_millis(1)));
+        assert!(typists.expire(t0 + TYPING_TIMEOUT));
+        assert_eq!(typists.describe(7).as_deref(), Some("bo is typing…"));
+        assert_eq!(typists.describe(8), None);
+
+        // Another notice keeps a typist on.
+        typists.heard(typing(7, "bo"), t0 + TYPING_TIMEOUT);
+        assert!(!typists.expire(t0 + TYPING_TIMEOUT * 3 / 2));
+        assert!(typists.expire(t0 + TYPING_TIMEOUT * 2));
+        assert_eq!(typists.describe(7), None);
+    }
+
+    #[test]
+    fn scrolling_stops_at_the_oldest_line_and_the_newest() {
+        // 30 lines in a 10-line window: at most 20 lines up.
+        assert_eq!(clamp_scroll(5, 30, 10), 5);
+        assert_eq!(clamp_scroll(50, 30, 10), 20);
+        assert_eq!(clamp_scroll(usize::MAX, 30, 10), 20);
+        // Everything fits, so there is nothing to scroll.
+        assert_eq!(clamp_scroll(5, 3, 10), 0);
+        assert_eq!(clamp_scroll(5, 10, 10), 0);
+        assert_eq!(clamp_scroll(0, 0, 0), 0);
+    }
+
+    #[test]
+    fn the_cursor_edits_by_whole_characters() {
+        let mut line = InputLine::default();
+        "héllo 世界".chars().for_each(|c| line.insert(c));
+        assert_eq!(line.cursor, line.text.len());
+
+        line.left();
+        line.backspace();
+        assert_eq!(line.text, "héllo 界");
+        assert_eq!(line.cursor, "héllo ".len());
+
+        line.home();
+        line.backspace();
+        line.delete();
+        line.right();
+        line.insert('X');
+        assert_eq!(line.text, "éXllo 界");
+        assert_eq!(line.cursor, "éX".len());
+
+        // Nothing to delete or move past at the end.
+        line.end();
+        line.delete();
+        line.right();
+        assert_eq!(line.text, "éXllo 界");
+        assert_eq!(line.cursor, line.text.len());
+
+        assert_eq!(line.take(), "éXllo 界");
+        assert_eq!((line.text.as_str(), line.cursor), ("", 0));
+    }
+
+    #[test]
+    fn ctrl_w_deletes_the_word_before_the_cursor_and_trailing_spaces() {
+        let mut line = InputLine::default();
+        "one two  three".chars().for_each(|c| line.insert(c));
+        line.cursor = "one two  ".len();
+        line.delete_word();
+        assert_eq!(line.text, "one three");
+        assert_eq!(line.cursor, "one ".len());
+
+        line.end();
+        line.delete_word();
+        assert_eq!(line.text, "one ");
+        line.delete_word();
+        assert_eq!(line.text, "");
+        line.delete_word();
+        assert_eq!((line.text.as_str(), line.cursor), ("", 0));
+    }
+
+    #[test]
+    fn slash_commands_parse_with_their_arguments() {
+        assert_eq!(
+            parse_command("/nick  ana "),
+            Ok(Command::Nick("ana".into()))
+        );
+        assert_eq!(parse_command("/topic 7"), Ok(Command::Topic(7)));
+        assert_eq!(parse_command("/topic 0xff"), Ok(Command::Topic(255)));
+        assert_eq!(parse_command("/room ops"), Ok(Command::Room("ops".into())));
+        assert_eq!(parse_command("/mute"), Ok(Command::Mute(None)));
+        assert_eq!(
+            parse_command("/mute nick:bo"),
+            Ok(Command::Mute(Some(Mute::Nick("bo".into()))))
+        );
+        assert_eq!(
+            parse_command("/unmute id:0a0b"),
+            Ok(Command::Unmute(Mute::MsgId(vec![0x0a, 0x0b])))
+        );
+        assert_eq!(parse_command(" /quit "), Ok(Command::Quit));
+        assert_eq!(parse_command("/leave"), Ok(Command::Leave));
+    }
+
+    #[test]
+    fn bad_slash_commands_say_what_is_wrong() {
+        assert_eq!(parse_command("/nick"), Err("usage: /nick NAME".into()));
+        assert_eq!(parse_command("/room "), Err("usage: /room NAME".into()));
+        assert_eq!(parse_command("/unmute"), Err("usage: /unmute WHO".into()));
+        assert_eq!(
+            parse_command("/topic 256"),
+            Err("not a topic (0-255): 256".into())
+        );
+        assert_eq!(
+            parse_command("/topic 0xzz"),
+            Err("not a topic (0-255): 0xzz".into())
+        );
+        assert_eq!(
+            parse_command("/nik ana"),
+            Err("unknown command /nik; try /help".into())
+        );
+    }
+
+    #[test]
+    fn tabs_count_unread_until_shown() {
+        let mut rooms = Rooms::new(1, None, None);
+        assert_eq!(rooms.join(2, Some("ops".into()), None), 1);
+        assert_eq!(rooms.join(1, None, None), 0, "a joined topic keeps its tab");
+        assert_eq!(rooms.current().topic, 1);
+
+        rooms.deliver(2, system_line("one".into())).unwrap();
+        rooms.deliver(2, system_line("two".into())).unwrap();
+        rooms.deliver(1, system_line("here".into())).unwrap();
+        assert!(rooms.deliver(9, system_line("lost".into())).is_none());
+        assert_eq!(rooms.rooms[0].unread, 0);
+        assert_eq!(rooms.rooms[1].unread, 2);
+
+        rooms.select(1);
+        assert_eq!(rooms.current().topic, 2);
+        assert_eq!(rooms.current().unread, 0);
+        assert_eq!(rooms.current().messages.len(), 2);
+
+        // Ctrl-N past the last tab wraps to the first.
+        rooms.select(rooms.current + 1);
+        assert_eq!(rooms.current().topic, 1);
+    }
+
+    #[test]
+    fn leaving_shows_a_neighbour_and_keeps_the_last_tab() {
+        let mut rooms = Rooms::new(1, None, None);
+        rooms.join(2, None, None);
+        rooms.join(3, None, None);
+        rooms.deliver(3, system_line("unseen".into()));
+
+        rooms.select(1);
+        assert_eq!(rooms.leave().map(|r| r.topic), Some(2));
+        assert_eq!(rooms.current().topic, 3);
+        assert_eq!(rooms.current().unread, 0);
+
+        assert_eq!(rooms.leave().map(|r| r.topic), Some(3));
+        assert_eq!(rooms.current().topic, 1);
+        assert!(rooms.leave().is_none());
+        assert_eq!(rooms.current().topic, 1);
+    }
+
+    #[test]
+    fn times_from_another_day_carry_the_date() {
+        let ms = |d: u32, h: u32, m: u32, s: u32| {
+            let (y, mo, d) = if d == 0 { (2023, 12, 31) } else { (2024, 1, d) };
+            Local
+                .with_ymd_and_hms(y, mo, d, h, m, s)
+                .unwrap()
+                .timestamp_millis() as u64
+        };
+        let now = ms(15, 0, 0, 2);
+        assert_eq!(clock(ms(15, 0, 0, 1), now), "00:00:01");
+        assert_eq!(clock(ms(14, 23, 59, 59) + 999, now), "01-14 23:59:59");
+        assert_eq!(clock(ms(0, 8, 5, 0), now), "12-31 08:05:00");
+        // Sent "tomorrow" by a skewed clock.
+        assert_eq!(clock(ms(16, 0, 0, 0), ms(15, 23, 59, 59)), "01-16 00:00:00");
+        assert_eq!(clock(ms(15, 12, 34, 56), ms(15, 23, 59, 59)), "12:34:56");
+        assert_eq!(clock(i64::MAX as u64, now), "--:--:--");
+    }
+
+    #[test]
+    fn wrapping_counts_wide_characters_as_two_columns() {
+        let rows = |text: &str, width: usize| -> Vec<String> {
+            wrap(text, width)
+                .into_iter()
+                .map(|r| text[r].to_string())
+                .collect()
+        };
+        assert_eq!(rows("ab 世界 cd", 5), ["ab ", "世界 ", "cd"]);
+        assert_eq!(rows("hi 🙂🙂", 4), ["hi ", "🙂🙂"]);
+        // A wide character that would straddle the edge starts the next row.
+        assert_eq!(rows("abcd世", 5), ["abcd", "世"]);
+        // One wider than the row still gets a row of its own.
+        assert_eq!(rows("世界", 1), ["世", "界"]);
+        // A word longer than the row breaks mid-word.
+        assert_eq!(rows("abcdefgh", 3), ["abc", "def", "gh"]);
+        assert_eq!(rows("a\nb", 5), ["a", "b"]);
+        assert_eq!(rows("", 5), [""]);
+
+        let text = "ab 世界 cd";
+        assert_eq!(wrap(text, 5), [0..3, 3..10, 10..12]);
+        assert_eq!(cursor_at(text, &wrap(text, 5), 6), (1, 2));
+    }
+
+    fn sending(id: u64) -> Sent {
+        Sent {
+            id,
+            msg_id: None,
+            delivery: Delivery::Sending,
+            acks: 0,
+        }
+    }
+
+    #[test]
+    fn an_echo_confirms_the_send_it_matches() {
+        let t0 = Instant::now();
+        let mut echoes = Echoes::default();
+        let mut sends = [sending(1), sending(2)];
+        echoes.sent(&mut sends[0], Some([1; MSG_ID_LEN]), t0);
+        echoes.sent(&mut sends[1], Some([2; MSG_ID_LEN]), t0);
+
+        echoes.heard(sends.iter_mut(), [2; MSG_ID_LEN]);
+        assert!(matches!(sends[0].delivery, Delivery::Sent(at) if at == t0));
+        assert!(matches!(sends[1].delivery, Delivery::Relayed));
+        assert_eq!(sends[1].mark(0), " ✓");
+
+        // Someone else's msg_id, and repeats of a settled one, change nothing.
+        echoes.heard(sends.iter_mut(), [9; MSG_ID_LEN]);
+        echoes.heard(sends.iter_mut(), [2; MSG_ID_LEN]);
+        assert!(sends[0].pending());
+        assert_eq!(echoes.early.len(), 1);
+    }
+
+    #[test]
+    fn an_echo_heard_before_tx_returns_is_kept_for_it() {
+        let mut echoes = Echoes::default();
+        let mut sends = [sending(1)];
+        echoes.heard(sends.iter_mut(), [1; MSG_ID_LEN]);
+        assert!(matches!(sends[0].delivery, Delivery::Sending));
+
+        echoes.sent(&mut sends[0], Some([1; MSG_ID_LEN]), Instant::now());
+        assert!(matches!(sends[0].delivery, Delivery::Relayed));
+        assert!(echoes.early.is_empty());
+    }
+
+    #[test]
+    fn an_unheard_send_times_out_as_unconfirmed() {
+        let t0 = Instant::now();
+        let mut echoes = Echoes::default();
+        let mut sends = [sending(1), sending(2)];
+        echoes.sent(&mut sends[0], Some([1; MSG_ID_LEN]), t0);
+        echoes.sent(&mut sends[1], None, t0);
+        assert_eq!(sends[1].mark(0), " not sent");
+
+        Echoes::expire(
+            sends.iter_mut(),
+            t0 + ECHO_TIMEOUT - Duration::from_millis(1),
+        );
+        assert!(sends[0].pending());
+        Echoes::expire(sends.iter_mut(), t0 + ECHO_TIMEOUT);
+        assert_eq!(sends[0].mark(0), " sent (unconfirmed)");
+        assert!(!sends[0].pending());
+
+        // Too late to count once it has timed out.
+        echoes.heard(sends.iter_mut(), [1; MSG_ID_LEN]);
+        assert_eq!(sends[0].mark(0), " sent (unconfirmed)");
+    }
+
+    #[test]
+    fn long_messages_split_at_sentences_then_spaces_then_mid_word() {
+        assert_eq!(
+            split_message("One two. Three four five.", 15),
+            ["One two.", "Three four", "five."]
+        );
+        assert_eq!(split_message("one two three", 9), ["one two", "three"]);
+        assert_eq!(split_message("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        // Never inside a character: each 世 is three bytes.
+        assert_eq!(split_message("世界世界", 7), ["世界", "世界"]);
+        assert_eq!(split_message("世界", 2), ["世", "界"]);
+        assert_eq!(split_message("  short  ", 20), ["short"]);
+        assert_eq!(split_message("", 20), [""]);
+    }
+
+    #[test]
+    fn every_split_part_fits_its_chunks_once_tagged_and_signed() {
+        let opts = TxOpts {
+            topic: 7,
+            ttl: 3,
+            dwell_ms: 1,
+            gap_ms: 0,
+            repeats: 1,
+            rate: 2.0,
+            burst: None,
+            adaptive: false,
+            rotate_in_place: false,
+            repeat_for: None,
+            chunk_size: MAX_PAYLOAD,
+            per_message_key: false,
+            private_topic: false,
+            compress: Compress::Never,
+            fec: Fec::None,
+            presence: false,
+        };
+        let sk = SigningKey::from_bytes(&[9; 32]);
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(12);
+        for (nick, subtopic, id_key) in [
+            (None, None, None),
+            (Some("ana"), Some("alerts"), None),
+            (Some("ana"), Some("alerts"), Some(&sk)),
+        ] {
+            for n in 1..=8 {
+                let Some(budget) = split_budget(n, &opts, nick, subtopic, id_key) else {
+                    let overhead = signed_len(tag_message(nick, subtopic, "").as_bytes(), id_key);
+                    assert!(overhead >= n * MAX_PAYLOAD);
+                    continue;
+                };
+                let parts = split_message(&text, budget);
+                assert!(parts.len() > 1);
+                for p in parts {
+                    let body = tag_message(nick, subtopic, &p);
+                    let len = signed_len(body.as_bytes(), id_key);
+                    assert!(estimate_airtime(len, &opts, true).chunks <= n, "{p:?}");
+                }
+            }
+        }
+    }
+}
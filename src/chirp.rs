@@ -0,0 +1,310 @@
+//! Embedding API: send and receive chirp messages from another program
+//! without going through the CLI.
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//!
+//! # async fn demo() -> anyhow::Result<()> {
+//! let chirp = ble_chirp::Chirp::builder()
+//!     .adapter(0)
+//!     .topic(7)
+//!     .passphrase("correct horse", "lobby")
+//!     .build()
+//!     .await?;
+//! let mut messages = chirp.subscribe();
+//! chirp.send("hello").await?;
+//! while let Some(m) = messages.next().await {
+//!     println!("[topic {}] {} ({} hops)", m.topic, m.text, m.hops);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+
+use crate::compress::Compress;
+use crate::fec::Fec;
+use crate::mesh::{MAX_REASM, REASM_TIMEOUT, SEEN_CAP, SEEN_TTL, tag_message};
+use crate::mute::MuteList;
+use crate::presence;
+use crate::relay::{MAX_RELAYS, RelayTopics};
+use crate::stats::Stats;
+use crate::{
+    DEFAULT_GAP_MS, MAX_PAYLOAD, MAX_RECONNECT, MsgId, RxOpts, TxOpts, adapter, crypto, rx_loop, tx,
+};
+
+/// A message received by [`Chirp::subscribe`].
+#[derive(Clone, Debug)]
+pub struct ChirpMessage {
+    pub topic: u8,
+    pub msg_id: MsgId,
+    pub text: String,
+    /// The sender's nickname, if it sent one.
+    pub nick: Option<String>,
+    /// Relays the message passed through on its way here.
+    pub hops: u8,
+    /// Ed25519 public key whose signature over the message verified, if any.
+    pub sender: Option<[u8; 32]>,
+}
+
+/// Configures a [`Chirp`]; start with [`Chirp::builder`].
+pub struct ChirpBuilder {
+    adapter: usize,
+    topic: u8,
+    ttl: u8,
+    rate: f64,
+    relay: bool,
+    key: Option<Key>,
+    nick: Option<String>,
+}
+
+enum Key {
+    Raw([u8; 32]),
+    Passphrase { passphrase: String, room: String },
+}
+
+impl ChirpBuilder {
+    /// Which BLE adapter to use, as for `--adapter`. Defaults to 0.
+    pub fn adapter(mut self, index: usize) -> Self {
+        self.adapter = index;
+        self
+    }
+
+    /// Topic to send on and receive from. Defaults to 7.
+    pub fn topic(mut self, topic: u8) -> Self {
+        self.topic = topic;
+        self
+    }
+
+    /// Relay hops allowed for sent messages. Defaults to 3.
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Chunks per second, as for `--rate`. Defaults to 2.
+    pub fn rate(mut self, rate: f64) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Relay other nodes' messages while subscribed. Defaults to true.
+    pub fn relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Encrypt with a 32-byte room key, as from `keygen`. Without a key
+    /// messages are sent and received in the clear.
+    ///
+    /// A key that can't have come from a random source is refused by
+    /// [`build`](Self::build), before any adapter is opened:
+    ///
+    /// ```
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let built = ble_chirp::Chirp::builder().key([0; 32]).build().await;
+    /// let err = built.err().expect("an all-zero key");
+    /// assert_eq!(err.to_string(), "key is all zeros");
+    /// # }
+    /// ```
+    pub fn key(mut self, key: [u8; 32]) -> Self {
+        self.key = Some(Key::Raw(key));
+        self
+    }
+
+    /// Encrypt with a key derived from `passphrase`, salted with `room`, as
+    /// for `--passphrase` with `--room`.
+    pub fn passphrase(mut self, passphrase: &str, room: &str) -> Self {
+        self.key = Some(Key::Passphrase {
+            passphrase: passphrase.to_string(),
+            room: room.to_string(),
+        });
+        self
+    }
+
+    /// Nickname sent with every message, as for `--nick`: 1 to 16 bytes,
+    /// no control characters.
+    ///
+    /// ```
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let built = ble_chirp::Chirp::builder().nick("line\nbreak").build().await;
+    /// let err = built.err().expect("a nickname with a newline");
+    /// assert!(err.to_string().contains("control characters"));
+    /// # }
+    /// ```
+    pub fn nick(mut self, nick: &str) -> Self {
+        self.nick = Some(nick.to_string());
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<Chirp> {
+        let nick = self
+            .nick
+            .as_deref()
+            .map(presence::parse_nick)
+            .transpose()
+            .map_err(anyhow::Error::msg)?;
+        let key = match self.key {
+            None => None,
+            Some(Key::Raw(bytes)) => {
+                let k = crypto::SecretKey::new(bytes);
+                crypto::validate_key_strength(&k)?;
+                Some(k)
+            }
+            Some(Key::Passphrase { passphrase, room }) => Some(crypto::derive_key(
+                &passphrase,
+                &crypto::salt_for(&room),
+                &crypto::KdfParams::default(),
+            )?),
+        };
+        Ok(Chirp {
+            adapter: adapter::resolve(self.adapter).await?,
+            topic: self.topic,
+            ttl: self.ttl,
+            rate: self.rate,
+            relay: self.relay,
+            key: key.map(|k| crypto::Keys::new(k, None)),
+            nick,
+            stats: Arc::new(Stats::default()),
+        })
+    }
+}
+
+/// A node on one topic: sends messages and streams the ones it receives.
+pub struct Chirp {
+    adapter: btleplug::platform::Adapter,
+    topic: u8,
+    ttl: u8,
+    rate: f64,
+    relay: bool,
+    key: Option<crypto::Keys>,
+    nick: Option<String>,
+    stats: Arc<Stats>,
+}
+
+impl Chirp {
+    pub fn builder() -> ChirpBuilder {
+        ChirpBuilder {
+            adapter: 0,
+            topic: 7,
+            ttl: 3,
+            rate: 2.0,
+            relay: true,
+            key: None,
+            nick: None,
+        }
+    }
+
+    /// Advertise `msg` once and return when every chunk has gone out, with
+    /// the msg_id it was sent under.
+    pub async fn send(&self, msg: &str) -> anyhow::Result<MsgId> {
+        let opts = TxOpts {
+            topic: self.topic,
+            ttl: self.ttl,
+            dwell_ms: 500,
+            gap_ms: DEFAULT_GAP_MS,
+            repeats: 1,
+            rate: self.rate,
+            burst: None,
+            adaptive: false,
+            rotate_in_place: false,
+            repeat_for: None,
+            chunk_size: MAX_PAYLOAD,
+            per_message_key: false,
+            private_topic: false,
+            compress: Compress::Never,
+            fec: Fec::None,
+            presence: false,
+        };
+        let body = tag_message(self.nick.as_deref(), None, msg);
+        tx(
+            self.adapter.clone(),
+            &opts,
+            body.as_bytes(),
+            self.key.as_ref(),
+            None,
+        )
+        .await
+    }
+
+    /// Receive counters, shared by every `subscribe` stream of this node.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Messages received on this node's topic from now on. Each call starts
+    /// its own scan, which stops when the stream is dropped.
+    pub fn subscribe(&self) -> impl Stream<Item = ChirpMessage> + use<> {
+        let (tx, rx) = unbounded_channel();
+        let opts = RxOpts {
+            topic_filter: Some(self.topic),
+            relay: self.relay,
+            keys: self.key.iter().cloned().collect(),
+            stats: self.stats.clone(),
+            relay_done: None,
+            queue: None,
+            events: None,
+            subtopic: None,
+            max_age: None,
+            debug_frames: false,
+            reasm_timeout: REASM_TIMEOUT,
+            max_reasm: MAX_REASM,
+            nack_after: None,
+            seen_cache: SEEN_CAP,
+            seen_ttl: SEEN_TTL,
+            max_relays: MAX_RELAYS,
+            relay_topics: RelayTopics::All,
+            suppress_relay: false,
+            max_reconnect: MAX_RECONNECT,
+            roster: None,
+            typing: None,
+            ack_as: None,
+            acks: None,
+            mutes: MuteList::default(),
+            rng_seed: None,
+        };
+        let task = tokio::spawn(rx_loop(
+            self.adapter.clone(),
+            opts,
+            move |d, _| {
+                let _ = tx.send(ChirpMessage {
+                    topic: d.topic,
+                    msg_id: d.msg_id,
+                    text: d.text(),
+                    nick: d.nick.clone(),
+                    hops: d.hops,
+                    sender: d.signer,
+                });
+            },
+            |_| {},
+        ));
+        Subscription { rx, task }
+    }
+}
+
+/// Stream returned by `Chirp::subscribe`; aborts its receive loop on drop.
+struct Subscription {
+    rx: UnboundedReceiver<ChirpMessage>,
+    task: tokio::task::JoinHandle<anyhow::Result<()>>,
+}
+
+impl Stream for Subscription {
+    type Item = ChirpMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ChirpMessage>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,85 @@
+use std::io::{Read, Write};
+
+use clap::ValueEnum;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+/// Largest body `inflate` will produce: 255 chunks of the biggest payload
+/// that fits an advertisement. Anything larger is a decompression bomb.
+const MAX_INFLATED: usize = 255 * crate::MAX_MD_LEN;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Compress {
+    /// Compress only when it saves bytes
+    Auto,
+    /// Always send the DEFLATE stream, even if it is larger
+    Always,
+    /// Send the body as is
+    #[default]
+    Never,
+}
+
+/// Raw DEFLATE `body` according to `mode`. Returns the bytes to chunk and
+/// whether they are compressed.
+pub fn deflate(body: &[u8], mode: Compress) -> (Vec<u8>, bool) {
+    if mode == Compress::Never {
+        return (body.to_vec(), false);
+    }
+    let mut enc = DeflateEncoder::new(Vec::new(), Compression::best());
+    let packed = enc.write_all(body).and_then(|()| enc.finish());
+    match packed {
+        Ok(packed) if mode == Compress::Always || packed.len() < body.len() => (packed, true),
+        _ => (body.to_vec(), false),
+    }
+}
+
+/// Inverse of `deflate`. `None` if the stream is corrupt or inflates past
+/// `MAX_INFLATED`.
+pub fn inflate(packed: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    DeflateDecoder::new(packed)
+        .take(MAX_INFLATED as u64 + 1)
+        .read_to_end(&mut out)
+        .ok()?;
+    (out.len() <= MAX_INFLATED).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{RngCore, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    #[test]
+    fn compressible_body_round_trips() {
+        let body = "the same words over and over ".repeat(10);
+        let (packed, compressed) = deflate(body.as_bytes(), Compress::Auto);
+        assert!(compressed);
+        assert!(packed.len() < body.len());
+        assert_eq!(inflate(&packed).unwrap(), body.as_bytes());
+    }
+
+    #[test]
+    fn incompressible_body_round_trips() {
+        let mut body = vec![0u8; 400];
+        StdRng::seed_from_u64(1).fill_bytes(&mut body);
+        let (packed, compressed) = deflate(&body, Compress::Auto);
+        assert!(!compressed);
+        assert_eq!(packed, body);
+
+        let (packed, compressed) = deflate(&body, Compress::Always);
+        assert!(compressed);
+        assert!(packed.len() > body.len());
+        assert_eq!(inflate(&packed).unwrap(), body);
+    }
+
+    #[test]
+    fn inflate_stops_at_max_inflated() {
+        let (at_cap, _) = deflate(&vec![0; MAX_INFLATED], Compress::Always);
+        assert_eq!(inflate(&at_cap).map(|b| b.len()), Some(MAX_INFLATED));
+        let (bomb, _) = deflate(&vec![0; MAX_INFLATED + 1], Compress::Always);
+        assert!(bomb.len() < 100);
+        assert_eq!(inflate(&bomb), None);
+    }
+}
@@ -0,0 +1,295 @@
+//! `--config`: defaults for the common flags from a TOML file, so they don't
+//! have to be repeated on every invocation.
+//!
+//! ```toml
+//! adapter = 1
+//! room = "lobby"
+//! key_file = "chirp.key"   # relative to this file
+//! nick = "ana"
+//! mute = ["nick:spammer", "id:a1b2c3d4"]
+//!
+//! [profiles.field]
+//! room = "ops"
+//! ttl = 5
+//! rate = 4.0
+//! dwell_ms = 200
+//! ```
+//!
+//! Precedence is command line, then the `--config-profile` table, then the
+//! top-level keys, then the built-in defaults. `mute` lists are the
+//! exception: the config's add to any `--mute` given.
+//!
+//! `rate` and `dwell_ms` only apply to the commands that take them on the
+//! command line: `tx` and `chat`, and `send-file` for `rate`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use serde::Deserialize;
+
+use crate::{Args, Cmd, PASSPHRASE_ENV, mute, presence};
+
+#[derive(Debug, Default, Deserialize)]
+struct Settings {
+    adapter: Option<usize>,
+    room: Option<String>,
+    ttl: Option<u8>,
+    rate: Option<f64>,
+    dwell_ms: Option<u64>,
+    key_file: Option<PathBuf>,
+    nick: Option<String>,
+    mute: Option<Vec<String>>,
+}
+
+impl Settings {
+    /// `self` with gaps filled from `base`.
+    fn or(self, base: Settings) -> Settings {
+        Settings {
+            adapter: self.adapter.or(base.adapter),
+            room: self.room.or(base.room),
+            ttl: self.ttl.or(base.ttl),
+            rate: self.rate.or(base.rate),
+            dwell_ms: self.dwell_ms.or(base.dwell_ms),
+            key_file: self.key_file.or(base.key_file),
+            nick: self.nick.or(base.nick),
+            mute: self.mute.or(base.mute),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Config {
+    #[serde(flatten)]
+    defaults: Settings,
+    #[serde(default)]
+    profiles: HashMap<String, Settings>,
+}
+
+/// `$XDG_CONFIG_HOME/ble-chirp`, falling back to `~/.config`.
+pub fn dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))?;
+    Some(base.join("ble-chirp"))
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(dir()?.join("config.toml"))
+}
+
+/// The settings `args` asks for. A missing default config file means no
+/// settings; a missing `--config` file or unknown profile is an error.
+fn load(args: &Args) -> anyhow::Result<Settings> {
+    let path = match &args.config {
+        Some(path) => path.clone(),
+        None => match default_path().filter(|p| p.exists()) {
+            Some(path) => path,
+            None if args.config_profile.is_some() => {
+                anyhow::bail!("--config-profile needs a config file")
+            }
+            None => return Ok(Settings::default()),
+        },
+    };
+    let text =
+        std::fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+    let mut config: Config =
+        toml::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+    let mut settings = match &args.config_profile {
+        Some(name) => config
+            .profiles
+            .remove(name)
+            .with_context(|| format!("no profile {name:?} in {}", path.display()))?
+            .or(config.defaults),
+        None => config.defaults,
+    };
+    if let Some(dir) = path.parent() {
+        settings.key_file = settings.key_file.map(|k| dir.join(k));
+    }
+    Ok(settings)
+}
+
+/// Whether `id` was given on the command line rather than defaulted.
+fn explicit(m: &ArgMatches, id: &str) -> bool {
+    !matches!(m.value_source(id), None | Some(ValueSource::DefaultValue))
+}
+
+/// Set `field` from the config unless `id` was given on the command line.
+fn fill<T: Clone>(field: &mut T, value: &Option<T>, m: &ArgMatches, id: &str) {
+    if let Some(v) = value.as_ref().filter(|_| !explicit(m, id)) {
+        *field = v.clone();
+    }
+}
+
+/// Fill in whatever the command line left at its default from the config
+/// file. Must run before the room is used to derive keys.
+pub fn apply(args: &mut Args, matches: &ArgMatches) -> anyhow::Result<()> {
+    let s = load(args)?;
+    fill(&mut args.adapter, &s.adapter, matches, "adapter");
+    // Any key given on the command line or in the environment wins.
+    if args.key_file.is_none()
+        && args.passphrase.is_empty()
+        && args.passphrase_file.is_none()
+        && std::env::var_os(PASSPHRASE_ENV).is_none()
+    {
+        args.key_file = s.key_file.clone();
+    }
+    if args.nick.is_none() {
+        args.nick = s
+            .nick
+            .as_deref()
+            .map(presence::parse_nick)
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("config nick: {e}"))?;
+    }
+    for m in s.mute.iter().flatten() {
+        let m = mute::parse_mute(m).map_err(|e| anyhow::anyhow!("config mute {m:?}: {e}"))?;
+        if !args.mute.contains(&m) {
+            args.mute.push(m);
+        }
+    }
+    let Some((_, sub)) = matches.subcommand() else {
+        return Ok(());
+    };
+    // A room only applies when no topic was picked explicitly.
+    let room_unless = |room: &mut Option<String>, topic_given: bool| {
+        if room.is_none() && !topic_given {
+            room.clone_from(&s.room);
+        }
+    };
+    match &mut args.cmd {
+        Cmd::Tx {
+            room,
+            topics,
+            all_topics,
+            ttl,
+            rate,
+            dwell_ms,
+            profile,
+            ..
+        } => {
+            room_unless(
+                room,
+                explicit(sub, "topic") || *all_topics || !topics.is_empty(),
+            );
+            fill(ttl, &s.ttl, sub, "ttl");
+            fill(rate, &s.rate, sub, "rate");
+            // A delivery --profile brings its own dwell.
+            if dwell_ms.is_none() && profile.is_none() {
+                *dwell_ms = s.dwell_ms;
+            }
+        }
+        Cmd::Chat {
+            room,
+            ttl,
+            rate,
+            dwell_ms,
+            profile,
+            ..
+        } => {
+            room_unless(room, explicit(sub, "topic"));
+            fill(ttl, &s.ttl, sub, "ttl");
+            fill(rate, &s.rate, sub, "rate");
+            if dwell_ms.is_none() && profile.is_none() {
+                *dwell_ms = s.dwell_ms;
+            }
+        }
+        Cmd::SendFile {
+            room, ttl, rate, ..
+        } => {
+            room_unless(room, explicit(sub, "topic"));
+            fill(ttl, &s.ttl, sub, "ttl");
+            fill(rate, &s.rate, sub, "rate");
+        }
+        Cmd::Rx { topic, room, .. }
+        | Cmd::RecvFile { topic, room, .. }
+        | Cmd::Signal { topic, room, .. } => room_unless(room, topic.is_some()),
+        Cmd::Calibrate { .. } | Cmd::Simulate { .. } | Cmd::Keygen { .. } | Cmd::ListAdapters => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// Parse `argv` with `config` as the `--config` file, then apply it.
+    fn applied(name: &str, config: &str, argv: &[&str]) -> Args {
+        let path = std::env::temp_dir().join(format!("chirp-{name}-{}.toml", std::process::id()));
+        std::fs::write(&path, config).unwrap();
+        let full = ["ble-chirp", "--config", path.to_str().unwrap()]
+            .into_iter()
+            .chain(argv.iter().copied());
+        let matches = Args::command().try_get_matches_from(full).unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        apply(&mut args, &matches).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        args
+    }
+
+    fn tx_ttl(args: &Args) -> u8 {
+        match args.cmd {
+            Cmd::Tx { ttl, .. } => ttl,
+            _ => unreachable!("parsed a tx command"),
+        }
+    }
+
+    #[test]
+    fn the_command_line_beats_the_profile_beats_the_top_level_keys() {
+        let config = "ttl = 4\n[profiles.field]\nttl = 5\n";
+        let ttl = |argv: &[&str]| tx_ttl(&applied("precedence", config, argv));
+        assert_eq!(ttl(&["tx", "hi"]), 4);
+        assert_eq!(ttl(&["--config-profile", "field", "tx", "hi"]), 5);
+        assert_eq!(
+            ttl(&["--config-profile", "field", "tx", "--ttl", "6", "hi"]),
+            6
+        );
+        // Given explicitly, even the default value wins.
+        assert_eq!(ttl(&["tx", "--ttl", "3", "hi"]), 3);
+        assert_eq!(tx_ttl(&applied("defaults", "", &["tx", "hi"])), 3);
+    }
+
+    #[test]
+    fn a_room_from_the_config_is_skipped_when_a_topic_is_given() {
+        let room = |argv: &[&str]| match applied("room", "room = \"lobby\"\n", argv).cmd {
+            Cmd::Rx { room, .. } => room,
+            _ => unreachable!("parsed an rx command"),
+        };
+        assert_eq!(room(&["rx"]).as_deref(), Some("lobby"));
+        assert_eq!(room(&["rx", "--topic", "9"]), None);
+    }
+
+    #[test]
+    fn chat_paces_its_sends_from_the_config_like_tx() {
+        let config = "rate = 4.0\ndwell_ms = 200\n";
+        let pacing = |argv: &[&str]| match applied("chat-pacing", config, argv).cmd {
+            Cmd::Chat { rate, dwell_ms, .. } => (rate, dwell_ms),
+            _ => unreachable!("parsed a chat command"),
+        };
+        assert_eq!(pacing(&["chat"]), (4.0, Some(200)));
+        assert_eq!(
+            pacing(&["chat", "--rate", "1", "--dwell-ms", "300"]),
+            (1.0, Some(300))
+        );
+        assert_eq!(pacing(&["chat", "--profile", "reliable"]), (4.0, None));
+    }
+
+    #[test]
+    fn config_mutes_add_to_the_command_line() {
+        let config = "mute = [\"nick:spammer\", \"nick:troll\"]\n";
+        let args = applied("mute", config, &["--mute", "nick:troll", "rx"]);
+        let nicks: Vec<_> = args
+            .mute
+            .iter()
+            .map(|m| match m {
+                mute::Mute::Nick(n) => n.as_str(),
+                _ => unreachable!("only nicks were muted"),
+            })
+            .collect();
+        assert_eq!(nicks, ["troll", "spammer"]);
+    }
+}
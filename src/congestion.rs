@@ -0,0 +1,122 @@
+//! Adaptive send rate for `--adaptive`. While sending, a passive scan counts
+//! other chirp frames on the air; when that rate says the channel is busy the
+//! send rate is halved, and otherwise it creeps back up to the configured
+//! `--rate` (AIMD).
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+
+use crate::transport::Transport;
+
+/// How often the send rate is re-evaluated.
+const WINDOW: Duration = Duration::from_secs(1);
+/// Chirp frames per second heard from others above which the channel counts
+/// as congested.
+const BUSY_FRAMES_PER_SEC: f64 = 10.0;
+/// Never throttle below this many chunks per second.
+const MIN_RATE: f64 = 0.25;
+/// Fraction of the configured rate recovered per quiet window.
+const INCREASE: f64 = 0.1;
+
+/// Counts chirp frames heard on an adapter until dropped.
+pub struct Monitor {
+    heard: Arc<AtomicU64>,
+    since: Instant,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Monitor {
+    pub fn start<T: Transport>(transport: &T) -> Self {
+        let mut events = transport.events();
+        let heard = Arc::new(AtomicU64::new(0));
+        let counter = heard.clone();
+        let task = tokio::spawn(async move {
+            while events.next().await.is_some() {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        Self {
+            heard,
+            since: Instant::now(),
+            task,
+        }
+    }
+
+    /// Frames heard per second since the last sample, once a full `WINDOW`
+    /// has passed.
+    pub fn sample(&mut self) -> Option<f64> {
+        let elapsed = self.since.elapsed();
+        if elapsed < WINDOW {
+            return None;
+        }
+        self.since = Instant::now();
+        Some(self.heard.swap(0, Ordering::Relaxed) as f64 / elapsed.as_secs_f64())
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Additive-increase, multiplicative-decrease controller for the send rate.
+pub struct Aimd {
+    max: f64,
+    rate: f64,
+}
+
+impl Aimd {
+    pub fn new(max: f64) -> Self {
+        Self { max, rate: max }
+    }
+
+    /// Next send rate given how many frames per second others are sending.
+    pub fn update(&mut self, heard_per_sec: f64) -> f64 {
+        self.rate = if heard_per_sec > BUSY_FRAMES_PER_SEC {
+            (self.rate / 2.0).max(MIN_RATE.min(self.max))
+        } else {
+            (self.rate + self.max * INCREASE).min(self.max)
+        };
+        self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUSY: f64 = BUSY_FRAMES_PER_SEC + 1.0;
+
+    #[test]
+    fn aimd_halves_when_busy_down_to_the_floor() {
+        let mut aimd = Aimd::new(4.0);
+        let rates: Vec<f64> = (0..6).map(|_| aimd.update(BUSY)).collect();
+        assert_eq!(rates, [2.0, 1.0, 0.5, MIN_RATE, MIN_RATE, MIN_RATE]);
+
+        // A configured rate under the floor is never exceeded.
+        let mut slow = Aimd::new(0.1);
+        assert_eq!(slow.update(BUSY), 0.1);
+    }
+
+    #[test]
+    fn aimd_recovers_a_tenth_of_the_rate_per_quiet_window_up_to_it() {
+        let mut aimd = Aimd::new(4.0);
+        assert_eq!(aimd.update(0.0), 4.0, "already at the configured rate");
+        aimd.update(BUSY);
+        aimd.update(BUSY);
+        assert_eq!(aimd.rate, 1.0);
+        // Exactly at the threshold is not busy.
+        let up = aimd.update(BUSY_FRAMES_PER_SEC);
+        assert!((up - 1.4).abs() < 1e-9, "{up}");
+        for _ in 0..6 {
+            aimd.update(0.0);
+        }
+        assert!((aimd.rate - 3.8).abs() < 1e-9, "{}", aimd.rate);
+        assert_eq!(aimd.update(0.0), 4.0);
+        assert_eq!(aimd.update(0.0), 4.0);
+    }
+}
@@ -1,33 +1,395 @@
 use anyhow::Result;
+use argon2::{Algorithm, Argon2, Params, Version};
 use chacha20poly1305::{
     ChaCha20Poly1305, Key, Nonce,
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
 };
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+pub use ed25519_dalek::SigningKey;
 
 pub type KeyBytes = [u8; 32];
 
-pub fn derive_key(passphrase: &str) -> KeyBytes {
-    let digest = Sha256::digest(passphrase.as_bytes());
+/// Key material that is wiped when dropped, so keys don't linger in freed
+/// memory (and from there in core dumps or swap). Deliberately not `Copy`:
+/// every copy would be one more buffer to wipe.
+#[derive(Clone)]
+pub struct SecretKey(KeyBytes);
+
+impl SecretKey {
+    pub fn new(bytes: KeyBytes) -> Self {
+        Self(bytes)
+    }
+
+    pub fn expose(&self) -> &KeyBytes {
+        &self.0
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Compare two keys without an early exit, so timing doesn't reveal how
+/// many leading bytes match.
+pub(crate) fn keys_equal(a: &SecretKey, b: &SecretKey) -> bool {
+    a.0.ct_eq(&b.0).into()
+}
+
+/// Fewest distinct byte values a key may contain. 32 random bytes almost
+/// always have more than 25; a key below this was typed or patterned.
+const MIN_DISTINCT_BYTES: usize = 16;
+
+/// Reject keys that can't have come from a random source: all zeros, or
+/// too few distinct byte values (e.g. a repeated pattern or ASCII text).
+pub(crate) fn validate_key_strength(key: &SecretKey) -> Result<()> {
+    if bool::from(key.0.ct_eq(&[0u8; 32])) {
+        anyhow::bail!("key is all zeros");
+    }
+    let mut present = [false; 256];
+    key.0.iter().for_each(|&b| present[b as usize] = true);
+    let distinct = present.iter().filter(|&&p| p).count();
+    if distinct < MIN_DISTINCT_BYTES {
+        anyhow::bail!(
+            "key has only {distinct} distinct byte values and looks low-entropy; generate one with `keygen`"
+        );
+    }
+    Ok(())
+}
+
+/// Argon2id cost parameters. Every participant in a room must use the same
+/// values, since they change the derived key.
+#[derive(Clone, Copy, Debug)]
+pub struct KdfParams {
+    pub mem_kib: u32,
+    pub iters: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// OWASP's minimum recommendation for Argon2id.
+    fn default() -> Self {
+        Self {
+            mem_kib: 19 * 1024,
+            iters: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Salt for `derive_key`: SHA-256 of the room name (or the `--salt` string),
+/// so TX and RX agree on it without sending anything.
+pub fn salt_for(room: &str) -> [u8; 32] {
+    Sha256::digest(room.as_bytes()).into()
+}
+
+pub fn derive_key(passphrase: &str, salt: &[u8; 32], params: &KdfParams) -> Result<SecretKey> {
+    let p = Params::new(params.mem_kib, params.iters, params.parallelism, Some(32))
+        .map_err(|e| anyhow::anyhow!("invalid KDF parameters: {e}"))?;
+    let mut k = SecretKey([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, p)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut k.0)
+        .map_err(|e| anyhow::anyhow!("derive key: {e}"))?;
+    Ok(k)
+}
+
+/// `nonce` and `aad` come from the frame header (see `Frame::nonce` and
+/// `Frame::aad`); the header is authenticated but not encrypted, so tampering
+/// with it makes `decrypt` fail.
+pub fn encrypt(key: &SecretKey, nonce: &[u8; 12], aad: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.0));
+    let nonce = Nonce::from_slice(nonce);
+    Ok(cipher.encrypt(nonce, Payload { msg: payload, aad })?)
+}
+
+pub fn decrypt(key: &SecretKey, nonce: &[u8; 12], aad: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.0));
+    let nonce = Nonce::from_slice(nonce);
+    Ok(cipher.decrypt(nonce, Payload { msg: payload, aad })?)
+}
+
+/// Passphrase key plus the optional session-key schedule derived from it.
+///
+/// With `rekey_secs` set, time is cut into epochs of that length (counted from
+/// the Unix epoch) and every message is sealed under a per-epoch session key
+/// `HKDF-SHA256(master, "ble-chirp session" || epoch)`. A leaked session key
+/// exposes only its own epoch, and each key only ever sees one epoch's worth of
+/// random 48-bit msg_ids, which bounds the chance of a nonce collision.
+///
+/// The epoch is never sent: peers must agree on wall-clock time to within one
+/// period. Receivers try the previous and next epochs too, so a message sent
+/// just before a boundary (or by a slightly skewed clock) still opens.
+#[derive(Clone)]
+pub struct Keys {
+    master: SecretKey,
+    rekey_secs: Option<u64>,
+}
+
+impl Keys {
+    pub fn new(master: SecretKey, rekey_secs: Option<u64>) -> Self {
+        Self {
+            master,
+            rekey_secs: rekey_secs.filter(|&s| s > 0),
+        }
+    }
+
+    /// Key to seal a new message with.
+    pub fn tx_key(&self) -> SecretKey {
+        match self.rekey_secs {
+            None => self.master.clone(),
+            Some(period) => epoch_key(&self.master, current_epoch(period)),
+        }
+    }
+
+    /// Ephemeral public key and session key for a new handshake-mode message.
+    pub fn tx_handshake(&self) -> ([u8; 32], SecretKey) {
+        handshake_initiate(&self.tx_key())
+    }
+
+    /// XOR mask for the topic byte of a private-topic frame. It changes with
+    /// every `msg_id`, so the visible byte says nothing about the channel to
+    /// anyone without the key. Derived from the master key rather than the
+    /// epoch key so it doesn't depend on clock agreement.
+    pub fn topic_mask(&self, msg_id: &[u8]) -> u8 {
+        let mut k = SecretKey([0u8; 32]);
+        Hkdf::<Sha256>::new(None, &self.master.0)
+            .expand(b"ble-chirp topic", &mut k.0)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(&k.0).expect("HMAC accepts keys of any length");
+        mac.update(msg_id);
+        mac.finalize().into_bytes()[0]
+    }
+
+    /// Open a chunk under whichever candidate key authenticates it.
+    pub fn decrypt(&self, nonce: &[u8; 12], aad: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+        self.decrypt_with(SecretKey::clone, nonce, aad, payload)
+    }
+
+    /// Open a chunk of a message announced by a handshake frame carrying
+    /// `eph_pub`.
+    pub fn decrypt_handshake(
+        &self,
+        eph_pub: &[u8; 32],
+        nonce: &[u8; 12],
+        aad: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.decrypt_with(|k| handshake_respond(k, eph_pub), nonce, aad, payload)
+    }
+
+    fn decrypt_with(
+        &self,
+        derive: impl Fn(&SecretKey) -> SecretKey,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut last = None;
+        for k in self.candidates() {
+            match decrypt(&derive(&k), nonce, aad, payload) {
+                Ok(p) => return Ok(p),
+                Err(err) => last = Some(err),
+            }
+        }
+        Err(last.unwrap_or_else(|| anyhow::anyhow!("no candidate epoch")))
+    }
+
+    /// The master key, or with rekeying the current, previous and next
+    /// epochs' keys.
+    fn candidates(&self) -> Vec<SecretKey> {
+        let Some(period) = self.rekey_secs else {
+            return vec![self.master.clone()];
+        };
+        let e = current_epoch(period);
+        [Some(e), e.checked_sub(1), e.checked_add(1)]
+            .into_iter()
+            .flatten()
+            .map(|epoch| epoch_key(&self.master, epoch))
+            .collect()
+    }
+}
+
+// Per-message keys (`--per-message-key`). Each message gets a fresh X25519
+// ephemeral key whose public half goes out in a handshake frame; its chunks
+// are sealed under `HKDF-SHA256(salt = room key, ikm = DH(ephemeral, room
+// point))`, and the ephemeral secret is dropped once the message is built.
+//
+// This is not forward secrecy, and the mode is deliberately not named as if
+// it were. Receivers are passive and have no key pair of their own, so the
+// other side of the DH is a point derived from the room key, whose secret
+// every room member can compute. Each message's key is independent (one
+// leaked message key exposes one message), but anyone holding the room key
+// can re-derive every recorded message's key from its handshake frame.
+
+fn room_secret(base: &SecretKey) -> StaticSecret {
     let mut k = [0u8; 32];
-    k.copy_from_slice(&digest);
+    Hkdf::<Sha256>::new(None, &base.0)
+        .expand(b"ble-chirp room x25519", &mut k)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    // StaticSecret wipes itself on drop; wipe the stack copy too.
+    let secret = StaticSecret::from(k);
+    k.zeroize();
+    secret
+}
+
+fn handshake_session(base: &SecretKey, shared: &[u8; 32]) -> SecretKey {
+    let mut k = SecretKey([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&base.0), shared)
+        .expand(b"ble-chirp handshake", &mut k.0)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    k
+}
+
+pub fn handshake_initiate(base: &SecretKey) -> ([u8; 32], SecretKey) {
+    let eph = StaticSecret::from(rand::random::<[u8; 32]>());
+    let room = PublicKey::from(&room_secret(base));
+    let shared = eph.diffie_hellman(&room);
+    (
+        PublicKey::from(&eph).to_bytes(),
+        handshake_session(base, shared.as_bytes()),
+    )
+}
+
+pub fn handshake_respond(base: &SecretKey, eph_pub: &[u8; 32]) -> SecretKey {
+    let shared = room_secret(base).diffie_hellman(&PublicKey::from(*eph_pub));
+    handshake_session(base, shared.as_bytes())
+}
+
+pub fn epoch_key(master: &SecretKey, epoch: u64) -> SecretKey {
+    let mut info = *b"ble-chirp session\0\0\0\0\0\0\0\0";
+    info[17..].copy_from_slice(&epoch.to_be_bytes());
+    let mut k = SecretKey([0u8; 32]);
+    Hkdf::<Sha256>::new(None, &master.0)
+        .expand(&info, &mut k.0)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
     k
 }
 
-pub fn encrypt(key: &KeyBytes, msg_id: &[u8; 4], seq: u8, payload: &[u8]) -> Result<Vec<u8>> {
-    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
-    let mut nonce_bytes = [0u8; 12];
-    nonce_bytes[..4].copy_from_slice(msg_id);
-    nonce_bytes[4] = seq;
-    let nonce = Nonce::from_slice(&nonce_bytes);
-    Ok(cipher.encrypt(nonce, payload)?)
+pub fn current_epoch(period_secs: u64) -> u64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() / period_secs
 }
 
-pub fn decrypt(key: &KeyBytes, msg_id: &[u8; 4], seq: u8, payload: &[u8]) -> Result<Vec<u8>> {
-    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
-    let mut nonce_bytes = [0u8; 12];
-    nonce_bytes[..4].copy_from_slice(msg_id);
-    nonce_bytes[4] = seq;
-    let nonce = Nonce::from_slice(&nonce_bytes);
-    Ok(cipher.decrypt(nonce, payload)?)
+pub const SIG_LEN: usize = 64;
+pub const PUBKEY_LEN: usize = 32;
+
+pub fn sign(identity: &SigningKey, msg: &[u8]) -> [u8; SIG_LEN] {
+    identity.sign(msg).to_bytes()
+}
+
+pub fn verify(pubkey: &[u8; PUBKEY_LEN], msg: &[u8], sig: &[u8; SIG_LEN]) -> bool {
+    VerifyingKey::from_bytes(pubkey)
+        .is_ok_and(|pk| pk.verify(msg, &Signature::from_bytes(sig)).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_message_gets_its_own_key() {
+        let room = SecretKey::new([0x42; 32]);
+        let (pub_a, key_a) = handshake_initiate(&room);
+        let (pub_b, key_b) = handshake_initiate(&room);
+        assert_ne!(pub_a, pub_b);
+        assert!(!keys_equal(&key_a, &key_b));
+        // Anyone with the room key derives the same message key: this is
+        // why the mode is not forward secrecy.
+        assert!(keys_equal(&handshake_respond(&room, &pub_a), &key_a));
+        assert!(keys_equal(&handshake_respond(&room, &pub_b), &key_b));
+    }
+
+    #[test]
+    fn a_dropped_key_leaves_only_zeros_behind() {
+        let mut slot = std::mem::MaybeUninit::new(SecretKey::new([0xa5; 32]));
+        // SAFETY: `slot` holds an initialised key, dropped exactly once here;
+        // its storage stays ours afterwards, and `[u8; 32]` has no invalid
+        // bit patterns, so reading the field back is sound.
+        let left = unsafe {
+            slot.as_mut_ptr().drop_in_place();
+            std::ptr::addr_of!((*slot.as_ptr()).0).read()
+        };
+        assert_eq!(left, [0; 32]);
+    }
+
+    #[test]
+    fn weak_keys_are_refused() {
+        let err = validate_key_strength(&SecretKey::new([0; 32])).unwrap_err();
+        assert!(err.to_string().contains("all zeros"), "{err}");
+
+        let patterned = SecretKey::new(*b"deadbeefdeadbeefdeadbeefdeadbeef");
+        let err = validate_key_strength(&patterned).unwrap_err();
+        assert!(err.to_string().contains("low-entropy"), "{err}");
+        let typed = SecretKey::new(*b"correct horse battery staple!!!!");
+        assert!(validate_key_strength(&typed).is_err());
+
+        let spread = std::array::from_fn(|i| i as u8 * 7);
+        validate_key_strength(&SecretKey::new(spread)).unwrap();
+    }
+
+    #[test]
+    fn keys_compare_by_every_byte() {
+        let a = SecretKey::new([0x42; 32]);
+        assert!(keys_equal(&a, &a.clone()));
+        for at in [0, 15, 31] {
+            let mut b = [0x42; 32];
+            b[at] ^= 1;
+            assert!(!keys_equal(&a, &SecretKey::new(b)), "differs at byte {at}");
+        }
+    }
+
+    #[test]
+    fn a_passphrase_derives_one_key_per_room() {
+        // Argon2's minimum cost keeps the test fast; the salt is what's tested.
+        let params = KdfParams {
+            mem_kib: 8,
+            iters: 1,
+            parallelism: 1,
+        };
+        let lobby = derive_key("hunter2", &salt_for("lobby"), &params).unwrap();
+        let again = derive_key("hunter2", &salt_for("lobby"), &params).unwrap();
+        let attic = derive_key("hunter2", &salt_for("attic"), &params).unwrap();
+        assert!(keys_equal(&lobby, &again));
+        assert!(!keys_equal(&lobby, &attic));
+
+        // Pinned: changing the salt silently splits every room in two, and
+        // any other sender that derives passphrase keys must change with it.
+        assert_eq!(
+            hex::encode(salt_for("lobby")),
+            "4b5dc076e7b9c122b3c89121a9710fc73b490d48e1ff3661557919175e505633"
+        );
+    }
+
+    #[test]
+    fn a_message_opens_one_epoch_either_side_and_no_further() {
+        // A long period keeps the test clear of an epoch boundary.
+        let period = 1 << 20;
+        let master = SecretKey::new([0x42; 32]);
+        let keys = Keys::new(master.clone(), Some(period));
+        let now = current_epoch(period);
+        let (nonce, aad) = ([7; 12], b"hdr");
+        for (epoch, opens) in [
+            (now - 2, false),
+            (now - 1, true),
+            (now, true),
+            (now + 1, true),
+            (now + 2, false),
+        ] {
+            let sealed = encrypt(&epoch_key(&master, epoch), &nonce, aad, b"hi").unwrap();
+            let opened = keys.decrypt(&nonce, aad, &sealed).is_ok();
+            assert_eq!(opened, opens, "epoch {epoch}");
+        }
+        assert!(keys_equal(&keys.tx_key(), &epoch_key(&master, now)));
+    }
 }
@@ -0,0 +1,474 @@
+//! Forward error correction for lossy links.
+//!
+//! `Fec::Fountain` is a systematic LT code: the message (prefixed with its
+//! length) is cut into `k` equal blocks, droplets `0..k` are the blocks
+//! themselves and every later droplet is the XOR of a pseudo-random set of
+//! blocks. A droplet's set is derived from the `msg_id` and the droplet id
+//! (carried in `seq`), so nothing but the id travels with it, and a receiver
+//! can rebuild the message from roughly any `k` droplets plus a few extra.
+//!
+//! `Fec::Rs` is a Reed-Solomon erasure code: the message is cut into exactly
+//! `k` data shards followed by `n - k` parity shards, any `k` of which
+//! rebuild it. It is cheaper than fountain coding when the loss rate is
+//! known and moderate.
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::{FLAG_FOUNTAIN, FLAG_PARITY, FLAG_RS, Frame, MsgId};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fec {
+    /// Plain chunks: every one of them must arrive.
+    #[default]
+    None,
+    /// LT fountain droplets; see the module docs.
+    Fountain,
+    /// `k` data shards plus `n - k` Reed-Solomon parity shards.
+    Rs { k: u8, n: u16 },
+}
+
+/// clap value parser for `--fec`: `none`, `fountain` or `rs:<k>:<n>`.
+pub fn parse_fec(s: &str) -> Result<Fec, String> {
+    match s {
+        "none" => return Ok(Fec::None),
+        "fountain" => return Ok(Fec::Fountain),
+        _ => {}
+    }
+    let Some((k, n)) = s.strip_prefix("rs:").and_then(|kn| kn.split_once(':')) else {
+        return Err(format!(
+            "unknown FEC mode {s:?}; expected none, fountain or rs:<k>:<n>"
+        ));
+    };
+    let k: u8 = k.parse().map_err(|e| format!("rs k {k:?}: {e}"))?;
+    let n: u16 = n.parse().map_err(|e| format!("rs n {n:?}: {e}"))?;
+    if k == 0 || n <= k as u16 || n as usize > MAX_DROPLETS {
+        return Err(format!("rs:{k}:{n} needs 0 < k < n <= {MAX_DROPLETS}"));
+    }
+    Ok(Fec::Rs { k, n })
+}
+
+/// Droplet ids and shard indices are one byte.
+pub const MAX_DROPLETS: usize = 256;
+/// Bytes of body length prepended to a coded message.
+pub const LEN_PREFIX: usize = 2;
+
+/// Source blocks `fountain_encode` cuts a `body_len`-byte message into.
+pub fn block_count(body_len: usize, block_len: usize) -> usize {
+    (LEN_PREFIX + body_len).div_ceil(block_len)
+}
+
+/// Cut `body` into `k` blocks of `block_len` bytes and emit `count`
+/// droplets as `(droplet id, k, payload)`, the same shape as
+/// `chunk_message`. `count` is clamped to `k..=MAX_DROPLETS`: droplet ids
+/// are one byte, so there are no new droplets past the 256th.
+pub fn fountain_encode(
+    body: &[u8],
+    block_len: usize,
+    msg_id: &MsgId,
+    count: usize,
+) -> anyhow::Result<Vec<(u8, u8, Vec<u8>)>> {
+    let mut source = prefixed(body)?;
+    let k = block_count(body.len(), block_len);
+    if k > u8::MAX as usize {
+        anyhow::bail!("message needs {k} blocks; fountain coding allows at most 255");
+    }
+    source.resize(k * block_len, 0);
+    let blocks: Vec<&[u8]> = source.chunks(block_len).collect();
+    let seed = seed_of(msg_id);
+    let count = count.clamp(k, MAX_DROPLETS);
+    Ok((0..count)
+        .map(|id| {
+            let mut data = vec![0u8; block_len];
+            for i in neighbors(seed, k, id as u8) {
+                xor_into(&mut data, blocks[i]);
+            }
+            (id as u8, k as u8, data)
+        })
+        .collect())
+}
+
+/// Cut `body` into `k` equal data shards and append `n - k` parity shards,
+/// as `(shard index, k, payload)`. Fails if a shard would be longer than
+/// `max_shard_len`, i.e. the message needs a larger `k`.
+pub fn rs_encode(
+    body: &[u8],
+    max_shard_len: usize,
+    k: u8,
+    n: u16,
+) -> anyhow::Result<Vec<(u8, u8, Vec<u8>)>> {
+    let mut source = prefixed(body)?;
+    let data = k as usize;
+    let shard_len = source.len().div_ceil(data);
+    if shard_len > max_shard_len {
+        anyhow::bail!(
+            "message needs {} data shards; raise k in rs:{k}:{n}",
+            block_count(body.len(), max_shard_len)
+        );
+    }
+    source.resize(data * shard_len, 0);
+    let mut shards: Vec<Vec<u8>> = source.chunks(shard_len).map(<[u8]>::to_vec).collect();
+    shards.resize(n as usize, vec![0; shard_len]);
+    ReedSolomon::new(data, n as usize - data)?.encode(&mut shards)?;
+    Ok(shards
+        .into_iter()
+        .enumerate()
+        .map(|(i, shard)| (i as u8, k, shard))
+        .collect())
+}
+
+/// Decoder for one FEC-coded message, picked by the first frame's flags.
+pub enum Decoder {
+    Fountain(FountainDecoder),
+    Rs(RsDecoder),
+}
+
+impl Decoder {
+    /// `None` if `f` is a plain chunk.
+    pub fn for_frame(f: &Frame) -> Option<Self> {
+        if f.has(FLAG_FOUNTAIN) {
+            Some(Self::Fountain(FountainDecoder::new(&f.msg_id, f.tot)))
+        } else if f.has(FLAG_RS) {
+            Some(Self::Rs(RsDecoder::new(f.tot)))
+        } else {
+            None
+        }
+    }
+
+    /// Add the opened payload of `f`.
+    pub fn add(&mut self, f: &Frame, data: Vec<u8>) {
+        match self {
+            Self::Fountain(d) => d.add(f.seq, data),
+            Self::Rs(d) => d.add(f.seq, f.has(FLAG_PARITY), data),
+        }
+    }
+
+    /// Source blocks or shards towards the `tot` needed.
+    pub fn recovered(&self) -> usize {
+        match self {
+            Self::Fountain(d) => d.recovered(),
+            Self::Rs(d) => d.recovered(),
+        }
+    }
+
+    /// Source blocks or data shards not yet recovered.
+    pub fn missing(&self) -> Vec<u8> {
+        let blocks = match self {
+            Self::Fountain(d) => &d.blocks,
+            Self::Rs(d) => &d.shards[..d.k],
+        };
+        blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.is_none())
+            .map(|(i, _)| i as u8)
+            .collect()
+    }
+
+    pub fn into_message(self) -> Option<Vec<u8>> {
+        match self {
+            Self::Fountain(d) => d.into_message(),
+            Self::Rs(d) => d.into_message(),
+        }
+    }
+}
+
+/// Peeling decoder for one fountain-coded message.
+pub struct FountainDecoder {
+    k: usize,
+    seed: u64,
+    block_len: Option<usize>,
+    blocks: Vec<Option<Vec<u8>>>,
+    recovered: usize,
+    /// Droplets that still cover more than one unknown block.
+    pending: Vec<(Vec<usize>, Vec<u8>)>,
+}
+
+impl FountainDecoder {
+    pub fn new(msg_id: &MsgId, k: u8) -> Self {
+        Self {
+            k: k as usize,
+            seed: seed_of(msg_id),
+            block_len: None,
+            blocks: vec![None; k as usize],
+            recovered: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Source blocks recovered so far.
+    pub fn recovered(&self) -> usize {
+        self.recovered
+    }
+
+    pub fn add(&mut self, id: u8, mut data: Vec<u8>) {
+        if *self.block_len.get_or_insert(data.len()) != data.len() {
+            return;
+        }
+        let mut unknown = Vec::new();
+        for i in neighbors(self.seed, self.k, id) {
+            match &self.blocks[i] {
+                Some(b) => xor_into(&mut data, b),
+                None => unknown.push(i),
+            }
+        }
+        match unknown.len() {
+            0 => {}
+            1 => self.recover(unknown[0], data),
+            _ => self.pending.push((unknown, data)),
+        }
+    }
+
+    /// Store block `i` and peel it out of every pending droplet, recovering
+    /// whatever that reduces to a single unknown.
+    fn recover(&mut self, i: usize, data: Vec<u8>) {
+        let mut ready = vec![(i, data)];
+        while let Some((i, data)) = ready.pop() {
+            if self.blocks[i].is_some() {
+                continue;
+            }
+            self.pending.retain_mut(|(unknown, d)| {
+                if let Some(pos) = unknown.iter().position(|&u| u == i) {
+                    unknown.swap_remove(pos);
+                    xor_into(d, &data);
+                }
+                if unknown.len() == 1 {
+                    ready.push((unknown[0], std::mem::take(d)));
+                    return false;
+                }
+                !unknown.is_empty()
+            });
+            self.blocks[i] = Some(data);
+            self.recovered += 1;
+        }
+    }
+
+    /// The original body, once `recovered` reaches `k`.
+    pub fn into_message(self) -> Option<Vec<u8>> {
+        let source: Vec<Vec<u8>> = self.blocks.into_iter().collect::<Option<_>>()?;
+        unprefixed(&source.concat())
+    }
+}
+
+/// Reed-Solomon decoder for one message. Shards are kept by index; `k` of
+/// them, data or parity, are enough.
+pub struct RsDecoder {
+    k: usize,
+    shard_len: Option<usize>,
+    shards: Vec<Option<Vec<u8>>>,
+    have: usize,
+}
+
+impl RsDecoder {
+    pub fn new(k: u8) -> Self {
+        Self {
+            k: k as usize,
+            shard_len: None,
+            shards: vec![None; MAX_DROPLETS],
+            have: 0,
+        }
+    }
+
+    pub fn recovered(&self) -> usize {
+        self.have.min(self.k)
+    }
+
+    /// Shards whose parity flag disagrees with their index, or whose length
+    /// differs from the first shard's, are ignored.
+    pub fn add(&mut self, index: u8, parity: bool, data: Vec<u8>) {
+        let i = index as usize;
+        if parity != (i >= self.k)
+            || *self.shard_len.get_or_insert(data.len()) != data.len()
+            || self.shards[i].is_some()
+        {
+            return;
+        }
+        self.shards[i] = Some(data);
+        self.have += 1;
+    }
+
+    /// The original body, once `recovered` reaches `k`.
+    pub fn into_message(mut self) -> Option<Vec<u8>> {
+        // The code is systematic over a Vandermonde matrix, so a parity
+        // shard's coefficients depend only on its index and `k`, never on
+        // `n`: decoding against the highest index seen rebuilds the data.
+        let total = self.shards.iter().rposition(Option::is_some)? + 1;
+        self.shards.truncate(total);
+        if total > self.k {
+            ReedSolomon::new(self.k, total - self.k)
+                .ok()?
+                .reconstruct_data(&mut self.shards)
+                .ok()?;
+        }
+        let source: Vec<Vec<u8>> = self
+            .shards
+            .into_iter()
+            .take(self.k)
+            .collect::<Option<_>>()?;
+        unprefixed(&source.concat())
+    }
+}
+
+/// `body` behind its u16 length, so padding can be stripped after decoding.
+fn prefixed(body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let len = u16::try_from(body.len())
+        .map_err(|_| anyhow::anyhow!("message too long for FEC coding"))?;
+    let mut source = len.to_le_bytes().to_vec();
+    source.extend_from_slice(body);
+    Ok(source)
+}
+
+/// Inverse of `prefixed` on padded blocks. `None` if the length prefix
+/// doesn't fit them, i.e. the blocks were inconsistent.
+fn unprefixed(source: &[u8]) -> Option<Vec<u8>> {
+    let len = u16::from_le_bytes([*source.first()?, *source.get(1)?]) as usize;
+    source.get(LEN_PREFIX..LEN_PREFIX + len).map(<[u8]>::to_vec)
+}
+
+/// Blocks XORed into droplet `id`. The first `k` droplets are the blocks
+/// themselves; the rest draw a degree from the robust soliton distribution
+/// and that many distinct blocks.
+fn neighbors(seed: u64, k: usize, id: u8) -> Vec<usize> {
+    if (id as usize) < k {
+        return vec![id as usize];
+    }
+    let mut rng = SplitMix(seed ^ (id as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    let u = rng.next_f64();
+    let degree = robust_soliton_cdf(k)
+        .iter()
+        .position(|&c| u < c)
+        .map_or(k, |d| d + 1);
+    let mut picked: Vec<usize> = Vec::with_capacity(degree);
+    while picked.len() < degree {
+        let i = (rng.next_u64() % k as u64) as usize;
+        if !picked.contains(&i) {
+            picked.push(i);
+        }
+    }
+    picked
+}
+
+/// Cumulative robust soliton distribution over degrees `1..=k`.
+fn robust_soliton_cdf(k: usize) -> Vec<f64> {
+    const C: f64 = 0.1;
+    const DELTA: f64 = 0.5;
+    let kf = k as f64;
+    let r = (C * (kf / DELTA).ln() * kf.sqrt()).max(1.0);
+    let spike = ((kf / r).floor() as usize).clamp(1, k);
+    let weights: Vec<f64> = (1..=k)
+        .map(|d| {
+            let df = d as f64;
+            let rho = if d == 1 {
+                1.0 / kf
+            } else {
+                1.0 / (df * (df - 1.0))
+            };
+            let tau = match d.cmp(&spike) {
+                std::cmp::Ordering::Less => r / (df * kf),
+                std::cmp::Ordering::Equal => r * (r / DELTA).ln().max(0.0) / kf,
+                std::cmp::Ordering::Greater => 0.0,
+            };
+            rho + tau
+        })
+        .collect();
+    let total: f64 = weights.iter().sum();
+    let mut acc = 0.0;
+    weights
+        .iter()
+        .map(|w| {
+            acc += w / total;
+            acc
+        })
+        .collect()
+}
+
+fn seed_of(msg_id: &MsgId) -> u64 {
+    let mut b = [0u8; 8];
+    b[..msg_id.len()].copy_from_slice(msg_id);
+    u64::from_le_bytes(b)
+}
+
+fn xor_into(dst: &mut [u8], src: &[u8]) {
+    dst.iter_mut().zip(src).for_each(|(d, s)| *d ^= s);
+}
+
+/// SplitMix64: tiny, seedable, and identical on every platform, which is all
+/// the droplet sets need.
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::seq::SliceRandom;
+    use rand::{RngCore, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    /// `len` random bytes, the same for every `seed`.
+    fn random_body(len: usize, seed: u64) -> Vec<u8> {
+        let mut body = vec![0u8; len];
+        StdRng::seed_from_u64(seed).fill_bytes(&mut body);
+        body
+    }
+
+    #[test]
+    fn fountain_decodes_with_30_percent_of_droplets_lost() {
+        let body = random_body(200, 1);
+        let mut rng = StdRng::seed_from_u64(2);
+        for n in 0..20u8 {
+            let msg_id = [n, 1, 2, 3, 4, 5];
+            // 11 blocks, three droplets per block as with `--repeats 2`.
+            let mut droplets = fountain_encode(&body, 20, &msg_id, 33).unwrap();
+            assert_eq!(droplets.len(), 33);
+            droplets.shuffle(&mut rng);
+            droplets.truncate(droplets.len() * 7 / 10);
+            let mut d = FountainDecoder::new(&msg_id, droplets[0].1);
+            for (id, _, data) in droplets {
+                d.add(id, data);
+            }
+            assert_eq!(
+                d.into_message().as_deref(),
+                Some(&body[..]),
+                "msg_id {msg_id:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn fountain_stops_at_256_droplets() {
+        let droplets = fountain_encode(&random_body(200, 1), 20, &[1; 6], 1000).unwrap();
+        assert_eq!(droplets.len(), MAX_DROPLETS);
+    }
+
+    #[test]
+    fn rs_decodes_from_any_k_shards_and_not_from_fewer() {
+        let body = random_body(100, 3);
+        let shards = rs_encode(&body, 20, 6, 10).unwrap();
+        assert_eq!(shards.len(), 10);
+        let decode = |picked: &[usize]| {
+            let mut d = RsDecoder::new(6);
+            for &i in picked {
+                let (index, _, data) = shards[i].clone();
+                d.add(index, index >= 6, data);
+            }
+            d.into_message()
+        };
+        // Data only, parity only but for two, and a mix, out of order.
+        for picked in [[0, 1, 2, 3, 4, 5], [9, 8, 7, 6, 0, 1], [5, 9, 2, 7, 0, 4]] {
+            assert_eq!(decode(&picked).as_deref(), Some(&body[..]), "{picked:?}");
+            assert_eq!(decode(&picked[..5]), None, "{:?}", &picked[..5]);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! `send-file` / `recv-file`: a file travels as one chirp message whose body
+//! is a manifest followed by the file's bytes.
+//!
+//! Manifest: `MAGIC`, name length (1 byte), file name (UTF-8), file length
+//! (u32 LE), SHA-256 of the file (32 bytes). The receiver checks both the
+//! length and the hash before writing anything.
+//!
+//! Chunks of a file still arriving are kept in memory only, so a receiver
+//! restarted mid-transfer starts that file over; nothing resumes from a
+//! `.part` file.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::mesh::ReasmEvent;
+use crate::transport::Transport;
+use crate::{MsgId, RxOpts, TxOpts, crypto, rx_loop, tx, validate_chunk_size};
+
+/// Marks a message body as a file, so `recv-file` skips ordinary messages.
+const MAGIC: &[u8; 4] = b"CHF1";
+const HASH_LEN: usize = 32;
+
+struct Manifest {
+    name: String,
+    sha256: [u8; HASH_LEN],
+}
+
+pub fn pack(name: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let name_len = u8::try_from(name.len()).context("file name longer than 255 bytes")?;
+    let len = u32::try_from(data.len()).context("file larger than 4 GiB")?;
+    let mut body = Vec::with_capacity(MAGIC.len() + 1 + name.len() + 4 + HASH_LEN + data.len());
+    body.extend_from_slice(MAGIC);
+    body.push(name_len);
+    body.extend_from_slice(name.as_bytes());
+    body.extend_from_slice(&len.to_le_bytes());
+    body.extend_from_slice(&Sha256::digest(data));
+    body.extend_from_slice(data);
+    Ok(body)
+}
+
+/// Inverse of `pack`. `None` if `body` isn't a file or is malformed; an
+/// error if it is a file whose length or hash doesn't match.
+fn unpack(body: &[u8]) -> Option<anyhow::Result<(Manifest, &[u8])>> {
+    let rest = body.strip_prefix(MAGIC)?;
+    let (&name_len, rest) = rest.split_first()?;
+    let (name, rest) = rest.split_at_checked(name_len as usize)?;
+    let name = std::str::from_utf8(name).ok()?.to_string();
+    let (len, rest) = rest.split_first_chunk::<4>()?;
+    let (sha256, data) = rest.split_first_chunk::<HASH_LEN>()?;
+    let len = u32::from_le_bytes(*len) as usize;
+    if data.len() != len {
+        return Some(Err(anyhow::anyhow!(
+            "{name}: expected {len} bytes, got {}",
+            data.len()
+        )));
+    }
+    if Sha256::digest(data).as_slice() != sha256 {
+        return Some(Err(anyhow::anyhow!("{name}: SHA-256 mismatch")));
+    }
+    Some(Ok((
+        Manifest {
+            name,
+            sha256: *sha256,
+        },
+        data,
+    )))
+}
+
+/// Read `path` and advertise it as one message.
+pub async fn send_file<T: Transport>(
+    transport: T,
+    opts: &TxOpts,
+    path: &Path,
+    key: Option<&crypto::Keys>,
+) -> anyhow::Result<()> {
+    let data = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("file name is not valid UTF-8")?;
+    let body = pack(name, &data)?;
+    validate_chunk_size(opts.chunk_size, key.is_some(), body.len())?;
+    println!(
+        "Sending {name} ({} bytes, sha256 {})",
+        data.len(),
+        hex::encode(Sha256::digest(&data))
+    );
+    tx(transport, opts, &body, key, None).await?;
+    Ok(())
+}
+
+/// Receive files into `out_dir` until the adapter is gone for good.
+pub async fn recv_file<T: Transport>(
+    transport: T,
+    mut opts: RxOpts,
+    out_dir: PathBuf,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(&out_dir).with_context(|| format!("create {}", out_dir.display()))?;
+    let (events_tx, mut events_rx) = unbounded_channel();
+    opts.events = Some(events_tx);
+    tokio::spawn(async move {
+        while let Some(ev) = events_rx.recv().await {
+            if let ReasmEvent::Chunk {
+                msg_id, have, tot, ..
+            } = ev
+            {
+                eprintln!("[file #{}] {have}/{tot} chunks", short(&msg_id));
+            }
+        }
+    });
+    rx_loop(
+        transport,
+        opts,
+        move |d, _| match unpack(&d.bytes) {
+            None => {}
+            Some(Err(e)) => eprintln!("[file #{}] dropped: {e:#}", short(&d.msg_id)),
+            Some(Ok((manifest, data))) => match save(&out_dir, &manifest, data) {
+                Ok(Some(path)) => println!(
+                    "[file #{}] wrote {} ({} bytes, sha256 verified)",
+                    short(&d.msg_id),
+                    path.display(),
+                    data.len()
+                ),
+                Ok(None) => println!(
+                    "[file #{}] {} already received",
+                    short(&d.msg_id),
+                    manifest.name
+                ),
+                Err(e) => eprintln!("[file #{}] {e:#}", short(&d.msg_id)),
+            },
+        },
+        |i| {
+            eprintln!(
+                "[file #{}] gave up with {}/{} chunks, missing {:?}",
+                short(&i.msg_id),
+                i.tot as usize - i.missing.len(),
+                i.tot,
+                i.missing
+            );
+        },
+    )
+    .await
+}
+
+/// Write `data` to `out_dir` under the manifest's name, via a temporary file
+/// and a rename so a reader never sees a partial file. `None` if an identical
+/// file is already there, e.g. from an earlier pass of a repeating sender.
+fn save(out_dir: &Path, manifest: &Manifest, data: &[u8]) -> anyhow::Result<Option<PathBuf>> {
+    // Only the last component, so a sender can't write outside `out_dir`.
+    let name = Path::new(&manifest.name)
+        .file_name()
+        .context("file name has no final component")?;
+    let path = out_dir.join(name);
+    if std::fs::read(&path).is_ok_and(|have| Sha256::digest(have).as_slice() == manifest.sha256) {
+        return Ok(None);
+    }
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".part");
+    std::fs::write(&tmp, data).with_context(|| format!("write {}", path.display()))?;
+    std::fs::rename(&tmp, &path).with_context(|| format!("rename into {}", path.display()))?;
+    Ok(Some(path))
+}
+
+fn short(msg_id: &MsgId) -> String {
+    hex::encode(&msg_id[..4])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{RngCore, SeedableRng, rngs::StdRng};
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+    use crate::tests::{rx_opts, tx_opts};
+    use crate::transport::LoopbackTransport;
+
+    #[tokio::test]
+    async fn a_file_crosses_the_loopback_transport_intact() {
+        let dir = std::env::temp_dir().join(format!("chirp-file-{}", std::process::id()));
+        let (src, out) = (dir.join("in"), dir.join("out"));
+        std::fs::create_dir_all(&src).unwrap();
+        let mut data = vec![0u8; 1000];
+        StdRng::seed_from_u64(1).fill_bytes(&mut data);
+        std::fs::write(src.join("notes.bin"), &data).unwrap();
+
+        let a = LoopbackTransport::new();
+        let recv = tokio::spawn(recv_file(a.peer(), rx_opts(7), out.clone()));
+        // Let it get as far as scanning before anything is sent.
+        sleep(Duration::from_millis(20)).await;
+        send_file(a, &tx_opts(7), &src.join("notes.bin"), None)
+            .await
+            .unwrap();
+        let path = out.join("notes.bin");
+        let got = timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(got) = std::fs::read(&path) {
+                    break got;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timed out");
+        assert_eq!(Sha256::digest(&got), Sha256::digest(&data));
+        assert!(!out.join("notes.bin.part").exists());
+        recv.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+//! Chat history: every message chat shows, sent or received, is appended as
+//! one JSON line to `history.jsonl` next to the config file, and the last
+//! `--history-limit` of them are shown again at the next start.
+//!
+//! The log holds decrypted plaintext. Anyone who can read the file can read
+//! the conversation, whatever key it was sent with; `--no-history` turns it
+//! off.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// One logged message.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub topic: u8,
+    /// Unix ms, as shown in chat.
+    pub ts: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nick: Option<String>,
+    /// 256-colour palette index the line was shown in.
+    pub color: u8,
+    /// The line as shown, sender included.
+    pub text: String,
+}
+
+/// `$XDG_CONFIG_HOME/ble-chirp/history.jsonl`, falling back to `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    Some(config::dir()?.join("history.jsonl"))
+}
+
+/// The last `limit` entries in `path`, oldest first. A missing file is an
+/// empty history; lines that don't parse, such as one cut short by a crash,
+/// are skipped.
+pub fn load_tail(path: &Path, limit: usize) -> anyhow::Result<Vec<Entry>> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+    let mut tail = VecDeque::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("read {}", path.display()))?;
+        let Ok(entry) = serde_json::from_str(&line) else {
+            continue;
+        };
+        if tail.len() == limit {
+            tail.pop_front();
+        }
+        if limit > 0 {
+            tail.push_back(entry);
+        }
+    }
+    Ok(tail.into())
+}
+
+/// The log, open for appending.
+pub struct History {
+    file: File,
+}
+
+impl History {
+    /// Open `path` for appending, creating it (readable only by the owner)
+    /// and its directory if needed.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(path)
+            .with_context(|| format!("open {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    /// Write `entry` as one line. Each line goes out in a single write, so a
+    /// crash loses at most the entry being written.
+    pub fn append(&mut self, entry: &Entry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(i: u64, nick: Option<&str>, text: &str) -> Entry {
+        Entry {
+            topic: 7,
+            ts: 1_700_000_000_000 + i,
+            nick: nick.map(str::to_string),
+            color: 16 + i as u8,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn entries_reload_as_written_newest_last() {
+        let dir = std::env::temp_dir().join(format!("chirp-history-{}", std::process::id()));
+        let path = dir.join("history.jsonl");
+        let written = [
+            entry(0, Some("ana"), "ana: hi"),
+            entry(1, None, "0a0b0c0d: two\nlines"),
+            entry(2, Some("bo"), r#"bo: "quoted" \ and tab\t"#),
+            entry(3, Some("ana"), "ana: 世界 🙂"),
+        ];
+        let mut history = History::open(&path).unwrap();
+        for e in &written {
+            history.append(e).unwrap();
+        }
+        // One entry per line, whatever the text holds.
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(raw.lines().count(), written.len());
+        assert!(!raw.contains("\"nick\":null"));
+
+        assert_eq!(load_tail(&path, 10).unwrap(), written);
+        assert_eq!(load_tail(&path, 2).unwrap(), written[2..]);
+        assert!(load_tail(&path, 0).unwrap().is_empty());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_missing_file_is_empty_and_a_torn_line_is_skipped() {
+        let path = std::env::temp_dir().join(format!("chirp-torn-{}.jsonl", std::process::id()));
+        assert!(load_tail(&path, 10).unwrap().is_empty());
+
+        let mut history = History::open(&path).unwrap();
+        history.append(&entry(0, None, "kept")).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"topic\":7,\"ts\":1,\"col")
+            .unwrap();
+        assert_eq!(load_tail(&path, 10).unwrap(), [entry(0, None, "kept")]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+//! Long-term sender identities.
+//!
+//! A signed message body is `text || signature (64) || public key (32)`, where
+//! the ed25519 signature covers the message's topic, send time and `msg_id`
+//! as well as `text` (see `Header`), so a signed body can't be replayed under
+//! another header. It is added once per message and topic, not per chunk, and
+//! goes through chunking and encryption like the text. Receivers treat any
+//! body whose trailer doesn't verify as unsigned, so unsigned senders keep
+//! working.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use zeroize::Zeroize;
+
+use crate::crypto::{self, PUBKEY_LEN, SIG_LEN, SigningKey};
+use crate::{MsgId, write_private};
+
+pub const SIGNED_OVERHEAD: usize = SIG_LEN + PUBKEY_LEN;
+
+/// The header fields a signature covers besides the text. `topic` is the
+/// real topic, before any private-topic masking.
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub topic: u8,
+    pub ts_ms: u64,
+    pub msg_id: MsgId,
+}
+
+impl Header {
+    fn signed_bytes(&self, text: &[u8]) -> Vec<u8> {
+        let mut b = Vec::with_capacity(1 + 8 + self.msg_id.len() + text.len());
+        b.push(self.topic);
+        b.extend_from_slice(&self.ts_ms.to_le_bytes());
+        b.extend_from_slice(&self.msg_id);
+        b.extend_from_slice(text);
+        b
+    }
+}
+
+/// Load the signing key from `path` (a hex-encoded 32-byte seed), creating
+/// the file with a fresh key if it doesn't exist.
+pub fn load_or_create(path: &Path) -> anyhow::Result<SigningKey> {
+    if !path.exists() {
+        let mut seed = rand::random::<[u8; 32]>();
+        let mut line = hex::encode(seed);
+        line.push('\n');
+        let res = write_private(path, line.as_bytes());
+        line.zeroize();
+        let key = SigningKey::from_bytes(&seed);
+        seed.zeroize();
+        res.with_context(|| format!("write identity file {}", path.display()))?;
+        eprintln!(
+            "created identity {}; public key (for --trust): {}",
+            path.display(),
+            hex::encode(key.verifying_key().to_bytes())
+        );
+        return Ok(key);
+    }
+    let mut raw = std::fs::read_to_string(path)
+        .with_context(|| format!("read identity file {}", path.display()))?;
+    let decoded = hex::decode(raw.trim());
+    raw.zeroize();
+    let mut bytes =
+        decoded.with_context(|| format!("identity file {} is not hex", path.display()))?;
+    let seed: Result<[u8; 32], _> = bytes.as_slice().try_into();
+    bytes.zeroize();
+    let Ok(mut seed) = seed else {
+        anyhow::bail!("identity file {} must hold a 32-byte key", path.display());
+    };
+    let key = SigningKey::from_bytes(&seed);
+    seed.zeroize();
+    Ok(key)
+}
+
+/// Append the signature trailer to `body`, sent under `header`, when an
+/// identity is configured.
+pub fn seal(identity: Option<&SigningKey>, header: &Header, body: &[u8]) -> Vec<u8> {
+    let mut out = body.to_vec();
+    if let Some(sk) = identity {
+        out.extend_from_slice(&crypto::sign(sk, &header.signed_bytes(body)));
+        out.extend_from_slice(&sk.verifying_key().to_bytes());
+    }
+    out
+}
+
+/// Split a body reassembled under `header` into the signed text and the
+/// verified signer's public key. Bodies without a trailer that verifies
+/// against `header` come back whole and unsigned.
+pub fn open<'a>(header: &Header, body: &'a [u8]) -> (&'a [u8], Option<[u8; PUBKEY_LEN]>) {
+    let Some(text_len) = body.len().checked_sub(SIGNED_OVERHEAD) else {
+        return (body, None);
+    };
+    let (text, trailer) = body.split_at(text_len);
+    let (sig, pk) = trailer.split_at(SIG_LEN);
+    let sig: [u8; SIG_LEN] = sig.try_into().expect("split at SIG_LEN");
+    let pk: [u8; PUBKEY_LEN] = pk.try_into().expect("trailer is SIGNED_OVERHEAD bytes");
+    if crypto::verify(&pk, &header.signed_bytes(text), &sig) {
+        (text, Some(pk))
+    } else {
+        (body, None)
+    }
+}
+
+/// Short, human-comparable form of a public key.
+pub fn fingerprint(pubkey: &[u8; PUBKEY_LEN]) -> String {
+    hex::encode(&pubkey[..4])
+}
+
+/// clap value parser for `--trust`.
+pub fn parse_pubkey(s: &str) -> Result<[u8; PUBKEY_LEN], String> {
+    let bytes = hex::decode(s).map_err(|e| format!("not hex: {e}"))?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("public key must be {PUBKEY_LEN} bytes"))
+}
+
+/// Senders the user has vouched for with `--trust`.
+#[derive(Clone, Default)]
+pub struct Trust(Vec<[u8; PUBKEY_LEN]>);
+
+impl Trust {
+    pub fn new(keys: Vec<[u8; PUBKEY_LEN]>) -> Self {
+        Trust(keys)
+    }
+
+    /// Sender label for display. With no `--trust` list, any verified signer
+    /// is shown by fingerprint and unsigned messages get no label. With a
+    /// list, anything not signed by a listed key is marked `[unverified]`.
+    pub fn label(&self, signer: Option<&[u8; PUBKEY_LEN]>) -> Option<String> {
+        match signer {
+            Some(pk) if self.0.is_empty() || self.0.contains(pk) => Some(fingerprint(pk)),
+            _ if self.0.is_empty() => None,
+            _ => Some("[unverified]".into()),
+        }
+    }
+}
+
+/// How soon after hearing an identity directly from one radio, hearing it
+/// directly from another means two nodes hold the key. Long enough to span
+/// a conversation, short against BLE address rotation (about 15 min).
+pub const DUP_SOURCE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How far a message may have been sent before the newest one from the same
+/// identity without suspicion. One node's send times only go forward, but a
+/// message can be delivered late by up to the reassembly timeout, plus the
+/// relays' backoff.
+pub const DUP_CLOCK_SLACK: Duration = Duration::from_secs(60);
+
+/// Why an identity looks shared by more than one node.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Clash<S> {
+    /// Heard directly from two radios within `DUP_SOURCE_WINDOW`.
+    Sources(S, S),
+    /// A message sent this long before the newest one, by the sender's clock.
+    Clock(Duration),
+}
+
+/// What was last heard from one identity.
+struct Last<S> {
+    ts_ms: u64,
+    /// The radio it was last heard from directly, and when.
+    direct: Option<(S, Instant)>,
+}
+
+/// Spots one identity key in use on more than one node, which misattributes
+/// their messages to each other. Frames carry no per-sender sequence number,
+/// so the evidence is what one sender can't produce: the same key heard
+/// directly from two radios at once, or send times going well backwards.
+pub struct Duplicates<S> {
+    last: HashMap<[u8; PUBKEY_LEN], Last<S>>,
+    /// Reported already; each identity is reported once.
+    flagged: HashSet<[u8; PUBKEY_LEN]>,
+}
+
+impl<S> Default for Duplicates<S> {
+    fn default() -> Self {
+        Self {
+            last: HashMap::new(),
+            flagged: HashSet::new(),
+        }
+    }
+}
+
+impl<S: Clone + PartialEq> Duplicates<S> {
+    /// Note a message from `signer` sent at `ts_ms`, heard at `now`. `direct`
+    /// is the radio it came from if it wasn't relayed. Returns the evidence
+    /// the first time `signer` looks shared, and `None` after that.
+    pub fn heard(
+        &mut self,
+        signer: &[u8; PUBKEY_LEN],
+        ts_ms: u64,
+        direct: Option<&S>,
+        now: Instant,
+    ) -> Option<Clash<S>> {
+        let last = self.last.entry(*signer).or_insert(Last {
+            ts_ms,
+            direct: None,
+        });
+        let mut clash = None;
+        if let Some((was, at)) = &last.direct
+            && let Some(src) = direct
+            && src != was
+            && now.duration_since(*at) < DUP_SOURCE_WINDOW
+        {
+            clash = Some(Clash::Sources(was.clone(), src.clone()));
+        }
+        let behind = Duration::from_millis(last.ts_ms.saturating_sub(ts_ms));
+        if clash.is_none() && behind > DUP_CLOCK_SLACK {
+            clash = Some(Clash::Clock(behind));
+        }
+        last.ts_ms = last.ts_ms.max(ts_ms);
+        if let Some(src) = direct {
+            last.direct = Some((src.clone(), now));
+        }
+        clash.filter(|_| self.flagged.insert(*signer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_node_per_identity_is_never_flagged() {
+        let mut dups = Duplicates::default();
+        let t0 = Instant::now();
+        // Direct, then relayed a little out of order, then direct again.
+        assert_eq!(dups.heard(&[1; 32], 10_000, Some(&"a"), t0), None);
+        assert_eq!(dups.heard(&[1; 32], 20_000, None, t0), None);
+        assert_eq!(dups.heard(&[1; 32], 15_000, None, t0), None);
+        assert_eq!(dups.heard(&[1; 32], 30_000, Some(&"a"), t0), None);
+        // Another radio, but after the address could have rotated.
+        let later = t0 + DUP_SOURCE_WINDOW;
+        assert_eq!(dups.heard(&[1; 32], 40_000, Some(&"b"), later), None);
+        // Another identity from the same radio is nothing to do with it.
+        assert_eq!(dups.heard(&[2; 32], 0, Some(&"b"), later), None);
+    }
+
+    #[test]
+    fn a_shared_identity_is_flagged_once() {
+        let mut dups = Duplicates::default();
+        let t0 = Instant::now();
+        dups.heard(&[1; 32], 10_000, Some(&"a"), t0);
+        let t1 = t0 + Duration::from_secs(1);
+        assert_eq!(
+            dups.heard(&[1; 32], 11_000, Some(&"b"), t1),
+            Some(Clash::Sources("a", "b"))
+        );
+        assert_eq!(dups.heard(&[1; 32], 12_000, Some(&"a"), t1), None);
+
+        // Relayed, so only the clocks give it away.
+        dups.heard(&[2; 32], 100_000, None, t0);
+        let behind = DUP_CLOCK_SLACK + Duration::from_secs(1);
+        let ts = 100_000 - behind.as_millis() as u64;
+        assert_eq!(
+            dups.heard(&[2; 32], ts, None, t0),
+            Some(Clash::Clock(behind))
+        );
+        assert_eq!(dups.heard(&[2; 32], 0, None, t0), None);
+    }
+
+    #[test]
+    fn signature_covers_the_header() {
+        let sk = SigningKey::from_bytes(&[7; 32]);
+        let header = Header {
+            topic: 7,
+            ts_ms: 1_700_000_000_000,
+            msg_id: [1, 2, 3, 4, 5, 6],
+        };
+        let body = seal(Some(&sk), &header, b"hi");
+        assert_eq!(body.len(), 2 + SIGNED_OVERHEAD);
+        let pk = sk.verifying_key().to_bytes();
+        assert_eq!(open(&header, &body), (&b"hi"[..], Some(pk)));
+
+        for moved in [
+            Header { topic: 8, ..header },
+            Header {
+                ts_ms: header.ts_ms + 1,
+                ..header
+            },
+            Header {
+                msg_id: [1, 2, 3, 4, 5, 7],
+                ..header
+            },
+        ] {
+            assert_eq!(open(&moved, &body), (&body[..], None), "{moved:?}");
+        }
+    }
+
+    #[test]
+    fn unsigned_bodies_open_whole() {
+        let header = Header {
+            topic: 7,
+            ts_ms: 0,
+            msg_id: [0; 6],
+        };
+        assert_eq!(seal(None, &header, b"hi"), b"hi");
+        let long = [0u8; SIGNED_OVERHEAD + 3];
+        assert_eq!(open(&header, &long), (&long[..], None));
+    }
+}
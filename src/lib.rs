@@ -0,0 +1,3926 @@
+//! Chunked, encrypted broadcast messages over BLE advertisements, with
+//! multi-hop relaying. The `ble-chirp` binary is a thin wrapper around
+//! [`run`]; to embed chirp in another program, use [`Chirp`].
+
+use sha2::{Digest, Sha256};
+
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use btleplug::api::{Central, CentralEvent, ScanFilter};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::sleep;
+use futures::StreamExt;
+use zeroize::Zeroize;
+
+mod adapter;
+
+mod beacon;
+
+mod bounded_set;
+
+mod chirp;
+pub use chirp::{Chirp, ChirpBuilder, ChirpMessage};
+
+mod calibrate;
+
+mod compress;
+
+mod config;
+
+mod congestion;
+
+#[cfg(feature = "tui")]
+mod chat_ui;
+#[cfg(feature = "tui")]
+mod signal_ui;
+#[cfg(feature = "tui")]
+mod history;
+#[cfg(feature = "tui")]
+mod ack_log;
+#[cfg(feature = "notify")]
+mod notify;
+
+mod crypto;
+
+mod fec;
+
+mod file_transfer;
+
+mod identity;
+
+mod mute;
+
+mod rate_limiter;
+use rate_limiter::RateLimiter;
+
+mod relay;
+use relay::RelayTracker;
+
+mod retransmit;
+
+mod schedule;
+use schedule::{DEFAULT_GAP_MS, Profile, Strategy};
+
+pub mod transport;
+use transport::{Advertiser, Transport};
+
+// Only chat runs a queue worker and queues its user's messages.
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+mod send_queue;
+
+mod simulate;
+use send_queue::{Priority, SendQueue};
+
+#[cfg(feature = "metrics")]
+mod metrics;
+
+mod mesh;
+use mesh::{Delivered, Incomplete, MeshNode, ReasmEvent};
+
+mod presence;
+
+pub mod stats;
+pub use stats::Stats;
+
+const COMPANY_ID: u16 = 0xFFFF; // manufacturer data key
+const VER: u8 = 9;
+/// Same layout as `VER`, but the topic byte is masked with
+/// `crypto::Keys::topic_mask`; see `--private-topic`.
+const VER_PRIVATE: u8 = 10;
+/// Unencrypted frame: same layout as `VER` plus a trailing CRC16, since
+/// there is no AEAD tag to catch corruption. `VER` frames are always sealed.
+const VER_PLAIN: u8 = 11;
+/// Control frame: same layout as `VER`, but the flags byte holds a `Control`
+/// kind instead of flags. Older readers refuse the version, so they never
+/// take a control frame for one of the kinds they know.
+const VER_CONTROL: u8 = 12;
+/// `VER_CONTROL`, unencrypted, with `VER_PLAIN`'s trailing CRC16.
+const VER_CONTROL_PLAIN: u8 = 13;
+/// 48 random bits: a birthday collision needs ~16M messages in flight
+/// rather than ~65k.
+const MSG_ID_LEN: usize = 6;
+pub type MsgId = [u8; MSG_ID_LEN];
+const CRC_LEN: usize = 2;
+// Header flag bits. Readers ignore bits they don't know, so adding one only
+// needs a version bump if older readers must refuse the frame.
+/// The payload is an ephemeral X25519 public key rather than a chunk; see
+/// `crypto::handshake_initiate`.
+const FLAG_HANDSHAKE: u8 = 0x01;
+/// The message body ends in a signature trailer; see `identity`.
+const FLAG_SIGNED: u8 = 0x02;
+/// `seq` is a fountain droplet id rather than a chunk index, and `tot` the
+/// number of source blocks; see `fec`.
+const FLAG_FOUNTAIN: u8 = 0x04;
+/// The message is Reed-Solomon coded: `tot` is the data shard count `k` and
+/// `seq` the shard index; see `fec`.
+const FLAG_RS: u8 = 0x08;
+/// A Reed-Solomon parity shard, `seq >= tot`.
+const FLAG_PARITY: u8 = 0x10;
+/// A repair request for another message; see `retransmit`.
+const FLAG_NACK: u8 = 0x20;
+/// The message is a presence beacon, for the roster rather than display;
+/// see `presence`.
+const FLAG_PRESENCE: u8 = 0x40;
+/// The message body is DEFLATE compressed; see `compress`.
+const FLAG_COMPRESSED: u8 = 0x80;
+const MAX_PAYLOAD: usize = 20; 
+const HEADER_LEN: usize = 2 + 1 + 1 + 1 + 1 + 1 + MSG_ID_LEN + 1 + 1 + 8;
+const AEAD_TAG_LEN: usize = 16;
+// One extended-advertising PDU carries 254 bytes of AD data; leave room for the
+// flags AD (3), the "chirp" local name (7) and the manufacturer AD header (2).
+const MAX_MD_LEN: usize = 254 - 3 - 7 - 2;
+const EMPTY_MSG: &str = "<empty>";
+const TRANSIENT_RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Default for `rx --max-reconnect`.
+const MAX_RECONNECT: u32 = 10;
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+/// How often `rx_loop` times out and NACKs partial messages when no frames
+/// arrive to trigger it.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a relayed (or repaired, or NACK) frame is advertised.
+const RELAY_DWELL: Duration = Duration::from_millis(300);
+const PASSPHRASE_ENV: &str = "BLE_CHIRP_PASSPHRASE";
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "ble-chirp",
+    about = "Broadcast/scan tiny messages via BLE advertising (mesh-style)"
+)]
+struct Args {
+    /// Read defaults for the adapter, room, ttl, rate, dwell and key file
+    /// from this TOML file [default: $XDG_CONFIG_HOME/ble-chirp/config.toml]
+    #[arg(long, value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
+    /// Apply the config file's [profiles.NAME] table over its top-level
+    /// defaults
+    #[arg(long, value_name = "NAME")]
+    config_profile: Option<String>,
+    #[arg(long, default_value_t = 0)]
+    adapter: usize,
+    /// Log to stderr: -v for debug events (dropped frames, decrypt failures,
+    /// completed messages), -vv to trace every relay. RUST_LOG overrides
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Passphrase for payload encryption/decryption. Repeat to also decrypt
+    /// other rooms' traffic; the first one is used for sending
+    #[arg(long, conflicts_with = "passphrase_file")]
+    passphrase: Vec<String>,
+    /// Read the passphrase from a file (first line) instead of argv
+    #[arg(long)]
+    passphrase_file: Option<std::path::PathBuf>,
+    /// Use a 32-byte key from a file (raw, hex or base64) instead of a
+    /// passphrase; see `keygen`
+    #[arg(long, conflicts_with_all = ["passphrase", "passphrase_file"])]
+    key_file: Option<std::path::PathBuf>,
+    /// Extra receive keys, one per line (hex or base64); `#` starts a comment
+    #[arg(long)]
+    keyring: Option<std::path::PathBuf>,
+    /// Key derivation salt; defaults to the --room name. Required with a
+    /// passphrase when using --topic instead of --room
+    #[arg(long)]
+    salt: Option<String>,
+    /// Argon2id memory cost in KiB (must match between peers)
+    #[arg(long, default_value_t = crypto::KdfParams::default().mem_kib)]
+    kdf_mem_kib: u32,
+    /// Argon2id iterations (must match between peers)
+    #[arg(long, default_value_t = crypto::KdfParams::default().iters)]
+    kdf_iters: u32,
+    /// Argon2id lanes (must match between peers)
+    #[arg(long, default_value_t = crypto::KdfParams::default().parallelism)]
+    kdf_parallelism: u32,
+    /// Sign sent messages with the ed25519 key in this file (created if missing)
+    #[arg(long)]
+    identity: Option<std::path::PathBuf>,
+    /// Nickname shown with your messages and announced by
+    /// --presence-interval
+    #[arg(long, value_parser = presence::parse_nick)]
+    nick: Option<String>,
+    /// Only treat messages signed by this public key (hex) as verified;
+    /// repeatable. Others are shown marked [unverified]
+    #[arg(long, value_parser = identity::parse_pubkey)]
+    trust: Vec<[u8; 32]>,
+    /// Never show messages from this sender, though still relay them:
+    /// key:HEX, id:MSGID-PREFIX, nick:NAME, or a bare key or nickname;
+    /// repeatable
+    #[arg(long, value_parser = mute::parse_mute)]
+    mute: Vec<mute::Mute>,
+    /// Drop received frames stamped more than N seconds from our clock
+    #[arg(long)]
+    max_age_secs: Option<u64>,
+    /// Rotate to a new session key every N seconds (peers' clocks must agree
+    /// to within N)
+    #[arg(long)]
+    rekey_secs: Option<u64>,
+    /// Serve Prometheus metrics on this address (e.g. 127.0.0.1:9100)
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Transmit a message (advertise chunked frames)
+    Tx {
+        /// Topic/channel (0-255)
+
+        #[arg(long, default_value_t = 7, conflicts_with = "room")]
+        topic: u8,
+        #[arg(long)]
+        room: Option<String>,
+        #[arg(long, default_value_t = 3)]
+        ttl: u8,
+        /// The message, or `-` to read it from stdin
+        #[arg(required_unless_present_any = ["msg_file", "stdin"])]
+        msg: Option<String>,
+        /// Read the message from a file (re-read on every beacon tick)
+        #[arg(long, conflicts_with = "msg")]
+        msg_file: Option<std::path::PathBuf>,
+        /// Read the message from stdin to EOF, like a `-` message
+        #[arg(long, conflicts_with_all = ["msg", "msg_file"])]
+        stdin: bool,
+        /// Tag the message with a sub-topic within its topic, e.g. alerts
+        #[arg(long, value_parser = mesh::parse_subtopic)]
+        subtopic: Option<String>,
+        /// Emergency broadcast: send on every topic 0-255
+        #[arg(long, conflicts_with_all = ["topic", "room", "topics"])]
+        all_topics: bool,
+        /// Send on each of these topics (comma-separated)
+        #[arg(long, value_delimiter = ',', conflicts_with_all = ["topic", "room"])]
+        topics: Vec<u8>,
+        /// Re-broadcast every N seconds until Ctrl-C
+        #[arg(long)]
+        every: Option<u64>,
+        /// Re-broadcast on a cron schedule (sec min hour dom mon dow [year])
+        #[cfg(feature = "cron")]
+        #[arg(long, conflicts_with = "every")]
+        cron: Option<String>,
+        /// Advertising time per chunk [default: 500, or set by --profile]
+        #[arg(long)]
+        dwell_ms: Option<u64>,
+        #[arg(long, default_value_t = 2.0)]
+        rate: f64,
+        /// Chunks that may go out back to back before --rate applies
+        /// [default: --rate, at least 1]
+        #[arg(long)]
+        burst: Option<f64>,
+        /// Slow down below --rate while other chirp traffic is heard
+        #[arg(long)]
+        adaptive: bool,
+        /// Pause between chunks [default: 60, or set by --strategy]
+        #[arg(long)]
+        gap_ms: Option<u64>,
+        /// Keep advertising between chunks and swap the payload, instead of
+        /// stopping for --gap-ms after each one
+        #[arg(long)]
+        rotate_in_place: bool,
+        /// Cycle through all chunks N times [default: 1, or set by
+        /// --strategy]
+        #[arg(long, value_name = "N")]
+        repeat: Option<u32>,
+        /// Keep cycling through all chunks for N seconds so late joiners
+        /// catch the whole message [default: off, or set by --profile]
+        #[arg(long, value_name = "N")]
+        repeat_secs: Option<u64>,
+        /// Preset delivery profile: `fast` is one 200 ms pass; `reliable` is
+        /// short-dwell repeats, fountain FEC and --repeat-secs 10. Explicit
+        /// flags override its settings
+        #[arg(long, value_enum)]
+        profile: Option<Profile>,
+        /// How to trade dwell time against repeats for reception probability
+        /// [default: fixed, or set by --profile]
+        #[arg(long, value_enum)]
+        strategy: Option<Strategy>,
+        /// Plaintext bytes per chunk (bounded by the advertisement budget)
+        #[arg(long, default_value_t = MAX_PAYLOAD)]
+        chunk_size: usize,
+        /// Delay the first advertisement by a random 0..=N ms to desync fleets
+        #[arg(long, default_value_t = 0)]
+        startup_jitter: u64,
+        /// Seed for reproducible jitter
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Seal the message under its own key, from a fresh X25519 ephemeral
+        /// key announced in a handshake frame (needs a passphrase). Not
+        /// forward secrecy: the passphrase still opens recorded traffic
+        #[arg(long)]
+        per_message_key: bool,
+        /// Mask the topic byte per message so only key holders can tell
+        /// which channel a frame is on (needs a passphrase)
+        #[arg(long)]
+        private_topic: bool,
+        /// DEFLATE the message before chunking
+        #[arg(long, value_enum, default_value_t)]
+        compress: compress::Compress,
+        /// Forward error correction: `none`, `fountain` to send coded
+        /// droplets any large enough subset of which rebuilds the message, or
+        /// `rs:<k>:<n>` for n Reed-Solomon shards any k of which do
+        /// [default: none, or set by --profile]
+        #[arg(long, value_parser = fec::parse_fec)]
+        fec: Option<fec::Fec>,
+        /// Keep running after sending and re-advertise chunks that receivers
+        /// NACK (see `rx --nack-after`) until Ctrl-C
+        #[arg(long)]
+        serve: bool,
+        /// Print the chunk count, bytes on air and time the message would
+        /// take, then exit without advertising
+        #[arg(long, conflicts_with = "serve")]
+        dry_run: bool,
+    },
+    Rx {
+
+        #[arg(long, conflicts_with = "room")]
+        topic: Option<u8>,
+        #[arg(long)]
+        room: Option<String>,
+        #[arg(long, default_value_t = true)]
+        relay: bool,
+        /// Only relay frames on these topics (comma-separated); frames on
+        /// other topics are still received
+        #[arg(long, value_delimiter = ',', value_name = "TOPICS")]
+        relay_topics: Vec<u8>,
+        /// Relay every topic except these (comma-separated)
+        #[arg(
+            long,
+            value_delimiter = ',',
+            value_name = "TOPICS",
+            conflicts_with = "relay_topics"
+        )]
+        no_relay_topics: Vec<u8>,
+        /// Print a line when every chunk of a message has been relayed
+        #[arg(long)]
+        relay_events: bool,
+        /// Show which peripheral delivered the final chunk of each message
+        #[arg(long)]
+        show_source: bool,
+        /// Print reassembly progress (message started / chunk / completed) to stderr
+        #[arg(long)]
+        progress: bool,
+        /// Announce --nick on the topic every N seconds so others' rosters
+        /// show this node
+        #[arg(long, value_name = "SECS")]
+        presence_interval: Option<u64>,
+        /// Write each message's raw bytes to stdout instead of a text line;
+        /// status lines go to stderr
+        #[arg(long)]
+        binary: bool,
+        /// With --binary, append the bytes to this file instead of stdout
+        #[arg(long, value_name = "FILE", requires = "binary")]
+        out: Option<std::path::PathBuf>,
+        /// `json` prints one JSON object per message (NDJSON) and moves
+        /// status lines to stderr
+        #[arg(long, value_enum, default_value_t, conflicts_with = "binary")]
+        format: RxFormat,
+        /// Only show messages tagged with this sub-topic
+        #[arg(long, value_parser = mesh::parse_subtopic)]
+        subtopic: Option<String>,
+        /// Print goodput and frame counters to stderr every few seconds
+        #[arg(long)]
+        stats: bool,
+        /// Print why each malformed frame was dropped to stderr
+        #[arg(long)]
+        debug_frames: bool,
+        /// Give up on a partial message after N seconds without a new chunk
+        #[arg(long, value_name = "SECS", default_value_t = mesh::REASM_TIMEOUT.as_secs())]
+        reasm_timeout: u64,
+        /// Reassemble at most N messages at once, evicting the least recently
+        /// updated
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = mesh::MAX_REASM,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        max_reasm: usize,
+        /// Ask the sender to resend the missing chunks of a message that has
+        /// had no new chunk for MS milliseconds (needs `tx --serve`)
+        #[arg(long, value_name = "MS")]
+        nack_after: Option<u64>,
+        /// Remember the last N frames to drop duplicates
+        #[arg(long, value_name = "N", default_value_t = mesh::SEEN_CAP)]
+        seen_cache: usize,
+        /// Stop treating a frame as a duplicate N seconds after first seeing it
+        #[arg(long, value_name = "SECS", default_value_t = mesh::SEEN_TTL.as_secs())]
+        seen_ttl: u64,
+        /// Give up after N failed attempts in a row to restart the scan or
+        /// re-acquire the adapter
+        #[arg(long, value_name = "N", default_value_t = MAX_RECONNECT)]
+        max_reconnect: u32,
+        /// Cancel a relay if another node is heard relaying the same chunk
+        /// during the backoff
+        #[arg(long)]
+        suppress_relay: bool,
+        /// Advertise at most N relays and acks at once; one whose turn comes
+        /// while all slots are busy is not sent
+        #[arg(long, value_name = "N", default_value_t = relay::MAX_RELAYS)]
+        max_relays: usize,
+    },
+
+
+    Chat {
+        #[arg(long, default_value_t = 7, conflicts_with = "room")]
+        topic: u8,
+        #[arg(long)]
+        room: Option<String>,
+        /// Also join these rooms, each in its own tab (comma-separated or
+        /// repeated). A passphrase salted by --room is salted by each of
+        /// these for its own room
+        #[arg(long, value_delimiter = ',')]
+        join: Vec<String>,
+        #[arg(long, default_value_t = 3)]
+        ttl: u8,
+        /// Split messages needing more than N chunks into separate messages
+        #[arg(long)]
+        split_chunks: Option<usize>,
+        /// Room color as TOPIC=COLOR (repeatable), e.g. --theme 7=green
+        #[arg(long = "theme")]
+        themes: Vec<String>,
+        /// Tag sent messages with this sub-topic and only show messages
+        /// carrying it
+        #[arg(long, value_parser = mesh::parse_subtopic)]
+        subtopic: Option<String>,
+        /// Mask the topic byte of sent messages (needs a passphrase)
+        #[arg(long)]
+        private_topic: bool,
+        /// Advertising time per chunk [default: 500, or set by --profile]
+        #[arg(long)]
+        dwell_ms: Option<u64>,
+        #[arg(long, default_value_t = 2.0)]
+        rate: f64,
+        /// Slow down while other chirp traffic is heard
+        #[arg(long)]
+        adaptive: bool,
+        /// Pause between chunks [default: 60, or set by --strategy]
+        #[arg(long)]
+        gap_ms: Option<u64>,
+        /// Cycle through all chunks of each message N times [default: 1, or
+        /// set by --strategy]
+        #[arg(long, value_name = "N")]
+        repeat: Option<u32>,
+        /// Keep cycling through all chunks of each message for N seconds
+        /// [default: off, or set by --profile]
+        #[arg(long, value_name = "N")]
+        repeat_secs: Option<u64>,
+        /// Preset delivery profile, as for `tx`. Explicit flags override its
+        /// settings
+        #[arg(long, value_enum)]
+        profile: Option<Profile>,
+        /// How to trade dwell time against repeats for reception probability
+        /// [default: fixed, or set by --profile]
+        #[arg(long, value_enum)]
+        strategy: Option<Strategy>,
+        /// Forward error correction for sent messages, as for `tx`
+        /// [default: none, or set by --profile]
+        #[arg(long, value_parser = fec::parse_fec)]
+        fec: Option<fec::Fec>,
+        /// Announce --nick every N seconds so others' rosters show you
+        #[arg(long, value_name = "SECS")]
+        presence_interval: Option<u64>,
+        /// Don't log messages to, or reload them from, the history file
+        #[arg(long)]
+        no_history: bool,
+        /// Reload at most N messages from the history file
+        #[arg(long, value_name = "N", default_value_t = 500)]
+        history_limit: usize,
+        /// Tell direct neighbours when you're typing (needs --nick), and show
+        /// who else is
+        #[arg(long)]
+        typing_indicator: bool,
+        /// Acknowledge each message you receive, and count who acknowledged
+        /// yours. Costs one extra frame per message per receiver
+        #[arg(long)]
+        ack: bool,
+        /// Append a JSON line to FILE for each acknowledgement of your
+        /// messages: msg_id, acker and time (needs --ack)
+        #[arg(long, value_name = "FILE", requires = "ack")]
+        ack_log: Option<std::path::PathBuf>,
+        /// Desktop notifications for messages received while the terminal
+        /// isn't focused
+        #[cfg(feature = "notify")]
+        #[arg(long, value_enum, default_value_t)]
+        notify: notify::Notify,
+    },
+    /// Send a file as one message, with its name, length and SHA-256
+    SendFile {
+        path: std::path::PathBuf,
+        #[arg(long, default_value_t = 7, conflicts_with = "room")]
+        topic: u8,
+        #[arg(long)]
+        room: Option<String>,
+        #[arg(long, default_value_t = 3)]
+        ttl: u8,
+        #[arg(long, default_value_t = 2.0)]
+        rate: f64,
+        /// Plaintext bytes per chunk (bounded by the advertisement budget)
+        #[arg(long, default_value_t = MAX_PAYLOAD)]
+        chunk_size: usize,
+        /// DEFLATE the file before chunking
+        #[arg(long, value_enum, default_value_t)]
+        compress: compress::Compress,
+        /// Forward error correction, as for `tx --fec`
+        #[arg(long, value_parser = fec::parse_fec, default_value = "none")]
+        fec: fec::Fec,
+        /// Keep cycling through all chunks for N seconds so late joiners
+        /// catch the whole file
+        #[arg(long, value_name = "N")]
+        repeat_secs: Option<u64>,
+    },
+    /// Receive files sent with send-file, verify them and write them out
+    RecvFile {
+        #[arg(long, default_value = ".")]
+        out_dir: std::path::PathBuf,
+        #[arg(long, conflicts_with = "room")]
+        topic: Option<u8>,
+        #[arg(long)]
+        room: Option<String>,
+    },
+    /// Measure how fast this adapter can advertise; needs a second adapter to scan
+    Calibrate {
+        /// Adapter index used to observe the advertisements
+        #[arg(long)]
+        rx_adapter: usize,
+        #[arg(long, default_value_t = 250)]
+        topic: u8,
+        /// Distinct frames advertised per trial
+        #[arg(long, default_value_t = 20)]
+        frames: u8,
+    },
+    /// Demo the mesh with in-process virtual nodes (no radio needed)
+    Simulate {
+        #[arg(long, default_value_t = 8)]
+        nodes: usize,
+        #[arg(long, value_enum, default_value_t = simulate::Topology::Line)]
+        topology: simulate::Topology,
+        /// Per-link probability of missing an advertisement (0.0-1.0)
+        #[arg(long, default_value_t = 0.1)]
+        loss: f64,
+        #[arg(long, default_value_t = 3)]
+        ttl: u8,
+        #[arg(long, default_value_t = 20)]
+        messages: usize,
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Write a fresh random 32-byte key (hex) for --key-file
+    Keygen {
+        /// Output file; must not exist yet
+        path: std::path::PathBuf,
+    },
+    /// List BLE adapters with the index to pass to --adapter
+    ListAdapters,
+    /// Live RSSI readout of one sender, for range testing
+    Signal {
+        #[arg(long, conflicts_with = "room")]
+        topic: Option<u8>,
+        #[arg(long)]
+        room: Option<String>,
+        /// Lock onto the peripheral whose id contains this string
+        #[arg(long)]
+        peer: Option<String>,
+    },
+}
+
+impl Cmd {
+    fn room(&self) -> Option<&str> {
+        match self {
+            Cmd::Tx { room, .. }
+            | Cmd::Rx { room, .. }
+            | Cmd::Chat { room, .. }
+            | Cmd::SendFile { room, .. }
+            | Cmd::RecvFile { room, .. }
+            | Cmd::Signal { room, .. } => room.as_deref(),
+            Cmd::Calibrate { .. }
+            | Cmd::Simulate { .. }
+            | Cmd::Keygen { .. }
+            | Cmd::ListAdapters => None,
+        }
+    }
+
+    /// The rooms `chat --join` adds.
+    fn joined(&self) -> &[String] {
+        match self {
+            Cmd::Chat { join, .. } => join,
+            _ => &[],
+        }
+    }
+}
+
+/// Send-side knobs shared by `tx` and the chat UI.
+#[derive(Clone, Copy)]
+pub(crate) struct TxOpts {
+    pub topic: u8,
+    pub ttl: u8,
+    pub dwell_ms: u64,
+    pub gap_ms: u64,
+    pub repeats: u32,
+    pub rate: f64,
+    /// Token-bucket capacity; `None` for `RateLimiter::new`'s default.
+    pub burst: Option<f64>,
+    /// Scale `rate` down while the channel is busy; see `congestion`.
+    pub adaptive: bool,
+    /// See `advert_steps`.
+    pub rotate_in_place: bool,
+    /// Repeat the whole schedule until this much time has passed.
+    pub repeat_for: Option<Duration>,
+    pub chunk_size: usize,
+    /// Seal each message under its own key; see `crypto::handshake_initiate`.
+    pub per_message_key: bool,
+    /// Send `VER_PRIVATE` frames with a masked topic byte.
+    pub private_topic: bool,
+    pub compress: compress::Compress,
+    pub fec: fec::Fec,
+    /// Send as a presence beacon; see `presence`.
+    pub presence: bool,
+}
+
+/// What a frame's payload holds: a control frame's `Control` kind, or else
+/// from its `FLAG_HANDSHAKE` and `FLAG_NACK` bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum FrameKind {
+    /// A chunk of message body.
+    Data,
+    /// Ephemeral X25519 public key for a handshake-mode message.
+    Handshake,
+    /// A `msg_id` and the chunks of it a receiver is missing.
+    Nack,
+    /// The sender's nickname, sent while they type in chat. One frame, sent
+    /// with TTL 0 and never relayed or reassembled; see `mesh::typing_frame`.
+    Typing,
+    /// A `msg_id` a receiver has reassembled, and who they are; see
+    /// `mesh::ack_frame`.
+    Ack,
+}
+
+/// The kind of a `VER_CONTROL` frame, sent in place of its flags byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Control {
+    Typing = 1,
+    Ack = 2,
+}
+
+impl Control {
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(Control::Typing),
+            2 => Some(Control::Ack),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Frame {
+    /// Topic as sent on the air; masked when `private` is set.
+    topic: u8,
+    /// A `VER_PRIVATE` frame.
+    private: bool,
+    /// A `VER_PLAIN` (or `VER_CONTROL_PLAIN`) frame: the payload is not
+    /// encrypted.
+    plain: bool,
+    /// A control frame of this kind; its `flags` are then always 0.
+    control: Option<Control>,
+    ttl: u8,
+    /// The `ttl` the sender set. Sealed in the AAD, unlike `ttl`, so a relay
+    /// can't change how far the message started from.
+    ttl0: u8,
+    /// `FLAG_*` bits, including any this build doesn't know; relays pass
+    /// them on unchanged.
+    flags: u8,
+    msg_id: MsgId,
+    seq: u8,
+    tot: u8,
+    /// Send time, Unix milliseconds. Shared by every chunk of a message.
+    ts_ms: u64,
+    payload: Vec<u8>,
+}
+
+/// Identifies one logical message. The topic and chunk count are folded in
+/// alongside `msg_id` so two different messages that happen to share an id
+/// are even less likely to merge during reassembly.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct MsgKey {
+    msg_id: MsgId,
+    topic: u8,
+    tot: u8,
+}
+
+impl Frame {
+    fn version(&self) -> u8 {
+        match (self.control, self.private, self.plain) {
+            (Some(_), _, true) => VER_CONTROL_PLAIN,
+            (Some(_), _, false) => VER_CONTROL,
+            (None, true, _) => VER_PRIVATE,
+            (None, false, true) => VER_PLAIN,
+            (None, false, false) => VER,
+        }
+    }
+
+    /// The flags byte on the air: a control frame's kind, else `flags`.
+    fn flags_byte(&self) -> u8 {
+        self.control.map_or(self.flags, |c| c as u8)
+    }
+
+    fn kind(&self) -> FrameKind {
+        if let Some(c) = self.control {
+            match c {
+                Control::Typing => FrameKind::Typing,
+                Control::Ack => FrameKind::Ack,
+            }
+        } else if self.has(FLAG_HANDSHAKE) {
+            FrameKind::Handshake
+        } else if self.has(FLAG_NACK) {
+            FrameKind::Nack
+        } else {
+            FrameKind::Data
+        }
+    }
+
+    /// Relays this frame has passed through.
+    fn hops(&self) -> u8 {
+        self.ttl0.saturating_sub(self.ttl)
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn key(&self) -> MsgKey {
+        MsgKey {
+            msg_id: self.msg_id,
+            topic: self.topic,
+            tot: self.tot,
+        }
+    }
+
+    /// `msg_id || seq || topic`, zero padded. The topic is included so a
+    /// message swept across several topics never reuses a nonce. Like `aad`,
+    /// it uses the real topic, so call it on a private frame before masking
+    /// (or after unmasking).
+    fn nonce(&self) -> [u8; 12] {
+        let mut n = [0u8; 12];
+        n[..MSG_ID_LEN].copy_from_slice(&self.msg_id);
+        n[MSG_ID_LEN] = self.seq;
+        n[MSG_ID_LEN + 1] = self.topic;
+        n
+    }
+
+    /// Header bytes bound into the AEAD: version, topic, initial ttl, flags,
+    /// msg_id, seq, tot, timestamp. `ttl` is left out because relays
+    /// decrement it.
+    fn aad(&self) -> [u8; 4 + MSG_ID_LEN + 2 + 8] {
+        let mut aad = [0u8; 4 + MSG_ID_LEN + 2 + 8];
+        aad[0] = self.version();
+        aad[1] = self.topic;
+        aad[2] = self.ttl0;
+        aad[3] = self.flags_byte();
+        aad[4..4 + MSG_ID_LEN].copy_from_slice(&self.msg_id);
+        aad[4 + MSG_ID_LEN] = self.seq;
+        aad[5 + MSG_ID_LEN] = self.tot;
+        aad[6 + MSG_ID_LEN..].copy_from_slice(&self.ts_ms.to_le_bytes());
+        aad
+    }
+}
+
+fn pack_frame(f: &Frame) -> Vec<u8> {
+    let mut b = Vec::with_capacity(HEADER_LEN + f.payload.len());
+    b.extend_from_slice(&COMPANY_ID.to_le_bytes());
+    b.push(f.version());
+    b.push(f.topic);
+    b.push(f.ttl);
+    b.push(f.ttl0);
+    b.push(f.flags_byte());
+    b.extend_from_slice(&f.msg_id);
+    b.push(f.seq);
+    b.push(f.tot);
+    b.extend_from_slice(&f.ts_ms.to_le_bytes());
+    b.extend_from_slice(&f.payload);
+    if f.plain {
+        // Covers everything after the company id, which is optional on receive.
+        let crc = crc16_ccitt(&b[2..]);
+        b.extend_from_slice(&crc.to_le_bytes());
+    }
+    b
+}
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xFFFF.
+fn crc16_ccitt(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Why `unpack_frame` refused a blob.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FrameError {
+    TooShort,
+    /// A 2-byte manufacturer prefix that isn't `COMPANY_ID`.
+    BadCompanyId(u16),
+    UnsupportedVersion(u8),
+    BadSeqTot { seq: u8, tot: u8 },
+    CrcMismatch,
+    /// A control frame of a kind this build doesn't know.
+    UnknownControl(u8),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooShort => write!(f, "shorter than a frame header"),
+            FrameError::BadCompanyId(cid) => write!(f, "company id {cid:#06x} is not ours"),
+            FrameError::UnsupportedVersion(v) => write!(f, "unsupported version {v}"),
+            FrameError::BadSeqTot { seq, tot } => write!(f, "chunk {seq} of {tot} is impossible"),
+            FrameError::CrcMismatch => write!(f, "CRC mismatch"),
+            FrameError::UnknownControl(k) => write!(f, "unknown control frame kind {k}"),
+        }
+    }
+}
+
+fn is_version(b: u8) -> bool {
+    matches!(b, VER | VER_PRIVATE | VER_PLAIN | VER_CONTROL | VER_CONTROL_PLAIN)
+}
+
+fn unpack_frame(md: &[u8]) -> Result<Frame, FrameError> {
+
+    let mut i = 0usize;
+
+    if md.len() >= 2 {
+        let cid = u16::from_le_bytes([md[0], md[1]]);
+        if cid == COMPANY_ID {
+            i = 2;
+        } else if !is_version(md[0]) && md.get(2).copied().is_some_and(is_version) {
+            // Looks like one of our frames behind someone else's prefix.
+            return Err(FrameError::BadCompanyId(cid));
+        }
+    }
+
+    if md.len() < i + HEADER_LEN - 2 {
+        return Err(FrameError::TooShort);
+    }
+
+    let (private, plain, control) = match md[i] {
+        VER => (false, false, false),
+        VER_PRIVATE => (true, false, false),
+        VER_PLAIN => (false, true, false),
+        VER_CONTROL => (false, false, true),
+        VER_CONTROL_PLAIN => (false, true, true),
+        v => return Err(FrameError::UnsupportedVersion(v)),
+    };
+    let mut md = md;
+    if plain {
+        let (body, crc) = md.split_last_chunk::<CRC_LEN>().ok_or(FrameError::TooShort)?;
+        if body.len() < i + HEADER_LEN - 2 {
+            return Err(FrameError::TooShort);
+        }
+        // A corrupted plain frame would otherwise reassemble as garbage text.
+        if crc16_ccitt(&body[i..]) != u16::from_le_bytes(*crc) {
+            return Err(FrameError::CrcMismatch);
+        }
+        md = body;
+    }
+    i += 1;
+
+    let topic = md[i];
+    i += 1;
+    let ttl = md[i];
+    i += 1;
+    let ttl0 = md[i];
+    i += 1;
+    let (flags, control) = if control {
+        let kind = Control::from_byte(md[i]).ok_or(FrameError::UnknownControl(md[i]))?;
+        (0, Some(kind))
+    } else {
+        (md[i], None)
+    };
+    i += 1;
+
+    let mut msg_id: MsgId = [0; MSG_ID_LEN];
+    msg_id.copy_from_slice(&md[i..i + MSG_ID_LEN]);
+    i += MSG_ID_LEN;
+
+    let seq = md[i];
+    i += 1;
+    let tot = md[i];
+    i += 1;
+    // Such a message could never complete and would sit in `reasm` forever.
+    // Fountain droplet ids and parity shard indices can exceed the block count.
+    if tot == 0 || (seq >= tot && flags & (FLAG_FOUNTAIN | FLAG_PARITY) == 0) {
+        return Err(FrameError::BadSeqTot { seq, tot });
+    }
+
+    let mut ts = [0u8; 8];
+    ts.copy_from_slice(&md[i..i + 8]);
+    let ts_ms = u64::from_le_bytes(ts);
+    i += 8;
+
+    let payload = md[i..].to_vec();
+    Ok(Frame {
+        topic,
+        private,
+        plain,
+        control,
+        ttl,
+        ttl0,
+        flags,
+        msg_id,
+        seq,
+        tot,
+        ts_ms,
+        payload,
+    })
+}
+
+/// Entry point for the `unpack_frame` fuzz target in `fuzz/`. Panics if
+/// parsing `md` panics, or if a frame it accepts doesn't pack back to the same
+/// bytes, give or take the optional company id. The unit tests run it on
+/// random input too.
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub fn fuzz_unpack_frame(md: &[u8]) {
+    let Ok(f) = unpack_frame(md) else {
+        return;
+    };
+    let packed = pack_frame(&f);
+    assert!(
+        packed == md || packed[2..] == *md,
+        "repacked frame differs from its input"
+    );
+}
+
+/// Always yields at least one chunk, so an empty message goes out as a single
+/// frame with an empty payload. Fails if `tot` would not fit in a `u8`.
+fn chunk_message(bytes: &[u8], chunk_size: usize) -> anyhow::Result<Vec<(u8, u8, Vec<u8>)>> {
+    let count = bytes.len().div_ceil(chunk_size).max(1);
+    let Ok(tot) = u8::try_from(count) else {
+        anyhow::bail!(
+            "message of {} bytes needs {count} chunks at chunk size {chunk_size}; at most {} fit",
+            bytes.len(),
+            u8::MAX
+        );
+    };
+    let mut v = Vec::new();
+    for i in 0..tot {
+        let s = (i as usize) * chunk_size;
+        let e = (s + chunk_size).min(bytes.len());
+        v.push((i, tot, bytes[s..e].to_vec()));
+    }
+    Ok(v)
+}
+
+/// One advertisement slot in `tx`.
+struct AdvertStep {
+    /// Index into the packed frames.
+    frame: usize,
+    /// Start advertising this frame; otherwise the previous one stays up.
+    restart: bool,
+    /// Stop the previous advertisement before restarting (in-place rotation
+    /// only; otherwise it was stopped after its own slot).
+    stop_first: bool,
+    /// Stop advertising and wait out the gap after the dwell.
+    stop_after: bool,
+}
+
+/// The order `tx` advertises `frames` in over `rounds` repeats. Repeats cycle
+/// through all frames so each copy of a chunk lands at a different point in
+/// a receiver's scan cycle.
+///
+/// Normally every slot starts and stops advertising. With `in_place` the
+/// advertiser stays up and is only restarted when the payload changes, and
+/// only the last slot stops it.
+fn advert_steps(frames: &[Vec<u8>], rounds: u32, in_place: bool) -> Vec<AdvertStep> {
+    let total = frames.len() * rounds as usize;
+    let mut steps: Vec<AdvertStep> = Vec::with_capacity(total);
+    for i in 0..total {
+        let frame = i % frames.len();
+        let changed = steps.last().is_none_or(|prev| frames[prev.frame] != frames[frame]);
+        steps.push(AdvertStep {
+            frame,
+            restart: !in_place || changed,
+            stop_first: in_place && changed && i > 0,
+            stop_after: !in_place || i + 1 == total,
+        });
+    }
+    steps
+}
+
+/// What sending one message costs; see `estimate_airtime`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct AirtimeEstimate {
+    /// Chunks, droplets or shards the message is cut into.
+    pub chunks: usize,
+    /// Advertisements across every repeat, handshake frames included.
+    pub frames: usize,
+    /// Manufacturer-data bytes across all of them.
+    pub bytes: usize,
+    /// Time to send them all once.
+    pub duration: Duration,
+}
+
+/// Estimate sending a `msg_len`-byte body, as handed to `tx` (signed, and
+/// compressed if it will be), with `opts`, without building any frames. Each
+/// advertisement takes its dwell plus the gap, or `1 / rate` if that is
+/// longer; `--burst` and `--adaptive` are ignored, as is `--repeat-secs`.
+pub(crate) fn estimate_airtime(msg_len: usize, opts: &TxOpts, encrypted: bool) -> AirtimeEstimate {
+    let size = opts.chunk_size.max(1);
+    let (chunks, payload, rounds) = match opts.fec {
+        fec::Fec::None => (msg_len.div_ceil(size).max(1), msg_len, opts.repeats),
+        // `tx` sends fresh droplets instead of repeating them.
+        fec::Fec::Fountain => {
+            let count = fec::block_count(msg_len, size) * (opts.repeats as usize + 1);
+            (count, count * size, 1)
+        }
+        fec::Fec::Rs { k, n } => {
+            let shard = (fec::LEN_PREFIX + msg_len).div_ceil(k.max(1) as usize);
+            (n as usize, n as usize * shard, opts.repeats)
+        }
+    };
+    let rounds = rounds.max(1) as usize;
+    let overhead = HEADER_LEN + if encrypted { AEAD_TAG_LEN } else { CRC_LEN };
+    let mut per_round = chunks * overhead + payload;
+    let mut frames = chunks;
+    if opts.per_message_key {
+        per_round += HEADER_LEN + 32;
+        frames += 1;
+    }
+    let frames = frames * rounds;
+    // In-place rotation only stops, and waits out the gap, once at the end.
+    let slot = Duration::from_millis(opts.dwell_ms)
+        + if opts.rotate_in_place {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(opts.gap_ms)
+        };
+    let slot = slot.max(Duration::from_secs_f64(1.0 / opts.rate.max(0.001)));
+    AirtimeEstimate {
+        chunks,
+        frames,
+        bytes: per_round * rounds,
+        duration: slot * frames as u32,
+    }
+}
+
+/// Check that a chunk of `chunk_size` bytes (plus header and either the AEAD
+/// tag or the CRC) fits in one advertisement, and that `tot` fits in a `u8`.
+fn validate_chunk_size(chunk_size: usize, encrypted: bool, msg_len: usize) -> anyhow::Result<()> {
+    let overhead = HEADER_LEN + if encrypted { AEAD_TAG_LEN } else { CRC_LEN };
+    if chunk_size == 0 || chunk_size + overhead > MAX_MD_LEN {
+        anyhow::bail!(
+            "chunk size {} out of range: must be 1..={} (advertisement budget {} bytes, {} bytes of header{})",
+            chunk_size,
+            MAX_MD_LEN - overhead,
+            MAX_MD_LEN,
+            overhead,
+            if encrypted { " incl. AEAD tag" } else { " incl. CRC" }
+        );
+    }
+    if msg_len.div_ceil(chunk_size) > u8::MAX as usize {
+        anyhow::bail!(
+            "message of {} bytes needs more than {} chunks at chunk size {}",
+            msg_len,
+            u8::MAX,
+            chunk_size
+        );
+    }
+    Ok(())
+}
+
+/// Name a binary stdin message is saved under by `recv-file`.
+const STDIN_FILE_NAME: &str = "stdin";
+
+/// Read a `tx` message from `input`, stdin outside tests, to EOF: `Ok` with
+/// the text, minus the trailing newline `echo` adds, or `Err` with the bytes
+/// if they aren't UTF-8.
+fn read_message(mut input: impl std::io::Read) -> anyhow::Result<Result<String, Vec<u8>>> {
+    let mut buf = Vec::new();
+    input
+        .read_to_end(&mut buf)
+        .context("read message from stdin")?;
+    Ok(match String::from_utf8(buf) {
+        Ok(text) => Ok(text.trim_end_matches(['\r', '\n']).to_string()),
+        Err(e) => Err(e.into_bytes()),
+    })
+}
+
+/// Length of `body` once `identity::seal` has added any signature trailer.
+fn signed_len(body: &[u8], id_key: Option<&crypto::SigningKey>) -> usize {
+    body.len() + id_key.map_or(0, |_| identity::SIGNED_OVERHEAD)
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn topic_from_room(room: &str) -> u8 {
+    let mut h = Sha256::new();
+    h.update(room.as_bytes());
+    let digest = h.finalize();
+    digest[0]
+}
+
+/// Passphrase precedence: `--passphrase` (all of them), then
+/// `--passphrase-file`, then the `BLE_CHIRP_PASSPHRASE` environment variable.
+fn load_passphrases(
+    args: Vec<String>,
+    file: Option<&std::path::Path>,
+) -> anyhow::Result<Vec<String>> {
+    if !args.is_empty() {
+        return Ok(args);
+    }
+    if let Some(path) = file {
+        let mut raw = std::fs::read_to_string(path)
+            .with_context(|| format!("read passphrase file {}", path.display()))?;
+        let p = raw.lines().next().unwrap_or_default().to_string();
+        raw.zeroize();
+        return Ok(vec![p]);
+    }
+    Ok(std::env::var(PASSPHRASE_ENV).ok().into_iter().collect())
+}
+
+/// Keys by topic, for rooms that don't share the main key.
+type RoomKeys = HashMap<u8, Vec<crypto::Keys>>;
+
+/// Every key this node can decrypt with, from `--key-file` or the
+/// passphrases, then `--keyring`. The first one is used for sending.
+///
+/// When the passphrases are salted with `--room`, each `chat --join` room
+/// gets its own keys too, by topic, salted with its own name as a node
+/// started with `--room` for it would be.
+fn load_keys(args: &mut Args) -> anyhow::Result<(Vec<crypto::Keys>, RoomKeys)> {
+    let mut passphrases =
+        load_passphrases(std::mem::take(&mut args.passphrase), args.passphrase_file.as_deref())?;
+    let mut keys = Vec::new();
+    let mut joined = Vec::new();
+    if let Some(path) = &args.key_file {
+        // A passphrase can still arrive via the environment; the key file wins.
+        passphrases.iter_mut().for_each(Zeroize::zeroize);
+        keys.push(load_key_file(path)?);
+    } else if !passphrases.is_empty() {
+        // Virtual nodes all share one salt, so the simulator can default it.
+        let simulated = matches!(args.cmd, Cmd::Simulate { .. }).then_some("simulate");
+        let Some(salt) = args.salt.as_deref().or(args.cmd.room()).or(simulated) else {
+            passphrases.iter_mut().for_each(Zeroize::zeroize);
+            anyhow::bail!("a passphrase without --room needs an explicit --salt");
+        };
+        let params = crypto::KdfParams {
+            mem_kib: args.kdf_mem_kib,
+            iters: args.kdf_iters,
+            parallelism: args.kdf_parallelism,
+        };
+        let salted_by_room = args.salt.is_none() && args.cmd.room().is_some();
+        let derive = |salt: &str| -> anyhow::Result<Vec<_>> {
+            let salt = crypto::salt_for(salt);
+            passphrases
+                .iter()
+                .map(|p| crypto::derive_key(p, &salt, &params))
+                .collect()
+        };
+        let derived = derive(salt);
+        let rooms: anyhow::Result<Vec<_>> = match salted_by_room {
+            true => args
+                .cmd
+                .joined()
+                .iter()
+                .map(|name| Ok((topic_from_room(name), derive(name)?)))
+                .collect(),
+            false => Ok(Vec::new()),
+        };
+        passphrases.iter_mut().for_each(Zeroize::zeroize);
+        keys.extend(derived?);
+        joined = rooms?;
+    }
+    if let Some(path) = &args.keyring {
+        let mut raw = std::fs::read_to_string(path)
+            .with_context(|| format!("read keyring {}", path.display()))?;
+        let parsed: Option<Vec<_>> = raw
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(parse_key_line)
+            .collect();
+        raw.zeroize();
+        let Some(ring) = parsed else {
+            anyhow::bail!(
+                "keyring {}: every line must be a 32-byte key in hex or base64",
+                path.display()
+            );
+        };
+        for k in ring {
+            crypto::validate_key_strength(&k)
+                .with_context(|| format!("keyring {}", path.display()))?;
+            // Each extra key costs an AEAD attempt on every frame.
+            if keys.iter().any(|have| crypto::keys_equal(have, &k)) {
+                eprintln!("warning: keyring {} repeats a key; ignoring it", path.display());
+                continue;
+            }
+            keys.push(k);
+        }
+    }
+    let rekey = |ks: Vec<crypto::SecretKey>| {
+        ks.into_iter()
+            .map(|k| crypto::Keys::new(k, args.rekey_secs))
+            .collect::<Vec<_>>()
+    };
+    let joined = joined
+        .into_iter()
+        .map(|(topic, ks)| (topic, rekey(ks)))
+        .collect();
+    Ok((rekey(keys), joined))
+}
+
+/// Read a 32-byte key from `path`: either exactly 32 raw bytes, or one line
+/// of hex or base64.
+fn load_key_file(path: &std::path::Path) -> anyhow::Result<crypto::SecretKey> {
+    let mut raw =
+        std::fs::read(path).with_context(|| format!("read key file {}", path.display()))?;
+    let key = match <[u8; 32]>::try_from(raw.as_slice()) {
+        Ok(k) => Some(crypto::SecretKey::new(k)),
+        Err(_) => parse_key_line(String::from_utf8_lossy(&raw).trim()),
+    };
+    raw.zeroize();
+    let key = key.with_context(|| {
+        format!(
+            "key file {} must hold 32 raw bytes or 32 bytes as hex/base64",
+            path.display()
+        )
+    })?;
+    crypto::validate_key_strength(&key).with_context(|| format!("key file {}", path.display()))?;
+    Ok(key)
+}
+
+fn parse_key_line(line: &str) -> Option<crypto::SecretKey> {
+    use base64::Engine;
+    let mut bytes = hex::decode(line)
+        .or_else(|_| base64::engine::general_purpose::STANDARD.decode(line))
+        .ok()?;
+    let key = bytes.as_slice().try_into().ok().map(crypto::SecretKey::new);
+    bytes.zeroize();
+    key
+}
+
+/// Create `path` readable only by the owner; fails if it already exists.
+#[cfg(unix)]
+fn write_private(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?
+        .write_all(data)
+}
+
+fn jitter_delay(window_ms: u64, seed: Option<u64>) -> Duration {
+    if window_ms == 0 {
+        return Duration::ZERO;
+    }
+    let mut rng = seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+    Duration::from_millis(rng.gen_range(0..=window_ms))
+}
+
+/// Send `tracing` events to stderr, keeping stdout for output. Warnings show
+/// by default; `verbose` raises the level unless `RUST_LOG` is set.
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => "warn",
+        1 => "debug",
+        _ => "trace",
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(format!("ble_chirp={level}")));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Parse the command line and run the `ble-chirp` CLI.
+pub async fn run() -> anyhow::Result<()> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    init_logging(args.verbose);
+    config::apply(&mut args, &matches)?;
+
+    if let Cmd::Keygen { path } = &args.cmd {
+        let key = crypto::SecretKey::new(rand::random());
+        let mut line = hex::encode(key.expose());
+        line.push('\n');
+        let res = write_private(path, line.as_bytes());
+        line.zeroize();
+        res.with_context(|| format!("write key file {}", path.display()))?;
+        println!("Wrote key to {}", path.display());
+        return Ok(());
+    }
+    if let Cmd::ListAdapters = args.cmd {
+        return adapter::list().await;
+    }
+
+    // Only `chat` reads the joined rooms' keys.
+    #[cfg_attr(not(feature = "tui"), allow(unused_variables))]
+    let (keys, join_keys) = load_keys(&mut args)?;
+    // Sends use the first key; receivers try them all.
+    let key = keys.first().cloned();
+
+    // The simulator needs no radio, so handle it before touching adapters.
+    if let Cmd::Simulate {
+        nodes,
+        topology,
+        loss,
+        ttl,
+        messages,
+        seed,
+    } = args.cmd
+    {
+        return simulate::simulate(simulate::SimOpts {
+            nodes,
+            topology,
+            loss: loss.clamp(0.0, 1.0),
+            ttl,
+            messages,
+            seed,
+            key,
+        });
+    }
+    let id_key = args
+        .identity
+        .as_deref()
+        .map(identity::load_or_create)
+        .transpose()?;
+    let trust = identity::Trust::new(args.trust);
+    let mutes = mute::MuteList::new(args.mute);
+    let nick = args.nick;
+    let max_age = args.max_age_secs.map(Duration::from_secs);
+    let adapter = adapter::resolve(args.adapter).await?;
+
+    let stats = Arc::new(Stats::default());
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = args.metrics_addr {
+        tokio::spawn(metrics::serve(addr, stats.clone()));
+    }
+    // Chat owns the terminal, so it shows the same dump on F2 instead.
+    #[cfg(unix)]
+    if !matches!(args.cmd, Cmd::Chat { .. }) {
+        tokio::spawn(stats::dump_on_sigusr1(stats.clone()));
+    }
+
+    match args.cmd {
+        Cmd::Tx {
+            topic,
+            room,
+            ttl,
+            msg,
+            msg_file,
+            stdin,
+            subtopic,
+            all_topics,
+            topics,
+            every,
+            #[cfg(feature = "cron")]
+            cron,
+            dwell_ms,
+            rate,
+            burst,
+            adaptive,
+            gap_ms,
+            rotate_in_place,
+            repeat,
+            repeat_secs,
+            profile,
+            strategy,
+            chunk_size,
+            startup_jitter,
+            seed,
+            per_message_key,
+            private_topic,
+            compress,
+            fec,
+            serve,
+            dry_run,
+        } => {
+            if per_message_key && key.is_none() {
+                anyhow::bail!("--per-message-key needs a passphrase");
+            }
+            if private_topic && key.is_none() {
+                anyhow::bail!("--private-topic needs a passphrase");
+            }
+            let delivery = schedule::resolve(
+                profile,
+                schedule::Knobs {
+                    strategy,
+                    dwell_ms,
+                    gap_ms,
+                    repeat,
+                    repeat_secs,
+                    fec,
+                },
+            );
+            let topic = room.map_or(topic, |r| topic_from_room(&r));
+            // Binary stdin goes out in a `send-file` manifest instead, so
+            // `recv-file` can save it byte for byte.
+            let mut stdin_file = None;
+            let msg = if stdin || msg.as_deref() == Some("-") {
+                match read_message(std::io::stdin())? {
+                    Ok(text) => Some(text),
+                    Err(data) => {
+                        stdin_file = Some(file_transfer::pack(STDIN_FILE_NAME, &data)?);
+                        None
+                    }
+                }
+            } else {
+                msg
+            };
+            let source = match (msg, msg_file) {
+                (Some(m), _) => {
+                    let m = mesh::tag_message(nick.as_deref(), subtopic.as_deref(), &m);
+                    Some(beacon::MsgSource::Inline(m))
+                }
+                (None, Some(path)) => Some(beacon::MsgSource::File {
+                    path,
+                    nick,
+                    subtopic,
+                }),
+                (None, None) => None,
+            };
+            let spec = every.map(|s| beacon::Spec::Every(Duration::from_secs(s.max(1))));
+            #[cfg(feature = "cron")]
+            let spec = match cron {
+                Some(expr) => Some(beacon::Spec::cron(&expr)?),
+                None => spec,
+            };
+            let opts = TxOpts {
+                topic,
+                ttl,
+                dwell_ms: delivery.dwell_ms,
+                gap_ms: delivery.gap_ms,
+                repeats: delivery.repeats,
+                rate,
+                burst,
+                adaptive,
+                rotate_in_place,
+                repeat_for: delivery.repeat_secs.map(Duration::from_secs),
+                chunk_size,
+                per_message_key,
+                private_topic,
+                compress,
+                fec: delivery.fec,
+                presence: false,
+            };
+            if spec.is_some() && (all_topics || !topics.is_empty()) {
+                anyhow::bail!("--all-topics/--topics can't be combined with a beacon schedule");
+            }
+            if stdin_file.is_some() && (spec.is_some() || all_topics || !topics.is_empty()) {
+                anyhow::bail!("binary stdin is sent once on one topic; use text or `send-file`");
+            }
+            // Checked on the bytes that would go out, so a message too long
+            // raw that fits once compressed is still sent.
+            let inline = match (&stdin_file, &source) {
+                (Some(body), _) => Some(body.as_slice()),
+                (None, Some(beacon::MsgSource::Inline(m))) => Some(m.as_bytes()),
+                _ => None,
+            };
+            if let Some(body) = inline {
+                let (packed, _) = packed_body(body, &opts, key.is_some(), id_key.as_ref());
+                validate_chunk_size(chunk_size, key.is_some(), packed.len())?;
+            }
+            if dry_run {
+                let body = match (stdin_file, &source) {
+                    (Some(body), _) => body,
+                    (None, Some(source)) => source.read()?.into_bytes(),
+                    (None, None) => anyhow::bail!("no message to estimate"),
+                };
+                let (packed, choice) = packed_body(&body, &opts, key.is_some(), id_key.as_ref());
+                if let Some(choice) = choice {
+                    println!("{choice}");
+                }
+                let est = estimate_airtime(packed.len(), &opts, key.is_some());
+                let n = match topics.len() {
+                    _ if all_topics => 256,
+                    0 => 1,
+                    n => n,
+                };
+                println!(
+                    "{} bytes in {} chunks: {} frames, {} bytes on air, ~{:.1}s{}",
+                    packed.len(),
+                    est.chunks,
+                    est.frames * n,
+                    est.bytes * n,
+                    (est.duration * n as u32).as_secs_f64(),
+                    if n > 1 { format!(" across {n} topics") } else { String::new() },
+                );
+                return Ok(());
+            }
+            transport::ensure_can_advertise(&adapter).await?;
+            sleep(jitter_delay(startup_jitter, seed)).await;
+            let server = serve.then(|| tokio::spawn(retransmit::serve(adapter.clone(), keys, rate)));
+            match spec {
+                Some(spec) => {
+                    let source = source.expect("binary stdin rejected above");
+                    beacon::run(adapter, opts, key, id_key, source, spec).await?
+                }
+                None if all_topics || !topics.is_empty() => {
+                    let Some(beacon::MsgSource::Inline(msg)) = source else {
+                        anyhow::bail!("--msg-file needs --every or --cron; pass the message inline to send once");
+                    };
+                    let topics: Vec<u8> = if all_topics {
+                        (0..=u8::MAX).collect()
+                    } else {
+                        topics
+                    };
+                    let chunks = signed_len(msg.as_bytes(), id_key.as_ref()).div_ceil(chunk_size).max(1);
+                    let frames = (chunks + per_message_key as usize) * topics.len();
+                    let secs = frames as f64 * opts.repeats as f64 / rate.max(f64::MIN_POSITIVE);
+                    eprintln!(
+                        "warning: broadcasting on {} topics = {} frames, ~{:.0}s of airtime at --rate {}",
+                        topics.len(),
+                        frames * opts.repeats as usize,
+                        secs,
+                        rate
+                    );
+                    tx_topics(adapter, &opts, &topics, msg.as_bytes(), key.as_ref(), id_key.as_ref())
+                        .await?;
+                }
+                None => {
+                    let body = match (stdin_file, source) {
+                        (Some(body), _) => body,
+                        (None, Some(beacon::MsgSource::Inline(msg))) => msg.into_bytes(),
+                        (None, _) => anyhow::bail!(
+                            "--msg-file needs --every or --cron; pass the message inline to send once"
+                        ),
+                    };
+                    tx(adapter, &opts, &body, key.as_ref(), id_key.as_ref()).await?;
+                }
+            }
+            if let Some(server) = server {
+                println!("Serving repairs until Ctrl-C...");
+                tokio::select! {
+                    res = server => res??,
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+        }
+        Cmd::Rx {
+            topic,
+            room,
+            relay,
+            relay_events,
+            show_source,
+            progress,
+            presence_interval,
+            binary,
+            out,
+            format,
+            subtopic,
+            stats: print_stats,
+            debug_frames,
+            reasm_timeout,
+            max_reasm,
+            nack_after,
+            seen_cache,
+            seen_ttl,
+            max_relays,
+            relay_topics,
+            no_relay_topics,
+            suppress_relay,
+            max_reconnect,
+        } => {
+            let topic = match (topic, room) {
+                (Some(t), _) => Some(t),
+                (_, Some(r)) => Some(topic_from_room(&r)),
+                _ => None,
+            };
+            if let Some(secs) = presence_interval {
+                let (Some(nick), Some(topic)) = (nick, topic) else {
+                    anyhow::bail!("--presence-interval needs --nick and a --topic or --room");
+                };
+                let opts = TxOpts {
+                    topic,
+                    ttl: 3,
+                    dwell_ms: schedule::DEFAULT_DWELL_MS,
+                    gap_ms: DEFAULT_GAP_MS,
+                    repeats: 1,
+                    rate: 2.0,
+                    burst: None,
+                    adaptive: false,
+                    rotate_in_place: false,
+                    repeat_for: None,
+                    chunk_size: MAX_PAYLOAD,
+                    per_message_key: false,
+                    private_topic: false,
+                    compress: compress::Compress::Never,
+                    fec: fec::Fec::None,
+                    presence: true,
+                };
+                let interval = presence::interval(secs)?;
+                tokio::spawn(presence::announce(adapter.clone(), opts, nick, id_key, key, interval, None));
+            }
+            if print_stats {
+                let stats = stats.clone();
+                tokio::spawn(async move {
+                    loop {
+                        sleep(STATS_INTERVAL).await;
+                        eprintln!("[stats] {}", stats.summary());
+                    }
+                });
+            }
+            let opts = RxOpts {
+                topic_filter: topic,
+                relay,
+                keys,
+                stats,
+                relay_done: None,
+                queue: None,
+                events: progress.then(spawn_progress_printer),
+                subtopic,
+                max_age,
+                debug_frames,
+                reasm_timeout: Duration::from_secs(reasm_timeout),
+                max_reasm,
+                nack_after: nack_after.map(Duration::from_millis),
+                seen_cache,
+                seen_ttl: Duration::from_secs(seen_ttl),
+                max_relays,
+                relay_topics: relay::RelayTopics::from_lists(relay_topics, no_relay_topics),
+                suppress_relay,
+                max_reconnect,
+                roster: None,
+                typing: None,
+                ack_as: None,
+                acks: None,
+                mutes: mutes.clone(),
+                rng_seed: None,
+            };
+            let output = match (binary, format) {
+                (true, _) => RxOutput::Binary(out),
+                (false, RxFormat::Text) => RxOutput::Text,
+                (false, RxFormat::Json) => RxOutput::Json,
+            };
+            rx(adapter, opts, trust, relay_events, show_source, output).await?
+        }
+        Cmd::SendFile {
+            path,
+            topic,
+            room,
+            ttl,
+            rate,
+            chunk_size,
+            compress,
+            fec,
+            repeat_secs,
+        } => {
+            let opts = TxOpts {
+                topic: room.map_or(topic, |r| topic_from_room(&r)),
+                ttl,
+                dwell_ms: schedule::DEFAULT_DWELL_MS,
+                gap_ms: DEFAULT_GAP_MS,
+                repeats: 1,
+                rate,
+                burst: None,
+                adaptive: false,
+                rotate_in_place: false,
+                repeat_for: repeat_secs.map(Duration::from_secs),
+                chunk_size,
+                per_message_key: false,
+                private_topic: false,
+                compress,
+                fec,
+                presence: false,
+            };
+            file_transfer::send_file(adapter, &opts, &path, key.as_ref()).await?
+        }
+        Cmd::RecvFile {
+            out_dir,
+            topic,
+            room,
+        } => {
+            let opts = RxOpts {
+                topic_filter: topic.or(room.map(|r| topic_from_room(&r))),
+                relay: true,
+                keys,
+                stats,
+                relay_done: None,
+                queue: None,
+                events: None,
+                subtopic: None,
+                max_age,
+                debug_frames: false,
+                reasm_timeout: mesh::REASM_TIMEOUT,
+                max_reasm: mesh::MAX_REASM,
+                nack_after: None,
+                seen_cache: mesh::SEEN_CAP,
+                seen_ttl: mesh::SEEN_TTL,
+                max_relays: relay::MAX_RELAYS,
+                relay_topics: relay::RelayTopics::All,
+                suppress_relay: false,
+                max_reconnect: MAX_RECONNECT,
+                roster: None,
+                typing: None,
+                ack_as: None,
+                acks: None,
+                mutes: mutes.clone(),
+                rng_seed: None,
+            };
+            file_transfer::recv_file(adapter, opts, out_dir).await?
+        }
+        #[cfg(feature = "tui")]
+        Cmd::Chat {
+            topic,
+            room,
+            join,
+            ttl,
+            split_chunks,
+            themes,
+            subtopic,
+            private_topic,
+            dwell_ms,
+            rate,
+            adaptive,
+            gap_ms,
+            repeat,
+            repeat_secs,
+            profile,
+            strategy,
+            fec,
+            presence_interval,
+            no_history,
+            history_limit,
+            typing_indicator,
+            ack,
+            ack_log,
+            #[cfg(feature = "notify")]
+            notify,
+        } => {
+            if private_topic && key.is_none() {
+                anyhow::bail!("--private-topic needs a passphrase");
+            }
+            if presence_interval.is_some() && nick.is_none() {
+                anyhow::bail!("--presence-interval needs --nick");
+            }
+            if typing_indicator && nick.is_none() {
+                anyhow::bail!("--typing-indicator needs --nick");
+            }
+            #[cfg(feature = "notify")]
+            if notify == notify::Notify::Mentions && nick.is_none() {
+                anyhow::bail!("--notify mentions needs --nick");
+            }
+            let delivery = schedule::resolve(
+                profile,
+                schedule::Knobs {
+                    strategy,
+                    dwell_ms,
+                    gap_ms,
+                    repeat,
+                    repeat_secs,
+                    fec,
+                },
+            );
+            transport::ensure_can_advertise(&adapter).await?;
+            let topic = room.as_deref().map_or(topic, topic_from_room);
+            let opts = chat_ui::ChatOpts {
+                topic,
+                room,
+                join,
+                join_keys,
+                ttl,
+                rate,
+                delivery,
+                split_chunks,
+                themes,
+                subtopic,
+                id_key,
+                trust,
+                max_age,
+                private_topic,
+                adaptive,
+                nick,
+                presence_interval: presence_interval.map(presence::interval).transpose()?,
+                history_limit: (!no_history).then_some(history_limit),
+                typing_indicator,
+                ack,
+                ack_log,
+                mutes,
+                #[cfg(feature = "notify")]
+                notify,
+            };
+            chat_ui::chat(adapter, opts, keys, stats).await?
+        }
+        #[cfg(not(feature = "tui"))]
+        Cmd::Chat { .. } => {
+            anyhow::bail!("`chat` is unavailable: ble-chirp was built without the `tui` feature")
+        }
+        Cmd::Calibrate {
+            rx_adapter,
+            topic,
+            frames,
+        } => {
+            let rx_adapter = adapter::resolve(rx_adapter).await?;
+            calibrate::calibrate(adapter, rx_adapter, topic, frames.max(1)).await?
+        }
+        Cmd::Simulate { .. } | Cmd::Keygen { .. } | Cmd::ListAdapters => unreachable!("handled above"),
+        #[cfg(feature = "tui")]
+        Cmd::Signal { topic, room, peer } => {
+            let topic = topic.or(room.map(|r| topic_from_room(&r)));
+            signal_ui::signal(adapter, topic, peer).await?
+        }
+        #[cfg(not(feature = "tui"))]
+        Cmd::Signal { .. } => {
+            anyhow::bail!("`signal` is unavailable: ble-chirp was built without the `tui` feature")
+        }
+    }
+    Ok(())
+}
+
+/// Send one message body, signed with `id_key` if there is one (see
+/// `identity::seal`). Returns the msg_id it went out with.
+pub(crate) async fn tx<T: Transport>(
+    transport: T,
+    opts: &TxOpts,
+    body: &[u8],
+    key: Option<&crypto::Keys>,
+    id_key: Option<&crypto::SigningKey>,
+) -> anyhow::Result<MsgId> {
+    tx_topics(transport, opts, &[opts.topic], body, key, id_key).await
+}
+
+/// Send one message on every topic in `topics`. Chunks are interleaved
+/// across topics (chunk 0 on each topic, then chunk 1, ...) and share one
+/// rate limiter, so a sweep costs the same airtime budget as any other send.
+pub(crate) async fn tx_topics<T: Transport>(
+    transport: T,
+    opts: &TxOpts,
+    topics: &[u8],
+    body: &[u8],
+    key: Option<&crypto::Keys>,
+    id_key: Option<&crypto::SigningKey>,
+) -> anyhow::Result<MsgId> {
+    let mut rng = StdRng::from_entropy();
+    tx_topics_with_rng(transport, opts, topics, body, key, id_key, &mut rng).await
+}
+
+/// `tx_topics`, drawing the msg_id (and so the fountain droplets) from
+/// `rng`; a seeded one makes the sends of a run repeat exactly.
+#[tracing::instrument(name = "tx", skip_all, fields(topics = topics.len(), len = body.len()))]
+pub(crate) async fn tx_topics_with_rng<T: Transport, R: Rng + Send>(
+    transport: T,
+    opts: &TxOpts,
+    topics: &[u8],
+    body: &[u8],
+    key: Option<&crypto::Keys>,
+    id_key: Option<&crypto::SigningKey>,
+    rng: &mut R,
+) -> anyhow::Result<MsgId> {
+    let TxOpts {
+        ttl,
+        dwell_ms,
+        gap_ms,
+        repeats,
+        rate,
+        burst,
+        adaptive,
+        rotate_in_place,
+        repeat_for,
+        per_message_key,
+        private_topic,
+        ..
+    } = *opts;
+    let mut msg_id = MsgId::default();
+    rng.fill(&mut msg_id);
+    let ts_ms = now_ms();
+    // A signature covers the topic, so each topic gets its own signed body;
+    // otherwise one encoding serves them all.
+    let encoded = match id_key {
+        Some(sk) => topics
+            .iter()
+            .map(|&topic| {
+                let header = identity::Header {
+                    topic,
+                    ts_ms,
+                    msg_id,
+                };
+                let signed = identity::seal(Some(sk), &header, body);
+                let mut e = encode_body(&signed, opts, key.is_some(), &msg_id)?;
+                e.flags |= FLAG_SIGNED;
+                Ok(e)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        None => vec![encode_body(body, opts, key.is_some(), &msg_id)?],
+    };
+    let encoded_for = |i: usize| &encoded[i.min(encoded.len() - 1)];
+    let rounds = encoded[0].rounds;
+    let transport_id = transport.identity().await;
+    let mut transport = transport;
+    let mut advertiser = transport.advertiser().await?;
+    relay::Originated::global().mark(msg_id);
+    let topic_desc = match topics {
+        [t] => t.to_string(),
+        ts => format!("{} topics", ts.len()),
+    };
+    println!(
+        "TX topic={} ttl={} chunks={} repeats={} msg_id={:02x?}",
+        topic_desc,
+        ttl,
+        encoded[0].chunks.len(),
+        repeats,
+        msg_id
+    );
+    if let Some(choice) = encoded[0].choice {
+        println!("{choice}");
+    }
+
+    let mask = match key {
+        Some(k) if private_topic => k.topic_mask(&msg_id),
+        _ => 0,
+    };
+    // One session key for the whole message, even across an epoch boundary.
+    let (key, eph_pub) = match key {
+        Some(k) if per_message_key => {
+            let (eph_pub, session) = k.tx_handshake();
+            (Some(session), Some(eph_pub))
+        }
+        k => (k.map(|k| k.tx_key()), None),
+    };
+    let mut frames = Vec::with_capacity((encoded[0].chunks.len() + 1) * topics.len());
+    // The handshake frame goes first so receivers usually have the key
+    // before the chunks arrive; repeats resend it with everything else.
+    if let Some(eph_pub) = eph_pub {
+        for (i, &topic) in topics.iter().enumerate() {
+            let tot = encoded_for(i).chunks[0].1;
+            frames.push(pack_frame(&Frame {
+                topic: topic ^ mask,
+                private: private_topic,
+                plain: false,
+                control: None,
+                ttl,
+                ttl0: ttl,
+                flags: FLAG_HANDSHAKE,
+                msg_id,
+                seq: 0,
+                tot,
+                ts_ms,
+                payload: eph_pub.to_vec(),
+            }));
+        }
+    }
+    // Kept so `tx --serve` can answer NACKs for this message.
+    let mut sent: HashMap<u8, Vec<Frame>> = HashMap::new();
+    let most_chunks = encoded.iter().map(|e| e.chunks.len()).max().unwrap_or(0);
+    for n in 0..most_chunks {
+        for (i, &topic) in topics.iter().enumerate() {
+            let Encoded { chunks, flags, .. } = encoded_for(i);
+            let Some((seq, tot, payload)) = chunks.get(n).cloned() else {
+                continue;
+            };
+            let mut f = Frame {
+                topic,
+                private: private_topic,
+                plain: key.is_none(),
+                control: None,
+                ttl,
+                ttl0: ttl,
+                flags: if flags & FLAG_RS != 0 && seq >= tot {
+                    flags | FLAG_PARITY
+                } else {
+                    *flags
+                },
+                msg_id,
+                seq,
+                tot,
+                ts_ms,
+                payload: payload.clone(),
+            };
+            // Nonce and AAD both include the topic, so seal per topic.
+            if let Some(ref k) = key {
+                f.payload = crypto::encrypt(k, &f.nonce(), &f.aad(), &payload)
+                    .context("encrypt payload")?;
+            }
+            f.topic ^= mask;
+            frames.push(pack_frame(&f));
+            sent.entry(seq).or_default().push(f);
+        }
+    }
+    retransmit::Cache::global().insert(msg_id, sent);
+
+    let mut rl = match burst {
+        Some(burst) => RateLimiter::with_burst(rate, burst),
+        None => RateLimiter::new(rate),
+    };
+    let mut congestion = if adaptive {
+        let monitor = congestion::Monitor::start(&transport);
+        Some((monitor, congestion::Aimd::new(rate)))
+    } else {
+        None
+    };
+    // With --repeat-secs the whole schedule runs again until time is up,
+    // so a receiver that starts listening mid-stream still gets every
+    // chunk; its `seen` cache drops the copies it already has.
+    let deadline = repeat_for.map(|d| tokio::time::Instant::now() + d);
+    loop {
+        for step in advert_steps(&frames, rounds, rotate_in_place) {
+            if let Some((monitor, aimd)) = &mut congestion
+                && let Some(heard) = monitor.sample()
+            {
+                rl.set_rate(aimd.update(heard));
+            }
+            rl.acquire().await;
+            if step.restart {
+                if step.stop_first {
+                    advertiser.stop().await?;
+                }
+                let md = frames[step.frame].clone();
+                if let Err(e) = advertiser.start(md.clone()).await {
+                    // The dongle may have been unplugged; wait for it and retry once.
+                    eprintln!("adv err: {e}; rebinding adapter");
+                    transport = T::rebind(&transport_id).await?;
+                    advertiser = transport.advertiser().await?;
+                    advertiser.start(md).await?;
+                }
+            }
+            sleep(Duration::from_millis(dwell_ms)).await;
+            if step.stop_after {
+                advertiser.stop().await?;
+                sleep(Duration::from_millis(gap_ms)).await;
+            }
+        }
+        if deadline.is_none_or(|d| tokio::time::Instant::now() >= d) {
+            break;
+        }
+    }
+    println!("Done.");
+    Ok(msg_id)
+}
+
+/// A message body cut up for the air: its chunks (or droplets or shards),
+/// how many rounds to send them, and the data frame flags.
+struct Encoded {
+    chunks: Vec<(u8, u8, Vec<u8>)>,
+    rounds: u32,
+    flags: u8,
+    /// How `--compress` decided, to report.
+    choice: Option<EncodingChoice>,
+}
+
+/// Compress `body` if that pays and split or FEC-code it as `opts` asks.
+fn encode_body(
+    body: &[u8],
+    opts: &TxOpts,
+    encrypted: bool,
+    msg_id: &MsgId,
+) -> anyhow::Result<Encoded> {
+    let (chunk_size, repeats) = (opts.chunk_size, opts.repeats);
+    let (body, choice) = pick_encoding(body, opts, encrypted);
+    let compressed = choice.is_some_and(|c| c.compressed);
+    // Checked on what is actually chunked: a body too long raw may fit once
+    // compressed, and DEFLATE can grow incompressible input past the limit.
+    let fits = validate_chunk_size(chunk_size, encrypted, body.len());
+    if compressed {
+        fits.context("after compression")?;
+    } else {
+        fits?;
+    }
+    let mut flags = 0;
+    if compressed {
+        flags |= FLAG_COMPRESSED;
+    }
+    if opts.presence {
+        flags |= FLAG_PRESENCE;
+    }
+    // A fountain message goes out once as fresh droplets, one source
+    // block's worth per repeat on top of the systematic pass, instead of
+    // repeating the same chunks.
+    let (chunks, rounds) = match opts.fec {
+        fec::Fec::None => (chunk_message(&body, chunk_size)?, repeats),
+        fec::Fec::Fountain => {
+            flags |= FLAG_FOUNTAIN;
+            let k = fec::block_count(body.len(), chunk_size);
+            let count = k * (repeats as usize + 1);
+            (fec::fountain_encode(&body, chunk_size, msg_id, count)?, 1)
+        }
+        fec::Fec::Rs { k, n } => {
+            flags |= FLAG_RS;
+            (fec::rs_encode(&body, chunk_size, k, n)?, repeats)
+        }
+    };
+    Ok(Encoded {
+        chunks,
+        rounds,
+        flags,
+        choice,
+    })
+}
+
+/// Which form of a body `--compress` picked, and what each would take.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct EncodingChoice {
+    compressed: bool,
+    raw_chunks: usize,
+    deflated_chunks: usize,
+}
+
+impl std::fmt::Display for EncodingChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (raw, deflated) = (self.raw_chunks, self.deflated_chunks);
+        match self.compressed {
+            true if deflated < raw => write!(f, "encoding=deflate chunks={deflated} (raw {raw}, saves {})", raw - deflated),
+            true => write!(f, "encoding=deflate chunks={deflated} (raw {raw})"),
+            false => write!(f, "encoding=raw chunks={raw} (deflate {deflated})"),
+        }
+    }
+}
+
+/// The bytes to chunk for `body`. With `--compress auto` that is whichever
+/// of the body and its DEFLATE stream takes fewer chunks, then fewer bytes
+/// on air; `always` and `never` decide alone. The choice is `None` when
+/// compression wasn't tried.
+fn pick_encoding(body: &[u8], opts: &TxOpts, encrypted: bool) -> (Vec<u8>, Option<EncodingChoice>) {
+    if opts.compress == compress::Compress::Never {
+        return (body.to_vec(), None);
+    }
+    let (packed, compressed) = compress::deflate(body, compress::Compress::Always);
+    if !compressed {
+        return (body.to_vec(), None);
+    }
+    let raw = estimate_airtime(body.len(), opts, encrypted);
+    let deflated = estimate_airtime(packed.len(), opts, encrypted);
+    let compressed = opts.compress == compress::Compress::Always
+        || (deflated.chunks, deflated.bytes) < (raw.chunks, raw.bytes);
+    let choice = EncodingChoice {
+        compressed,
+        raw_chunks: raw.chunks,
+        deflated_chunks: deflated.chunks,
+    };
+    (if compressed { packed } else { body.to_vec() }, Some(choice))
+}
+
+/// What `tx` would chunk for `body` on `opts.topic`, and how `--compress`
+/// decided. The header is a stand-in: the signature is as long, and
+/// compresses as badly, whatever it covers.
+fn packed_body(
+    body: &[u8],
+    opts: &TxOpts,
+    encrypted: bool,
+    id_key: Option<&crypto::SigningKey>,
+) -> (Vec<u8>, Option<EncodingChoice>) {
+    let header = identity::Header {
+        topic: opts.topic,
+        ts_ms: now_ms(),
+        msg_id: MsgId::default(),
+    };
+    pick_encoding(&identity::seal(id_key, &header, body), opts, encrypted)
+}
+
+/// Receive-side knobs for `rx_loop`.
+pub(crate) struct RxOpts {
+    pub topic_filter: Option<u8>,
+    pub relay: bool,
+    /// Decryption keys, tried in order; empty for plaintext.
+    pub keys: Vec<crypto::Keys>,
+    pub stats: Arc<Stats>,
+    /// Receives a `msg_id` once every chunk of it has been relayed.
+    pub relay_done: Option<UnboundedSender<MsgId>>,
+    /// Route relays through a shared send queue instead of spawning them.
+    pub queue: Option<SendQueue>,
+    /// Reassembly progress, alongside the `on_msg` callback.
+    pub events: Option<UnboundedSender<ReasmEvent>>,
+    /// Only deliver messages tagged with this sub-topic.
+    pub subtopic: Option<String>,
+    /// Replay window; see `MeshNode::with_max_age`.
+    pub max_age: Option<Duration>,
+    /// Log why malformed frames were dropped.
+    pub debug_frames: bool,
+    /// See `MeshNode::with_reasm_timeout`.
+    pub reasm_timeout: Duration,
+    /// See `MeshNode::with_max_reasm`.
+    pub max_reasm: usize,
+    /// See `MeshNode::with_nack_after`.
+    pub nack_after: Option<Duration>,
+    /// See `MeshNode::with_seen_cache`.
+    pub seen_cache: usize,
+    /// See `MeshNode::with_seen_ttl`.
+    pub seen_ttl: Duration,
+    /// Relays and acks on air at once; more are dropped, not queued.
+    pub max_relays: usize,
+    /// Topics to forward when `relay` is on.
+    pub relay_topics: relay::RelayTopics,
+    /// See `relay::Pending`.
+    pub suppress_relay: bool,
+    /// Failed recovery attempts in a row before `rx_loop` gives up.
+    pub max_reconnect: u32,
+    /// Roster changes from presence beacons; see `presence::Roster`.
+    pub roster: Option<UnboundedSender<presence::RosterEvent>>,
+    /// Typing notices, for a chat UI.
+    pub typing: Option<UnboundedSender<mesh::Typing>>,
+    /// See `MeshNode::with_ack_as`.
+    pub ack_as: Option<[u8; mesh::ACKER_LEN]>,
+    /// Acknowledgements heard, for a chat UI.
+    pub acks: Option<UnboundedSender<mesh::Ack>>,
+    /// Senders whose messages are dropped rather than delivered.
+    pub mutes: mute::MuteList,
+    /// Seed for relay backoff and the msg_ids of NACKs and acks, to replay a
+    /// run exactly; `None` draws one from the OS.
+    pub rng_seed: Option<u64>,
+}
+
+/// Receive until the adapter is gone for good. `on_msg` gets each completed
+/// message; `on_incomplete` gets each partial message evicted by the
+/// reassembly timeout or `max_reasm`.
+#[tracing::instrument(name = "rx", skip_all, fields(topic = ?opts.topic_filter))]
+pub(crate) async fn rx_loop<T, F, G>(
+    transport: T,
+    opts: RxOpts,
+    mut on_msg: F,
+    mut on_incomplete: G,
+) -> anyhow::Result<()>
+where
+    T: Transport,
+    F: FnMut(Delivered, &T::Source) + Send + 'static,
+    G: FnMut(Incomplete) + Send + 'static,
+{
+    let RxOpts {
+        topic_filter,
+        relay,
+        keys,
+        stats,
+        relay_done,
+        queue,
+        events,
+        subtopic,
+        max_age,
+        debug_frames,
+        reasm_timeout,
+        max_reasm,
+        nack_after,
+        seen_cache,
+        seen_ttl,
+        max_relays,
+        relay_topics,
+        suppress_relay,
+        max_reconnect,
+        roster,
+        typing,
+        ack_as,
+        acks,
+        mutes,
+        rng_seed,
+    } = opts;
+    let mut roster = presence::Roster::new(presence::PRESENCE_TIMEOUT, roster);
+    let mut duplicates = identity::Duplicates::default();
+    let mut rng = rng_seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+    let mut node = MeshNode::new(
+        topic_filter,
+        relay,
+        keys,
+        stats.clone(),
+        events,
+        relay::Originated::global(),
+    )
+    .with_subtopic_filter(subtopic)
+    .with_max_age(max_age)
+    .with_debug_frames(debug_frames)
+    .with_reasm_timeout(reasm_timeout)
+    .with_max_reasm(max_reasm)
+    .with_nack_after(nack_after)
+    .with_seen_cache(seen_cache)
+    .with_seen_ttl(seen_ttl)
+    .with_ack_as(ack_as)
+    // Seeded from `rng`, so one seed replays the node's frames and the relay
+    // backoffs alike.
+    .with_rng(StdRng::seed_from_u64(rng.r#gen()));
+    // Only tracked for `--relay-events`; nothing else reads it.
+    let relays = relay_done.is_some().then(RelayTracker::default);
+    let relay_slots = Arc::new(tokio::sync::Semaphore::new(max_relays));
+    let pending = suppress_relay.then(relay::Pending::default);
+    let transport_id = transport.identity().await;
+    let mut transport = transport;
+
+    // On stderr, so `rx --binary` can own stdout.
+    eprintln!(
+        "Listening... {}",
+        topic_filter
+            .map(|t| format!("(topic={})", t))
+            .unwrap_or_default()
+    );
+
+    // Scan errors and stream ends are first treated as transient (restart the
+    // scan on the same adapter); after a few in a row the adapter is assumed
+    // gone and rebound. Retries back off exponentially, and only
+    // `max_reconnect` failures in a row are fatal. `seen` and `reasm` live in
+    // `node`, so a message half received before a dropout can still complete
+    // after it.
+    let mut failures = 0u32;
+    loop {
+        if failures > 0 {
+            if failures > max_reconnect {
+                anyhow::bail!("adapter did not recover after {max_reconnect} attempts");
+            }
+            sleep(retry_delay(failures)).await;
+        }
+        if failures > TRANSIENT_RETRIES {
+            tracing::warn!(attempt = failures, "adapter unresponsive; rebinding");
+            match T::rebind(&transport_id).await {
+                Ok(t) => transport = t,
+                Err(e) => {
+                    failures += 1;
+                    tracing::warn!(attempt = failures, error = %e, "rebind failed");
+                    continue;
+                }
+            }
+        }
+        let mut events = match transport.scan().await {
+            Ok(ev) => ev,
+            Err(e) => {
+                failures += 1;
+                tracing::warn!(attempt = failures, error = %e, "scan failed");
+                continue;
+            }
+        };
+        let mut healthy = false;
+        let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            let evt = tokio::select! {
+                evt = events.next() => evt,
+                _ = sweep.tick() => {
+                    let now = std::time::Instant::now();
+                    stats.sample_density(now);
+                    roster.expire(now);
+                    let swept = node.sweep(now);
+                    swept.incomplete.into_iter().for_each(&mut on_incomplete);
+                    send_nacks(&transport, swept.nacks);
+                    continue;
+                }
+            };
+            let Some((source, md)) = evt else {
+                break;
+            };
+            healthy = true;
+            if let Some(p) = &pending {
+                p.overheard(&md, &source);
+            }
+            let handled = node.handle(&md);
+            handled.incomplete.into_iter().for_each(&mut on_incomplete);
+            send_nacks(&transport, handled.nacks);
+            // Muting a sender is private: a muted message is received and
+            // relayed like any other, but we don't acknowledge it.
+            let muted = handled.delivered.as_ref().is_some_and(|d| mutes.is_muted(d));
+            if let Some(f) = handled.ack.filter(|_| !muted) {
+                // Acks share the relay slots, so a burst of messages can't
+                // become a burst of acks on the radio.
+                match relay_slots.clone().try_acquire_owned() {
+                    Ok(slot) => {
+                        let transport = transport.clone();
+                        tokio::spawn(async move {
+                            do_relay(transport, f).await;
+                            drop(slot);
+                        });
+                    }
+                    Err(_) => relay_dropped(&stats, &f, max_relays, debug_frames),
+                }
+            }
+            if let Some((tx, t)) = typing.as_ref().zip(handled.typing) {
+                let _ = tx.send(t);
+            }
+            if let Some((tx, a)) = acks.as_ref().zip(handled.heard_ack) {
+                let _ = tx.send(a);
+            }
+            if let Some(d) = &handled.delivered
+                && let Some(signer) = &d.signer
+                && let Some(clash) = duplicates.heard(
+                    signer,
+                    d.ts_ms,
+                    (d.hops == 0).then_some(&source),
+                    std::time::Instant::now(),
+                )
+            {
+                Stats::inc(&stats.duplicate_identities);
+                let why = match clash {
+                    identity::Clash::Sources(a, b) => format!("heard directly from both {a:?} and {b:?}"),
+                    identity::Clash::Clock(d) => format!("sent a message {}s older than its last", d.as_secs()),
+                };
+                tracing::warn!(
+                    identity = %identity::fingerprint(signer),
+                    why,
+                    "identity looks shared by more than one node; their messages can't be told apart"
+                );
+            }
+            match handled.delivered {
+                // Relayed all the same, below.
+                Some(d) if mutes.is_muted(&d) => {}
+                Some(d) if d.presence => roster.heard(&d, std::time::Instant::now()),
+                Some(d) => on_msg(d, &source),
+                None => {}
+            }
+            if let Some(f) = handled.relay.filter(|f| relay_topics.allows(f)) {
+                Stats::inc(&stats.relay_queue);
+                let backoff = relay::backoff(stats.density.frames_per_sec(), &mut rng);
+                if let Some(p) = &pending {
+                    p.start(&f, &source);
+                }
+                let relay_fut = do_relay(transport.clone(), f.clone());
+                let relay_slots = relay_slots.clone();
+                let relays = relays.clone();
+                let relay_done = relay_done.clone();
+                let pending = pending.clone();
+                let stats = stats.clone();
+                let job = async move {
+                    // Checked as late as possible, right before the
+                    // radio is used. A suppressed chunk still counts
+                    // as done: a neighbour has carried it on.
+                    let finished = if pending.as_ref().is_none_or(|p| p.finish(&f)) {
+                        // A slot is held only while on air, not through
+                        // the backoff, so a flood of frames cannot pile up
+                        // on the radio.
+                        match relay_slots.try_acquire_owned() {
+                            Ok(_slot) => {
+                                relay_fut.await;
+                                true
+                            }
+                            Err(_) => {
+                                relay_dropped(&stats, &f, max_relays, debug_frames);
+                                false
+                            }
+                        }
+                    } else {
+                        Stats::inc(&stats.relays_suppressed);
+                        let msg_id = hex::encode(f.msg_id);
+                        if debug_frames {
+                            tracing::warn!(
+                                msg_id,
+                                seq = f.seq,
+                                tot = f.tot,
+                                "relay suppressed: already relayed nearby"
+                            );
+                        } else {
+                            tracing::debug!(
+                                msg_id,
+                                seq = f.seq,
+                                tot = f.tot,
+                                "relay suppressed: already relayed nearby"
+                            );
+                        }
+                        true
+                    };
+                    Stats::dec(&stats.relay_queue);
+                    if finished
+                        && let Some((relays, done)) = relays.zip(relay_done)
+                        && relays.finish(&f)
+                    {
+                        let _ = done.send(f.msg_id);
+                    }
+                };
+                let queue = queue.clone();
+                tokio::spawn(async move {
+                    // Back off before queueing so a queued relay never
+                    // holds the radio while it waits.
+                    sleep(backoff).await;
+                    match queue {
+                        Some(q) => q.push(Priority::Relay, job),
+                        None => job.await,
+                    }
+                });
+            }
+        }
+        if healthy {
+            failures = 0;
+        }
+        failures += 1;
+        tracing::warn!(attempt = failures, "event stream ended; restarting scan");
+    }
+}
+
+/// Wait before recovery attempt `failures`: `RETRY_DELAY`, doubling per
+/// attempt up to 64 times that.
+fn retry_delay(failures: u32) -> Duration {
+    RETRY_DELAY * 2u32.pow(failures.saturating_sub(1).min(6))
+}
+
+/// Advertise our own NACK frames; see `retransmit`.
+fn send_nacks<T: Transport>(transport: &T, nacks: Vec<Frame>) {
+    for f in nacks {
+        tokio::spawn(do_relay(transport.clone(), f));
+    }
+}
+
+async fn scan_events(
+    adapter: &btleplug::platform::Adapter,
+) -> anyhow::Result<std::pin::Pin<Box<dyn futures::Stream<Item = CentralEvent> + Send>>> {
+    adapter.start_scan(ScanFilter::default()).await?;
+    Ok(Central::events(adapter).await?)
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum RxFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Where `rx` writes delivered messages.
+enum RxOutput {
+    /// One annotated line per message on stdout.
+    Text,
+    /// One `JsonMessage` per line on stdout; status lines go to stderr.
+    Json,
+    /// Only the raw message bytes, to stdout or appended to a file. Status
+    /// lines move to stderr so they never mix with the data.
+    Binary(Option<std::path::PathBuf>),
+}
+
+/// A delivered message as printed by `rx --format json`.
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    /// When it was delivered, in Unix milliseconds.
+    ts: u64,
+    topic: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtopic: Option<&'a str>,
+    /// The sender's `--nick`, if it sent one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nick: Option<&'a str>,
+    msg_id: String,
+    hops: u8,
+    /// Whether a signature over the body verified.
+    verified: bool,
+    /// The body if it is valid UTF-8...
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+    /// ...otherwise the body in standard base64.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base64: Option<String>,
+}
+
+impl<'a> JsonMessage<'a> {
+    fn new(d: &'a Delivered) -> Self {
+        use base64::Engine;
+        let text = std::str::from_utf8(&d.bytes).ok();
+        Self {
+            ts: now_ms(),
+            topic: d.topic,
+            subtopic: d.subtopic.as_deref(),
+            nick: d.nick.as_deref(),
+            msg_id: hex::encode(d.msg_id),
+            hops: d.hops,
+            verified: d.signer.is_some(),
+            text,
+            base64: text
+                .is_none()
+                .then(|| base64::engine::general_purpose::STANDARD.encode(&d.bytes)),
+        }
+    }
+}
+
+async fn rx<T: Transport>(
+    transport: T,
+    mut opts: RxOpts,
+    trust: identity::Trust,
+    relay_events: bool,
+    show_source: bool,
+    output: RxOutput,
+) -> anyhow::Result<()> {
+    use std::io::{IsTerminal, Write};
+
+    let json = matches!(output, RxOutput::Json);
+    let mut sink: Option<Box<dyn Write + Send>> = match output {
+        RxOutput::Text | RxOutput::Json => None,
+        RxOutput::Binary(None) => Some(Box::new(std::io::stdout())),
+        RxOutput::Binary(Some(path)) => Some(Box::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("open {}", path.display()))?,
+        )),
+    };
+    // Keep stdout for the messages alone when something will parse it.
+    let binary = sink.is_some() || json;
+    // Nicknames are coloured only for a person reading a terminal.
+    let color = std::io::stdout().is_terminal();
+    // Only worth showing which key opened a message when there is a choice.
+    let show_key = opts.keys.len() > 1;
+    opts.relay_done = relay_events.then(|| {
+        let (done_tx, mut done_rx) = tokio::sync::mpsc::unbounded_channel::<MsgId>();
+        tokio::spawn(async move {
+            while let Some(id) = done_rx.recv().await {
+                let line = format!("[relayed #{}]", hex::encode(id));
+                if binary {
+                    eprintln!("{line}");
+                } else {
+                    println!("{line}");
+                }
+            }
+        });
+        done_tx
+    });
+    let roster_trust = trust.clone();
+    let (roster_tx, mut roster_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(ev) = roster_rx.recv().await {
+            let line = match ev {
+                presence::RosterEvent::Joined(p) => {
+                    let from = roster_trust
+                        .label(p.key.as_ref())
+                        .map(|l| format!(" {l}"))
+                        .unwrap_or_default();
+                    format!("[presence] {}{} is here{}", p.nick, from, hops_label(p.hops))
+                }
+                presence::RosterEvent::Left(p) => format!("[presence] {} left", p.nick),
+            };
+            if binary {
+                eprintln!("{line}");
+            } else {
+                println!("{line}");
+            }
+        }
+    });
+    opts.roster = Some(roster_tx);
+    // Shows which chunks never arrived, to help tune dwell time and rate.
+    let on_incomplete = move |i: Incomplete| {
+        let id_hex = hex::encode(i.msg_id);
+        let got = i.tot as usize - i.missing.len();
+        let line = format!("[incomplete #{}] got {}/{}, missing {:?}", &id_hex[..8], got, i.tot, i.missing);
+        if binary {
+            eprintln!("{line}");
+        } else {
+            println!("{line}");
+        }
+    };
+    rx_loop(transport, opts, move |d, source| {
+        if let Some(w) = &mut sink {
+            // Flushed per message so a reader sees each one as it completes.
+            if let Err(e) = w.write_all(&d.bytes).and_then(|()| w.flush()) {
+                eprintln!("write err: {e}");
+            }
+            return;
+        }
+        if json {
+            match serde_json::to_string(&JsonMessage::new(&d)) {
+                Ok(line) => println!("{line}"),
+                Err(e) => eprintln!("json err: {e}"),
+            }
+            return;
+        }
+        let id_hex = hex::encode(d.msg_id);
+        let sub = d.subtopic.as_deref().map(|s| format!(" #{s}")).unwrap_or_default();
+        let who = match &d.nick {
+            Some(n) if color => format!("\x1b[38;5;{}m{n}\x1b[0m", presence::nick_color(n)),
+            Some(n) => n.clone(),
+            None => format!("#{}", &id_hex[..8]),
+        };
+        let hops = hops_label(d.hops);
+        let mut from = trust
+            .label(d.signer.as_ref())
+            .map(|l| format!(" {l}"))
+            .unwrap_or_default();
+        if let Some(i) = d.key_index.filter(|_| show_key) {
+            from.push_str(&format!(" [key {i}]"));
+        }
+        if show_source {
+            println!("[topic {}{}] {}{}{} ({:?}): {}", d.topic, sub, who, from, hops, source, d.text());
+        } else {
+            println!("[topic {}{}] {}{}{}: {}", d.topic, sub, who, from, hops, d.text());
+        }
+    }, on_incomplete)
+    .await
+}
+
+/// ` (n hops)` for display after a sender, or nothing for a direct one.
+fn hops_label(hops: u8) -> String {
+    match hops {
+        0 => String::new(),
+        1 => " (1 hop)".to_string(),
+        n => format!(" ({n} hops)"),
+    }
+}
+
+fn spawn_progress_printer() -> UnboundedSender<ReasmEvent> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(ev) = rx.recv().await {
+            match ev {
+                ReasmEvent::Started { msg_id, topic, tot } => {
+                    eprintln!("[#{}] started on topic {} ({} chunks)", hex::encode(msg_id), topic, tot)
+                }
+                ReasmEvent::Chunk {
+                    msg_id,
+                    seq,
+                    have,
+                    tot,
+                } => eprintln!("[#{}] chunk {} ({}/{})", hex::encode(msg_id), seq, have, tot),
+                ReasmEvent::Completed { msg_id, topic, len } => {
+                    eprintln!("[#{}] completed on topic {} ({} bytes)", hex::encode(msg_id), topic, len)
+                }
+                ReasmEvent::Expired { msg_id, have, tot } => {
+                    eprintln!("[#{}] expired with {}/{} chunks", hex::encode(msg_id), have, tot)
+                }
+            }
+        }
+    });
+    tx
+}
+
+/// Count and log a relay or ack not sent because all `max_relays` slots
+/// were on air.
+fn relay_dropped(stats: &Stats, f: &Frame, max_relays: usize, debug_frames: bool) {
+    Stats::inc(&stats.relays_dropped);
+    let msg_id = hex::encode(f.msg_id);
+    if debug_frames {
+        tracing::warn!(
+            max_relays,
+            msg_id,
+            seq = f.seq,
+            tot = f.tot,
+            "relay dropped: too many in flight"
+        );
+    } else {
+        tracing::debug!(
+            max_relays,
+            msg_id,
+            seq = f.seq,
+            tot = f.tot,
+            "relay dropped: too many in flight"
+        );
+    }
+}
+
+/// Advertise one frame for `RELAY_DWELL`.
+#[tracing::instrument(name = "relay", skip_all, fields(msg_id = %hex::encode(f.msg_id), seq = f.seq))]
+async fn do_relay<T: Transport>(transport: T, f: Frame) {
+    if let Err(e) = transport.advertise(pack_frame(&f), RELAY_DWELL).await {
+        tracing::warn!(error = %e, "relay advertise failed");
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use proptest::prelude::{Strategy, any, prop, prop_assert_eq, proptest};
+    use crate::transport::LoopbackTransport;
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio::time::timeout;
+
+    /// Fast enough for tests: no dwell to speak of and no rate limit.
+    pub(crate) fn tx_opts(topic: u8) -> TxOpts {
+        TxOpts {
+            topic,
+            ttl: 3,
+            dwell_ms: 1,
+            gap_ms: 0,
+            repeats: 1,
+            rate: 10_000.0,
+            burst: None,
+            adaptive: false,
+            rotate_in_place: false,
+            repeat_for: None,
+            chunk_size: MAX_PAYLOAD,
+            per_message_key: false,
+            private_topic: false,
+            compress: compress::Compress::Never,
+            fec: fec::Fec::None,
+            presence: false,
+        }
+    }
+
+    /// The CLI's defaults, receiving `topic` without relaying.
+    pub(crate) fn rx_opts(topic: u8) -> RxOpts {
+        RxOpts {
+            topic_filter: Some(topic),
+            relay: false,
+            keys: Vec::new(),
+            stats: Arc::new(Stats::default()),
+            relay_done: None,
+            queue: None,
+            events: None,
+            subtopic: None,
+            max_age: None,
+            debug_frames: false,
+            reasm_timeout: mesh::REASM_TIMEOUT,
+            max_reasm: mesh::MAX_REASM,
+            nack_after: None,
+            seen_cache: mesh::SEEN_CAP,
+            seen_ttl: mesh::SEEN_TTL,
+            max_relays: relay::MAX_RELAYS,
+            relay_topics: relay::RelayTopics::All,
+            suppress_relay: false,
+            max_reconnect: MAX_RECONNECT,
+            roster: None,
+            typing: None,
+            ack_as: None,
+            acks: None,
+            mutes: mute::MuteList::default(),
+            rng_seed: None,
+        }
+    }
+
+    /// Run `rx_loop` on `transport`, sending each delivered message and who
+    /// it came from down the returned channel. The loop is already scanning
+    /// when this returns.
+    pub(crate) async fn listen<T: Transport>(
+        transport: T,
+        opts: RxOpts,
+    ) -> (
+        tokio::sync::mpsc::UnboundedReceiver<(Delivered, T::Source)>,
+        tokio::task::JoinHandle<anyhow::Result<()>>,
+    ) {
+        let (tx, rx) = unbounded_channel();
+        let task = tokio::spawn(rx_loop(
+            transport,
+            opts,
+            move |d, source: &T::Source| {
+                let _ = tx.send((d, source.clone()));
+            },
+            |_| {},
+        ));
+        // Let it get as far as subscribing before anything is sent.
+        sleep(Duration::from_millis(20)).await;
+        (rx, task)
+    }
+
+    pub(crate) async fn next<T>(rx: &mut tokio::sync::mpsc::UnboundedReceiver<T>) -> T {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("timed out")
+            .expect("channel closed")
+    }
+
+    /// Every chunk of `body` as `tx` packs it without a key, sent now.
+    pub(crate) fn plain_frames(topic: u8, msg_id: MsgId, body: &[u8]) -> Vec<Vec<u8>> {
+        let ts_ms = now_ms();
+        chunk_message(body, MAX_PAYLOAD)
+            .unwrap()
+            .into_iter()
+            .map(|(seq, tot, payload)| {
+                pack_frame(&Frame {
+                    topic,
+                    private: false,
+                    plain: true,
+                    control: None,
+                    ttl: 3,
+                    ttl0: 3,
+                    flags: 0,
+                    msg_id,
+                    seq,
+                    tot,
+                    ts_ms,
+                    payload,
+                })
+            })
+            .collect()
+    }
+
+    /// Any frame `tx` or a relay could build: a private frame is never
+    /// plain, a control frame is never private and has no flags, `tot` is
+    /// never 0, and `seq < tot` unless the flags allow more.
+    fn any_frame() -> impl Strategy<Value = Frame> {
+        (
+            any::<(u8, bool, bool, u8, u8, u8, MsgId)>(),
+            (any::<(u8, u8, u64)>(), proptest::option::of(any::<u8>())),
+            proptest::collection::vec(any::<u8>(), 0..=MAX_MD_LEN - HEADER_LEN),
+        )
+            .prop_map(
+                |((topic, private, plain, ttl, ttl0, flags, msg_id), ((seq, tot, ts_ms), control), payload)| {
+                    let control = control.and_then(Control::from_byte);
+                    let (private, flags) = match control {
+                        Some(_) => (false, 0),
+                        None => (private, flags),
+                    };
+                    let tot = tot.max(1);
+                    let seq = if flags & (FLAG_FOUNTAIN | FLAG_PARITY) == 0 {
+                        seq % tot
+                    } else {
+                        seq
+                    };
+                    Frame {
+                        topic,
+                        private,
+                        plain: plain && !private,
+                        control,
+                        ttl,
+                        ttl0,
+                        flags,
+                        msg_id,
+                        seq,
+                        tot,
+                        ts_ms,
+                        payload,
+                    }
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn frames_round_trip(f in any_frame()) {
+            prop_assert_eq!(unpack_frame(&pack_frame(&f)).unwrap(), f);
+        }
+
+        #[test]
+        fn unpack_frame_survives_any_bytes(md in proptest::collection::vec(any::<u8>(), 0..64)) {
+            fuzz_unpack_frame(&md);
+        }
+
+        /// Mostly well-formed input, which reaches further into the parser
+        /// than random bytes do.
+        #[test]
+        fn unpack_frame_survives_damaged_frames(
+            f in any_frame(),
+            at in any::<prop::sample::Index>(),
+            byte in any::<u8>(),
+            cut in any::<prop::sample::Index>(),
+        ) {
+            let mut md = pack_frame(&f);
+            let i = at.index(md.len());
+            md[i] = byte;
+            md.truncate(cut.index(md.len() + 1));
+            fuzz_unpack_frame(&md);
+        }
+    }
+
+    /// Key the fixtures in tests/fixtures are sealed under.
+    const GOLDEN_KEY: [u8; 32] = [0x42; 32];
+
+    /// `hi` as chunk 0 of 1 on topic 7, ttl 3, msg_id `deadbeefcafe`, sent at
+    /// 1700000000000 ms: the frame each golden fixture holds, as `tx` would
+    /// build it.
+    fn golden_frame(
+        keys: &crypto::Keys,
+        private: bool,
+        plain: bool,
+        control: Option<Control>,
+    ) -> Frame {
+        let mut f = Frame {
+            topic: 7,
+            private,
+            plain,
+            control,
+            ttl: 3,
+            ttl0: 3,
+            flags: 0,
+            msg_id: [0xde, 0xad, 0xbe, 0xef, 0xca, 0xfe],
+            seq: 0,
+            tot: 1,
+            ts_ms: 1_700_000_000_000,
+            payload: b"hi".to_vec(),
+        };
+        if !plain {
+            f.payload = crypto::encrypt(&keys.tx_key(), &f.nonce(), &f.aad(), b"hi").unwrap();
+        }
+        if private {
+            f.topic ^= keys.topic_mask(&f.msg_id);
+        }
+        f
+    }
+
+    #[test]
+    fn golden_frames_pack_and_unpack() {
+        let keys = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY), None);
+        let typing = Some(Control::Typing);
+        for (fixture, version, private, plain, control) in [
+            (include_str!("../tests/fixtures/frame_v9.hex"), VER, false, false, None),
+            (include_str!("../tests/fixtures/frame_v10.hex"), VER_PRIVATE, true, false, None),
+            (include_str!("../tests/fixtures/frame_v11.hex"), VER_PLAIN, false, true, None),
+            (include_str!("../tests/fixtures/frame_v12.hex"), VER_CONTROL, false, false, typing),
+            (include_str!("../tests/fixtures/frame_v13.hex"), VER_CONTROL_PLAIN, false, true, typing),
+        ] {
+            let md = hex::decode(fixture.trim()).unwrap();
+            assert_eq!(md[2], version);
+            let f = golden_frame(&keys, private, plain, control);
+            assert_eq!(hex::encode(pack_frame(&f)), fixture.trim(), "packing v{version}");
+            assert_eq!(unpack_frame(&md).unwrap(), f, "unpacking v{version}");
+            // The company id is optional on receive.
+            assert_eq!(unpack_frame(&md[2..]).unwrap(), f, "unpacking bare v{version}");
+
+            let mut real = f.clone();
+            if private {
+                real.topic ^= keys.topic_mask(&f.msg_id);
+                assert_eq!(real.topic, 7);
+            }
+            let text = if plain {
+                f.payload
+            } else {
+                keys.decrypt(&real.nonce(), &real.aad(), &f.payload).unwrap()
+            };
+            assert_eq!(text, b"hi", "opening v{version}");
+        }
+    }
+
+    #[test]
+    fn typing_notices_are_control_frames() {
+        let f = mesh::typing_frame(7, "ana", None, &mut rand::thread_rng()).unwrap();
+        let mut md = pack_frame(&f);
+        assert_eq!((md[2], md[6]), (VER_CONTROL_PLAIN, Control::Typing as u8));
+        assert_eq!(unpack_frame(&md).unwrap().kind(), FrameKind::Typing);
+
+        // A kind from a newer build is refused, not guessed at.
+        md[6] = 0x7f;
+        let crc = crc16_ccitt(&md[2..md.len() - CRC_LEN]);
+        let at = md.len() - CRC_LEN;
+        md[at..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(unpack_frame(&md), Err(FrameError::UnknownControl(0x7f)));
+    }
+
+    #[test]
+    fn each_frame_error_names_what_is_wrong() {
+        let keys = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY), None);
+        let plain = pack_frame(&golden_frame(&keys, false, true, None));
+        let sealed = pack_frame(&golden_frame(&keys, false, false, None));
+
+        assert_eq!(unpack_frame(&plain[..HEADER_LEN - 1]), Err(FrameError::TooShort));
+        assert_eq!(unpack_frame(&[]), Err(FrameError::TooShort));
+
+        let mut md = sealed.clone();
+        md[..2].copy_from_slice(&0x1234u16.to_le_bytes());
+        assert_eq!(unpack_frame(&md), Err(FrameError::BadCompanyId(0x1234)));
+
+        let mut md = sealed.clone();
+        md[2] = 0x42;
+        assert_eq!(unpack_frame(&md), Err(FrameError::UnsupportedVersion(0x42)));
+
+        let mut md = sealed.clone();
+        md[14] = 0;
+        assert_eq!(unpack_frame(&md), Err(FrameError::BadSeqTot { seq: 0, tot: 0 }));
+
+        let mut md = plain.clone();
+        let last = md.len() - 1;
+        md[last] ^= 0xff;
+        assert_eq!(unpack_frame(&md), Err(FrameError::CrcMismatch));
+
+        let mut f = golden_frame(&keys, false, true, Some(Control::Typing));
+        f.control = None;
+        f.flags = 0x7f;
+        let mut md = pack_frame(&f);
+        md[2] = VER_CONTROL_PLAIN;
+        let at = md.len() - CRC_LEN;
+        let crc = crc16_ccitt(&md[2..at]);
+        md[at..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(unpack_frame(&md), Err(FrameError::UnknownControl(0x7f)));
+    }
+
+    #[test]
+    fn impossible_seq_and_tot_are_rejected() {
+        let keys = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY), None);
+        for (seq, tot, flags) in [(0, 0, 0), (1, 1, 0), (5, 3, 0), (0, 0, FLAG_FOUNTAIN)] {
+            let mut f = golden_frame(&keys, false, true, None);
+            (f.seq, f.tot, f.flags) = (seq, tot, flags);
+            assert_eq!(
+                unpack_frame(&pack_frame(&f)),
+                Err(FrameError::BadSeqTot { seq, tot }),
+                "chunk {seq} of {tot}, flags {flags:#x}"
+            );
+        }
+
+        // Repair symbols are numbered past `tot` on purpose.
+        for flags in [FLAG_FOUNTAIN, FLAG_PARITY] {
+            let mut f = golden_frame(&keys, false, true, None);
+            (f.seq, f.tot, f.flags) = (5, 3, flags);
+            assert_eq!(unpack_frame(&pack_frame(&f)), Ok(f));
+        }
+    }
+
+    #[test]
+    fn a_flipped_payload_byte_in_a_plain_frame_fails_the_crc() {
+        let keys = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY), None);
+        let md = pack_frame(&golden_frame(&keys, false, true, None));
+        assert_eq!(md[2], VER_PLAIN);
+        let payload = md.len() - CRC_LEN - 2..md.len() - CRC_LEN;
+        for at in payload {
+            for bit in 0..8 {
+                let mut bad = md.clone();
+                bad[at] ^= 1 << bit;
+                assert_eq!(unpack_frame(&bad), Err(FrameError::CrcMismatch), "byte {at} bit {bit}");
+            }
+        }
+    }
+
+    /// Every header byte but `ttl`, which relays decrement, is bound into the
+    /// AEAD: changing any of them must fail to open, and `rx_loop` must drop
+    /// the frame rather than deliver it under the altered header.
+    #[tokio::test]
+    async fn corrupted_header_fails_to_decrypt_and_is_dropped() {
+        const TTL_AT: usize = 4;
+        let keys = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY), None);
+        let good = pack_frame(&golden_frame(&keys, false, false, None));
+        let mut corrupted = Vec::new();
+        for i in (2..HEADER_LEN).filter(|&i| i != TTL_AT) {
+            for bit in 0..8 {
+                let mut md = good.clone();
+                md[i] ^= 1 << bit;
+                let Ok(f) = unpack_frame(&md) else {
+                    continue;
+                };
+                assert!(
+                    keys.decrypt(&f.nonce(), &f.aad(), &f.payload).is_err(),
+                    "byte {i} bit {bit} still opens"
+                );
+                corrupted.push(md);
+            }
+        }
+        assert!(!corrupted.is_empty());
+
+        let air = LoopbackTransport::new();
+        let stats = Arc::new(Stats::default());
+        let opts = RxOpts {
+            topic_filter: None,
+            keys: vec![keys],
+            stats: stats.clone(),
+            ..rx_opts(7)
+        };
+        let (mut got, task) = listen(air.peer(), opts).await;
+        for md in corrupted.into_iter().chain([good]) {
+            air.advertise(md, Duration::ZERO).await.unwrap();
+        }
+        // Frames are handled in order, so the intact one arrives last.
+        let (d, _) = next(&mut got).await;
+        assert_eq!(d.msg_id, [0xde, 0xad, 0xbe, 0xef, 0xca, 0xfe]);
+        assert_eq!((d.topic, d.ts_ms), (7, 1_700_000_000_000));
+        assert!(got.try_recv().is_err());
+        assert!(stats.snapshot().decrypt_failures > 0);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn a_private_topic_is_masked_on_air_and_unmasked_on_receipt() {
+        let air = LoopbackTransport::new();
+        let keys = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY), None);
+        let opts = RxOpts {
+            keys: vec![keys.clone()],
+            ..rx_opts(7)
+        };
+        let (mut got, task) = listen(air.peer(), opts).await;
+        let mut tap = air.peer().scan().await.unwrap();
+        let opts = TxOpts {
+            private_topic: true,
+            ..tx_opts(7)
+        };
+        let mut on_air = Vec::new();
+        for _ in 0..8 {
+            let msg_id = tx(air.clone(), &opts, b"hi", Some(&keys), None).await.unwrap();
+            let (_, md) = tap.next().await.unwrap();
+            assert_eq!(md[2], VER_PRIVATE);
+            assert_eq!(md[3] ^ keys.topic_mask(&msg_id), 7);
+            on_air.push(md[3]);
+            let (d, _) = next(&mut got).await;
+            assert_eq!((d.msg_id, d.topic), (msg_id, 7));
+        }
+        // Each msg_id masks differently, so the byte on air is not the topic.
+        assert!(on_air.iter().any(|&t| t != 7), "{on_air:?}");
+        task.abort();
+    }
+
+    /// Send an encrypted three-chunk message from `air` and record its frames
+    /// off the air, as an attacker in range would.
+    async fn captured_message(air: &LoopbackTransport, keys: &crypto::Keys) -> (MsgId, Vec<Vec<u8>>) {
+        let mut tap = air.peer().scan().await.unwrap();
+        let body = [b'x'; 3 * MAX_PAYLOAD];
+        let msg_id = tx(air.clone(), &tx_opts(7), &body, Some(keys), None).await.unwrap();
+        let mut frames = Vec::new();
+        while let Ok(Some((_, md))) = timeout(Duration::from_millis(50), tap.next()).await {
+            frames.push(md);
+        }
+        assert_eq!(frames.len(), 3);
+        (msg_id, frames)
+    }
+
+    /// Advertise `frames` again from a node of the attacker's, then a fresh
+    /// message, and return what `got` delivers first.
+    async fn replay(
+        air: &LoopbackTransport,
+        keys: &crypto::Keys,
+        frames: &[Vec<u8>],
+        got: &mut tokio::sync::mpsc::UnboundedReceiver<(Delivered, usize)>,
+    ) -> MsgId {
+        let attacker = air.peer();
+        for md in frames {
+            attacker.advertise(md.clone(), Duration::ZERO).await.unwrap();
+        }
+        // Frames are handled in order, so a replay that got through shows up
+        // before this.
+        tx(air.clone(), &tx_opts(7), b"fresh", Some(keys), None).await.unwrap();
+        next(got).await.0.msg_id
+    }
+
+    #[tokio::test]
+    async fn replay_after_the_seen_window_is_rejected() {
+        let keys = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY), None);
+        let air = LoopbackTransport::new();
+        let stats = Arc::new(Stats::default());
+        let opts = RxOpts {
+            keys: vec![keys.clone()],
+            stats: stats.clone(),
+            seen_ttl: Duration::from_millis(20),
+            ..rx_opts(7)
+        };
+        let (mut got, task) = listen(air.peer(), opts).await;
+        let (msg_id, frames) = captured_message(&air, &keys).await;
+        assert_eq!(next(&mut got).await.0.msg_id, msg_id);
+
+        // `seen` has forgotten every chunk; the completed message's
+        // timestamp is what stops them.
+        sleep(Duration::from_millis(50)).await;
+        assert_ne!(replay(&air, &keys, &frames, &mut got).await, msg_id);
+        assert_eq!(stats.snapshot().stale, 3);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn replay_to_a_node_that_missed_the_message_needs_max_age() {
+        let keys = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY), None);
+        let air = LoopbackTransport::new();
+        let (msg_id, frames) = captured_message(&air, &keys).await;
+        sleep(Duration::from_millis(300)).await;
+
+        // Started after the message went out, as after a restart: nothing
+        // remembers it, so without a clock window the replay is delivered.
+        let opts = RxOpts { keys: vec![keys.clone()], ..rx_opts(7) };
+        let (mut got, task) = listen(air.peer(), opts).await;
+        assert_eq!(replay(&air, &keys, &frames, &mut got).await, msg_id);
+        task.abort();
+
+        let stats = Arc::new(Stats::default());
+        let opts = RxOpts {
+            keys: vec![keys.clone()],
+            stats: stats.clone(),
+            max_age: Some(Duration::from_millis(200)),
+            ..rx_opts(7)
+        };
+        let (mut got, task) = listen(air.peer(), opts).await;
+        assert_ne!(replay(&air, &keys, &frames, &mut got).await, msg_id);
+        assert_eq!(stats.snapshot().stale, 3);
+        task.abort();
+    }
+
+    #[test]
+    fn auto_compression_picks_the_fewest_chunks() {
+        let opts = |compress| TxOpts { compress, ..tx_opts(7) };
+        let auto = opts(compress::Compress::Auto);
+        let text = "the same words over and over ".repeat(10);
+        let (packed, choice) = pick_encoding(text.as_bytes(), &auto, true);
+        let choice = choice.unwrap();
+        assert!(choice.compressed);
+        assert_eq!(choice.deflated_chunks, packed.len().div_ceil(MAX_PAYLOAD));
+        assert!(choice.deflated_chunks < choice.raw_chunks);
+        assert_eq!(
+            choice.to_string(),
+            format!(
+                "encoding=deflate chunks={} (raw 15, saves {})",
+                choice.deflated_chunks,
+                15 - choice.deflated_chunks
+            )
+        );
+
+        let mut noise = [0; 3 * MAX_PAYLOAD];
+        StdRng::seed_from_u64(1).fill(&mut noise[..]);
+        let (body, choice) = pick_encoding(&noise, &auto, true);
+        assert_eq!(body, noise);
+        assert_eq!(choice.unwrap().to_string(), "encoding=raw chunks=3 (deflate 4)");
+        // Forced, even though it costs a chunk.
+        let (body, choice) = pick_encoding(&noise, &opts(compress::Compress::Always), true);
+        assert_ne!(body, noise);
+        assert_eq!(choice.unwrap().to_string(), "encoding=deflate chunks=4 (raw 3)");
+        assert_eq!(pick_encoding(&noise, &opts(compress::Compress::Never), true), (noise.to_vec(), None));
+    }
+
+    #[test]
+    fn airtime_estimate_matches_the_frames_tx_builds() {
+        let opts = TxOpts { repeats: 2, ..tx_opts(7) };
+        for len in [0, 1, MAX_PAYLOAD, MAX_PAYLOAD + 1, 100 * MAX_PAYLOAD, 255 * MAX_PAYLOAD] {
+            let body = vec![b'x'; len];
+            for encrypted in [false, true] {
+                let est = estimate_airtime(len, &opts, encrypted);
+                let chunks = encode_body(&body, &opts, encrypted, &[0; MSG_ID_LEN]).unwrap().chunks;
+                let overhead = HEADER_LEN + if encrypted { AEAD_TAG_LEN } else { CRC_LEN };
+                let payload: usize = chunks.iter().map(|c| c.2.len()).sum();
+                assert_eq!(est.chunks, chunks.len(), "{len} bytes");
+                assert_eq!(est.frames, 2 * chunks.len());
+                assert_eq!(est.bytes, 2 * (chunks.len() * overhead + payload));
+                // Each frame takes its 1 ms dwell; the rate allows more.
+                assert_eq!(est.duration, Duration::from_millis(est.frames as u64));
+            }
+        }
+        // Past 255 chunks it still counts them, so callers can refuse.
+        let est = estimate_airtime(255 * MAX_PAYLOAD + 1, &opts, true);
+        assert_eq!(est.chunks, 256);
+        assert!(chunk_message(&vec![0; 255 * MAX_PAYLOAD + 1], MAX_PAYLOAD).is_err());
+
+        // A per-message key adds its handshake frame to every round.
+        let keyed = TxOpts { per_message_key: true, ..opts };
+        let est = estimate_airtime(MAX_PAYLOAD, &keyed, true);
+        assert_eq!((est.chunks, est.frames), (1, 4));
+
+        // Fountain droplets go out once: a systematic pass plus one block's
+        // worth per repeat. Reed-Solomon sends all `n` shards every round.
+        let fountain = TxOpts { fec: fec::Fec::Fountain, ..opts };
+        let k = fec::block_count(10 * MAX_PAYLOAD, MAX_PAYLOAD);
+        let est = estimate_airtime(10 * MAX_PAYLOAD, &fountain, true);
+        assert_eq!((est.chunks, est.frames), (3 * k, 3 * k));
+        let rs = TxOpts { fec: fec::Fec::Rs { k: 4, n: 6 }, ..opts };
+        let est = estimate_airtime(10 * MAX_PAYLOAD, &rs, true);
+        assert_eq!((est.chunks, est.frames), (6, 12));
+    }
+
+    #[test]
+    fn multi_line_stdin_is_chunked_like_an_argument() {
+        // 3 lines of 20 bytes, one ending in CRLF, then the newline `echo`
+        // or an editor adds.
+        let input = "first line, 20 bytes\nsecond line, 20 byte\r\nthird line, 20 bytes\n";
+        let text = read_message(input.as_bytes()).unwrap().unwrap();
+        assert_eq!(text.len(), 63);
+        assert_eq!(text.lines().count(), 3);
+        // Line breaks inside the message are sent; only the last is dropped.
+        let chunks = encode_body(text.as_bytes(), &tx_opts(7), true, &[0; MSG_ID_LEN]).unwrap().chunks;
+        assert_eq!(chunks.len(), 4);
+        let sent: Vec<u8> = chunks.into_iter().flat_map(|(_, _, p)| p).collect();
+        assert_eq!(sent, text.as_bytes());
+
+        // Not UTF-8: kept byte for byte, trailing newline included.
+        let binary = b"\xff\xfe\nraw\n";
+        assert_eq!(read_message(&binary[..]).unwrap(), Err(binary.to_vec()));
+    }
+
+    #[test]
+    fn chunk_message_refuses_more_than_255_chunks() {
+        assert_eq!(chunk_message(&[0; 255 * 4], 4).unwrap().len(), 255);
+        assert!(chunk_message(&[0; 255 * 4 + 1], 4).is_err());
+    }
+
+    /// `advert_steps` as (frame, restart, stop_first, stop_after).
+    fn steps(frames: &[&[u8]], rounds: u32, in_place: bool) -> Vec<(usize, bool, bool, bool)> {
+        let frames: Vec<Vec<u8>> = frames.iter().map(|f| f.to_vec()).collect();
+        advert_steps(&frames, rounds, in_place)
+            .iter()
+            .map(|s| (s.frame, s.restart, s.stop_first, s.stop_after))
+            .collect()
+    }
+
+    #[test]
+    fn every_advert_slot_starts_and_stops_unless_rotating_in_place() {
+        let frames: [&[u8]; 3] = [b"a", b"a", b"b"];
+        assert_eq!(
+            steps(&frames, 1, false),
+            [(0, true, false, true), (1, true, false, true), (2, true, false, true)]
+        );
+        // In place, an identical frame stays up, and only the last slot stops.
+        assert_eq!(
+            steps(&frames, 2, true),
+            [
+                (0, true, false, false),
+                (1, false, false, false),
+                (2, true, true, false),
+                (0, true, true, false),
+                (1, false, false, false),
+                (2, true, true, true),
+            ]
+        );
+        // A single frame is started once and stopped once.
+        assert_eq!(
+            steps(&[b"a"], 3, true),
+            [(0, true, false, false), (0, false, false, false), (0, false, false, true)]
+        );
+    }
+
+    #[test]
+    fn repeats_cycle_through_every_frame_before_the_next_round() {
+        let frames = [vec![0], vec![1], vec![2]];
+        for in_place in [false, true] {
+            let order: Vec<usize> = advert_steps(&frames, 2, in_place)
+                .iter()
+                .map(|s| s.frame)
+                .collect();
+            assert_eq!(order, [0, 1, 2, 0, 1, 2]);
+        }
+        for (n, rounds) in [(1, 1), (1, 5), (4, 3)] {
+            assert_eq!(advert_steps(&vec![vec![0]; n], rounds, false).len(), n * rounds as usize);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeat_secs_runs_whole_rounds_until_time_is_up() {
+        use futures::FutureExt;
+        let air = LoopbackTransport::new();
+        let mut heard = air.peer().scan().await.unwrap();
+        let opts = TxOpts {
+            dwell_ms: 100,
+            chunk_size: 4,
+            repeat_for: Some(Duration::from_secs(1)),
+            ..tx_opts(7)
+        };
+        let start = tokio::time::Instant::now();
+        tx(air, &opts, &[0; 12], None, None).await.unwrap();
+        // Three 100 ms chunks a round: the fourth round is the first to end
+        // at or past 1 s, and it is finished rather than cut short.
+        assert_eq!(start.elapsed(), Duration::from_millis(1200));
+        let mut seqs = Vec::new();
+        while let Some(Some((_, md))) = heard.next().now_or_never() {
+            seqs.push(unpack_frame(&md).unwrap().seq);
+        }
+        assert_eq!(seqs, [0, 1, 2].repeat(4));
+    }
+
+    #[tokio::test]
+    async fn deflate_growing_the_body_past_255_chunks_is_an_error() {
+        let opts = TxOpts {
+            compress: compress::Compress::Always,
+            ..tx_opts(7)
+        };
+        let mut body = vec![0u8; 255 * opts.chunk_size];
+        StdRng::seed_from_u64(1).fill(&mut body[..]);
+        validate_chunk_size(opts.chunk_size, false, body.len()).unwrap();
+        let err = tx(LoopbackTransport::new(), &opts, &body, None, None)
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("after compression"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn a_body_over_255_chunks_is_sent_if_it_fits_compressed() {
+        let opts = TxOpts {
+            compress: compress::Compress::Auto,
+            ..tx_opts(7)
+        };
+        let body = "the same words over and over ".repeat(300);
+        assert!(body.len().div_ceil(opts.chunk_size) > 255);
+        // As `tx` on the command line checks it before sending.
+        let (packed, _) = packed_body(body.as_bytes(), &opts, false, None);
+        validate_chunk_size(opts.chunk_size, false, packed.len()).unwrap();
+        let a = LoopbackTransport::new();
+        let (mut got, task) = listen(a.peer(), rx_opts(7)).await;
+        let msg_id = tx(a, &opts, body.as_bytes(), None, None).await.unwrap();
+        let (d, _) = next(&mut got).await;
+        assert_eq!((d.msg_id, d.text()), (msg_id, body));
+        task.abort();
+    }
+
+    #[test]
+    fn json_lines_carry_text_or_base64_and_skip_what_is_absent() {
+        let mut d = Delivered {
+            topic: 7,
+            msg_id: [0xde, 0xad, 0xbe, 0xef, 0xca, 0xfe],
+            subtopic: None,
+            bytes: b"hi".to_vec(),
+            signer: None,
+            key_index: None,
+            hops: 1,
+            presence: false,
+            nick: Some("ana".into()),
+            ts_ms: 0,
+        };
+        let line = |d: &Delivered| {
+            let line = serde_json::to_string(&JsonMessage::new(d)).unwrap();
+            assert!(!line.contains('\n'));
+            let mut v: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert!(v["ts"].as_u64().unwrap() > 0);
+            v.as_object_mut().unwrap().remove("ts");
+            v
+        };
+        assert_eq!(
+            line(&d),
+            serde_json::json!({
+                "topic": 7,
+                "nick": "ana",
+                "msg_id": "deadbeefcafe",
+                "hops": 1,
+                "verified": false,
+                "text": "hi",
+            })
+        );
+
+        (d.bytes, d.nick, d.subtopic) = (vec![0xff, 0x00], None, Some("ops".into()));
+        d.signer = Some([1; 32]);
+        assert_eq!(
+            line(&d),
+            serde_json::json!({
+                "topic": 7,
+                "subtopic": "ops",
+                "msg_id": "deadbeefcafe",
+                "hops": 1,
+                "verified": true,
+                "base64": "/wA=",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn rx_binary_writes_exactly_the_bytes_sent() {
+        let path = std::env::temp_dir().join(format!("chirp-rx-binary-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut body = vec![0u8; 200];
+        StdRng::seed_from_u64(1).fill(&mut body[..]);
+        let a = LoopbackTransport::new();
+        let output = RxOutput::Binary(Some(path.clone()));
+        let task = tokio::spawn(rx(a.peer(), rx_opts(7), Default::default(), false, false, output));
+        // Let it get as far as scanning before anything is sent.
+        sleep(Duration::from_millis(20)).await;
+        tx(a, &tx_opts(7), &body, None, None).await.unwrap();
+        let got = timeout(Duration::from_secs(5), async {
+            loop {
+                match std::fs::read(&path) {
+                    Ok(got) if got.len() >= body.len() => break got,
+                    _ => sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("timed out");
+        assert_eq!(got, body);
+        task.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn key_files_hold_raw_hex_or_base64_keys() {
+        use base64::Engine;
+        let key: [u8; 32] = std::array::from_fn(|i| i as u8 * 7);
+        let path = std::env::temp_dir().join(format!("chirp-key-file-{}", std::process::id()));
+        let load = |contents: &[u8]| {
+            std::fs::write(&path, contents).unwrap();
+            load_key_file(&path).map(|k| *k.expose())
+        };
+
+        assert_eq!(load(&key).unwrap(), key);
+        assert_eq!(load(format!("{}\n", hex::encode(key)).as_bytes()).unwrap(), key);
+        let b64 = base64::engine::general_purpose::STANDARD.encode(key);
+        assert_eq!(load(format!("{b64}\n").as_bytes()).unwrap(), key);
+
+        for wrong in [&key[..31], &[key.as_slice(), &[0]].concat()] {
+            let err = load(wrong).unwrap_err();
+            assert!(err.to_string().contains("must hold 32 raw bytes"), "{err}");
+        }
+        assert!(load(hex::encode(&key[..31]).as_bytes()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn joined_rooms_get_keys_salted_with_their_own_name() {
+        // Argon2's minimum cost keeps the test fast.
+        let load = |argv: &[&str]| {
+            let kdf = ["--kdf-mem-kib", "8", "--kdf-iters", "1", "--kdf-parallelism", "1"];
+            let argv = ["ble-chirp", "--passphrase", "hunter2"].iter().chain(&kdf).chain(argv);
+            load_keys(&mut Args::try_parse_from(argv).unwrap()).unwrap()
+        };
+        let (lobby, joined) = load(&["chat", "--room", "lobby", "--join", "ops"]);
+        let (ops, none) = load(&["chat", "--room", "ops"]);
+        assert!(none.is_empty());
+        let ops_joined = &joined[&topic_from_room("ops")];
+        assert!(crypto::keys_equal(&ops_joined[0].tx_key(), &ops[0].tx_key()));
+        assert!(!crypto::keys_equal(&lobby[0].tx_key(), &ops[0].tx_key()));
+
+        // With one --salt for every room, they all share the main key.
+        let (_, joined) = load(&["--salt", "s", "chat", "--room", "lobby", "--join", "ops"]);
+        assert!(joined.is_empty());
+    }
+
+    #[test]
+    fn max_reasm_must_be_at_least_1() {
+        let parse = |n: &str| Args::try_parse_from(["ble-chirp", "rx", "--max-reasm", n]);
+        assert!(parse("0").is_err());
+        assert!(parse("1").is_ok());
+    }
+
+    #[tokio::test]
+    async fn loopback_nodes_exchange_a_message() {
+        let a = LoopbackTransport::new();
+        let b = a.peer();
+        let (mut got, task) = listen(b, rx_opts(7)).await;
+        let body = "a message long enough to need several chunks on the air";
+        let msg_id = tx(a, &tx_opts(7), body.as_bytes(), None, None).await.unwrap();
+        let (d, source) = next(&mut got).await;
+        assert_eq!(d.msg_id, msg_id);
+        assert_eq!(d.text(), body);
+        assert_eq!(source, 0);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn a_frame_bounced_between_two_relays_dies_out() {
+        let air = LoopbackTransport::new();
+        // `seen` forgets at once, so only `relayed` can stop the bouncing.
+        let relaying = || RxOpts {
+            relay: true,
+            seen_ttl: Duration::ZERO,
+            ..rx_opts(7)
+        };
+        let (_, a) = listen(air.peer(), relaying()).await;
+        let (_, b) = listen(air.peer(), relaying()).await;
+        let mut heard = air.peer().scan().await.unwrap();
+        let msg_id = [3; MSG_ID_LEN];
+        // One chunk of three: an incomplete message is never stale, so the
+        // replay check can't stop the bouncing either.
+        let body = [b'x'; 3 * MAX_PAYLOAD];
+        let mut f = unpack_frame(&plain_frames(7, msg_id, &body)[0]).unwrap();
+        (f.ttl, f.ttl0) = (u8::MAX, u8::MAX);
+        air.advertise(pack_frame(&f), Duration::ZERO).await.unwrap();
+        // The original and one relay from each node; a bounce would keep
+        // going for as long as the ttl lasts.
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(3);
+        let mut copies = 0;
+        while let Ok(Some((_, md))) = tokio::time::timeout_at(deadline, heard.next()).await {
+            copies += usize::from(unpack_frame(&md).is_ok_and(|f| f.msg_id == msg_id));
+        }
+        assert_eq!(copies, 3);
+        a.abort();
+        b.abort();
+    }
+
+    #[tokio::test]
+    async fn relays_past_max_relays_are_dropped() {
+        let air = LoopbackTransport::new();
+        let stats = Arc::new(Stats::default());
+        let opts = RxOpts {
+            relay: true,
+            max_relays: 1,
+            stats: stats.clone(),
+            ..rx_opts(7)
+        };
+        let (_, relay) = listen(air.peer(), opts).await;
+        let mut heard = air.peer().scan().await.unwrap();
+        // Three backoffs inside one 400 ms window: at least two of them are
+        // less than a 300 ms relay dwell apart, so they can't both have the
+        // one slot.
+        let msg_id = [4; MSG_ID_LEN];
+        for md in plain_frames(7, msg_id, &[b'x'; 3 * MAX_PAYLOAD]) {
+            air.advertise(md, Duration::ZERO).await.unwrap();
+        }
+        let mut relayed = 0;
+        while let Ok(Some((_, md))) = timeout(Duration::from_secs(2), heard.next()).await {
+            relayed += usize::from(unpack_frame(&md).is_ok_and(|f| f.ttl == 2));
+        }
+        let s = stats.snapshot();
+        assert!(s.relays_dropped > 0, "{s:?}");
+        assert_eq!(relayed as u64 + s.relays_dropped, 3);
+        assert_eq!(s.relay_queue, 0);
+        relay.abort();
+    }
+
+    #[tokio::test]
+    async fn relay_topics_can_forward_a_topic_outside_the_display_filter() {
+        let air = LoopbackTransport::new();
+        let opts = RxOpts {
+            relay: true,
+            relay_topics: relay::RelayTopics::Only(vec![8]),
+            ..rx_opts(7)
+        };
+        let (mut got, relay) = listen(air.peer(), opts).await;
+        let mut heard = air.peer().scan().await.unwrap();
+        for topic in [7, 8] {
+            let md = plain_frames(topic, [topic; MSG_ID_LEN], b"hi").remove(0);
+            air.advertise(md, Duration::ZERO).await.unwrap();
+        }
+        assert_eq!(next(&mut got).await.0.topic, 7);
+        let mut relayed = Vec::new();
+        while let Ok(Some((_, md))) = timeout(Duration::from_secs(1), heard.next()).await {
+            let f = unpack_frame(&md).unwrap();
+            if f.ttl < f.ttl0 {
+                relayed.push(f.topic);
+            }
+        }
+        assert_eq!(relayed, [8]);
+        assert!(got.try_recv().is_err());
+        relay.abort();
+    }
+
+    /// A loopback node whose first scan fails to start and whose second
+    /// ends after one advertisement, like an adapter that is briefly busy
+    /// and then drops its scan.
+    #[derive(Clone)]
+    struct FlakyTransport {
+        inner: LoopbackTransport,
+        scans: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl FlakyTransport {
+        /// Wait until `scan` has been called `n` times, and a moment more
+        /// for the stream to subscribe.
+        async fn scanned(&self, n: usize) {
+            while self.scans.load(std::sync::atomic::Ordering::Relaxed) < n {
+                sleep(Duration::from_millis(10)).await;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    impl Transport for FlakyTransport {
+        type Source = usize;
+        type Advertiser = LoopbackTransport;
+        type Identity = FlakyTransport;
+
+        async fn advertiser(&self) -> anyhow::Result<LoopbackTransport> {
+            self.inner.advertiser().await
+        }
+
+        async fn scan(&self) -> anyhow::Result<transport::Heard<usize>> {
+            match self.scans.fetch_add(1, std::sync::atomic::Ordering::Relaxed) {
+                0 => anyhow::bail!("adapter busy"),
+                1 => Ok(self.inner.scan().await?.take(1).boxed()),
+                _ => self.inner.scan().await,
+            }
+        }
+
+        async fn identity(&self) -> Self {
+            self.clone()
+        }
+
+        async fn rebind(id: &Self) -> anyhow::Result<Self> {
+            Ok(id.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn rx_rescans_after_a_failed_or_ended_scan() {
+        let air = LoopbackTransport::new();
+        let node = FlakyTransport {
+            inner: air.peer(),
+            scans: Arc::default(),
+        };
+        let (mut got, task) = listen(node.clone(), rx_opts(7)).await;
+        for (scan, msg_id) in [(2, [1; MSG_ID_LEN]), (3, [2; MSG_ID_LEN])] {
+            node.scanned(scan).await;
+            let md = plain_frames(7, msg_id, b"hi").remove(0);
+            air.advertise(md, Duration::ZERO).await.unwrap();
+            assert_eq!(next(&mut got).await.0.msg_id, msg_id, "on scan {scan}");
+        }
+        assert!(!task.is_finished());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn a_dropped_chunk_is_recovered_through_a_nack() {
+        let a = LoopbackTransport::new();
+        let opts = RxOpts {
+            nack_after: Some(Duration::from_millis(100)),
+            ..rx_opts(7)
+        };
+        let (mut got, task) = listen(a.peer(), opts).await;
+        let server = tokio::spawn(retransmit::serve(a.clone(), Vec::new(), 10_000.0));
+        let msg_id = [4; MSG_ID_LEN];
+        let body = [b'x'; 3 * MAX_PAYLOAD];
+        let frames = plain_frames(7, msg_id, &body);
+        // Cached for `serve` as `tx` would, but chunk 1 is lost on the way.
+        let sent = frames
+            .iter()
+            .map(|md| unpack_frame(md).unwrap())
+            .map(|f| (f.seq, vec![f]))
+            .collect();
+        retransmit::Cache::global().insert(msg_id, sent);
+        for md in [&frames[0], &frames[2]] {
+            a.advertise(md.clone(), Duration::ZERO).await.unwrap();
+        }
+        let (d, _) = next(&mut got).await;
+        assert_eq!((d.msg_id, d.bytes), (msg_id, body.to_vec()));
+        task.abort();
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn per_message_key_messages_open_with_the_room_key() {
+        let a = LoopbackTransport::new();
+        let keys = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY), None);
+        let opts = RxOpts {
+            keys: vec![keys.clone()],
+            ..rx_opts(7)
+        };
+        let (mut got, task) = listen(a.peer(), opts).await;
+        let opts = TxOpts {
+            per_message_key: true,
+            ..tx_opts(7)
+        };
+        for body in ["first", "second"] {
+            let msg_id = tx(a.clone(), &opts, body.as_bytes(), Some(&keys), None)
+                .await
+                .unwrap();
+            let (d, _) = next(&mut got).await;
+            assert_eq!((d.msg_id, d.text().as_str()), (msg_id, body));
+        }
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn rooms_sharing_a_topic_each_open_only_their_own_messages() {
+        let a = LoopbackTransport::new();
+        let lobby = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY), None);
+        let attic = crypto::Keys::new(crypto::SecretKey::new(GOLDEN_KEY.map(|b| !b)), None);
+        let with = |keys: &[&crypto::Keys]| RxOpts {
+            keys: keys.iter().map(|&k| k.clone()).collect(),
+            ..rx_opts(7)
+        };
+        let (mut lobby_rx, lobby_task) = listen(a.peer(), with(&[&lobby])).await;
+        let (mut attic_rx, attic_task) = listen(a.peer(), with(&[&attic])).await;
+        let (mut both_rx, both_task) = listen(a.peer(), with(&[&lobby, &attic])).await;
+
+        let from_lobby = tx(a.clone(), &tx_opts(7), b"lobby", Some(&lobby), None).await.unwrap();
+        let from_attic = tx(a.clone(), &tx_opts(7), b"attic", Some(&attic), None).await.unwrap();
+
+        assert_eq!(next(&mut lobby_rx).await.0.msg_id, from_lobby);
+        assert_eq!(next(&mut attic_rx).await.0.msg_id, from_attic);
+        let mut both = [next(&mut both_rx).await.0.text(), next(&mut both_rx).await.0.text()];
+        both.sort();
+        assert_eq!(both, ["attic", "lobby"]);
+        sleep(Duration::from_millis(50)).await;
+        assert!(lobby_rx.try_recv().is_err(), "the lobby opened an attic message");
+        assert!(attic_rx.try_recv().is_err(), "the attic opened a lobby message");
+        for task in [lobby_task, attic_task, both_task] {
+            task.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn muted_messages_are_not_acked() {
+        let a = LoopbackTransport::new();
+        let acker = RxOpts {
+            ack_as: Some([1; mesh::ACKER_LEN]),
+            mutes: mute::MuteList::new(vec![mute::parse_mute("nick:spam").unwrap()]),
+            ..rx_opts(7)
+        };
+        let (_, acker) = listen(a.peer(), acker).await;
+        let (heard, acks) = unbounded_channel();
+        let (_, watcher) = listen(
+            a.peer(),
+            RxOpts {
+                acks: Some(heard),
+                ..rx_opts(7)
+            },
+        )
+        .await;
+        let mut acks = acks;
+        // Not sent with `tx`: it would mark the messages as ours in the
+        // process-wide `Originated`, and nobody acks their own messages.
+        let spam = mesh::tag_message(Some("spam"), None, "buy now");
+        for (msg_id, body) in [([1; MSG_ID_LEN], spam.as_bytes()), ([2; MSG_ID_LEN], b"hello")] {
+            for md in plain_frames(7, msg_id, body) {
+                a.advertise(md, Duration::ZERO).await.unwrap();
+            }
+        }
+        // Acks go out in order, so one for the muted message would come first.
+        assert_eq!(next(&mut acks).await.msg_id, [2; MSG_ID_LEN]);
+        acker.abort();
+        watcher.abort();
+    }
+
+    /// A signature covers the topic, so a sweep signs each topic's copy
+    /// separately and every one of them verifies.
+    #[tokio::test]
+    async fn signed_sweep_verifies_on_every_topic() {
+        let a = LoopbackTransport::new();
+        let opts = RxOpts {
+            topic_filter: None,
+            ..rx_opts(7)
+        };
+        let (mut got, task) = listen(a.peer(), opts).await;
+        let sk = crypto::SigningKey::from_bytes(&[9; 32]);
+        let msg_id = tx_topics(a, &tx_opts(7), &[7, 8], b"signed", None, Some(&sk))
+            .await
+            .unwrap();
+        let mut topics = Vec::new();
+        for _ in 0..2 {
+            let (d, _) = next(&mut got).await;
+            assert_eq!((d.msg_id, d.text().as_str()), (msg_id, "signed"));
+            assert_eq!(d.signer, Some(sk.verifying_key().to_bytes()));
+            topics.push(d.topic);
+        }
+        topics.sort();
+        assert_eq!(topics, [7, 8]);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn two_nodes_on_one_identity_are_counted_once() {
+        let a = LoopbackTransport::new();
+        let b = a.peer();
+        let opts = rx_opts(7);
+        let stats = opts.stats.clone();
+        let (mut got, task) = listen(a.peer(), opts).await;
+        let sk = crypto::SigningKey::from_bytes(&[9; 32]);
+        for (i, node) in [a.clone(), b, a].into_iter().enumerate() {
+            tx(node, &tx_opts(7), format!("{i}").as_bytes(), None, Some(&sk)).await.unwrap();
+            next(&mut got).await;
+        }
+        assert_eq!(stats.snapshot().duplicate_identities, 1);
+        task.abort();
+    }
+}
@@ -0,0 +1,1232 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::bounded_set::{BoundedMap, BoundedSet};
+use crate::compress;
+use crate::fec;
+use crate::identity;
+use crate::presence;
+use crate::relay::{OWN_IDS_CAP, Originated};
+use crate::retransmit;
+use crate::stats::Stats;
+use crate::{
+    Control, EMPTY_MSG, FLAG_COMPRESSED, FLAG_NACK, FLAG_PRESENCE, FLAG_SIGNED, Frame, FrameKind,
+    MSG_ID_LEN, MsgId, MsgKey, crypto, now_ms, unpack_frame,
+};
+
+/// Default for `MeshNode::with_seen_cache`.
+pub const SEEN_CAP: usize = 2048;
+/// Default for `MeshNode::with_seen_ttl`.
+pub const SEEN_TTL: Duration = Duration::from_secs(300);
+/// Default for `MeshNode::with_reasm_timeout`.
+pub const REASM_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default for `MeshNode::with_max_reasm`.
+pub const MAX_REASM: usize = 256;
+/// Hops a NACK may take back towards the sender.
+const NACK_TTL: u8 = 3;
+/// A chat sends a typing notice at most this often while its user types.
+#[cfg(feature = "tui")]
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+/// A typist not heard from for this long has stopped.
+#[cfg(feature = "tui")]
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A sub-topic travels at the start of the message body as
+/// `\x1f<tag>\x1f<text>`, so it is covered by the AEAD like the text and
+/// costs no header bytes on untagged messages. The RF topic still does the
+/// routing; the tag only sorts messages within it.
+const SUBTOPIC_MARK: char = '\u{1f}';
+pub const MAX_SUBTOPIC_LEN: usize = 16;
+/// The sender's `--nick` travels the same way, ahead of any sub-topic tag:
+/// `\x1e<nick>\x1e`.
+const NICK_MARK: char = '\u{1e}';
+
+/// clap value parser for `--subtopic`: accepts `alerts` or `#alerts`.
+pub fn parse_subtopic(s: &str) -> Result<String, String> {
+    let tag = s.strip_prefix('#').unwrap_or(s);
+    if tag.is_empty() || tag.len() > MAX_SUBTOPIC_LEN {
+        return Err(format!("sub-topic must be 1..={MAX_SUBTOPIC_LEN} bytes"));
+    }
+    if tag.chars().any(char::is_control) {
+        return Err("sub-topic must not contain control characters".into());
+    }
+    Ok(tag.to_string())
+}
+
+/// Prefix `text` with the sender's nickname and its sub-topic tag, if any.
+pub fn tag_message(nick: Option<&str>, subtopic: Option<&str>, text: &str) -> String {
+    let nick = nick
+        .map(|n| format!("{NICK_MARK}{n}{NICK_MARK}"))
+        .unwrap_or_default();
+    let tag = subtopic
+        .map(|t| format!("{SUBTOPIC_MARK}{t}{SUBTOPIC_MARK}"))
+        .unwrap_or_default();
+    format!("{nick}{tag}{text}")
+}
+
+/// Inverse of `tag_message`'s nickname prefix.
+pub fn split_nick(body: &[u8]) -> (Option<&str>, &[u8]) {
+    split_marked(body, NICK_MARK)
+}
+
+/// Inverse of `tag_message`'s sub-topic prefix.
+pub fn split_subtopic(body: &[u8]) -> (Option<&str>, &[u8]) {
+    split_marked(body, SUBTOPIC_MARK)
+}
+
+/// Split a `<mark><tag><mark>` prefix off `body`. Bodies without a
+/// well-formed UTF-8 tag are untagged; the rest of the body is left as
+/// bytes.
+fn split_marked(body: &[u8], mark: char) -> (Option<&str>, &[u8]) {
+    let mark = mark as u8;
+    body.strip_prefix(&[mark])
+        .and_then(|rest| {
+            let end = rest.iter().position(|&b| b == mark)?;
+            Some((std::str::from_utf8(&rest[..end]).ok()?, &rest[end + 1..]))
+        })
+        .map_or((None, body), |(tag, rest)| (Some(tag), rest))
+}
+
+/// Reassembly state changes reported on `RxOpts::events`.
+#[derive(Debug)]
+pub(crate) enum ReasmEvent {
+    Started {
+        msg_id: MsgId,
+        topic: u8,
+        tot: u8,
+    },
+    Chunk {
+        msg_id: MsgId,
+        seq: u8,
+        have: u8,
+        tot: u8,
+    },
+    Completed {
+        msg_id: MsgId,
+        topic: u8,
+        len: usize,
+    },
+    /// Evicted incomplete: no chunk arrived for the reassembly timeout, or
+    /// it was the least recently updated when `max_reasm` was reached.
+    Expired {
+        msg_id: MsgId,
+        have: u8,
+        tot: u8,
+    },
+}
+
+/// A message evicted before it completed.
+pub(crate) struct Incomplete {
+    pub msg_id: MsgId,
+    pub tot: u8,
+    /// Chunks (or, for FEC, source blocks) that never arrived.
+    pub missing: Vec<u8>,
+}
+
+pub(crate) struct Delivered {
+    pub topic: u8,
+    pub msg_id: MsgId,
+    pub subtopic: Option<String>,
+    /// The message body, without its sub-topic tag or signature trailer.
+    pub bytes: Vec<u8>,
+    /// Public key whose signature over the body verified, if any.
+    pub signer: Option<[u8; 32]>,
+    /// Which of `RxOpts::keys` decrypted the message.
+    pub key_index: Option<usize>,
+    /// Relays the final chunk passed through on its way here.
+    pub hops: u8,
+    /// A presence beacon (`FLAG_PRESENCE`) rather than a message to show.
+    pub presence: bool,
+    /// The sender's `--nick`, if it tagged the message with one.
+    pub nick: Option<String>,
+    /// When the sender sent it, by the sender's clock, Unix milliseconds.
+    pub ts_ms: u64,
+}
+
+impl Delivered {
+    /// `bytes` as text for display, replacing invalid UTF-8. A zero-length
+    /// message is still a message, so it shows as `EMPTY_MSG` rather than a
+    /// blank line.
+    pub fn text(&self) -> String {
+        if self.bytes.is_empty() {
+            EMPTY_MSG.to_string()
+        } else {
+            String::from_utf8_lossy(&self.bytes).into_owned()
+        }
+    }
+}
+
+/// A message still being reassembled.
+struct Partial {
+    chunks: HashMap<u8, Vec<u8>>,
+    /// Key that opened the latest chunk.
+    key_index: Option<usize>,
+    /// Real topic; differs from the frame's for private-topic frames.
+    topic: u8,
+    /// Flags of the latest chunk.
+    flags: u8,
+    /// Set instead of `chunks` for an FEC-coded message.
+    fec: Option<fec::Decoder>,
+    /// When the latest chunk arrived.
+    updated: Instant,
+    /// When we last NACKed it.
+    nacked: Option<Instant>,
+}
+
+impl Partial {
+    fn new(fec: Option<fec::Decoder>) -> Self {
+        Self {
+            chunks: HashMap::new(),
+            key_index: None,
+            topic: 0,
+            flags: 0,
+            fec,
+            updated: Instant::now(),
+            nacked: None,
+        }
+    }
+
+    /// Chunks, or blocks recovered so far, towards `tot`.
+    fn have(&self) -> usize {
+        self.fec
+            .as_ref()
+            .map_or(self.chunks.len(), fec::Decoder::recovered)
+    }
+
+    fn missing(&self, tot: u8) -> Vec<u8> {
+        match &self.fec {
+            Some(decoder) => decoder.missing(),
+            None => (0..tot).filter(|i| !self.chunks.contains_key(i)).collect(),
+        }
+    }
+}
+
+/// A decrypted chunk.
+struct Opened {
+    payload: Vec<u8>,
+    key_index: Option<usize>,
+    topic: u8,
+}
+
+/// What the caller should do after a frame was handled.
+#[derive(Default)]
+pub(crate) struct Handled {
+    pub delivered: Option<Delivered>,
+    /// Frame to rebroadcast, TTL already decremented.
+    pub relay: Option<Frame>,
+    /// Partial messages evicted while handling the frame.
+    pub incomplete: Vec<Incomplete>,
+    /// NACK frames to advertise for messages stuck missing chunks.
+    pub nacks: Vec<Frame>,
+    /// A typing notice heard from a neighbour.
+    pub typing: Option<Typing>,
+    /// Acknowledgement to send for the message just delivered.
+    pub ack: Option<Frame>,
+    /// An acknowledgement heard for someone's message, possibly ours.
+    pub heard_ack: Option<Ack>,
+}
+
+/// Someone is typing in chat on `topic`.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub struct Typing {
+    pub topic: u8,
+    pub nick: String,
+}
+
+/// `acker` has reassembled `msg_id`.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub struct Ack {
+    pub msg_id: MsgId,
+    /// The acker's identity public key, or a random per-run id without one.
+    pub acker: [u8; ACKER_LEN],
+}
+
+/// Length of `Ack::acker` on the wire.
+pub const ACKER_LEN: usize = 32;
+
+/// Radio-independent receive pipeline for one node: topic filter, dedup,
+/// decrypt, reassembly and the relay decision. `rx_loop` feeds it frames off
+/// the air; `simulate` feeds it frames from virtual neighbors.
+pub(crate) struct MeshNode {
+    topic_filter: Option<u8>,
+    relay: bool,
+    keys: Vec<crypto::Keys>,
+    stats: Arc<Stats>,
+    events: Option<UnboundedSender<ReasmEvent>>,
+    originated: Originated,
+    subtopic_filter: Option<String>,
+    /// When each recent frame was first seen.
+    seen: BoundedMap<(MsgKey, FrameKind, u8), Instant>,
+    seen_ttl: Duration,
+    reasm: HashMap<MsgKey, Partial>,
+    relayed: BoundedSet<(MsgId, FrameKind, u8)>,
+    max_age_ms: Option<u64>,
+    /// Timestamp of the newest completed message per key, so a replayed
+    /// copy is refused even after `seen` has forgotten it.
+    newest: BoundedMap<MsgKey, u64>,
+    /// Ephemeral public keys from handshake frames, per message.
+    handshakes: BoundedMap<MsgKey, [u8; 32]>,
+    debug_frames: bool,
+    reasm_timeout: Duration,
+    max_reasm: usize,
+    nack_after: Option<Duration>,
+    ack_as: Option<[u8; ACKER_LEN]>,
+    /// Draws the msg_ids of the NACKs and acks this node sends.
+    rng: StdRng,
+}
+
+impl MeshNode {
+    pub fn new(
+        topic_filter: Option<u8>,
+        relay: bool,
+        keys: Vec<crypto::Keys>,
+        stats: Arc<Stats>,
+        events: Option<UnboundedSender<ReasmEvent>>,
+        originated: Originated,
+    ) -> Self {
+        Self {
+            topic_filter,
+            relay,
+            keys,
+            stats,
+            events,
+            originated,
+            subtopic_filter: None,
+            seen: BoundedMap::new(SEEN_CAP),
+            seen_ttl: SEEN_TTL,
+            reasm: HashMap::new(),
+            relayed: BoundedSet::new(OWN_IDS_CAP),
+            max_age_ms: None,
+            newest: BoundedMap::new(OWN_IDS_CAP),
+            handshakes: BoundedMap::new(SEEN_CAP),
+            debug_frames: false,
+            reasm_timeout: REASM_TIMEOUT,
+            max_reasm: MAX_REASM,
+            nack_after: None,
+            ack_as: None,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Draw the msg_ids of NACKs and acks from `rng` instead of the OS, so a
+    /// seeded run sends the same frames every time.
+    pub fn with_rng(mut self, rng: StdRng) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Log why each malformed frame was dropped as a warning, so it shows
+    /// without `-v`.
+    pub fn with_debug_frames(mut self, on: bool) -> Self {
+        self.debug_frames = on;
+        self
+    }
+
+    /// Only deliver messages tagged with this sub-topic. Filtered messages are
+    /// still reassembled and relayed.
+    pub fn with_subtopic_filter(mut self, subtopic: Option<String>) -> Self {
+        self.subtopic_filter = subtopic;
+        self
+    }
+
+    /// Drop frames whose timestamp is more than `max_age` away from our clock,
+    /// in either direction.
+    pub fn with_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_age_ms = max_age.map(|d| d.as_millis() as u64);
+        self
+    }
+
+    /// Give up on a partial message once no chunk of it has arrived for
+    /// `timeout`.
+    pub fn with_reasm_timeout(mut self, timeout: Duration) -> Self {
+        self.reasm_timeout = timeout;
+        self
+    }
+
+    /// Reassemble at most `max` messages at once; a new one beyond that
+    /// evicts the least recently updated.
+    pub fn with_max_reasm(mut self, max: usize) -> Self {
+        self.max_reasm = max;
+        self
+    }
+
+    /// Remember the last `cap` frames for duplicate suppression.
+    pub fn with_seen_cache(mut self, cap: usize) -> Self {
+        self.seen = BoundedMap::new(cap);
+        self
+    }
+
+    /// Stop suppressing a frame once it was first seen `ttl` ago, so a
+    /// message can legitimately be sent again on a quiet channel.
+    pub fn with_seen_ttl(mut self, ttl: Duration) -> Self {
+        self.seen_ttl = ttl;
+        self
+    }
+
+    /// NACK a partial message once it has gone `after` without a new chunk,
+    /// and again every `after` while it stays stuck.
+    pub fn with_nack_after(mut self, after: Option<Duration>) -> Self {
+        self.nack_after = after;
+        self
+    }
+
+    /// Acknowledge each message delivered, other than presence beacons and
+    /// our own, as `acker`.
+    pub fn with_ack_as(mut self, acker: Option<[u8; ACKER_LEN]>) -> Self {
+        self.ack_as = acker;
+        self
+    }
+
+    /// Time out and NACK partial messages as of `now`. `handle` does this on
+    /// every frame; call it on a timer too so a quiet channel still does.
+    pub fn sweep(&mut self, now: Instant) -> Handled {
+        Handled {
+            incomplete: self.evict_stale(now),
+            nacks: self.due_nacks(now),
+            ..Handled::default()
+        }
+    }
+
+    /// Drop partial messages not updated within the reassembly timeout as
+    /// of `now`, returning what each was missing.
+    fn evict_stale(&mut self, now: Instant) -> Vec<Incomplete> {
+        let timeout = self.reasm_timeout;
+        let events = &self.events;
+        let mut evicted = Vec::new();
+        self.reasm.retain(|mk, p| {
+            let fresh = now.saturating_duration_since(p.updated) < timeout;
+            if !fresh {
+                evicted.push(expire(events, mk, p));
+            }
+            fresh
+        });
+        Stats::set(&self.stats.in_flight, self.reasm.len() as u64);
+        Stats::add(&self.stats.evicted, evicted.len() as u64);
+        evicted
+    }
+
+    /// NACK frames for partial messages due one. FEC-coded messages are
+    /// left alone: any further droplets or shards will do, not specific ones.
+    fn due_nacks(&mut self, now: Instant) -> Vec<Frame> {
+        let Some(after) = self.nack_after else {
+            return Vec::new();
+        };
+        let mut nacks = Vec::new();
+        for (mk, p) in &mut self.reasm {
+            let last = p.nacked.map_or(p.updated, |t| t.max(p.updated));
+            if p.fec.is_some() || now.saturating_duration_since(last) < after {
+                continue;
+            }
+            p.nacked = Some(now);
+            let key = p
+                .key_index
+                .and_then(|i| self.keys.get(i))
+                .or(self.keys.first());
+            if let Some(f) = nack_frame(mk, &p.missing(mk.tot), key, &mut self.rng) {
+                self.originated.mark(f.msg_id);
+                nacks.push(f);
+            }
+        }
+        nacks
+    }
+
+    /// Make room for a new message under `max_reasm`.
+    fn evict_oldest(&mut self) -> Option<Incomplete> {
+        let mk = self
+            .reasm
+            .iter()
+            .min_by_key(|(_, p)| p.updated)
+            .map(|(&mk, _)| mk)?;
+        let p = self.reasm.remove(&mk)?;
+        Stats::inc(&self.stats.evicted);
+        Some(expire(&self.events, &mk, &p))
+    }
+
+    /// Handle one manufacturer-data blob.
+    pub fn handle(&mut self, md: &[u8]) -> Handled {
+        let mut out = self.sweep(Instant::now());
+        let mut f = match unpack_frame(md) {
+            Ok(f) => f,
+            Err(e) => {
+                if self.debug_frames {
+                    tracing::warn!(len = md.len(), reason = %e, "dropped frame");
+                } else {
+                    tracing::debug!(len = md.len(), reason = %e, "dropped frame");
+                }
+                return out;
+            }
+        };
+        let stats = self.stats.clone();
+        Stats::inc(&stats.frames_rx);
+        // A private frame's topic is masked; it is checked per key in `open`.
+        // A frame for another topic isn't ours to show, but a relay still
+        // forwards it, so it goes through the relay decision below.
+        let other_topic = self.topic_filter.is_some_and(|t| f.topic != t) && !f.private;
+        if other_topic && !self.relay {
+            Stats::inc(&stats.dropped);
+            Stats::inc(&stats.dropped_topic);
+            return out;
+        }
+
+        let mk = f.key();
+        let seen_key = (mk, f.kind(), f.seq);
+        let now = Instant::now();
+        if self
+            .seen
+            .get(&seen_key)
+            .is_some_and(|&t| now.saturating_duration_since(t) < self.seen_ttl)
+        {
+            Stats::inc(&stats.dropped);
+            Stats::inc(&stats.dropped_dup);
+            return out;
+        }
+        // Re-inserting makes an expired entry the newest again.
+        self.seen.remove(&seen_key);
+        self.seen.insert(seen_key, now);
+
+        // Outside the clock window, or a copy of a message we already
+        // completed that `seen` has since forgotten: treat as a replay.
+        if self
+            .max_age_ms
+            .is_some_and(|max| now_ms().abs_diff(f.ts_ms) > max)
+            || self.newest.get(&mk).is_some_and(|&t| f.ts_ms <= t)
+        {
+            Stats::inc(&stats.stale);
+            return out;
+        }
+
+        match f.kind() {
+            _ if other_topic => {
+                Stats::inc(&stats.dropped);
+                Stats::inc(&stats.dropped_topic);
+            }
+            FrameKind::Handshake => {
+                if let Ok(eph_pub) = f.payload.as_slice().try_into() {
+                    self.handshakes.insert(mk, eph_pub);
+                }
+            }
+            // Answered by `tx --serve`; a receiver only relays it.
+            FrameKind::Nack => {}
+            FrameKind::Ack => {
+                out.heard_ack = self.open(&f).and_then(|o| parse_ack(&o.payload));
+            }
+            FrameKind::Typing => {
+                out.typing = self.open(&f).and_then(|o| {
+                    let nick = String::from_utf8(o.payload).ok()?;
+                    Some(Typing {
+                        topic: o.topic,
+                        nick: presence::parse_nick(&nick).ok()?,
+                    })
+                });
+            }
+            FrameKind::Data => {
+                let Some(opened) = self.open(&f) else {
+                    return out;
+                };
+                if !self.reasm.contains_key(&mk) && self.reasm.len() >= self.max_reasm {
+                    out.incomplete.extend(self.evict_oldest());
+                }
+                let key_index = opened.key_index;
+                out.delivered = self.reassemble(&f, mk, opened);
+                out.ack = self
+                    .ack_as
+                    .zip(out.delivered.as_ref())
+                    .filter(|(_, d)| !d.presence && !self.originated.contains(&d.msg_id))
+                    .and_then(|(acker, d)| {
+                        let key = key_index.and_then(|i| self.keys.get(i));
+                        ack_frame(d.topic, &d.msg_id, &acker, key, &mut self.rng)
+                    });
+                if let Some(a) = &out.ack {
+                    self.originated.mark(a.msg_id);
+                }
+            }
+        }
+        Stats::set(&stats.in_flight, self.reasm.len() as u64);
+
+        // Never relay our own messages, and relay any given chunk at most once
+        // even after `seen` forgets it, so two relaying peers can't bounce a
+        // frame forever.
+        if self.relay
+            && f.ttl > 0
+            && f.kind() != FrameKind::Typing
+            && !self.originated.contains(&f.msg_id)
+            && self.relayed.insert((f.msg_id, f.kind(), f.seq))
+        {
+            f.ttl -= 1;
+            Stats::inc(&stats.relayed);
+            tracing::trace!(
+                msg_id = %hex::encode(f.msg_id),
+                seq = f.seq,
+                ttl = f.ttl,
+                "relaying"
+            );
+            out.relay = Some(f);
+        }
+        out
+    }
+
+    /// Decrypt a data chunk. On failure the chunk is forgotten by `seen` so a
+    /// repeat can retry, e.g. once the message's handshake frame has arrived.
+    /// Keys are tried in order and the first that authenticates wins; the
+    /// chunk only counts as a decrypt failure once all of them have failed.
+    ///
+    /// A private frame is unmasked with each key in turn, and only keys that
+    /// unmask it to the topic filter are tried; the AEAD then authenticates
+    /// the unmasked topic.
+    fn open(&mut self, f: &Frame) -> Option<Opened> {
+        if self.keys.is_empty() && f.plain {
+            return Some(Opened {
+                payload: f.payload.clone(),
+                key_index: None,
+                topic: f.topic,
+            });
+        }
+        let candidates: Vec<(usize, u8)> = self
+            .keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| {
+                let topic = if f.private {
+                    f.topic ^ k.topic_mask(&f.msg_id)
+                } else {
+                    f.topic
+                };
+                (i, topic)
+            })
+            .filter(|&(_, t)| self.topic_filter.is_none_or(|want| want == t))
+            .collect();
+        if candidates.is_empty() && !self.keys.is_empty() {
+            Stats::inc(&self.stats.dropped);
+            Stats::inc(&self.stats.dropped_topic);
+            return None;
+        }
+        let eph_pub = self.handshakes.get(&f.key());
+        let opened = candidates.into_iter().find_map(|(i, topic)| {
+            let k = &self.keys[i];
+            let real = Frame { topic, ..f.clone() };
+            let (nonce, aad) = (real.nonce(), real.aad());
+            let res = match eph_pub {
+                Some(eph_pub) => k.decrypt_handshake(eph_pub, &nonce, &aad, &f.payload),
+                None => k.decrypt(&nonce, &aad, &f.payload),
+            };
+            res.ok().map(|payload| Opened {
+                payload,
+                key_index: Some(i),
+                topic,
+            })
+        });
+        if opened.is_none() {
+            tracing::debug!(msg_id = %hex::encode(f.msg_id), seq = f.seq, "decrypt failed");
+            Stats::inc(&self.stats.decrypt_failures);
+            self.seen.remove(&(f.key(), f.kind(), f.seq));
+        }
+        opened
+    }
+
+    /// Add a decrypted chunk; returns the message once all chunks are in.
+    fn reassemble(&mut self, f: &Frame, mk: MsgKey, opened: Opened) -> Option<Delivered> {
+        let stats = &self.stats;
+        let events = &self.events;
+        let entry = self.reasm.entry(mk).or_insert_with(|| {
+            emit(
+                events,
+                ReasmEvent::Started {
+                    msg_id: mk.msg_id,
+                    topic: mk.topic,
+                    tot: mk.tot,
+                },
+            );
+            Partial::new(fec::Decoder::for_frame(f))
+        });
+        entry.updated = Instant::now();
+        let have = match &mut entry.fec {
+            Some(decoder) => {
+                decoder.add(f, opened.payload);
+                decoder.recovered()
+            }
+            None => {
+                entry.chunks.insert(f.seq, opened.payload);
+                entry.chunks.len()
+            }
+        };
+        entry.key_index = opened.key_index;
+        entry.topic = opened.topic;
+        entry.flags = f.flags;
+        emit(
+            events,
+            ReasmEvent::Chunk {
+                msg_id: mk.msg_id,
+                seq: f.seq,
+                have: have as u8,
+                tot: mk.tot,
+            },
+        );
+
+        // Check every index rather than the count, so nothing that slipped
+        // past the `seq < tot` check can complete a message with a hole.
+        let complete = match &entry.fec {
+            Some(_) => have == mk.tot as usize,
+            None => (0..mk.tot).all(|i| entry.chunks.contains_key(&i)),
+        };
+        if !complete {
+            return None;
+        }
+        let Partial {
+            chunks,
+            key_index,
+            topic,
+            flags,
+            fec: decoder,
+            ..
+        } = self.reasm.remove(&mk)?;
+        self.newest.insert(mk, f.ts_ms);
+        let mut bytes = match decoder {
+            Some(decoder) => {
+                let Some(bytes) = decoder.into_message() else {
+                    Stats::inc(&stats.dropped);
+                    return None;
+                };
+                bytes
+            }
+            None => (0..mk.tot)
+                .map(|i| chunks.get(&i).map(Vec::as_slice))
+                .collect::<Option<Vec<_>>>()?
+                .concat(),
+        };
+        if flags & FLAG_COMPRESSED != 0 {
+            let Some(inflated) = compress::inflate(&bytes) else {
+                Stats::inc(&stats.dropped);
+                return None;
+            };
+            bytes = inflated;
+        }
+
+        let (signed, signer) = if flags & FLAG_SIGNED != 0 {
+            let header = identity::Header {
+                topic,
+                ts_ms: f.ts_ms,
+                msg_id: mk.msg_id,
+            };
+            identity::open(&header, &bytes)
+        } else {
+            (&bytes[..], None)
+        };
+        let (nick, tagged) = split_nick(signed);
+        let (subtopic, body) = split_subtopic(tagged);
+        let (nick, subtopic, body) = (
+            nick.map(str::to_string),
+            subtopic.map(str::to_string),
+            body.to_vec(),
+        );
+        Stats::inc(&stats.completed);
+        stats.goodput.record(bytes.len());
+        tracing::debug!(
+            msg_id = %hex::encode(mk.msg_id),
+            topic = mk.topic,
+            len = bytes.len(),
+            "message complete"
+        );
+        emit(
+            events,
+            ReasmEvent::Completed {
+                msg_id: mk.msg_id,
+                topic: mk.topic,
+                len: bytes.len(),
+            },
+        );
+        // Beacons are untagged but still belong on the roster.
+        let presence = flags & FLAG_PRESENCE != 0;
+        let wanted = presence
+            || self
+                .subtopic_filter
+                .as_ref()
+                .is_none_or(|want| subtopic.as_ref() == Some(want));
+        wanted.then(|| Delivered {
+            topic,
+            msg_id: mk.msg_id,
+            subtopic,
+            bytes: body,
+            signer,
+            key_index,
+            hops: f.hops(),
+            presence,
+            nick,
+            ts_ms: f.ts_ms,
+        })
+    }
+}
+
+fn emit(events: &Option<UnboundedSender<ReasmEvent>>, ev: ReasmEvent) {
+    if let Some(tx) = events {
+        let _ = tx.send(ev);
+    }
+}
+
+/// Ask the sender of `mk` for the chunks in `missing`, sealed with `key`
+/// when there is one.
+fn nack_frame(
+    mk: &MsgKey,
+    missing: &[u8],
+    key: Option<&crypto::Keys>,
+    rng: &mut impl Rng,
+) -> Option<Frame> {
+    let mut f = Frame {
+        topic: mk.topic,
+        private: false,
+        plain: key.is_none(),
+        control: None,
+        ttl: NACK_TTL,
+        ttl0: NACK_TTL,
+        flags: FLAG_NACK,
+        msg_id: rng.r#gen(),
+        seq: 0,
+        tot: 1,
+        ts_ms: now_ms(),
+        payload: retransmit::nack_payload(&mk.msg_id, missing),
+    };
+    if let Some(k) = key {
+        f.payload = crypto::encrypt(&k.tx_key(), &f.nonce(), &f.aad(), &f.payload).ok()?;
+    }
+    Some(f)
+}
+
+/// A typing notice for `nick` on `topic`, sealed with `key` if there is one:
+/// a `Control::Typing` frame, which older readers refuse. It reaches direct
+/// neighbours only.
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub(crate) fn typing_frame(
+    topic: u8,
+    nick: &str,
+    key: Option<&crypto::Keys>,
+    rng: &mut impl Rng,
+) -> Option<Frame> {
+    let mut f = Frame {
+        topic,
+        private: false,
+        plain: key.is_none(),
+        control: Some(Control::Typing),
+        ttl: 0,
+        ttl0: 0,
+        flags: 0,
+        msg_id: rng.r#gen(),
+        seq: 0,
+        tot: 1,
+        ts_ms: now_ms(),
+        payload: nick.as_bytes().to_vec(),
+    };
+    if let Some(k) = key {
+        f.payload = crypto::encrypt(&k.tx_key(), &f.nonce(), &f.aad(), &f.payload).ok()?;
+    }
+    Some(f)
+}
+
+/// Acknowledge `msg_id` on `topic` as `acker`, sealed with `key` if there is
+/// one: a `Control::Ack` frame, which older readers refuse. The payload is the
+/// `msg_id` followed by the acker.
+pub(crate) fn ack_frame(
+    topic: u8,
+    msg_id: &MsgId,
+    acker: &[u8; ACKER_LEN],
+    key: Option<&crypto::Keys>,
+    rng: &mut impl Rng,
+) -> Option<Frame> {
+    let mut f = Frame {
+        topic,
+        private: false,
+        plain: key.is_none(),
+        control: Some(Control::Ack),
+        ttl: NACK_TTL,
+        ttl0: NACK_TTL,
+        flags: 0,
+        msg_id: rng.r#gen(),
+        seq: 0,
+        tot: 1,
+        ts_ms: now_ms(),
+        payload: [msg_id.as_slice(), acker].concat(),
+    };
+    if let Some(k) = key {
+        f.payload = crypto::encrypt(&k.tx_key(), &f.nonce(), &f.aad(), &f.payload).ok()?;
+    }
+    Some(f)
+}
+
+fn parse_ack(payload: &[u8]) -> Option<Ack> {
+    if payload.len() != MSG_ID_LEN + ACKER_LEN {
+        return None;
+    }
+    let (msg_id, acker) = payload.split_at(MSG_ID_LEN);
+    Some(Ack {
+        msg_id: msg_id.try_into().ok()?,
+        acker: acker.try_into().ok()?,
+    })
+}
+
+/// Report `p` as evicted before it completed.
+fn expire(events: &Option<UnboundedSender<ReasmEvent>>, mk: &MsgKey, p: &Partial) -> Incomplete {
+    emit(
+        events,
+        ReasmEvent::Expired {
+            msg_id: mk.msg_id,
+            have: p.have() as u8,
+            tot: mk.tot,
+        },
+    );
+    Incomplete {
+        msg_id: mk.msg_id,
+        tot: mk.tot,
+        missing: p.missing(mk.tot),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::plain_frames;
+    use crate::{MAX_PAYLOAD, pack_frame};
+
+    /// A node on topic 7 with no keys, acknowledging as `[1; 32]`.
+    fn acking_node() -> MeshNode {
+        MeshNode::new(
+            Some(7),
+            false,
+            Vec::new(),
+            Arc::new(Stats::default()),
+            None,
+            Originated::new(),
+        )
+        .with_ack_as(Some([1; ACKER_LEN]))
+    }
+
+    /// A relaying node on topic 7 with no keys.
+    fn relaying_node() -> MeshNode {
+        MeshNode::new(
+            Some(7),
+            true,
+            Vec::new(),
+            Arc::new(Stats::default()),
+            None,
+            Originated::new(),
+        )
+    }
+
+    #[test]
+    fn a_frame_is_relayed_only_once() {
+        let frames = plain_frames(7, [1, 2, 3, 4, 5, 6], &[b'x'; 3 * MAX_PAYLOAD]);
+        // With `seen` forgetting the frame at once, only `relayed` stops
+        // the second copy.
+        let mut node = relaying_node().with_seen_ttl(Duration::ZERO);
+        let relays: Vec<Frame> = [&frames[0], &frames[0]]
+            .into_iter()
+            .filter_map(|md| node.handle(md).relay)
+            .collect();
+        assert_eq!(relays.len(), 1);
+        assert_eq!((relays[0].seq, relays[0].ttl), (0, 2));
+    }
+
+    #[test]
+    fn messages_sharing_a_msg_id_are_each_delivered() {
+        let id = [1, 2, 3, 4, 5, 6];
+        let long = plain_frames(7, id, &[b'x'; 2 * MAX_PAYLOAD]);
+        let short = plain_frames(7, id, b"short");
+        let elsewhere = plain_frames(8, id, b"short");
+        let mut node = MeshNode::new(
+            None,
+            false,
+            Vec::new(),
+            Arc::new(Stats::default()),
+            None,
+            Originated::new(),
+        );
+        let delivered: Vec<(u8, String)> = [&long[0], &short[0], &elsewhere[0], &long[1]]
+            .into_iter()
+            .filter_map(|md| node.handle(md).delivered)
+            .map(|d| (d.topic, d.text()))
+            .collect();
+        assert_eq!(
+            delivered,
+            [
+                (7, "short".to_string()),
+                (8, "short".to_string()),
+                (7, "x".repeat(2 * MAX_PAYLOAD)),
+            ]
+        );
+    }
+
+    #[test]
+    fn an_empty_message_is_delivered_as_empty() {
+        let frames = plain_frames(7, [1, 2, 3, 4, 5, 6], b"");
+        assert_eq!(frames.len(), 1);
+        let d = relaying_node().handle(&frames[0]).delivered.unwrap();
+        assert!(d.bytes.is_empty());
+        assert_eq!(d.text(), EMPTY_MSG);
+    }
+
+    #[test]
+    fn chunks_arriving_out_of_order_reassemble_in_order() {
+        let body: Vec<u8> = (0..3 * MAX_PAYLOAD as u8).collect();
+        let frames = plain_frames(7, [1, 2, 3, 4, 5, 6], &body);
+        let mut node = relaying_node();
+        assert!(node.handle(&frames[2]).delivered.is_none());
+        assert!(node.handle(&frames[0]).delivered.is_none());
+        let d = node.handle(&frames[1]).delivered.unwrap();
+        assert_eq!(d.bytes, body);
+    }
+
+    #[test]
+    fn a_relay_forwards_topics_it_does_not_show() {
+        let frames = plain_frames(8, [1, 2, 3, 4, 5, 6], b"elsewhere");
+        let out = relaying_node().handle(&frames[0]);
+        assert!(out.delivered.is_none());
+        assert_eq!(out.relay.map(|f| f.topic), Some(8));
+        assert!(acking_node().handle(&frames[0]).relay.is_none());
+    }
+
+    #[test]
+    fn each_relay_adds_a_hop() {
+        let frames = plain_frames(7, [1, 2, 3, 4, 5, 6], b"hi");
+        let direct = acking_node().handle(&frames[0]).delivered.unwrap();
+        assert_eq!(direct.hops, 0);
+        let once = relaying_node().handle(&frames[0]).relay.unwrap();
+        let twice = relaying_node().handle(&pack_frame(&once)).relay.unwrap();
+        assert_eq!((once.ttl, twice.ttl, twice.ttl0), (2, 1, 3));
+        let far = acking_node().handle(&pack_frame(&twice)).delivered.unwrap();
+        assert_eq!(far.hops, 2);
+    }
+
+    #[test]
+    fn a_nick_prefix_round_trips_and_is_delivered() {
+        let body = tag_message(Some("ana"), Some("ops"), "hi");
+        let (nick, rest) = split_nick(body.as_bytes());
+        assert_eq!(nick, Some("ana"));
+        assert_eq!(split_subtopic(rest), (Some("ops"), &b"hi"[..]));
+        assert_eq!(split_nick(b"hi"), (None, &b"hi"[..]));
+        // An unterminated prefix is part of the text.
+        assert_eq!(split_nick(b"\x1eana"), (None, &b"\x1eana"[..]));
+
+        let body = tag_message(Some("ana"), None, "hi");
+        let frames = plain_frames(7, [1, 2, 3, 4, 5, 6], body.as_bytes());
+        let d = relaying_node().handle(&frames[0]).delivered.unwrap();
+        assert_eq!((d.nick.as_deref(), d.text().as_str()), (Some("ana"), "hi"));
+    }
+
+    /// Log lines a `fmt` subscriber wrote.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_malformed_frame_logs_why_it_was_dropped() {
+        for (debug_frames, level) in [(false, "DEBUG"), (true, "WARN")] {
+            let logs = Captured::default();
+            let subscriber = tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::DEBUG)
+                .with_ansi(false)
+                .with_writer({
+                    let logs = logs.clone();
+                    move || logs.clone()
+                })
+                .finish();
+            let mut node = relaying_node().with_debug_frames(debug_frames);
+            tracing::subscriber::with_default(subscriber, || node.handle(&[0xff, 0xff, 9]));
+            let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+            assert!(
+                logs.contains(&format!("{level} ble_chirp::mesh: dropped frame len=3 reason=shorter than a frame header")),
+                "{logs}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_timed_out_message_reports_the_chunks_it_missed() {
+        let frames = plain_frames(7, [1, 2, 3, 4, 5, 6], &[b'x'; 4 * MAX_PAYLOAD]);
+        let mut node = relaying_node();
+        for md in [&frames[0], &frames[3]] {
+            node.handle(md);
+        }
+        let later = Instant::now() + REASM_TIMEOUT + Duration::from_secs(1);
+        let incomplete = node.sweep(later).incomplete;
+        assert_eq!(incomplete.len(), 1);
+        assert_eq!(
+            (incomplete[0].tot, &incomplete[0].missing[..]),
+            (4, &[1, 2][..])
+        );
+    }
+
+    #[test]
+    fn a_stale_partial_message_is_evicted_after_the_timeout() {
+        let frames = plain_frames(7, [1, 2, 3, 4, 5, 6], &[b'x'; 2 * MAX_PAYLOAD]);
+        let stats = Arc::new(Stats::default());
+        let mut node = MeshNode::new(
+            Some(7),
+            false,
+            Vec::new(),
+            stats.clone(),
+            None,
+            Originated::new(),
+        )
+        .with_reasm_timeout(Duration::from_secs(10));
+        let start = Instant::now();
+        node.handle(&frames[0]);
+        assert!(
+            node.sweep(start + Duration::from_secs(9))
+                .incomplete
+                .is_empty()
+        );
+        assert_eq!(
+            node.sweep(start + Duration::from_secs(11)).incomplete.len(),
+            1
+        );
+        let s = stats.snapshot();
+        assert_eq!((s.evicted, s.in_flight), (1, 0));
+        // What arrives afterwards starts over rather than completing it.
+        assert!(node.handle(&frames[1]).delivered.is_none());
+    }
+
+    #[test]
+    fn a_new_message_past_max_reasm_evicts_the_oldest() {
+        let mut node = relaying_node().with_max_reasm(2);
+        let body = [b'x'; 2 * MAX_PAYLOAD];
+        let mut evicted = Vec::new();
+        for n in 1..=3 {
+            let frames = plain_frames(7, [n; 6], &body);
+            evicted.extend(node.handle(&frames[0]).incomplete);
+        }
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(
+            (evicted[0].msg_id, &evicted[0].missing[..]),
+            ([1; 6], &[1][..])
+        );
+        // The two newest are still being reassembled.
+        for n in 2..=3 {
+            let frames = plain_frames(7, [n; 6], &body);
+            assert!(node.handle(&frames[1]).delivered.is_some());
+        }
+    }
+
+    #[test]
+    fn one_reassembled_message_is_acked_once() {
+        let mut node = acking_node();
+        let msg_id = [1, 2, 3, 4, 5, 6];
+        let frames = plain_frames(7, msg_id, &[b'x'; 3 * MAX_PAYLOAD]);
+        assert_eq!(frames.len(), 3);
+        // Every chunk heard twice, as with `--repeat 2`.
+        let acks: Vec<Frame> = frames
+            .iter()
+            .chain(&frames)
+            .filter_map(|md| node.handle(md).ack)
+            .collect();
+        assert_eq!(acks.len(), 1);
+
+        let ack = unpack_frame(&pack_frame(&acks[0])).unwrap();
+        assert_eq!(ack.version(), crate::VER_CONTROL_PLAIN);
+        assert_eq!(ack.kind(), FrameKind::Ack);
+        let heard = parse_ack(&ack.payload).unwrap();
+        assert_eq!((heard.msg_id, heard.acker), (msg_id, [1; ACKER_LEN]));
+    }
+
+    #[test]
+    fn replaying_a_delivered_message_delivers_nothing() {
+        let frames = plain_frames(7, [1, 2, 3, 4, 5, 6], &[b'x'; 3 * MAX_PAYLOAD]);
+        // `seen` catches a replay while it remembers the chunks; with it
+        // forgetting them at once, the completed message's timestamp does.
+        for seen_ttl in [SEEN_TTL, Duration::ZERO] {
+            let stats = Arc::new(Stats::default());
+            let mut node = MeshNode::new(
+                Some(7),
+                false,
+                Vec::new(),
+                stats.clone(),
+                None,
+                Originated::new(),
+            )
+            .with_seen_ttl(seen_ttl);
+            let delivered = |node: &mut MeshNode| {
+                frames
+                    .iter()
+                    .filter_map(|md| node.handle(md).delivered)
+                    .count()
+            };
+            assert_eq!(delivered(&mut node), 1);
+            assert_eq!(delivered(&mut node), 0, "seen_ttl {seen_ttl:?}");
+            let s = stats.snapshot();
+            assert_eq!((s.completed, s.dropped_dup + s.stale), (1, 3));
+        }
+    }
+
+    #[test]
+    fn a_seen_frame_is_forgotten_after_the_seen_ttl() {
+        let frames = plain_frames(7, [1, 2, 3, 4, 5, 6], &[b'x'; 3 * MAX_PAYLOAD]);
+        let stats = Arc::new(Stats::default());
+        let mut node = MeshNode::new(
+            Some(7),
+            false,
+            Vec::new(),
+            stats.clone(),
+            None,
+            Originated::new(),
+        )
+        .with_seen_ttl(Duration::from_millis(50));
+        node.handle(&frames[0]);
+        node.handle(&frames[0]);
+        assert_eq!(stats.snapshot().dropped_dup, 1);
+        std::thread::sleep(Duration::from_millis(60));
+        node.handle(&frames[0]);
+        assert_eq!(stats.snapshot().dropped_dup, 1, "expired, so not a duplicate");
+        // Seeing it again restarted its TTL.
+        node.handle(&frames[0]);
+        assert_eq!(stats.snapshot().dropped_dup, 2);
+    }
+
+    #[test]
+    fn a_seeded_rng_fixes_msg_ids_and_backoffs() {
+        let frames = plain_frames(7, [1, 2, 3, 4, 5, 6], &[b'x'; 3 * MAX_PAYLOAD]);
+        let mut node = acking_node()
+            .with_nack_after(Some(Duration::ZERO))
+            .with_rng(StdRng::seed_from_u64(42));
+        let mut want = StdRng::seed_from_u64(42);
+        // A chunk short, so every sweep asks for it; then the ack.
+        let mut nacks = Vec::new();
+        for md in &frames[..2] {
+            nacks.extend(node.handle(md).nacks);
+        }
+        nacks.extend(node.sweep(Instant::now()).nacks);
+        assert!(!nacks.is_empty());
+        for nack in &nacks {
+            assert_eq!(nack.msg_id, want.r#gen::<MsgId>());
+        }
+        let last = node.handle(&frames[2]);
+        for nack in &last.nacks {
+            assert_eq!(nack.msg_id, want.r#gen::<MsgId>());
+        }
+        assert_eq!(last.ack.unwrap().msg_id, want.r#gen::<MsgId>());
+
+        let typing = typing_frame(7, "ann", None, &mut StdRng::seed_from_u64(42)).unwrap();
+        assert_eq!(typing.msg_id, StdRng::seed_from_u64(42).r#gen::<MsgId>());
+
+        // The jitter window is 400 ms, doubling per 10 frames/s up to 6.4 s.
+        let mut rng = StdRng::seed_from_u64(42);
+        let backoffs = [0.0, 25.0, 1000.0].map(|density| crate::relay::backoff(density, &mut rng));
+        let mut want = StdRng::seed_from_u64(42);
+        let windows = [400, 1600, 6400];
+        assert_eq!(
+            backoffs,
+            windows.map(|w| Duration::from_millis(100 + want.gen_range(0..w)))
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::stats::Stats;
+
+/// Serve `stats` as Prometheus text on every request to `addr`. The request
+/// path is ignored; this is a scrape target, not a web server.
+pub async fn serve(addr: SocketAddr, stats: Arc<Stats>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("bind metrics endpoint {addr}"))?;
+    eprintln!("metrics on http://{addr}/metrics");
+    loop {
+        let (mut sock, _) = listener.accept().await?;
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let _ = sock.read(&mut buf).await;
+            let body = render(&stats);
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = sock.write_all(resp.as_bytes()).await;
+        });
+    }
+}
+
+/// Render the counters in Prometheus text exposition format.
+fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+    let rows: [(&str, &str, &str, &AtomicU64); 14] = [
+        (
+            "chirp_frames_received_total",
+            "counter",
+            "Frames parsed off the air",
+            &stats.frames_rx,
+        ),
+        (
+            "chirp_frames_relayed_total",
+            "counter",
+            "Frames scheduled for relay",
+            &stats.relayed,
+        ),
+        (
+            "chirp_relays_suppressed_total",
+            "counter",
+            "Relays skipped because a neighbour relayed the frame first",
+            &stats.relays_suppressed,
+        ),
+        (
+            "chirp_relays_dropped_total",
+            "counter",
+            "Relays and acks not sent because --max-relays were on air",
+            &stats.relays_dropped,
+        ),
+        (
+            "chirp_frames_dropped_total",
+            "counter",
+            "Frames and messages dropped, for any reason",
+            &stats.dropped,
+        ),
+        (
+            "chirp_frames_dropped_topic_total",
+            "counter",
+            "Frames for a topic this node doesn't follow",
+            &stats.dropped_topic,
+        ),
+        (
+            "chirp_frames_dropped_duplicate_total",
+            "counter",
+            "Frames already seen within the duplicate window",
+            &stats.dropped_dup,
+        ),
+        (
+            "chirp_decrypt_failures_total",
+            "counter",
+            "Frames that failed AEAD decryption",
+            &stats.decrypt_failures,
+        ),
+        (
+            "chirp_frames_stale_total",
+            "counter",
+            "Frames rejected as too old, too new, or replayed",
+            &stats.stale,
+        ),
+        (
+            "chirp_messages_completed_total",
+            "counter",
+            "Messages reassembled",
+            &stats.completed,
+        ),
+        (
+            "chirp_duplicate_identities_total",
+            "counter",
+            "Identities heard from more than one node",
+            &stats.duplicate_identities,
+        ),
+        (
+            "chirp_messages_evicted_total",
+            "counter",
+            "Partial messages given up on by timeout or to make room",
+            &stats.evicted,
+        ),
+        (
+            "chirp_messages_in_flight",
+            "gauge",
+            "Partially reassembled messages",
+            &stats.in_flight,
+        ),
+        (
+            "chirp_relay_queue",
+            "gauge",
+            "Relays waiting for or using the radio",
+            &stats.relay_queue,
+        ),
+    ];
+    for (name, kind, help, v) in rows {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        let _ = writeln!(out, "{name} {}", v.load(Ordering::Relaxed));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_metric_has_help_type_and_a_value() {
+        let stats = Stats::default();
+        Stats::add(&stats.frames_rx, 42);
+        Stats::set(&stats.relay_queue, 3);
+        let text = render(&stats);
+        assert!(text.ends_with('\n'));
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 14 * 3);
+        for metric in lines.chunks(3) {
+            let name = metric[0]
+                .strip_prefix("# HELP ")
+                .and_then(|rest| rest.split_once(' '))
+                .map(|(name, _)| name)
+                .unwrap();
+            assert!(name.starts_with("chirp_"), "{name}");
+            assert!(
+                name.chars().all(|c| c.is_ascii_lowercase() || c == '_'),
+                "{name}"
+            );
+            let kind = metric[1].strip_prefix(&format!("# TYPE {name} ")).unwrap();
+            assert_eq!(kind == "counter", name.ends_with("_total"), "{name}");
+            let value = metric[2].strip_prefix(&format!("{name} ")).unwrap();
+            value.parse::<u64>().unwrap();
+        }
+
+        assert!(lines.contains(&"chirp_frames_received_total 42"));
+        assert!(lines.contains(&"# TYPE chirp_relay_queue gauge"));
+        assert!(lines.contains(&"chirp_relay_queue 3"));
+        assert!(lines.contains(&"chirp_relays_dropped_total 0"));
+    }
+}
@@ -0,0 +1,195 @@
+//! Muted senders: `--mute`, the config file's `mute` list, and chat's
+//! `/mute`. `rx_loop` drops their messages once reassembled and verified, so
+//! they are never shown, but still relays their frames.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::crypto::PUBKEY_LEN;
+use crate::identity;
+use crate::mesh::Delivered;
+use crate::{MSG_ID_LEN, presence};
+
+/// One muted sender.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mute {
+    /// Messages signed by this key.
+    Key([u8; PUBKEY_LEN]),
+    /// Messages whose msg_id starts with these bytes, as chat labels senders
+    /// without a nickname.
+    MsgId(Vec<u8>),
+    /// Messages tagged with this nickname.
+    Nick(String),
+}
+
+/// Parse `key:HEX`, `id:HEX`, `nick:NAME`, or a bare 64-digit hex public key
+/// or nickname.
+pub fn parse_mute(s: &str) -> Result<Mute, String> {
+    if let Some(hex) = s.strip_prefix("key:") {
+        return identity::parse_pubkey(hex).map(Mute::Key);
+    }
+    if let Some(hex) = s.strip_prefix("id:") {
+        let prefix = hex::decode(hex).map_err(|e| format!("not hex: {e}"))?;
+        if prefix.is_empty() || prefix.len() > MSG_ID_LEN {
+            return Err(format!("msg_id prefix must be 1..={MSG_ID_LEN} bytes"));
+        }
+        return Ok(Mute::MsgId(prefix));
+    }
+    if let Some(nick) = s.strip_prefix("nick:") {
+        return presence::parse_nick(nick).map(Mute::Nick);
+    }
+    match identity::parse_pubkey(s) {
+        Ok(key) => Ok(Mute::Key(key)),
+        Err(_) => presence::parse_nick(s).map(Mute::Nick),
+    }
+}
+
+impl Mute {
+    fn matches(&self, d: &Delivered) -> bool {
+        match self {
+            Mute::Key(key) => d.signer.as_ref() == Some(key),
+            Mute::MsgId(prefix) => d.msg_id.starts_with(prefix),
+            Mute::Nick(nick) => d.nick.as_ref() == Some(nick),
+        }
+    }
+}
+
+/// Round-trips through `parse_mute`.
+impl fmt::Display for Mute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mute::Key(key) => write!(f, "key:{}", hex::encode(key)),
+            Mute::MsgId(prefix) => write!(f, "id:{}", hex::encode(prefix)),
+            Mute::Nick(nick) => write!(f, "nick:{nick}"),
+        }
+    }
+}
+
+/// The mutes in force, shared between `rx_loop` and whoever edits them.
+#[derive(Clone, Default)]
+pub struct MuteList(Arc<Mutex<Vec<Mute>>>);
+
+// Only chat's `/mute` and `/unmute` change the list once it is running.
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+impl MuteList {
+    pub fn new(mutes: Vec<Mute>) -> Self {
+        MuteList(Arc::new(Mutex::new(mutes)))
+    }
+
+    /// Add `m`; false if it was already muted.
+    pub fn add(&self, m: Mute) -> bool {
+        let mut mutes = self.0.lock().unwrap();
+        if mutes.contains(&m) {
+            return false;
+        }
+        mutes.push(m);
+        true
+    }
+
+    /// Remove `m`; false if it wasn't muted.
+    pub fn remove(&self, m: &Mute) -> bool {
+        let mut mutes = self.0.lock().unwrap();
+        let before = mutes.len();
+        mutes.retain(|x| x != m);
+        mutes.len() < before
+    }
+
+    pub fn list(&self) -> Vec<Mute> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Whether any mute matches `d`.
+    pub fn is_muted(&self, d: &Delivered) -> bool {
+        self.0.lock().unwrap().iter().any(|m| m.matches(d))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(
+        msg_id: [u8; MSG_ID_LEN],
+        signer: Option<[u8; 32]>,
+        nick: Option<&str>,
+    ) -> Delivered {
+        Delivered {
+            topic: 7,
+            msg_id,
+            subtopic: None,
+            bytes: b"hi".to_vec(),
+            signer,
+            key_index: None,
+            hops: 0,
+            presence: false,
+            nick: nick.map(str::to_string),
+            ts_ms: 0,
+        }
+    }
+
+    #[test]
+    fn mutes_parse_by_prefix_and_print_back() {
+        let key = [0xab; PUBKEY_LEN];
+        let hex = hex::encode(key);
+        assert_eq!(parse_mute(&format!("key:{hex}")), Ok(Mute::Key(key)));
+        assert_eq!(parse_mute(&hex), Ok(Mute::Key(key)));
+        assert_eq!(parse_mute("id:0a0b"), Ok(Mute::MsgId(vec![0x0a, 0x0b])));
+        assert_eq!(parse_mute("nick:bo"), Ok(Mute::Nick("bo".into())));
+        assert_eq!(parse_mute("bo"), Ok(Mute::Nick("bo".into())));
+
+        for m in [
+            Mute::Key(key),
+            Mute::MsgId(vec![1, 2, 3]),
+            Mute::Nick("bo".into()),
+        ] {
+            assert_eq!(parse_mute(&m.to_string()), Ok(m));
+        }
+    }
+
+    #[test]
+    fn bad_mutes_are_refused() {
+        assert!(parse_mute("key:abcd").unwrap_err().contains("32 bytes"));
+        assert!(parse_mute("id:").unwrap_err().contains("1..=6 bytes"));
+        assert!(
+            parse_mute("id:00112233445566")
+                .unwrap_err()
+                .contains("1..=6 bytes")
+        );
+        assert!(parse_mute("id:xyz").unwrap_err().starts_with("not hex"));
+        assert!(parse_mute("nick:").is_err());
+    }
+
+    #[test]
+    fn each_mute_matches_only_its_own_sender() {
+        let ana = [1; PUBKEY_LEN];
+        let mutes = MuteList::new(vec![
+            Mute::Key(ana),
+            Mute::MsgId(vec![0xbe, 0xef]),
+            Mute::Nick("cy".into()),
+        ]);
+        let id = [0; MSG_ID_LEN];
+
+        assert!(mutes.is_muted(&message(id, Some(ana), None)));
+        assert!(!mutes.is_muted(&message(id, Some([2; PUBKEY_LEN]), None)));
+        assert!(mutes.is_muted(&message([0xbe, 0xef, 1, 2, 3, 4], None, None)));
+        assert!(!mutes.is_muted(&message([0xbe, 0xee, 1, 2, 3, 4], None, None)));
+        assert!(mutes.is_muted(&message(id, None, Some("cy"))));
+        assert!(!mutes.is_muted(&message(id, None, Some("cyd"))));
+        assert!(!mutes.is_muted(&message(id, None, None)));
+    }
+
+    #[test]
+    fn a_mute_is_added_and_removed_once() {
+        let mutes = MuteList::default();
+        let bo = Mute::Nick("bo".into());
+        assert!(mutes.add(bo.clone()));
+        assert!(!mutes.add(bo.clone()));
+        assert_eq!(mutes.list(), std::slice::from_ref(&bo));
+        assert!(mutes.is_muted(&message([0; MSG_ID_LEN], None, Some("bo"))));
+
+        assert!(mutes.remove(&bo));
+        assert!(!mutes.remove(&bo));
+        assert!(mutes.list().is_empty());
+        assert!(!mutes.is_muted(&message([0; MSG_ID_LEN], None, Some("bo"))));
+    }
+}
@@ -0,0 +1,134 @@
+//! Desktop notifications for chat: `chat --notify` pops one up for messages
+//! that arrive while the terminal doesn't have focus.
+
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+
+/// At most one notification this often. Messages in between are counted
+/// into the next one rather than shown.
+pub const NOTIFY_INTERVAL: Duration = Duration::from_secs(10);
+/// Characters of the message shown in a notification.
+const SNIPPET_LEN: usize = 80;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Notify {
+    #[default]
+    Off,
+    /// Only messages that mention your --nick
+    Mentions,
+    /// Every message received
+    All,
+}
+
+/// Whether `text` mentions `nick`: case-insensitively, and not as part of a
+/// longer word, so `al` isn't mentioned by "also". An empty nick is never
+/// mentioned.
+pub fn mentions(text: &str, nick: &str) -> bool {
+    if nick.is_empty() {
+        return false;
+    }
+    let (text, nick) = (text.to_lowercase(), nick.to_lowercase());
+    let word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+    text.match_indices(&nick).any(|(i, m)| {
+        !word(text[..i].chars().next_back()) && !word(text[i + m.len()..].chars().next())
+    })
+}
+
+/// Decides which messages to notify of, and sends the notifications.
+pub struct Notifier {
+    mode: Notify,
+    /// Focus as last reported by the terminal; notifications are only held
+    /// back once it has said it has focus, since not every terminal says.
+    focused: bool,
+    last: Option<Instant>,
+    /// Wanted but rate limited since the last notification.
+    held: usize,
+}
+
+impl Notifier {
+    pub fn new(mode: Notify) -> Self {
+        Self {
+            mode,
+            focused: false,
+            last: None,
+            held: 0,
+        }
+    }
+
+    pub fn set_focused(&mut self, focused: bool) {
+        self.focused = focused;
+    }
+
+    /// Notify of `body`, received in `room` and shown as `line`, if the mode
+    /// wants it and `NOTIFY_INTERVAL` has passed. `nick` is ours, for
+    /// `Notify::Mentions`.
+    pub fn message(
+        &mut self,
+        room: &str,
+        line: &str,
+        body: &str,
+        nick: Option<&str>,
+        now: Instant,
+    ) {
+        let wanted = match self.mode {
+            Notify::Off => false,
+            Notify::Mentions => nick.is_some_and(|n| mentions(body, n)),
+            Notify::All => true,
+        };
+        if !wanted || self.focused {
+            return;
+        }
+        if self
+            .last
+            .is_some_and(|t| now.saturating_duration_since(t) < NOTIFY_INTERVAL)
+        {
+            self.held += 1;
+            return;
+        }
+        self.last = Some(now);
+        let mut snippet: String = line.chars().take(SNIPPET_LEN).collect();
+        if snippet.len() < line.len() {
+            snippet.push('…');
+        }
+        if self.held > 0 {
+            snippet.push_str(&format!(" (+{} more)", self.held));
+            self.held = 0;
+        }
+        let summary = format!("ble-chirp: {room}");
+        // Showing one can block on the notification daemon.
+        tokio::task::spawn_blocking(move || {
+            let _ = notify_rust::Notification::new()
+                .summary(&summary)
+                .body(&snippet)
+                .show();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_mention_is_the_nick_as_a_whole_word_in_any_case() {
+        assert!(mentions("hey ana", "ana"));
+        assert!(mentions("ANA, are you there?", "ana"));
+        assert!(mentions("ask Ana", "aNA"));
+        assert!(mentions("@ana: look", "ana"));
+        assert!(mentions("(ana)", "ana"));
+        assert!(mentions("ana", "ana"));
+        // Found past an earlier match inside a word.
+        assert!(mentions("banana for ana", "ana"));
+    }
+
+    #[test]
+    fn a_nick_inside_a_longer_word_or_an_empty_one_is_no_mention() {
+        assert!(!mentions("banana", "ana"));
+        assert!(!mentions("anastasia", "ana"));
+        assert!(!mentions("ana2 said", "ana"));
+        assert!(!mentions("hello", ""));
+        assert!(!mentions("", ""));
+        assert!(!mentions(" ", ""));
+    }
+}
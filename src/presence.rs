@@ -0,0 +1,262 @@
+//! Presence beacons: with `--presence-interval`, a node periodically sends
+//! its nickname as a message flagged `FLAG_PRESENCE`, signed if it has an
+//! `--identity`. Receivers don't show these as messages; `rx_loop` keeps a
+//! roster of who has been heard recently instead, and reports who joins and
+//! who goes quiet.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::mesh::Delivered;
+use crate::send_queue::{Priority, SendQueue};
+use crate::{TxOpts, crypto, tx};
+
+/// With the two marks `tag_message` adds, a nickname still fits in one
+/// `MAX_PAYLOAD` chunk.
+pub const MAX_NICK_LEN: usize = 16;
+/// A peer not heard from for this long leaves the roster.
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// clap value parser for `--nick`.
+pub fn parse_nick(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.len() > MAX_NICK_LEN {
+        return Err(format!("nickname must be 1..={MAX_NICK_LEN} bytes"));
+    }
+    if s.chars().any(char::is_control) {
+        return Err("nickname must not contain control characters".into());
+    }
+    Ok(s.to_string())
+}
+
+/// 256-colour palette index for `nick`, the same on every run and every
+/// node, skipping the 16 system colours.
+pub fn nick_color(nick: &str) -> u8 {
+    // FNV-1a: stable across builds, unlike `DefaultHasher`.
+    let hash = nick.bytes().fold(0x811c_9dc5u32, |h, b| {
+        (h ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    });
+    (hash % 216) as u8 + 16
+}
+
+/// Validate `--presence-interval`: beacons must come often enough that a
+/// receiver hears a few of them per `PRESENCE_TIMEOUT`.
+pub fn interval(secs: u64) -> anyhow::Result<Duration> {
+    let interval = Duration::from_secs(secs);
+    if interval.is_zero() || interval > PRESENCE_TIMEOUT / 3 {
+        anyhow::bail!(
+            "--presence-interval must be 1..={} seconds",
+            (PRESENCE_TIMEOUT / 3).as_secs()
+        );
+    }
+    Ok(interval)
+}
+
+/// Advertise `nick` every `interval`, forever. With a `queue`, beacons wait
+/// behind the user's own messages.
+pub async fn announce(
+    adapter: btleplug::platform::Adapter,
+    opts: TxOpts,
+    nick: String,
+    id_key: Option<crypto::SigningKey>,
+    key: Option<crypto::Keys>,
+    interval: Duration,
+    queue: Option<SendQueue>,
+) {
+    let opts = TxOpts {
+        presence: true,
+        ..opts
+    };
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        match &queue {
+            Some(q) => {
+                let (adapter, nick) = (adapter.clone(), nick.clone());
+                let (key, id_key) = (key.clone(), id_key.clone());
+                q.push(Priority::Relay, async move {
+                    let _ = tx(
+                        adapter,
+                        &opts,
+                        nick.as_bytes(),
+                        key.as_ref(),
+                        id_key.as_ref(),
+                    )
+                    .await;
+                });
+            }
+            None => {
+                if let Err(e) = tx(
+                    adapter.clone(),
+                    &opts,
+                    nick.as_bytes(),
+                    key.as_ref(),
+                    id_key.as_ref(),
+                )
+                .await
+                {
+                    eprintln!("presence tx err: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Who a roster entry is: its signing key if the beacon was signed, else
+/// just the nickname.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PeerId {
+    Key([u8; 32]),
+    Nick(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub nick: String,
+    /// Public key whose signature over the beacon verified, if any.
+    pub key: Option<[u8; 32]>,
+    /// Relays the latest beacon passed through.
+    pub hops: u8,
+    pub last_seen: Instant,
+}
+
+impl Peer {
+    pub fn id(&self) -> PeerId {
+        match self.key {
+            Some(key) => PeerId::Key(key),
+            None => PeerId::Nick(self.nick.clone()),
+        }
+    }
+}
+
+/// Roster changes reported on `RxOpts::roster`.
+#[derive(Debug)]
+pub enum RosterEvent {
+    /// A peer was heard for the first time, or changed its nickname.
+    Joined(Peer),
+    /// A peer went `PRESENCE_TIMEOUT` without a beacon.
+    Left(Peer),
+}
+
+/// Peers heard from recently, by identity.
+pub struct Roster {
+    peers: HashMap<PeerId, Peer>,
+    timeout: Duration,
+    events: Option<UnboundedSender<RosterEvent>>,
+}
+
+impl Roster {
+    pub fn new(timeout: Duration, events: Option<UnboundedSender<RosterEvent>>) -> Self {
+        Self {
+            peers: HashMap::new(),
+            timeout,
+            events,
+        }
+    }
+
+    /// Record a presence beacon heard at `now`. Beacons whose body isn't a
+    /// valid nickname are ignored.
+    pub fn heard(&mut self, d: &Delivered, now: Instant) {
+        let Some(nick) = std::str::from_utf8(&d.bytes)
+            .ok()
+            .and_then(|s| parse_nick(s).ok())
+        else {
+            return;
+        };
+        let peer = Peer {
+            nick,
+            key: d.signer,
+            hops: d.hops,
+            last_seen: now,
+        };
+        let changed = self
+            .peers
+            .get(&peer.id())
+            .is_none_or(|old| old.nick != peer.nick);
+        if changed {
+            self.emit(RosterEvent::Joined(peer.clone()));
+        }
+        self.peers.insert(peer.id(), peer);
+    }
+
+    /// Drop peers not heard from within the timeout as of `now`.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let mut left = Vec::new();
+        self.peers.retain(|_, p| {
+            let fresh = now.saturating_duration_since(p.last_seen) < timeout;
+            if !fresh {
+                left.push(p.clone());
+            }
+            fresh
+        });
+        for p in left {
+            self.emit(RosterEvent::Left(p));
+        }
+    }
+
+    fn emit(&self, ev: RosterEvent) {
+        if let Some(tx) = &self.events {
+            let _ = tx.send(ev);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn beacon(nick: &str, signer: Option<[u8; 32]>) -> Delivered {
+        Delivered {
+            topic: 7,
+            msg_id: [0; 6],
+            subtopic: None,
+            bytes: nick.as_bytes().to_vec(),
+            signer,
+            key_index: None,
+            hops: 0,
+            presence: true,
+            nick: None,
+            ts_ms: 0,
+        }
+    }
+
+    #[test]
+    fn a_peer_leaves_the_roster_a_timeout_after_its_last_beacon() {
+        let (tx, mut events) = unbounded_channel();
+        let mut roster = Roster::new(Duration::from_secs(60), Some(tx));
+        let t0 = Instant::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+
+        roster.heard(&beacon("ana", None), at(0));
+        assert!(matches!(events.try_recv(), Ok(RosterEvent::Joined(p)) if p.nick == "ana"));
+        roster.heard(&beacon("ana", None), at(30));
+        roster.heard(&beacon("\u{7}", None), at(30));
+        roster.expire(at(89));
+        assert!(events.try_recv().is_err(), "still fresh and nothing new");
+
+        roster.expire(at(90));
+        assert!(matches!(events.try_recv(), Ok(RosterEvent::Left(p)) if p.nick == "ana"));
+        roster.expire(at(200));
+        assert!(events.try_recv().is_err(), "left only once");
+    }
+
+    #[test]
+    fn a_signed_peer_renaming_itself_joins_again_under_its_key() {
+        let (tx, mut events) = unbounded_channel();
+        let mut roster = Roster::new(PRESENCE_TIMEOUT, Some(tx));
+        let now = Instant::now();
+        roster.heard(&beacon("ana", Some([1; 32])), now);
+        roster.heard(&beacon("anna", Some([1; 32])), now);
+        let joined: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|ev| match ev {
+                RosterEvent::Joined(p) => (p.id(), p.nick),
+                RosterEvent::Left(p) => panic!("{} left", p.nick),
+            })
+            .collect();
+        let key = PeerId::Key([1; 32]);
+        assert_eq!(joined, [(key.clone(), "ana".into()), (key, "anna".into())]);
+    }
+}
@@ -11,7 +11,13 @@ pub struct RateLimiter {
 
 impl RateLimiter {
     pub fn new(rate: f64) -> Self {
-        let cap = rate.max(1.0);
+        Self::with_burst(rate, rate.max(1.0))
+    }
+
+    /// Like `new`, but allow up to `burst` acquisitions back to back after
+    /// idling before settling to `rate` per second.
+    pub fn with_burst(rate: f64, burst: f64) -> Self {
+        let cap = burst.max(1.0);
         Self {
             capacity: cap,
             tokens: cap,
@@ -22,16 +28,37 @@ impl RateLimiter {
 
     pub async fn acquire(&mut self) {
         loop {
-            self.refill();
-            if self.tokens >= 1.0 {
+            if self.available_tokens() >= 1.0 {
                 self.tokens -= 1.0;
                 return;
             }
-            let needed = (1.0 - self.tokens) / self.rate;
-            sleep(Duration::from_secs_f64(needed)).await;
+            sleep(self.wait_estimate()).await;
         }
     }
 
+    /// Refill at `rate` per second from now on; tokens already in the bucket
+    /// are kept.
+    pub fn set_rate(&mut self, rate: f64) {
+        self.refill();
+        self.rate = rate;
+    }
+
+    /// Tokens in the bucket right now, without taking one.
+    pub fn available_tokens(&mut self) -> f64 {
+        self.refill();
+        self.tokens
+    }
+
+    /// How long `acquire` would sleep before the next token; zero if one is
+    /// available now.
+    pub fn wait_estimate(&mut self) -> Duration {
+        let tokens = self.available_tokens();
+        if tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - tokens) / self.rate)
+    }
+
     fn refill(&mut self) {
         let now = Instant::now();
         let elapsed = now.duration_since(self.last).as_secs_f64();
@@ -39,3 +66,63 @@ impl RateLimiter {
         self.last = now;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_bucket_sends_a_burst_then_throttles_to_the_rate() {
+        let mut limiter = RateLimiter::with_burst(2.0, 5.0);
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_bucket_reports_its_tokens_and_the_wait_for_the_next() {
+        let mut limiter = RateLimiter::with_burst(2.0, 3.0);
+        assert_eq!(limiter.available_tokens(), 3.0);
+        assert_eq!(limiter.wait_estimate(), Duration::ZERO);
+        for _ in 0..3 {
+            limiter.acquire().await;
+        }
+        assert_eq!(limiter.available_tokens(), 0.0);
+        assert_eq!(limiter.wait_estimate(), Duration::from_millis(500));
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert_eq!(limiter.available_tokens(), 0.5);
+        assert_eq!(limiter.wait_estimate(), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_spacing_follows_set_rate_down_and_up() {
+        let mut limiter = RateLimiter::new(2.0);
+        let start = Instant::now();
+        let next_at = async |limiter: &mut RateLimiter| {
+            limiter.acquire().await;
+            start.elapsed()
+        };
+        next_at(&mut limiter).await;
+        next_at(&mut limiter).await;
+        assert_eq!(next_at(&mut limiter).await, Duration::from_millis(500));
+
+        limiter.set_rate(1.0);
+        assert_eq!(next_at(&mut limiter).await, Duration::from_millis(1500));
+        assert_eq!(next_at(&mut limiter).await, Duration::from_millis(2500));
+
+        limiter.set_rate(4.0);
+        assert_eq!(next_at(&mut limiter).await, Duration::from_millis(2750));
+        assert_eq!(next_at(&mut limiter).await, Duration::from_millis(3000));
+
+        // Time already waited at the old rate still counts.
+        tokio::time::advance(Duration::from_millis(250)).await;
+        limiter.set_rate(0.5);
+        assert_eq!(limiter.available_tokens(), 1.0);
+        assert_eq!(next_at(&mut limiter).await, Duration::from_millis(3250));
+        assert_eq!(next_at(&mut limiter).await, Duration::from_millis(5250));
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::bounded_set::{BoundedMap, BoundedSet};
+use crate::{Frame, FrameKind, MsgId, unpack_frame};
+
+// Much larger than the rx `seen` window so a frame that comes back to us after
+// `seen` has rolled over is still recognized.
+pub const OWN_IDS_CAP: usize = 16384;
+
+/// Default for `rx --max-relays`.
+pub const MAX_RELAYS: usize = 8;
+
+/// Every relay waits at least this long before advertising.
+const BACKOFF_MIN_MS: u64 = 100;
+/// Jitter window on a quiet channel.
+const BACKOFF_WINDOW_MS: u64 = 400;
+/// Each this many frames per second heard doubles the jitter window...
+const BACKOFF_DENSITY_STEP: f64 = 10.0;
+/// ...up to this many times (6.4 s).
+const BACKOFF_MAX_DOUBLINGS: u32 = 4;
+
+/// How long to wait before relaying, given the channel density in frames
+/// per second (see `stats::Density`). The more neighbours are transmitting,
+/// the more likely they are relaying the same frame, so the jitter window
+/// doubles with density to spread their relays apart.
+pub fn backoff(density: f64, rng: &mut impl Rng) -> Duration {
+    let doublings = ((density / BACKOFF_DENSITY_STEP) as u32).min(BACKOFF_MAX_DOUBLINGS);
+    let window = BACKOFF_WINDOW_MS << doublings;
+    Duration::from_millis(BACKOFF_MIN_MS + rng.gen_range(0..window))
+}
+
+/// Which topics a node forwards; see `rx --relay-topics`.
+#[derive(Clone, Debug, Default)]
+pub enum RelayTopics {
+    #[default]
+    All,
+    Only(Vec<u8>),
+    Except(Vec<u8>),
+}
+
+impl RelayTopics {
+    /// From `--relay-topics` / `--no-relay-topics`; clap keeps them exclusive.
+    pub fn from_lists(only: Vec<u8>, except: Vec<u8>) -> Self {
+        match (only.is_empty(), except.is_empty()) {
+            (false, _) => Self::Only(only),
+            (true, false) => Self::Except(except),
+            (true, true) => Self::All,
+        }
+    }
+
+    /// A private-topic frame's topic byte is masked, so it never matches a
+    /// list: it is forwarded under `Except` and not under `Only`.
+    pub fn allows(&self, f: &Frame) -> bool {
+        let listed = |topics: &[u8]| !f.private && topics.contains(&f.topic);
+        match self {
+            Self::All => true,
+            Self::Only(topics) => listed(topics),
+            Self::Except(topics) => !listed(topics),
+        }
+    }
+}
+
+/// Relays waiting out their backoff under `rx --suppress-relay`, and whether
+/// another node was heard relaying the same chunk meanwhile.
+/// `S` is the transport's `Source`.
+#[derive(Clone)]
+pub struct Pending<S>(Arc<Mutex<HashMap<ChunkKey, (S, bool)>>>);
+
+type ChunkKey = (MsgId, FrameKind, u8);
+
+impl<S> Default for Pending<S> {
+    fn default() -> Self {
+        Self(Arc::default())
+    }
+}
+
+impl<S: Clone + PartialEq> Pending<S> {
+    /// `f` will be relayed after a backoff; `source` is who we heard it from.
+    pub fn start(&self, f: &Frame, source: &S) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert((f.msg_id, f.kind(), f.seq), (source.clone(), false));
+    }
+
+    /// Note a received advertisement. A copy of a pending chunk that has
+    /// already been relayed, from anyone other than the node we first heard
+    /// it from, means a neighbour has covered it. The sender's own repeats
+    /// don't count.
+    pub fn overheard(&self, md: &[u8], source: &S) {
+        let Ok(f) = unpack_frame(md) else {
+            return;
+        };
+        if f.hops() == 0 {
+            return;
+        }
+        if let Some((first, heard)) = self.0.lock().unwrap().get_mut(&(f.msg_id, f.kind(), f.seq)) {
+            *heard |= first != source;
+        }
+    }
+
+    /// Stop tracking `f`; true if its relay should go ahead.
+    pub fn finish(&self, f: &Frame) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(&(f.msg_id, f.kind(), f.seq))
+            .is_none_or(|(_, heard)| !heard)
+    }
+}
+
+static GLOBAL_ORIGINATED: LazyLock<Originated> = LazyLock::new(Originated::new);
+
+/// msg_ids a node originated, so a peer bouncing our own message back is never
+/// relayed again.
+#[derive(Clone)]
+pub struct Originated(Arc<Mutex<BoundedSet<MsgId>>>);
+
+impl Originated {
+    pub fn new() -> Self {
+        Originated(Arc::new(Mutex::new(BoundedSet::new(OWN_IDS_CAP))))
+    }
+
+    /// The set for this process, shared by `tx` and `rx_loop` (e.g. in chat).
+    pub fn global() -> Self {
+        GLOBAL_ORIGINATED.clone()
+    }
+
+    pub fn mark(&self, msg_id: MsgId) {
+        self.0.lock().unwrap().insert(msg_id);
+    }
+
+    pub fn contains(&self, msg_id: &MsgId) -> bool {
+        self.0.lock().unwrap().contains(msg_id)
+    }
+}
+
+impl Default for Originated {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Chunks `RelayTracker` remembers relaying, across all messages.
+const RELAYED_CAP: usize = 4096;
+
+/// Tracks which chunks of each message this node has finished relaying so a
+/// supervisor can be told when the node has done its part for a `msg_id`.
+/// Bounded, so chunks of messages never fully relayed are forgotten rather
+/// than kept forever.
+#[derive(Clone)]
+pub struct RelayTracker(Arc<Mutex<Relayed>>);
+
+struct Relayed {
+    chunks: BoundedSet<ChunkKey>,
+    /// Distinct chunks relayed so far, per message not yet done.
+    counts: BoundedMap<MsgId, usize>,
+}
+
+impl Default for RelayTracker {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Relayed {
+            chunks: BoundedSet::new(RELAYED_CAP),
+            counts: BoundedMap::new(RELAYED_CAP),
+        })))
+    }
+}
+
+impl RelayTracker {
+    /// Record that `f` finished relaying. Returns true exactly once per
+    /// message, when the last of its `tot` data chunks completes. For an
+    /// FEC-coded message those are its source blocks or data shards: parity
+    /// shards and repair droplets don't count, nor do handshakes.
+    pub fn finish(&self, f: &Frame) -> bool {
+        if f.kind() != FrameKind::Data || f.seq >= f.tot {
+            return false;
+        }
+        let mut relayed = self.0.lock().unwrap();
+        if !relayed.chunks.insert((f.msg_id, f.kind(), f.seq)) {
+            return false;
+        }
+        let count = match relayed.counts.get_mut(&f.msg_id) {
+            Some(count) => {
+                *count += 1;
+                *count
+            }
+            None => {
+                relayed.counts.insert(f.msg_id, 1);
+                1
+            }
+        };
+        if count < f.tot as usize {
+            return false;
+        }
+        relayed.counts.remove(&f.msg_id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::plain_frames;
+    use crate::{MAX_PAYLOAD, unpack_frame};
+
+    #[test]
+    fn a_message_is_done_once_every_chunk_is_relayed() {
+        let frames: Vec<Frame> = plain_frames(7, [1; 6], &[b'x'; 3 * MAX_PAYLOAD])
+            .iter()
+            .map(|md| unpack_frame(md).unwrap())
+            .collect();
+        let relays = RelayTracker::default();
+        // Repeats of a chunk count once, and done fires only once.
+        let done = [0, 0, 2, 1, 1, 2].map(|i| relays.finish(&frames[i]));
+        assert_eq!(done, [false, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn handshakes_and_parity_shards_do_not_count() {
+        let mut chunk = unpack_frame(&plain_frames(7, [2; 6], b"hi")[0]).unwrap();
+        chunk.tot = 2;
+        let mut handshake = chunk.clone();
+        handshake.flags = crate::FLAG_HANDSHAKE;
+        let mut parity = chunk.clone();
+        parity.flags = crate::FLAG_RS | crate::FLAG_PARITY;
+        parity.seq = 2;
+        let relays = RelayTracker::default();
+        assert!(!relays.finish(&handshake));
+        assert!(!relays.finish(&parity));
+        assert!(!relays.finish(&chunk));
+        chunk.seq = 1;
+        assert!(relays.finish(&chunk));
+    }
+
+    #[test]
+    fn a_relay_is_suppressed_only_once_another_node_has_relayed_it() {
+        let md = plain_frames(7, [3; 6], b"hi").remove(0);
+        let f = unpack_frame(&md).unwrap();
+        let mut relayed = f.clone();
+        relayed.ttl -= 1;
+        let relayed = crate::pack_frame(&relayed);
+        let (sender, relay, other) = (1, 2, 3);
+
+        // Heard only from the sender, repeats and all: we are the only
+        // witness carrying it on, so the relay goes ahead.
+        let pending = Pending::default();
+        pending.start(&f, &sender);
+        pending.overheard(&md, &sender);
+        pending.overheard(&md, &other);
+        pending.overheard(&relayed, &sender);
+        assert!(pending.finish(&f));
+
+        // A second witness relayed it meanwhile: ours is redundant.
+        pending.start(&f, &sender);
+        pending.overheard(&relayed, &relay);
+        assert!(!pending.finish(&f));
+        // Only until the chunk's next relay.
+        pending.start(&f, &sender);
+        assert!(pending.finish(&f));
+    }
+}
@@ -0,0 +1,120 @@
+//! NACK-driven repair. A receiver stuck on a message advertises a NACK frame
+//! naming the message and the chunks it is missing; a sender running with
+//! `tx --serve` re-advertises just those chunks from its cache.
+//!
+//! A NACK is a single-chunk frame with `FLAG_NACK` set and its own fresh
+//! `msg_id`, so it never shares a nonce with the message it asks about. Its
+//! payload is the target `msg_id` followed by one byte per missing `seq`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use futures::StreamExt;
+
+use crate::bounded_set::BoundedMap;
+use crate::transport::Transport;
+use crate::{Frame, FrameKind, MSG_ID_LEN, MsgId, RateLimiter, crypto, do_relay, unpack_frame};
+
+/// Messages whose frames are kept for repair.
+pub const CACHE_CAP: usize = 16;
+
+static GLOBAL_CACHE: LazyLock<Cache> = LazyLock::new(Cache::new);
+
+/// Data frames of recently sent messages by `msg_id`, then `seq`; one frame
+/// per topic the message went out on.
+#[derive(Clone)]
+pub struct Cache(Arc<Mutex<BoundedMap<MsgId, BySeq>>>);
+
+type BySeq = HashMap<u8, Vec<Frame>>;
+
+impl Cache {
+    pub fn new() -> Self {
+        Cache(Arc::new(Mutex::new(BoundedMap::new(CACHE_CAP))))
+    }
+
+    /// The cache for this process, filled by `tx` and read by `serve`.
+    pub fn global() -> Self {
+        GLOBAL_CACHE.clone()
+    }
+
+    pub fn insert(&self, msg_id: MsgId, frames: HashMap<u8, Vec<Frame>>) {
+        self.0.lock().unwrap().insert(msg_id, frames);
+    }
+
+    /// Frames for the requested chunks that are still cached.
+    fn lookup(&self, msg_id: &MsgId, seqs: &[u8]) -> Vec<Frame> {
+        let cache = self.0.lock().unwrap();
+        let Some(chunks) = cache.get(msg_id) else {
+            return Vec::new();
+        };
+        seqs.iter()
+            .filter_map(|seq| chunks.get(seq))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn nack_payload(msg_id: &MsgId, missing: &[u8]) -> Vec<u8> {
+    let mut p = msg_id.to_vec();
+    p.extend_from_slice(missing);
+    p
+}
+
+/// Inverse of `nack_payload`. `None` if there is no `msg_id` or nothing is
+/// asked for.
+pub fn parse_nack(payload: &[u8]) -> Option<(MsgId, &[u8])> {
+    let (id, missing) = payload.split_first_chunk::<MSG_ID_LEN>()?;
+    (!missing.is_empty()).then_some((*id, missing))
+}
+
+/// Answer NACKs for messages in `Cache::global()` until the transport's
+/// event stream ends. NACKs are opened with any of `keys`, or taken as is
+/// when there are none; repairs share one rate limiter.
+pub async fn serve<T: Transport>(
+    transport: T,
+    keys: Vec<crypto::Keys>,
+    rate: f64,
+) -> anyhow::Result<()> {
+    let cache = Cache::global();
+    let mut rl = RateLimiter::new(rate);
+    let mut events = transport.events();
+    while let Some(md) = events.next().await {
+        let Some(f) = unpack_frame(&md)
+            .ok()
+            .filter(|f| f.kind() == FrameKind::Nack)
+        else {
+            continue;
+        };
+        let Some(payload) = open(&keys, &f) else {
+            continue;
+        };
+        let Some((msg_id, missing)) = parse_nack(&payload) else {
+            continue;
+        };
+        let frames = cache.lookup(&msg_id, missing);
+        if frames.is_empty() {
+            continue;
+        }
+        println!("NACK for #{}: resending {:?}", hex::encode(msg_id), missing);
+        for f in frames {
+            rl.acquire().await;
+            do_relay(transport.clone(), f).await;
+        }
+    }
+    Ok(())
+}
+
+fn open(keys: &[crypto::Keys], f: &Frame) -> Option<Vec<u8>> {
+    if f.plain {
+        return keys.is_empty().then(|| f.payload.clone());
+    }
+    keys.iter()
+        .find_map(|k| k.decrypt(&f.nonce(), &f.aad(), &f.payload).ok())
+}
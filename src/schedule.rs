@@ -0,0 +1,232 @@
+//! Dwell/gap/repeat planning for `tx`.
+//!
+//! Reception model: a passive scanner listens for `SCAN_WINDOW_MS` out of
+//! every `SCAN_INTERVAL_MS`. An advertisement lasting `d` ms overlaps a scan
+//! window with probability `p = min(1, (d + W) / I)`. Sending a chunk `r` times
+//! at unrelated scan phases gives `1 - (1 - p)^r`.
+//!
+//! - `long-dwell`: advertise each chunk for a full scan cycle (`d = I + W`),
+//!   so every duty-cycled scanner sees it once; one pass, long airtime/chunk.
+//! - `short-dwell`: advertise for one scan window (`d = W`) and repeat enough
+//!   times to reach `TARGET_CATCH`; chunks are cycled round-robin so repeats
+//!   of one chunk land at different phases. Lower latency per pass, more
+//!   advertiser restarts.
+
+use clap::ValueEnum;
+
+use crate::fec::Fec;
+
+pub const DEFAULT_DWELL_MS: u64 = 500;
+pub const DEFAULT_GAP_MS: u64 = 60;
+const SCAN_WINDOW_MS: u64 = 100;
+const SCAN_INTERVAL_MS: u64 = 1000;
+const TARGET_CATCH: f64 = 0.95;
+// Small odd offset so repeats don't phase-lock with the scan interval.
+const PHASE_SKEW_MS: u64 = 7;
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum Strategy {
+    /// Use --dwell-ms as given, one pass
+    #[default]
+    Fixed,
+    /// Cover a whole scan cycle per chunk, one pass
+    LongDwell,
+    /// One scan window per chunk, repeated until likely caught
+    ShortDwell,
+}
+
+/// Named bundles of the knobs above, and of `--fec` and `--repeat-secs`, for
+/// users who don't want to tune them.
+///
+/// - `fast`: `fixed` strategy, 200 ms dwell, one pass. Lowest airtime.
+/// - `reliable`: `short-dwell` strategy (one scan window per chunk, repeated
+///   round-robin until ~95% catch probability), `fountain` FEC, and the whole
+///   schedule kept running for `RELIABLE_REPEAT_SECS`.
+///
+/// Each flag given explicitly overrides what the profile sets for it; see
+/// `resolve`.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Profile {
+    Fast,
+    Reliable,
+}
+
+/// How long `--profile reliable` keeps cycling through a message.
+pub const RELIABLE_REPEAT_SECS: u64 = 10;
+
+impl Profile {
+    fn knobs(self) -> Knobs {
+        match self {
+            Profile::Fast => Knobs {
+                strategy: Some(Strategy::Fixed),
+                dwell_ms: Some(200),
+                ..Knobs::default()
+            },
+            Profile::Reliable => Knobs {
+                strategy: Some(Strategy::ShortDwell),
+                fec: Some(Fec::Fountain),
+                repeat_secs: Some(RELIABLE_REPEAT_SECS),
+                ..Knobs::default()
+            },
+        }
+    }
+}
+
+/// The `tx` flags a `Profile` can set, as given on the command line: `None`
+/// leaves one to the profile, then to the strategy or built-in default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Knobs {
+    pub strategy: Option<Strategy>,
+    pub dwell_ms: Option<u64>,
+    pub gap_ms: Option<u64>,
+    pub repeat: Option<u32>,
+    pub repeat_secs: Option<u64>,
+    pub fec: Option<Fec>,
+}
+
+/// What `tx` sends with once `resolve` has settled every knob.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delivery {
+    pub dwell_ms: u64,
+    pub gap_ms: u64,
+    pub repeats: u32,
+    pub repeat_secs: Option<u64>,
+    pub fec: Fec,
+}
+
+/// Settle the delivery knobs: each explicit flag in `given` wins, then
+/// `profile`, then what the strategy plans. A profile's dwell belongs to its
+/// strategy, so an explicit `--strategy` drops it along with the strategy.
+pub fn resolve(profile: Option<Profile>, given: Knobs) -> Delivery {
+    let mut preset = profile.map(Profile::knobs).unwrap_or_default();
+    if given.strategy.is_some() {
+        preset.dwell_ms = None;
+        preset.gap_ms = None;
+    }
+    let strategy = given.strategy.or(preset.strategy).unwrap_or_default();
+    let plan = plan(
+        strategy,
+        given.dwell_ms.or(preset.dwell_ms),
+        given.gap_ms.or(preset.gap_ms),
+    );
+    Delivery {
+        dwell_ms: plan.dwell_ms,
+        gap_ms: plan.gap_ms,
+        repeats: given.repeat.unwrap_or(plan.repeats).max(1),
+        repeat_secs: given.repeat_secs.or(preset.repeat_secs),
+        fec: given.fec.or(preset.fec).unwrap_or_default(),
+    }
+}
+
+pub struct Plan {
+    pub dwell_ms: u64,
+    pub gap_ms: u64,
+    pub repeats: u32,
+}
+
+/// Dwell, gap and repeats for `strategy`. An explicit `dwell_ms` or `gap_ms`
+/// replaces the strategy's own, and `short-dwell` then counts its repeats
+/// for that dwell.
+pub fn plan(strategy: Strategy, dwell_ms: Option<u64>, gap_ms: Option<u64>) -> Plan {
+    let gap = gap_ms.unwrap_or(DEFAULT_GAP_MS);
+    match strategy {
+        Strategy::Fixed => Plan {
+            dwell_ms: dwell_ms.unwrap_or(DEFAULT_DWELL_MS),
+            gap_ms: gap,
+            repeats: 1,
+        },
+        Strategy::LongDwell => Plan {
+            dwell_ms: dwell_ms.unwrap_or(SCAN_INTERVAL_MS + SCAN_WINDOW_MS),
+            gap_ms: gap,
+            repeats: 1,
+        },
+        Strategy::ShortDwell => {
+            let dwell_ms = dwell_ms.unwrap_or(SCAN_WINDOW_MS);
+            let p = catch_probability(dwell_ms);
+            let repeats = if p >= 1.0 {
+                1
+            } else {
+                ((1.0 - TARGET_CATCH).ln() / (1.0 - p).ln()).ceil() as u32
+            };
+            Plan {
+                dwell_ms,
+                gap_ms: gap_ms.unwrap_or(SCAN_WINDOW_MS + PHASE_SKEW_MS),
+                repeats: repeats.max(1),
+            }
+        }
+    }
+}
+
+/// Probability a single advertisement of `dwell_ms` overlaps a scan window.
+pub fn catch_probability(dwell_ms: u64) -> f64 {
+    ((dwell_ms + SCAN_WINDOW_MS) as f64 / SCAN_INTERVAL_MS as f64).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_fill_in_what_the_flags_leave_unset() {
+        let fast = resolve(Some(Profile::Fast), Knobs::default());
+        assert_eq!(
+            fast,
+            Delivery {
+                dwell_ms: 200,
+                gap_ms: DEFAULT_GAP_MS,
+                repeats: 1,
+                repeat_secs: None,
+                fec: Fec::None,
+            }
+        );
+
+        let reliable = resolve(Some(Profile::Reliable), Knobs::default());
+        assert_eq!(reliable.dwell_ms, SCAN_WINDOW_MS);
+        assert_eq!(reliable.gap_ms, SCAN_WINDOW_MS + PHASE_SKEW_MS);
+        assert_eq!(reliable.repeats, 14, "1 - 0.8^14 is the first past 95%");
+        assert_eq!(reliable.repeat_secs, Some(RELIABLE_REPEAT_SECS));
+        assert_eq!(reliable.fec, Fec::Fountain);
+
+        let none = resolve(None, Knobs::default());
+        assert_eq!((none.dwell_ms, none.repeats), (DEFAULT_DWELL_MS, 1));
+        assert_eq!((none.repeat_secs, none.fec), (None, Fec::None));
+    }
+
+    #[test]
+    fn explicit_flags_beat_the_profile() {
+        let given = Knobs {
+            dwell_ms: Some(300),
+            gap_ms: Some(20),
+            repeat: Some(2),
+            repeat_secs: Some(60),
+            fec: Some(Fec::None),
+            ..Knobs::default()
+        };
+        assert_eq!(
+            resolve(Some(Profile::Reliable), given),
+            Delivery {
+                dwell_ms: 300,
+                gap_ms: 20,
+                repeats: 2,
+                repeat_secs: Some(60),
+                fec: Fec::None,
+            }
+        );
+
+        // A longer dwell needs fewer repeats to reach the target.
+        let dwell = Knobs {
+            dwell_ms: Some(400),
+            ..Knobs::default()
+        };
+        let reliable = resolve(Some(Profile::Reliable), dwell);
+        assert_eq!((reliable.dwell_ms, reliable.repeats), (400, 5));
+
+        // An explicit strategy takes its own dwell, not the profile's.
+        let long = Knobs {
+            strategy: Some(Strategy::LongDwell),
+            ..Knobs::default()
+        };
+        let fast = resolve(Some(Profile::Fast), long);
+        assert_eq!(fast.dwell_ms, SCAN_INTERVAL_MS + SCAN_WINDOW_MS);
+    }
+}
@@ -0,0 +1,128 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+/// Higher variants are sent first; ties go out in enqueue order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Relay,
+    User,
+}
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Entry {
+    prio: Priority,
+    seq: u64,
+    job: Job,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.prio == other.prio && self.seq == other.seq
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.prio
+            .cmp(&other.prio)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    heap: BinaryHeap<Entry>,
+    next_seq: u64,
+}
+
+/// Serializes everything that advertises so only one send owns the radio at a
+/// time, and lets the local user's messages jump ahead of queued relays.
+#[derive(Clone)]
+pub struct SendQueue {
+    inner: Arc<Mutex<Inner>>,
+    notify: Arc<Notify>,
+}
+
+impl SendQueue {
+    /// Create a queue and spawn its worker.
+    pub fn spawn() -> Self {
+        let q = SendQueue {
+            inner: Arc::default(),
+            notify: Arc::new(Notify::new()),
+        };
+        let worker = q.clone();
+        tokio::spawn(async move {
+            loop {
+                while let Some(job) = worker.pop() {
+                    job.await;
+                }
+                worker.notify.notified().await;
+            }
+        });
+        q
+    }
+
+    pub fn push(&self, prio: Priority, job: impl Future<Output = ()> + Send + 'static) {
+        let mut inner = self.inner.lock().unwrap();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.heap.push(Entry {
+            prio,
+            seq,
+            job: Box::pin(job),
+        });
+        self.notify.notify_one();
+    }
+
+    fn pop(&self) -> Option<Job> {
+        self.inner.lock().unwrap().heap.pop().map(|e| e.job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::{mpsc, oneshot};
+
+    #[tokio::test]
+    async fn a_user_message_goes_out_before_queued_relays() {
+        let q = SendQueue::spawn();
+        let (sent, mut order) = mpsc::unbounded_channel();
+        // Hold the worker busy so everything after this queues up.
+        let (release, held) = oneshot::channel::<()>();
+        let first = sent.clone();
+        q.push(Priority::Relay, async move {
+            held.await.unwrap();
+            first.send("busy").unwrap();
+        });
+        tokio::task::yield_now().await;
+        for (prio, name) in [
+            (Priority::Relay, "relay 1"),
+            (Priority::Relay, "relay 2"),
+            (Priority::User, "user"),
+        ] {
+            let sent = sent.clone();
+            q.push(prio, async move { sent.send(name).unwrap() });
+        }
+        release.send(()).unwrap();
+        let mut got = Vec::new();
+        for _ in 0..4 {
+            got.push(order.recv().await.unwrap());
+        }
+        assert_eq!(got, ["busy", "user", "relay 1", "relay 2"]);
+    }
+}
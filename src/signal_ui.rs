@@ -0,0 +1,150 @@
+use std::io::stdout;
+use std::time::Duration;
+
+use btleplug::api::{Central, CentralEvent, ScanFilter};
+use crossterm::{
+    event::{self, Event as CEvent},
+    execute,
+    terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
+};
+use futures::StreamExt;
+use ratatui::{backend::CrosstermBackend, prelude::*, widgets::*};
+
+use crate::{COMPANY_ID, unpack_frame};
+
+const HISTORY: usize = 200;
+const EMA_ALPHA: f64 = 0.2;
+
+/// Running RSSI readout for a single sender.
+#[derive(Default)]
+struct RssiTrack {
+    history: Vec<i16>,
+    ema: Option<f64>,
+    min: Option<i16>,
+    max: Option<i16>,
+}
+
+impl RssiTrack {
+    fn push(&mut self, rssi: i16) {
+        if self.history.len() >= HISTORY {
+            self.history.remove(0);
+        }
+        self.history.push(rssi);
+        let r = rssi as f64;
+        self.ema = Some(self.ema.map_or(r, |e| e + EMA_ALPHA * (r - e)));
+        self.min = Some(self.min.map_or(rssi, |m| m.min(rssi)));
+        self.max = Some(self.max.map_or(rssi, |m| m.max(rssi)));
+    }
+
+    fn current(&self) -> Option<i16> {
+        self.history.last().copied()
+    }
+
+    fn summary(&self) -> String {
+        let fmt = |v: Option<i16>| v.map_or("-".into(), |v| format!("{v} dBm"));
+        format!(
+            "current {}  min {}  max {}  ema {}",
+            fmt(self.current()),
+            fmt(self.min),
+            fmt(self.max),
+            self.ema.map_or("-".into(), |e| format!("{e:.1} dBm"))
+        )
+    }
+}
+
+/// Lock onto one sender (the first chirp seen on `topic`, or the peripheral
+/// whose id contains `peer`) and plot its RSSI until a key is pressed.
+pub async fn signal(
+    adapter: btleplug::platform::Adapter,
+    topic: Option<u8>,
+    peer: Option<String>,
+) -> anyhow::Result<()> {
+    adapter.start_scan(ScanFilter::default()).await?;
+    let mut events = adapter.events().await?;
+
+    let (rssi_tx, mut rssi_rx) = tokio::sync::mpsc::unbounded_channel::<(String, i16)>();
+    tokio::spawn(async move {
+        let mut locked = None;
+        while let Some(evt) = events.next().await {
+            match evt {
+                CentralEvent::ManufacturerDataAdvertisement {
+                    id,
+                    manufacturer_data,
+                } if locked.is_none() => {
+                    let Some(f) = manufacturer_data
+                        .get(&COMPANY_ID)
+                        .and_then(|md| unpack_frame(md).ok())
+                    else {
+                        continue;
+                    };
+                    let name = format!("{id:?}");
+                    if topic.is_some_and(|t| t != f.topic)
+                        || peer.as_ref().is_some_and(|p| !name.contains(p.as_str()))
+                    {
+                        continue;
+                    }
+                    locked = Some(id);
+                }
+                CentralEvent::RssiUpdate { id, rssi } if locked.as_ref() == Some(&id) => {
+                    if rssi_tx.send((format!("{id:?}"), rssi)).is_err() {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+
+    enable_raw_mode()?;
+    let mut out = stdout();
+    execute!(out, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(out))?;
+
+    let mut track = RssiTrack::default();
+    let mut sender = String::from("waiting for a sender...");
+    loop {
+        while let Ok((id, rssi)) = rssi_rx.try_recv() {
+            sender = id;
+            track.push(rssi);
+        }
+
+        terminal.draw(|f| {
+            let rows = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(3), Constraint::Min(1)].as_ref())
+                .split(f.size());
+            let readout = Paragraph::new(track.summary()).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(sender.as_str()),
+            );
+            f.render_widget(readout, rows[0]);
+            // Sparkline wants unsigned values; shift the typical -100..0 dBm range.
+            let data: Vec<u64> = track
+                .history
+                .iter()
+                .map(|r| (*r as i64 + 100).max(0) as u64)
+                .collect();
+            let spark = Sparkline::default()
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("RSSI (any key to exit)"),
+                )
+                .data(&data)
+                .max(100);
+            f.render_widget(spark, rows[1]);
+        })?;
+
+        if event::poll(Duration::from_millis(100))?
+            && let CEvent::Key(_) = event::read()?
+        {
+            break;
+        }
+    }
+
+    disable_raw_mode()?;
+    execute!(std::io::stdout(), LeaveAlternateScreen)?;
+    println!("{sender}: {}", track.summary());
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::mesh::MeshNode;
+use crate::relay::Originated;
+use crate::stats::Stats;
+use crate::{Frame, MAX_PAYLOAD, MsgId, chunk_message, crypto, now_ms, pack_frame};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Topology {
+    /// 0 - 1 - 2 - ... - n-1
+    Line,
+    /// Node 0 in the middle, everyone else one hop out
+    Star,
+    /// Random spanning tree plus a few extra links
+    Random,
+}
+
+pub struct SimOpts {
+    pub nodes: usize,
+    pub topology: Topology,
+    /// Probability that any single neighbor misses a given advertisement.
+    pub loss: f64,
+    pub ttl: u8,
+    pub messages: usize,
+    pub seed: Option<u64>,
+    pub key: Option<crypto::Keys>,
+}
+
+/// Run a scripted exchange over in-process virtual nodes that share the real
+/// receive pipeline (`MeshNode`) and print per-node delivery statistics.
+pub fn simulate(opts: SimOpts) -> anyhow::Result<()> {
+    let n = opts.nodes.max(2);
+    let mut rng = opts
+        .seed
+        .map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+    let links = build_links(n, opts.topology, &mut rng);
+
+    let originated: Vec<Originated> = (0..n).map(|_| Originated::new()).collect();
+    let mut nodes: Vec<MeshNode> = originated
+        .iter()
+        .map(|o| {
+            MeshNode::new(
+                None,
+                true,
+                opts.key.iter().cloned().collect(),
+                Arc::new(Stats::default()),
+                None,
+                o.clone(),
+            )
+        })
+        .collect();
+
+    let mut received = vec![0usize; n];
+    let mut sent = vec![0usize; n];
+    let mut origin_of: HashMap<MsgId, usize> = HashMap::new();
+    // Advertisements in flight: (broadcasting node, manufacturer data).
+    let mut air: VecDeque<(usize, Vec<u8>)> = VecDeque::new();
+
+    for m in 0..opts.messages {
+        let origin = m % n;
+        let msg_id: MsgId = rng.r#gen();
+        originated[origin].mark(msg_id);
+        origin_of.insert(msg_id, origin);
+        let text = format!("message {m} from node {origin}");
+        for (seq, tot, payload) in chunk_message(text.as_bytes(), MAX_PAYLOAD)? {
+            let mut f = Frame {
+                topic: 0,
+                private: false,
+                plain: opts.key.is_none(),
+                control: None,
+                ttl: opts.ttl,
+                ttl0: opts.ttl,
+                flags: 0,
+                msg_id,
+                seq,
+                tot,
+                ts_ms: now_ms(),
+                payload,
+            };
+            if let Some(ref k) = opts.key {
+                f.payload = crypto::encrypt(&k.tx_key(), &f.nonce(), &f.aad(), &f.payload)?;
+            }
+            air.push_back((origin, pack_frame(&f)));
+        }
+
+        while let Some((from, md)) = air.pop_front() {
+            sent[from] += 1;
+            for &to in &links[from] {
+                if rng.r#gen::<f64>() < opts.loss {
+                    continue;
+                }
+                let handled = nodes[to].handle(&md);
+                if let Some(d) = handled.delivered
+                    && origin_of.get(&d.msg_id) != Some(&to)
+                {
+                    received[to] += 1;
+                }
+                if let Some(f) = handled.relay {
+                    air.push_back((to, pack_frame(&f)));
+                }
+            }
+        }
+    }
+
+    println!(
+        "{} nodes, {:?} topology, loss {:.0}%, ttl {}, {} messages",
+        n,
+        opts.topology,
+        opts.loss * 100.0,
+        opts.ttl,
+        opts.messages
+    );
+    println!("node  neighbors  received  advertised");
+    for (i, neighbors) in links.iter().enumerate() {
+        let expected = opts.messages - (0..opts.messages).filter(|m| m % n == i).count();
+        println!(
+            "{:>4}  {:>9}  {:>4}/{:<4}  {:>10}",
+            i,
+            neighbors.len(),
+            received[i],
+            expected,
+            sent[i]
+        );
+    }
+    let delivered: usize = received.iter().sum();
+    let possible = opts.messages * (n - 1);
+    println!(
+        "delivery rate {:.1}% ({}/{}), {} advertisements total",
+        100.0 * delivered as f64 / possible.max(1) as f64,
+        delivered,
+        possible,
+        sent.iter().sum::<usize>()
+    );
+    Ok(())
+}
+
+fn build_links(n: usize, topology: Topology, rng: &mut StdRng) -> Vec<Vec<usize>> {
+    let mut links = vec![Vec::new(); n];
+    let mut link = |a: usize, b: usize| {
+        if a != b && !links[a].contains(&b) {
+            links[a].push(b);
+            links[b].push(a);
+        }
+    };
+    match topology {
+        Topology::Line => (1..n).for_each(|i| link(i - 1, i)),
+        Topology::Star => (1..n).for_each(|i| link(0, i)),
+        Topology::Random => {
+            for i in 1..n {
+                let j = rng.gen_range(0..i);
+                link(i, j);
+            }
+            for _ in 0..n / 2 {
+                let (a, b) = (rng.gen_range(0..n), rng.gen_range(0..n));
+                link(a, b);
+            }
+        }
+    }
+    links
+}
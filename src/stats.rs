@@ -0,0 +1,273 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+const GOODPUT_WINDOW: Duration = Duration::from_secs(30);
+/// Weight of the newest sample in the smoothed `Density`.
+const DENSITY_ALPHA: f64 = 0.3;
+
+/// Counters shared between `rx_loop` and anything that reports on it.
+#[derive(Default)]
+pub struct Stats {
+    pub frames_rx: AtomicU64,
+    /// Frames passed on for relaying, suppressed ones included.
+    pub relayed: AtomicU64,
+    /// Relays skipped because a neighbour was heard relaying the frame first;
+    /// see `relay::Pending`.
+    pub relays_suppressed: AtomicU64,
+    /// Relays and acks not sent because `--max-relays` were already on air.
+    pub relays_dropped: AtomicU64,
+    /// Every frame or message dropped, for any reason; some are broken down
+    /// below.
+    pub dropped: AtomicU64,
+    /// Frames for a topic this node doesn't follow.
+    pub dropped_topic: AtomicU64,
+    /// Frames already seen within the duplicate window.
+    pub dropped_dup: AtomicU64,
+    pub decrypt_failures: AtomicU64,
+    /// Frames outside `--max-age-secs` or replaying a completed message.
+    pub stale: AtomicU64,
+    /// Messages reassembled.
+    pub completed: AtomicU64,
+    /// Identities heard from more than one node; see `identity::Duplicates`.
+    pub duplicate_identities: AtomicU64,
+    /// Partial messages given up on, by timeout or to make room.
+    pub evicted: AtomicU64,
+    pub in_flight: AtomicU64,
+    /// Relays waiting out their backoff or using the radio.
+    pub relay_queue: AtomicU64,
+    pub goodput: Goodput,
+    pub density: Density,
+}
+
+impl Stats {
+    pub fn inc(counter: &AtomicU64) {
+        Self::add(counter, 1);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn dec(gauge: &AtomicU64) {
+        gauge.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(gauge: &AtomicU64, v: u64) {
+        gauge.store(v, Ordering::Relaxed);
+    }
+
+    /// Fold the frames received so far into `density`; called once per
+    /// sweep of `rx_loop`.
+    pub fn sample_density(&self, now: Instant) {
+        self.density
+            .update(self.frames_rx.load(Ordering::Relaxed), now);
+    }
+
+    /// Every counter as of now, for `to_json` or a library user.
+    pub fn snapshot(&self) -> Snapshot {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        Snapshot {
+            frames_rx: get(&self.frames_rx),
+            relayed: get(&self.relayed),
+            relays_suppressed: get(&self.relays_suppressed),
+            relays_dropped: get(&self.relays_dropped),
+            dropped: get(&self.dropped),
+            dropped_topic: get(&self.dropped_topic),
+            dropped_dup: get(&self.dropped_dup),
+            decrypt_failures: get(&self.decrypt_failures),
+            stale: get(&self.stale),
+            completed: get(&self.completed),
+            duplicate_identities: get(&self.duplicate_identities),
+            evicted: get(&self.evicted),
+            in_flight: get(&self.in_flight),
+            relay_queue: get(&self.relay_queue),
+            goodput_bytes_per_sec: self.goodput.bytes_per_sec(),
+            density_frames_per_sec: self.density.frames_per_sec(),
+        }
+    }
+
+    /// `snapshot` as one line of JSON, as dumped on SIGUSR1.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.snapshot()).expect("plain numbers serialize")
+    }
+
+    /// One-line summary for `rx --stats` and the chat status bar.
+    pub fn summary(&self) -> String {
+        format!(
+            "goodput {:.1} B/s | density {:.1} frames/s | frames {} | relayed {} | dropped {} | decrypt fail {} | stale {} | in flight {}",
+            self.goodput.bytes_per_sec(),
+            self.density.frames_per_sec(),
+            self.frames_rx.load(Ordering::Relaxed),
+            self.relayed.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.decrypt_failures.load(Ordering::Relaxed),
+            self.stale.load(Ordering::Relaxed),
+            self.in_flight.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Print `stats` as JSON to stderr on every SIGUSR1, e.g. from
+/// `kill -USR1 <pid>` on a headless relay.
+#[cfg(unix)]
+pub(crate) async fn dump_on_sigusr1(stats: std::sync::Arc<Stats>) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let Ok(mut usr1) = signal(SignalKind::user_defined1()) else {
+        return;
+    };
+    while usr1.recv().await.is_some() {
+        eprintln!("{}", stats.to_json());
+    }
+}
+
+/// `Stats` read at one moment; see its fields for what each counts.
+#[derive(Clone, Debug, Serialize)]
+pub struct Snapshot {
+    pub frames_rx: u64,
+    pub relayed: u64,
+    pub relays_suppressed: u64,
+    pub relays_dropped: u64,
+    pub dropped: u64,
+    pub dropped_topic: u64,
+    pub dropped_dup: u64,
+    pub decrypt_failures: u64,
+    pub stale: u64,
+    pub completed: u64,
+    pub duplicate_identities: u64,
+    pub evicted: u64,
+    pub in_flight: u64,
+    pub relay_queue: u64,
+    pub goodput_bytes_per_sec: f64,
+    pub density_frames_per_sec: f64,
+}
+
+/// Useful throughput: bytes of completed messages per second of wall time
+/// over the last `GOODPUT_WINDOW`. Headers, AEAD tags, repeats, duplicates and
+/// messages that never complete all count against it, since none of them show
+/// up here.
+pub struct Goodput {
+    started: Instant,
+    samples: Mutex<VecDeque<(Instant, usize)>>,
+}
+
+impl Default for Goodput {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            samples: Mutex::default(),
+        }
+    }
+}
+
+impl Goodput {
+    pub fn record(&self, bytes: usize) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back((now, bytes));
+        prune(&mut samples, now);
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        prune(&mut samples, now);
+        // Until a full window has passed, divide by the time we've actually
+        // been listening rather than understating the rate.
+        let span = now.duration_since(self.started).min(GOODPUT_WINDOW);
+        let bytes: usize = samples.iter().map(|(_, b)| b).sum();
+        bytes as f64 / span.as_secs_f64().max(1.0)
+    }
+}
+
+/// Chirp frames heard per second, smoothed over recent samples of
+/// `Stats::frames_rx`. Stands in for how many neighbours are transmitting.
+#[derive(Default)]
+pub struct Density(Mutex<DensityState>);
+
+#[derive(Default)]
+struct DensityState {
+    last: Option<(Instant, u64)>,
+    rate: f64,
+}
+
+impl Density {
+    /// Fold in the frame count as of `now`; call at a steady interval.
+    pub fn update(&self, frames_rx: u64, now: Instant) {
+        let mut s = self.0.lock().unwrap();
+        if let Some((at, frames)) = s.last {
+            let secs = now.duration_since(at).as_secs_f64();
+            if secs > 0.0 {
+                let sample = frames_rx.saturating_sub(frames) as f64 / secs;
+                s.rate += DENSITY_ALPHA * (sample - s.rate);
+            }
+        }
+        s.last = Some((now, frames_rx));
+    }
+
+    pub fn frames_per_sec(&self) -> f64 {
+        self.0.lock().unwrap().rate
+    }
+}
+
+fn prune(samples: &mut VecDeque<(Instant, usize)>, now: Instant) {
+    while samples
+        .front()
+        .is_some_and(|(t, _)| now.duration_since(*t) > GOODPUT_WINDOW)
+    {
+        samples.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_snapshot_reads_every_counter() {
+        let stats = Stats::default();
+        Stats::add(&stats.frames_rx, 5);
+        Stats::inc(&stats.relayed);
+        Stats::inc(&stats.relays_dropped);
+        Stats::add(&stats.dropped, 3);
+        Stats::inc(&stats.dropped_topic);
+        Stats::inc(&stats.completed);
+        Stats::set(&stats.in_flight, 2);
+        Stats::dec(&stats.in_flight);
+        stats.goodput.record(100);
+
+        let s = stats.snapshot();
+        assert_eq!((s.frames_rx, s.relayed, s.relays_dropped), (5, 1, 1));
+        assert_eq!((s.dropped, s.dropped_topic, s.dropped_dup), (3, 1, 0));
+        assert_eq!((s.completed, s.in_flight, s.relay_queue), (1, 1, 0));
+        // Less than a second in, so over one second rather than less.
+        assert_eq!(s.goodput_bytes_per_sec, 100.0);
+
+        let json: serde_json::Value = serde_json::from_str(&stats.to_json()).unwrap();
+        let fields = json.as_object().unwrap();
+        assert_eq!(fields.len(), 16);
+        assert_eq!(json["frames_rx"], 5);
+        assert_eq!(json["relays_dropped"], 1);
+        assert_eq!(json["goodput_bytes_per_sec"], 100.0);
+        assert!(!stats.to_json().contains('\n'));
+    }
+
+    #[test]
+    fn density_follows_the_frame_rate_smoothly() {
+        let density = Density::default();
+        let t0 = Instant::now();
+        density.update(0, t0);
+        assert_eq!(density.frames_per_sec(), 0.0);
+        density.update(10, t0 + Duration::from_secs(1));
+        assert!((density.frames_per_sec() - 3.0).abs() < 1e-9);
+        // A quiet second pulls it back down, but not to zero.
+        density.update(10, t0 + Duration::from_secs(2));
+        assert!((density.frames_per_sec() - 2.1).abs() < 1e-9);
+        // A repeated instant is not a sample.
+        density.update(50, t0 + Duration::from_secs(2));
+        assert!((density.frames_per_sec() - 2.1).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,328 @@
+//! The radio, reduced to what sending, receiving and relaying need: an
+//! advertiser that puts one payload at a time on the air, a scan of
+//! everyone else's, and a way to find the radio again after it goes away.
+//! `tx` and `rx_loop` are generic over it, so the whole pipeline can run
+//! over `LoopbackTransport` instead of real hardware.
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use btleplug::api::CentralEvent;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::sleep;
+
+use crate::{COMPANY_ID, adapter, scan_events};
+
+/// Advertisements buffered per `LoopbackTransport` listener before the
+/// oldest are dropped.
+const LOOPBACK_CAP: usize = 1024;
+
+/// Chirp manufacturer data heard on the air, with who sent it.
+pub type Heard<S> = BoxStream<'static, (S, Vec<u8>)>;
+
+pub trait Transport: Clone + Send + Sync + 'static {
+    /// Who an advertisement was heard from.
+    type Source: Clone + Debug + Eq + Hash + Send + Sync + 'static;
+    type Advertiser: Advertiser;
+    /// What `rebind` needs to find this transport again.
+    type Identity: Send + Sync + 'static;
+
+    /// A handle to advertise with, one payload at a time.
+    fn advertiser(&self) -> impl Future<Output = anyhow::Result<Self::Advertiser>> + Send;
+
+    /// Start scanning. The stream ends if the scan does; scan again to
+    /// recover.
+    fn scan(&self) -> impl Future<Output = anyhow::Result<Heard<Self::Source>>> + Send;
+
+    fn identity(&self) -> impl Future<Output = Self::Identity> + Send;
+
+    /// Wait for the radio `id` names to come back, after it has stopped
+    /// working, and return a fresh handle to it.
+    fn rebind(id: &Self::Identity) -> impl Future<Output = anyhow::Result<Self>> + Send;
+
+    /// Advertise `md` (chirp manufacturer data, i.e. a packed frame) for
+    /// `dwell`, then stop.
+    fn advertise(
+        &self,
+        md: Vec<u8>,
+        dwell: Duration,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async move {
+            let mut adv = self.advertiser().await?;
+            adv.start(md).await?;
+            sleep(dwell).await;
+            let _ = adv.stop().await;
+            Ok(())
+        }
+    }
+
+    /// Chirp manufacturer data of every advertisement heard from now on. A
+    /// scan that fails to start is logged and yields an empty stream.
+    fn events(&self) -> BoxStream<'static, Vec<u8>> {
+        let transport = self.clone();
+        futures::stream::once(async move { transport.scan().await })
+            .filter_map(|scan| async move {
+                scan.map_err(|e| tracing::warn!(error = %e, "scan failed"))
+                    .ok()
+            })
+            .flatten()
+            .map(|(_, md)| md)
+            .boxed()
+    }
+
+    /// Whether `advertise` can work at all, checked without advertising.
+    fn can_advertise(&self) -> impl Future<Output = bool> + Send {
+        async move { self.advertiser().await.is_ok() }
+    }
+}
+
+/// One advertising slot.
+pub trait Advertiser: Send {
+    /// Put `md` on the air, replacing whatever this advertiser already had.
+    fn start(&mut self, md: Vec<u8>) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    fn stop(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// Fail with the likely causes if `transport` can't advertise, so a sender
+/// stops before it starts rather than at its first frame.
+pub async fn ensure_can_advertise<T: Transport>(transport: &T) -> anyhow::Result<()> {
+    if transport.can_advertise().await {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "this adapter can't advertise, so nothing can be sent. Likely causes: the OS \
+         (btleplug can't advertise on macOS); permissions (on Linux, BlueZ needs you in the \
+         `bluetooth` group, or CAP_NET_ADMIN); or an adapter without peripheral mode. \
+         Receiving still works; `list-adapters` shows which adapters can advertise"
+    )
+}
+
+impl Transport for btleplug::platform::Adapter {
+    type Source = btleplug::platform::PeripheralId;
+    type Advertiser = btleplug::platform::Peripheral;
+    type Identity = String;
+
+    /// Only macOS is known not to advertise up front; elsewhere, getting a
+    /// peripheral handle is where a missing adapter or permissions show up.
+    async fn advertiser(&self) -> anyhow::Result<Self::Advertiser> {
+        #[cfg(target_os = "macos")]
+        {
+            anyhow::bail!("advertising not supported on macOS via btleplug");
+        }
+
+        #[cfg(not(target_os = "macos"))]
+        {
+            use anyhow::Context;
+            self.peripheral().await.context("create peripheral")
+        }
+    }
+
+    async fn scan(&self) -> anyhow::Result<Heard<Self::Source>> {
+        Ok(scan_events(self)
+            .await?
+            .filter_map(|evt| async move {
+                match evt {
+                    CentralEvent::ManufacturerDataAdvertisement {
+                        id,
+                        mut manufacturer_data,
+                    } => manufacturer_data.remove(&COMPANY_ID).map(|md| (id, md)),
+                    _ => None,
+                }
+            })
+            .boxed())
+    }
+
+    async fn identity(&self) -> String {
+        adapter::identity(self).await
+    }
+
+    async fn rebind(id: &String) -> anyhow::Result<Self> {
+        adapter::rebind(id).await
+    }
+}
+
+/// Never reached on macOS, where `advertiser` fails.
+#[cfg(target_os = "macos")]
+impl Advertiser for btleplug::platform::Peripheral {
+    async fn start(&mut self, _md: Vec<u8>) -> anyhow::Result<()> {
+        anyhow::bail!("advertising not supported on macOS via btleplug")
+    }
+
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+impl Advertiser for btleplug::platform::Peripheral {
+    async fn start(&mut self, md: Vec<u8>) -> anyhow::Result<()> {
+        use btleplug::api::{AdvertisementData, AdvertisingOptions};
+        let adv = AdvertisementData {
+            local_name: Some("chirp".into()),
+            manufacturer_data: Some([(COMPANY_ID, md)].into()),
+            service_data: None,
+            services: None,
+            appearance: None,
+            tx_power_level: None,
+            solicited_services: None,
+        };
+        self.start_advertising(adv, AdvertisingOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        self.stop_advertising().await?;
+        Ok(())
+    }
+}
+
+/// An in-memory radio. Each `LoopbackTransport::new` is a separate air;
+/// `peer` adds another node to it, and an advertisement from any node
+/// reaches every other node on the same air. Clones are the same node.
+/// There is no loss or range; drop or delay frames in the listener to model
+/// them.
+#[derive(Clone)]
+pub struct LoopbackTransport {
+    air: broadcast::Sender<(usize, Vec<u8>)>,
+    nodes: Arc<AtomicUsize>,
+    id: usize,
+}
+
+impl LoopbackTransport {
+    pub fn new() -> Self {
+        let (air, _) = broadcast::channel(LOOPBACK_CAP);
+        Self {
+            air,
+            nodes: Arc::new(AtomicUsize::new(1)),
+            id: 0,
+        }
+    }
+
+    /// Another node on the same air.
+    pub fn peer(&self) -> Self {
+        Self {
+            air: self.air.clone(),
+            nodes: self.nodes.clone(),
+            id: self.nodes.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for LoopbackTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for LoopbackTransport {
+    /// The sending node's index on its air, in order of `peer` calls.
+    type Source = usize;
+    type Advertiser = LoopbackTransport;
+    type Identity = LoopbackTransport;
+
+    async fn advertiser(&self) -> anyhow::Result<Self> {
+        Ok(self.clone())
+    }
+
+    async fn scan(&self) -> anyhow::Result<Heard<usize>> {
+        let me = self.id;
+        Ok(
+            futures::stream::unfold(self.air.subscribe(), move |mut rx| async move {
+                loop {
+                    match rx.recv().await {
+                        // A radio doesn't hear its own advertisements.
+                        Ok((from, _)) if from == me => continue,
+                        Ok(heard) => return Some((heard, rx)),
+                        // Like a scanner that missed some advertisements.
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            })
+            .boxed(),
+        )
+    }
+
+    async fn identity(&self) -> Self {
+        self.clone()
+    }
+
+    async fn rebind(id: &Self) -> anyhow::Result<Self> {
+        Ok(id.clone())
+    }
+}
+
+/// Sends each payload once, when it starts; stopping is a no-op.
+impl Advertiser for LoopbackTransport {
+    async fn start(&mut self, md: Vec<u8>) -> anyhow::Result<()> {
+        // No listeners is not an error: nobody was in range.
+        let _ = self.air.send((self.id, md));
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A radio that can scan but not advertise, like a Mac or an adapter
+    /// without peripheral mode.
+    #[derive(Clone)]
+    struct ReceiveOnly(LoopbackTransport);
+
+    impl Transport for ReceiveOnly {
+        type Source = usize;
+        type Advertiser = LoopbackTransport;
+        type Identity = ReceiveOnly;
+
+        async fn advertiser(&self) -> anyhow::Result<LoopbackTransport> {
+            anyhow::bail!("peripheral mode not supported")
+        }
+
+        async fn scan(&self) -> anyhow::Result<Heard<usize>> {
+            self.0.scan().await
+        }
+
+        async fn identity(&self) -> Self {
+            self.clone()
+        }
+
+        async fn rebind(id: &Self) -> anyhow::Result<Self> {
+            Ok(id.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn a_radio_that_cannot_advertise_is_refused_up_front() {
+        let air = LoopbackTransport::new();
+        assert!(ensure_can_advertise(&air).await.is_ok());
+
+        let rx_only = ReceiveOnly(air.peer());
+        assert!(!rx_only.can_advertise().await);
+        let err = ensure_can_advertise(&rx_only)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("this adapter can't advertise"), "{err}");
+        assert!(err.contains("list-adapters"), "{err}");
+
+        // Sending would have failed at the first frame instead.
+        let err = rx_only
+            .advertise(vec![1], Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "peripheral mode not supported");
+    }
+}